@@ -1,7 +1,8 @@
 use clap::Args;
 use poorly::{
     core::{
-        schema::Columns,
+        database::DEFAULT_DB,
+        schema::{unquote_identifier, ColumnDefault, Columns, Defaults},
         types::{ColumnSet, DataType, TypedValue},
     },
     grpc::proto,
@@ -17,6 +18,17 @@ pub enum Command {
         from: String,
         columns: Vec<String>,
         conditions: ColumnSet,
+        max_rows: Option<u64>,
+    },
+    SelectOne {
+        db: String,
+        from: String,
+        conditions: ColumnSet,
+    },
+    Count {
+        db: String,
+        from: String,
+        conditions: ColumnSet,
     },
     Insert {
         db: String,
@@ -33,11 +45,16 @@ pub enum Command {
         db: String,
         from: String,
         conditions: ColumnSet,
+        compact: bool,
     },
     Create {
         db: String,
         table: String,
         columns: Columns,
+        defaults: Defaults,
+        compressed: bool,
+        segment_size: Option<u64>,
+        if_not_exists: bool,
     },
     CreateDb {
         name: String,
@@ -45,10 +62,16 @@ pub enum Command {
     Drop {
         db: String,
         table: String,
+        if_exists: bool,
     },
     DropDb {
         name: String,
     },
+    ConvertDb {
+        name: String,
+        to_kind: String,
+        swap: bool,
+    },
     Alter {
         db: String,
         table: String,
@@ -57,6 +80,19 @@ pub enum Command {
     ShowTables {
         db: String,
     },
+    ShowCreate {
+        db: String,
+        table: String,
+    },
+    DescribeDb {
+        db: String,
+    },
+    Comment {
+        db: String,
+        table: String,
+        column: Option<String>,
+        text: String,
+    },
     Join {
         db: String,
         table1: String,
@@ -64,6 +100,69 @@ pub enum Command {
         columns: Vec<String>,
         conditions: ColumnSet,
         join_on: HashMap<String, String>,
+        max_rows: Option<u64>,
+    },
+    Repair {
+        db: String,
+    },
+    CheckIntegrity {
+        db: String,
+    },
+    ReloadSchema {
+        db: String,
+    },
+    SelectDeleted {
+        db: String,
+        from: String,
+        conditions: ColumnSet,
+    },
+    Undelete {
+        db: String,
+        table: String,
+        offset: u64,
+    },
+    Sample {
+        db: String,
+        table: String,
+        n: u64,
+    },
+    First {
+        db: String,
+        from: String,
+        column: String,
+        descending: bool,
+    },
+    CopyInto {
+        db: String,
+        src: String,
+        dst: String,
+        conditions: ColumnSet,
+        column_map: HashMap<String, String>,
+    },
+    CreateAs {
+        db: String,
+        table: String,
+        select: Box<Command>,
+    },
+    Vacuum {
+        db: String,
+        table: String,
+    },
+    Flush {
+        db: String,
+        close: bool,
+    },
+    Snapshot {
+        db: String,
+    },
+    ReleaseSnapshot {
+        snapshot: u64,
+    },
+    DeleteKeys {
+        db: String,
+        from: String,
+        column: String,
+        keys: Vec<TypedValue>,
     },
 }
 
@@ -71,79 +170,180 @@ impl FromStr for Command {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.trim().split_whitespace().collect();
+        // Comment <db> <table>[.<column>] [<text>], the text is the rest of
+        // the line and may contain spaces
+        if let Some(rest) = s.trim().strip_prefix("Comment ") {
+            let (db, rest) = rest
+                .trim_start()
+                .split_once(' ')
+                .ok_or_else(|| anyhow::anyhow!("Comment needs a database and a table"))?;
+            let rest = rest.trim_start();
+            let (target, text) = split_once_unquoted(rest, ' ').unwrap_or((rest, ""));
+            let (table, column) = match split_once_unquoted(target, '.') {
+                Some((table, column)) => (table, Some(unquote_identifier(column))),
+                None => (target, None),
+            };
+            return Ok(Command::Comment {
+                db: db.to_string(),
+                table: unquote_identifier(table),
+                column,
+                text: text.to_string(),
+            });
+        }
+
+        let parts: Vec<&str> = split_unquoted(s.trim(), char::is_whitespace)
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect();
 
         match parts.as_slice() {
-            ["Select", db, from, columns, conditions] => {
+            // Select <db> <table> <columns> <conditions> [max_rows=<n>]
+            ["Select", db, from, columns, conditions, options @ ..] => {
                 // Parse and construct Select variant
 
-                let columns = columns.split(',').map(|s| s.to_string()).collect();
-                let conditions = conditions
-                    .split(',')
-                    .map(|s| parse_key_val::<TypedValue>(s))
-                    .collect::<Result<_, _>>()?;
+                let columns = parse_names(columns);
+                let conditions = parse_conditions(conditions)?;
 
                 Ok(Command::Select {
                     db: db.to_string(),
-                    from: from.to_string(),
+                    from: unquote_identifier(from),
                     columns,
                     conditions,
+                    max_rows: parse_max_rows(options)?,
+                })
+            }
+            ["SelectOne", db, from, conditions] => {
+                // Parse and construct SelectOne variant
+                let conditions = parse_conditions(conditions)?;
+
+                Ok(Command::SelectOne {
+                    db: db.to_string(),
+                    from: unquote_identifier(from),
+                    conditions,
                 })
             }
+            // count [<db>] <table> [where <conditions>]
+            ["Count" | "count", from] => Ok(Command::Count {
+                db: DEFAULT_DB.to_string(),
+                from: unquote_identifier(from),
+                conditions: HashMap::new(),
+            }),
+            ["Count" | "count", from, "where", conditions] => Ok(Command::Count {
+                db: DEFAULT_DB.to_string(),
+                from: unquote_identifier(from),
+                conditions: parse_conditions(conditions)?,
+            }),
+            ["Count" | "count", db, from] => Ok(Command::Count {
+                db: db.to_string(),
+                from: unquote_identifier(from),
+                conditions: HashMap::new(),
+            }),
+            ["Count" | "count", db, from, "where", conditions] => Ok(Command::Count {
+                db: db.to_string(),
+                from: unquote_identifier(from),
+                conditions: parse_conditions(conditions)?,
+            }),
+            ["SelectDeleted", db, from] => Ok(Command::SelectDeleted {
+                db: db.to_string(),
+                from: unquote_identifier(from),
+                conditions: HashMap::new(),
+            }),
+            ["SelectDeleted", db, from, conditions] => Ok(Command::SelectDeleted {
+                db: db.to_string(),
+                from: unquote_identifier(from),
+                conditions: parse_conditions(conditions)?,
+            }),
+            // sample [<db>] <table> <n>
+            ["Sample" | "sample", table, n] => Ok(Command::Sample {
+                db: DEFAULT_DB.to_string(),
+                table: unquote_identifier(table),
+                n: n.parse()?,
+            }),
+            ["Sample" | "sample", db, table, n] => Ok(Command::Sample {
+                db: db.to_string(),
+                table: unquote_identifier(table),
+                n: n.parse()?,
+            }),
+            // First <db> <table> <column> [desc]
+            ["First", db, from, column, options @ ..] if matches!(options, [] | ["desc"]) => {
+                Ok(Command::First {
+                    db: db.to_string(),
+                    from: unquote_identifier(from),
+                    column: unquote_identifier(column),
+                    descending: !options.is_empty(),
+                })
+            }
+            ["Undelete", db, table, offset] => Ok(Command::Undelete {
+                db: db.to_string(),
+                table: unquote_identifier(table),
+                offset: offset.parse()?,
+            }),
             ["Insert", db, into, values] => {
                 // Parse and construct Insert variant
-                let values = values
-                    .split(',')
-                    .map(|s| parse_key_val::<TypedValue>(s))
-                    .collect::<Result<_, _>>()?;
+                let values = parse_conditions(values)?;
                 Ok(Command::Insert {
                     db: db.to_string(),
-                    into: into.to_string(),
+                    into: unquote_identifier(into),
                     values,
                 })
             }
             ["Update", db, table, set, conditions] => {
                 // Parse and construct Update variant
-                let set = set
-                    .split(',')
-                    .map(|s| parse_key_val::<TypedValue>(s))
-                    .collect::<Result<_, _>>()?;
-                let conditions = conditions
-                    .split(',')
-                    .map(|s| parse_key_val::<TypedValue>(s))
-                    .collect::<Result<_, _>>()?;
+                let set = parse_conditions(set)?;
+                let conditions = parse_conditions(conditions)?;
 
                 Ok(Command::Update {
                     db: db.to_string(),
-                    table: table.to_string(),
+                    table: unquote_identifier(table),
                     set,
                     conditions,
                 })
             }
-            ["Delete", db, from, conditions] => {
+            // Delete <db> <table> <conditions> [compact]
+            ["Delete", db, from, conditions, options @ ..]
+                if matches!(options, [] | ["compact"]) =>
+            {
                 // Parse and construct Delete variant
-                let conditions = conditions
-                    .split(',')
-                    .map(|s| parse_key_val::<TypedValue>(s))
-                    .collect::<Result<_, _>>()?;
+                let conditions = parse_conditions(conditions)?;
 
                 Ok(Command::Delete {
                     db: db.to_string(),
-                    from: from.to_string(),
+                    from: unquote_identifier(from),
                     conditions,
+                    compact: !options.is_empty(),
                 })
             }
-            ["Create", db, table, columns] => {
+            // Create <db> <table> <columns> [compressed] [segment=<bytes>] [if_not_exists]
+            ["Create", db, table, columns, options @ ..] => {
                 // Parse and construct Create variant
-                let columns = columns
-                    .split(',')
-                    .map(|s| parse_key_val::<DataType>(s))
-                    .collect::<Result<_, _>>()?;
+                let mut defaults = HashMap::new();
+                let columns = split_unquoted(columns, |c| c == ',')
+                    .into_iter()
+                    .map(|s| {
+                        let (column, default) = parse_column(s)?;
+                        if let Some(default) = default {
+                            defaults.insert(column.0.clone(), default);
+                        }
+                        Ok(column)
+                    })
+                    .collect::<Result<_, anyhow::Error>>()?;
+                let mut segment_size = None;
+                for option in options {
+                    if let Some(size) = option.strip_prefix("segment=") {
+                        segment_size = Some(size.parse()?);
+                    } else if !["compressed", "if_not_exists"].contains(option) {
+                        return Err(anyhow::anyhow!("invalid create option: {}", option));
+                    }
+                }
 
                 Ok(Command::Create {
                     db: db.to_string(),
-                    table: table.to_string(),
+                    table: unquote_identifier(table),
                     columns,
+                    defaults,
+                    compressed: options.contains(&"compressed"),
+                    segment_size,
+                    if_not_exists: options.contains(&"if_not_exists"),
                 })
             }
             ["CreateDb", name] => {
@@ -152,11 +352,13 @@ impl FromStr for Command {
                     name: name.to_string(),
                 })
             }
-            ["Drop", db, table] => {
+            // Drop <db> <table> [if_exists]
+            ["Drop", db, table, options @ ..] if matches!(options, [] | ["if_exists"]) => {
                 // Parse and construct Drop variant
                 Ok(Command::Drop {
                     db: db.to_string(),
-                    table: table.to_string(),
+                    table: unquote_identifier(table),
+                    if_exists: !options.is_empty(),
                 })
             }
             ["DropDb", name] => {
@@ -165,16 +367,21 @@ impl FromStr for Command {
                     name: name.to_string(),
                 })
             }
+            // ConvertDb <name> <poorly|sqlite> [swap]
+            ["ConvertDb", name, to_kind, options @ ..] if matches!(options, [] | ["swap"]) => {
+                Ok(Command::ConvertDb {
+                    name: name.to_string(),
+                    to_kind: to_kind.to_string(),
+                    swap: !options.is_empty(),
+                })
+            }
             ["Alter", db, table, rename] => {
                 // Parse and construct Alter variant
-                let rename = rename
-                    .split(',')
-                    .map(|s| parse_key_val::<String>(s))
-                    .collect::<Result<_, _>>()?;
+                let rename = parse_pairs(rename)?;
 
                 Ok(Command::Alter {
                     db: db.to_string(),
-                    table: table.to_string(),
+                    table: unquote_identifier(table),
                     rename,
                 })
             }
@@ -182,50 +389,260 @@ impl FromStr for Command {
                 // Parse and construct ShowTables variant
                 Ok(Command::ShowTables { db: db.to_string() })
             }
-            ["Join", db, table1, table2, columns, conditions, join_on] => {
+            ["ShowCreate", db, table] => Ok(Command::ShowCreate {
+                db: db.to_string(),
+                table: unquote_identifier(table),
+            }),
+            ["DescribeDb", db] => Ok(Command::DescribeDb { db: db.to_string() }),
+            ["Repair", db] => {
+                // Parse and construct Repair variant
+                Ok(Command::Repair { db: db.to_string() })
+            }
+            ["CheckIntegrity", db] => Ok(Command::CheckIntegrity { db: db.to_string() }),
+            // DeleteKeys <db> <table> <column> <key>,<key>,...
+            ["DeleteKeys", db, from, column, keys] => Ok(Command::DeleteKeys {
+                db: db.to_string(),
+                from: unquote_identifier(from),
+                column: unquote_identifier(column),
+                keys: keys
+                    .split(',')
+                    .map(TypedValue::try_from)
+                    .collect::<Result<_, _>>()?,
+            }),
+            // Flush <db> [close]
+            ["Flush", db] => Ok(Command::Flush {
+                db: db.to_string(),
+                close: false,
+            }),
+            ["Flush", db, "close"] => Ok(Command::Flush {
+                db: db.to_string(),
+                close: true,
+            }),
+            // Snapshot <db>
+            ["Snapshot", db] => Ok(Command::Snapshot { db: db.to_string() }),
+            // ReleaseSnapshot <id>
+            ["ReleaseSnapshot", snapshot] => Ok(Command::ReleaseSnapshot {
+                snapshot: snapshot.parse()?,
+            }),
+            ["Vacuum", db, table] => Ok(Command::Vacuum {
+                db: db.to_string(),
+                table: unquote_identifier(table),
+            }),
+            ["ReloadSchema", db] => {
+                // Parse and construct ReloadSchema variant
+                Ok(Command::ReloadSchema { db: db.to_string() })
+            }
+            // Join <db> <table1> <table2> <columns> <conditions> <join_on> [max_rows=<n>]
+            ["Join", db, table1, table2, columns, conditions, join_on, options @ ..] => {
                 // Parse and construct Join variant
-                let columns = columns.split(',').map(|s| s.to_string()).collect();
+                let columns = parse_names(columns);
                 let conditions = if conditions != &"_" {
-                    conditions
-                        .split(',')
-                        .map(|s| parse_key_val::<TypedValue>(s))
-                        .collect::<Result<_, _>>()?
+                    parse_conditions(conditions)?
                 } else {
                     HashMap::new()
                 };
 
-                let join_on = join_on
-                    .split(',')
-                    .map(|s| parse_key_val::<String>(s))
-                    .collect::<Result<_, _>>()?;
+                let join_on = parse_pairs(join_on)?;
 
                 Ok(Command::Join {
                     db: db.to_string(),
-                    table1: table1.to_string(),
-                    table2: table2.to_string(),
+                    table1: unquote_identifier(table1),
+                    table2: unquote_identifier(table2),
                     columns,
                     conditions,
                     join_on,
+                    max_rows: parse_max_rows(options)?,
+                })
+            }
+            // CopyInto <db> <src> <dst> <conditions|_> [<column map>]
+            ["CopyInto", db, src, dst, conditions, column_map @ ..] if column_map.len() <= 1 => {
+                let conditions = if conditions != &"_" {
+                    parse_conditions(conditions)?
+                } else {
+                    HashMap::new()
+                };
+                let column_map = match column_map {
+                    [column_map] => parse_pairs(column_map)?,
+                    _ => HashMap::new(),
+                };
+
+                Ok(Command::CopyInto {
+                    db: db.to_string(),
+                    src: unquote_identifier(src),
+                    dst: unquote_identifier(dst),
+                    conditions,
+                    column_map,
                 })
             }
+            // CreateAs <db> <table> <Select or Join command>
+            ["CreateAs", db, table, select @ ..] if !select.is_empty() => Ok(Command::CreateAs {
+                db: db.to_string(),
+                table: unquote_identifier(table),
+                select: Box::new(select.join(" ").parse()?),
+            }),
             // Add more patterns for other variants
             _ => Err(anyhow::anyhow!("invalid command: {}", s)),
         }
     }
 }
 
+impl Command {
+    /// Parses a command followed by an optional `using <value>,...` clause.
+    /// With one, values written as `?` or `$N` are placeholders for those
+    /// parameters, which the server binds positionally (`?`s are numbered
+    /// left to right). Without one they are taken as they are.
+    pub fn parse(line: &str) -> Result<(Self, Option<Vec<TypedValue>>), anyhow::Error> {
+        let parts: Vec<&str> = split_unquoted(line, char::is_whitespace)
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect();
+        let (command, params) = match parts.iter().position(|part| *part == "using") {
+            Some(pos) => (&parts[..pos], Some(parts[pos + 1..].join(" "))),
+            None => (&parts[..], None),
+        };
+        let Some(params) = params else {
+            return Ok((Command::from_str(&command.join(" "))?, None));
+        };
+
+        let mut placeholders = 0;
+        let command: Vec<String> = command
+            .iter()
+            .map(|part| {
+                split_unquoted(part, |c| c == ',')
+                    .into_iter()
+                    .map(|item| match item.strip_suffix("=?") {
+                        Some(key) => {
+                            placeholders += 1;
+                            format!("{}=${}", key, placeholders)
+                        }
+                        None => item.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect();
+
+        let params = params
+            .split(',')
+            .filter(|param| !param.is_empty())
+            .map(TypedValue::from)
+            .collect();
+
+        Ok((Command::from_str(&command.join(" "))?, Some(params)))
+    }
+
+    /// The query of a command with a `using` clause, sent with `params`
+    pub fn with_params(self, params: Vec<TypedValue>) -> proto::Query {
+        let mut query = self.into_query(true);
+        query.params = params.into_iter().map(Into::into).collect();
+        query
+    }
+}
+
+// With `placeholders`, values like `$2` stand for the second query parameter
+fn to_proto(value: TypedValue, placeholders: bool) -> proto::TypedValue {
+    if let (true, TypedValue::String(s)) = (placeholders, &value) {
+        if let Some(n) = s.strip_prefix('$').and_then(|n| n.parse().ok()) {
+            return proto::TypedValue {
+                data: Some(proto::typed_value::Data::Param(n)),
+            };
+        }
+    }
+    value.into()
+}
+
+// Splits `s` at every character `delimiter` matches, except inside double
+// quotes, so quoted names can hold spaces, commas and the like
+fn split_unquoted(s: &str, delimiter: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && delimiter(c) {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// Like `str::split_once`, except inside double quotes
+fn split_once_unquoted(s: &str, delimiter: char) -> Option<(&str, &str)> {
+    let first = split_unquoted(s, |c| c == delimiter)[0];
+    (first.len() < s.len()).then(|| (first, &s[first.len() + delimiter.len_utf8()..]))
+}
+
+/// Parse comma-separated names, which may be quoted like `"first name"`
+fn parse_names(s: &str) -> Vec<String> {
+    split_unquoted(s, |c| c == ',')
+        .into_iter()
+        .map(unquote_identifier)
+        .collect()
+}
+
+/// Parse comma-separated `column=value` conditions
+fn parse_conditions(s: &str) -> Result<ColumnSet, anyhow::Error> {
+    split_unquoted(s, |c| c == ',')
+        .into_iter()
+        .map(parse_key_val::<TypedValue>)
+        .collect()
+}
+
+/// Parse comma-separated `name=name` pairs, like renamed or joined columns
+fn parse_pairs(s: &str) -> Result<HashMap<String, String>, anyhow::Error> {
+    split_unquoted(s, |c| c == ',')
+        .into_iter()
+        .map(|pair| {
+            let (key, value) = parse_key_val::<String>(pair)?;
+            Ok((key, unquote_identifier(&value)))
+        })
+        .collect()
+}
+
+/// Parse a `column=type[:default=now|:auto_update|:auto_increment]` column of `Create`
+fn parse_column(s: &str) -> Result<((String, DataType), Option<ColumnDefault>), anyhow::Error> {
+    let (column, spec) = split_once_unquoted(s, '=')
+        .ok_or_else(|| anyhow::anyhow!("invalid column=type: no `=` found in `{}`", s))?;
+    let (data_type, default) = match spec.split_once(':') {
+        Some((data_type, attribute)) => {
+            let default = ColumnDefault::from_attribute(attribute)
+                .ok_or_else(|| anyhow::anyhow!("invalid column attribute: {}", attribute))?;
+            (data_type, Some(default))
+        }
+        None => (spec, None),
+    };
+    Ok((
+        (unquote_identifier(column), DataType::try_from(data_type)?),
+        default,
+    ))
+}
+
+/// Parse the `max_rows=<n>` option of a select or join, the only one they have
+fn parse_max_rows(options: &[&str]) -> Result<Option<u64>, anyhow::Error> {
+    match options {
+        [] => Ok(None),
+        [option] => match option.strip_prefix("max_rows=") {
+            Some(max_rows) => Ok(Some(max_rows.parse()?)),
+            None => Err(anyhow::anyhow!("invalid option: {}", option)),
+        },
+        _ => Err(anyhow::anyhow!("too many options: {}", options.join(" "))),
+    }
+}
+
 /// Parse a single key-value pair
 fn parse_key_val<'a, T>(s: &'a str) -> Result<(String, T), anyhow::Error>
 where
     T: TryFrom<&'a str>,
     <T as TryFrom<&'a str>>::Error: Error + 'static,
 {
-    let pos = s
-        .find('=')
+    let (key, value) = split_once_unquoted(s, '=')
         .ok_or_else(|| anyhow::anyhow!("invalid key=value: no `=` found in `{}`", s))?;
     Ok((
-        s[..pos].to_string(),
-        s[pos + 1..]
+        unquote_identifier(key),
+        value
             .try_into()
             .map_err(|_| anyhow::anyhow!("cannot convert"))?,
     ))
@@ -233,83 +650,172 @@ where
 
 impl From<Command> for proto::Query {
     fn from(command: Command) -> Self {
-        macro_rules! parse_key_val {
+        command.into_query(false)
+    }
+}
+
+impl Command {
+    fn into_query(self, placeholders: bool) -> proto::Query {
+        // Converts key-value pairs, `$N` values may become placeholders
+        macro_rules! parse_values {
             ($s:expr) => {
-                $s.into_iter().map(|(k, v)| (k, v.into())).collect()
+                $s.into_iter()
+                    .map(|(k, v)| (k, to_proto(v, placeholders)))
+                    .collect()
             };
         }
 
-        match command {
+        let query = match self {
             Command::Select {
                 db,
                 from,
                 columns,
                 conditions,
-            } => proto::Query {
-                query: Some(proto::query::Query::Select(proto::Select {
-                    db,
-                    from,
-                    columns,
-                    conditions: parse_key_val!(conditions),
-                })),
-            },
-            Command::Insert { db, into, values } => proto::Query {
-                query: Some(proto::query::Query::Insert(proto::Insert {
-                    db,
-                    into,
-                    values: parse_key_val!(values),
-                })),
-            },
+                max_rows,
+            } => proto::query::Query::Select(proto::Select {
+                db,
+                from,
+                columns,
+                conditions: parse_values!(conditions),
+                predicates: HashMap::new(),
+                order_by: vec![],
+                distinct_on: vec![],
+                max_rows,
+                as_vectors: false,
+                since_serial: None,
+                post_filter: HashMap::new(),
+                with_types: false,
+                include_deleted: false,
+                snapshot: None,
+                in_select: HashMap::new(),
+            }),
+            Command::SelectOne {
+                db,
+                from,
+                conditions,
+            } => proto::query::Query::SelectOne(proto::SelectOne {
+                db,
+                from,
+                conditions: parse_values!(conditions),
+            }),
+            Command::Count {
+                db,
+                from,
+                conditions,
+            } => proto::query::Query::Count(proto::Count {
+                db,
+                from,
+                conditions: parse_values!(conditions),
+            }),
+            Command::Insert { db, into, values } => proto::query::Query::Insert(proto::Insert {
+                db,
+                into,
+                values: parse_values!(values),
+                with_offset: false,
+                returning: vec![],
+            }),
             Command::Update {
                 db,
                 table,
                 set,
                 conditions,
-            } => proto::Query {
-                query: Some(proto::query::Query::Update(proto::Update {
-                    db,
-                    table,
-                    set: parse_key_val!(set),
-                    conditions: parse_key_val!(conditions),
-                })),
-            },
+            } => proto::query::Query::Update(proto::Update {
+                db,
+                table,
+                set: parse_values!(set),
+                expressions: HashMap::new(),
+                conditions: parse_values!(conditions),
+                predicates: HashMap::new(),
+                with_offset: false,
+                limit: None,
+            }),
             Command::Delete {
                 db,
                 from,
                 conditions,
-            } => proto::Query {
-                query: Some(proto::query::Query::Delete(proto::Delete {
-                    db,
-                    from,
-                    conditions: parse_key_val!(conditions),
-                })),
-            },
-            Command::Create { db, table, columns } => proto::Query {
-                query: Some(proto::query::Query::Create(proto::Create {
-                    db,
-                    table,
-                    columns: parse_key_val!(columns),
-                })),
-            },
-            Command::CreateDb { name } => proto::Query {
-                query: Some(proto::query::Query::CreateDb(proto::CreateDb { db: name })),
-            },
-            Command::Drop { db, table } => proto::Query {
-                query: Some(proto::query::Query::Drop(proto::Drop { db, table })),
-            },
-            Command::DropDb { name } => proto::Query {
-                query: Some(proto::query::Query::DropDb(proto::DropDb { db: name })),
-            },
-            Command::Alter { db, table, rename } => proto::Query {
-                query: Some(proto::query::Query::Alter(proto::Alter {
-                    db,
-                    table,
-                    rename,
-                })),
-            },
-            Command::ShowTables { db } => proto::Query {
-                query: Some(proto::query::Query::ShowTables(proto::ShowTables { db })),
-            },
+                compact,
+            } => proto::query::Query::Delete(proto::Delete {
+                db,
+                from,
+                conditions: parse_values!(conditions),
+                predicates: HashMap::new(),
+                limit: None,
+                compact,
+            }),
+            Command::Create {
+                db,
+                table,
+                columns,
+                defaults,
+                compressed,
+                segment_size,
+                if_not_exists,
+            } => proto::query::Query::Create(proto::Create {
+                db,
+                table,
+                columns: columns
+                    .into_iter()
+                    .map(|(name, data_type)| proto::Column {
+                        name,
+                        data_type: data_type.into(),
+                    })
+                    .collect(),
+                defaults: defaults
+                    .into_iter()
+                    .map(|(column, default)| {
+                        let default = match default {
+                            ColumnDefault::Now => proto::ColumnDefault::Now,
+                            ColumnDefault::AutoUpdate => proto::ColumnDefault::AutoUpdate,
+                            ColumnDefault::AutoIncrement => proto::ColumnDefault::AutoIncrement,
+                        };
+                        (column, default.into())
+                    })
+                    .collect(),
+                compressed,
+                if_not_exists,
+                segment_size,
+            }),
+            Command::CreateDb { name } => {
+                proto::query::Query::CreateDb(proto::CreateDb { db: name })
+            }
+            Command::Drop {
+                db,
+                table,
+                if_exists,
+            } => proto::query::Query::Drop(proto::Drop {
+                db,
+                table,
+                if_exists,
+            }),
+            Command::DropDb { name } => proto::query::Query::DropDb(proto::DropDb { db: name }),
+            Command::ConvertDb {
+                name,
+                to_kind,
+                swap,
+            } => proto::query::Query::ConvertDb(proto::ConvertDb {
+                db: name,
+                to_kind,
+                swap,
+            }),
+            Command::Alter { db, table, rename } => {
+                proto::query::Query::Alter(proto::Alter { db, table, rename })
+            }
+            Command::ShowTables { db } => proto::query::Query::ShowTables(proto::ShowTables { db }),
+            Command::ShowCreate { db, table } => {
+                proto::query::Query::ShowCreate(proto::ShowCreate { db, table })
+            }
+            Command::DescribeDb { db } => proto::query::Query::DescribeDb(proto::DescribeDb { db }),
+            Command::Comment {
+                db,
+                table,
+                column,
+                text,
+            } => proto::query::Query::Comment(proto::Comment {
+                db,
+                table,
+                column,
+                text,
+            }),
             Command::Join {
                 db,
                 table1,
@@ -317,16 +823,299 @@ impl From<Command> for proto::Query {
                 columns,
                 conditions,
                 join_on,
-            } => proto::Query {
-                query: Some(proto::query::Query::Join(proto::Join {
+                max_rows,
+            } => proto::query::Query::Join(proto::Join {
+                db,
+                table1,
+                table2,
+                columns,
+                conditions: parse_values!(conditions),
+                join_on,
+                using: vec![],
+                max_rows,
+                post_filter: HashMap::new(),
+                limit: None,
+            }),
+            Command::SelectDeleted {
+                db,
+                from,
+                conditions,
+            } => proto::query::Query::SelectDeleted(proto::SelectDeleted {
+                db,
+                from,
+                conditions: parse_values!(conditions),
+            }),
+            Command::Undelete { db, table, offset } => {
+                proto::query::Query::Undelete(proto::Undelete { db, table, offset })
+            }
+            Command::Sample { db, table, n } => {
+                proto::query::Query::Sample(proto::Sample { db, table, n })
+            }
+            Command::First {
+                db,
+                from,
+                column,
+                descending,
+            } => proto::query::Query::First(proto::First {
+                db,
+                from,
+                predicates: HashMap::new(),
+                column,
+                descending,
+            }),
+            Command::Repair { db } => proto::query::Query::Repair(proto::Repair { db }),
+            Command::CheckIntegrity { db } => {
+                proto::query::Query::CheckIntegrity(proto::CheckIntegrity { db })
+            }
+            Command::ReloadSchema { db } => {
+                proto::query::Query::ReloadSchema(proto::ReloadSchema { db })
+            }
+            Command::CopyInto {
+                db,
+                src,
+                dst,
+                conditions,
+                column_map,
+            } => proto::query::Query::CopyInto(proto::CopyInto {
+                db,
+                src,
+                dst,
+                conditions: parse_values!(conditions),
+                column_map,
+            }),
+            Command::DeleteKeys {
+                db,
+                from,
+                column,
+                keys,
+            } => proto::query::Query::DeleteKeys(proto::DeleteKeys {
+                db,
+                from,
+                column,
+                keys: keys
+                    .into_iter()
+                    .map(|key| to_proto(key, placeholders))
+                    .collect(),
+            }),
+            Command::Flush { db, close } => proto::query::Query::Flush(proto::Flush { db, close }),
+            Command::Snapshot { db } => proto::query::Query::Snapshot(proto::Snapshot { db }),
+            Command::ReleaseSnapshot { snapshot } => {
+                proto::query::Query::ReleaseSnapshot(proto::ReleaseSnapshot { snapshot })
+            }
+            Command::Vacuum { db, table } => {
+                proto::query::Query::Vacuum(proto::Vacuum { db, table })
+            }
+            Command::CreateAs { db, table, select } => {
+                proto::query::Query::CreateAs(Box::new(proto::CreateAs {
                     db,
-                    table1,
-                    table2,
-                    columns,
-                    conditions: parse_key_val!(conditions),
-                    join_on,
-                })),
-            },
+                    table,
+                    select: Some(Box::new(select.into_query(placeholders))),
+                }))
+            }
+        };
+
+        proto::Query {
+            query: Some(query),
+            params: vec![],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count() -> Result<(), anyhow::Error> {
+        let command = Command::from_str("count users where active=1")?;
+        let Command::Count {
+            db,
+            from,
+            conditions,
+        } = command
+        else {
+            panic!("expected a count command, got {:?}", command);
+        };
+        assert_eq!(db, DEFAULT_DB);
+        assert_eq!(from, "users");
+        assert_eq!(
+            conditions,
+            [("active".to_string(), TypedValue::String("1".to_string()))].into()
+        );
+
+        let query: proto::Query = Command::from_str("count shop items")?.into();
+        let Some(proto::query::Query::Count(count)) = query.query else {
+            panic!("expected a count query");
+        };
+        assert_eq!(count.db, "shop");
+        assert_eq!(count.from, "items");
+        assert!(count.conditions.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_rows() -> Result<(), anyhow::Error> {
+        let query: proto::Query =
+            Command::from_str("Select shop items id,name id=1 max_rows=5")?.into();
+        let Some(proto::query::Query::Select(select)) = query.query else {
+            panic!("expected a select query");
+        };
+        assert_eq!(select.max_rows, Some(5));
+
+        let query: proto::Query =
+            Command::from_str("Join shop items prices _ _ items.id=prices.id max_rows=7")?.into();
+        let Some(proto::query::Query::Join(join)) = query.query else {
+            panic!("expected a join query");
+        };
+        assert_eq!(join.max_rows, Some(7));
+
+        let query: proto::Query = Command::from_str("Select shop items id id=1")?.into();
+        let Some(proto::query::Query::Select(select)) = query.query else {
+            panic!("expected a select query");
+        };
+        assert_eq!(select.max_rows, None);
+        assert!(Command::from_str("Select shop items id id=1 limit=5").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn params() -> Result<(), anyhow::Error> {
+        let param = |n| proto::TypedValue {
+            data: Some(proto::typed_value::Data::Param(n)),
+        };
+
+        let (command, params) = Command::parse("Insert poorly users name=?,age=? using bob,42")?;
+        let params = params.unwrap();
+        assert_eq!(
+            params,
+            vec![TypedValue::from("bob"), TypedValue::from("42")]
+        );
+        let query = command.with_params(params);
+        let Some(proto::query::Query::Insert(insert)) = query.query else {
+            panic!("expected an insert query");
+        };
+        assert_eq!(insert.values["name"], param(1));
+        assert_eq!(insert.values["age"], param(2));
+
+        let (command, params) = Command::parse("Update poorly users age=$2 name=$1 using bob,43")?;
+        let params = params.unwrap();
+        assert_eq!(params.len(), 2);
+        let query = command.with_params(params);
+        let Some(proto::query::Query::Update(update)) = query.query else {
+            panic!("expected an update query");
+        };
+        assert_eq!(update.set["age"], param(2));
+        assert_eq!(update.conditions["name"], param(1));
+
+        // Without `using` nothing is bound, and `$N` is just text
+        let (_, params) = Command::parse("count users where active=1")?;
+        assert!(params.is_none());
+        let (command, params) = Command::parse("Insert poorly users price=$5,name=?")?;
+        assert!(params.is_none());
+        let query: proto::Query = command.into();
+        let Some(proto::query::Query::Insert(insert)) = query.query else {
+            panic!("expected an insert query");
+        };
+        assert_eq!(insert.values["price"], TypedValue::from("$5").into());
+        assert_eq!(insert.values["name"], TypedValue::from("?").into());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn show_create_round_trips() -> Result<(), anyhow::Error> {
+        use poorly::core::{engine::poorly::Poorly, types::Query};
+
+        async fn execute(poorly: &mut Poorly, command: &str) -> Result<String, anyhow::Error> {
+            let query: proto::Query = Command::from_str(command)?.into();
+            let rows = poorly
+                .execute(Query::try_from(query.query.unwrap())?)
+                .await?;
+            Ok(match rows.first().map(|row| &row["ddl"]) {
+                Some(TypedValue::String(ddl)) => ddl.clone(),
+                _ => String::new(),
+            })
+        }
+
+        let mut poorly = Poorly::in_memory();
+        execute(
+            &mut poorly,
+            "Create poorly users name=string,id=serial,seen=timestamp:auto_update compressed",
+        )
+        .await?;
+        execute(&mut poorly, "Comment poorly users.name Full name, as typed").await?;
+        let ddl = execute(&mut poorly, "ShowCreate poorly users").await?;
+        assert_eq!(
+            ddl,
+            "Create poorly users name=string,id=serial,seen=timestamp:auto_update compressed\n\
+             Comment poorly users.name Full name, as typed"
+        );
+
+        let mut fresh = Poorly::in_memory();
+        for command in ddl.lines() {
+            execute(&mut fresh, command).await?;
+        }
+        assert_eq!(execute(&mut fresh, "ShowCreate poorly users").await?, ddl);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quoted_identifiers() -> Result<(), anyhow::Error> {
+        use poorly::core::{engine::poorly::Poorly, types::Query};
+
+        async fn execute(
+            poorly: &mut Poorly,
+            command: &str,
+        ) -> Result<Vec<ColumnSet>, anyhow::Error> {
+            let query: proto::Query = Command::from_str(command)?.into();
+            Ok(poorly
+                .execute(Query::try_from(query.query.unwrap())?)
+                .await?)
+        }
+
+        let mut poorly = Poorly::in_memory();
+        execute(
+            &mut poorly,
+            r#"Create poorly "the people" "first name"=string,"says ""hi"", too"=string"#,
+        )
+        .await?;
+        execute(
+            &mut poorly,
+            r#"Insert poorly "the people" "first name"=ann,"says ""hi"", too"=yes"#,
+        )
+        .await?;
+        execute(
+            &mut poorly,
+            r#"Insert poorly "the people" "first name"=bob,"says ""hi"", too"=no"#,
+        )
+        .await?;
+
+        let rows = execute(
+            &mut poorly,
+            r#"Select poorly "the people" "says ""hi"", too" "first name"=ann"#,
+        )
+        .await?;
+        assert_eq!(
+            rows,
+            vec![[("says \"hi\", too".to_string(), TypedValue::from("yes"))].into()]
+        );
+
+        let rows = execute(&mut poorly, r#"ShowCreate poorly "the people""#).await?;
+        assert_eq!(
+            rows[0]["ddl"],
+            TypedValue::from(
+                r#"Create poorly "the people" "first name"=string,"says ""hi"", too"=string"#
+            )
+        );
+
+        // Names still can't hold what tells a table from its columns
+        assert!(execute(&mut poorly, r#"Create poorly t "a.b"=string"#)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
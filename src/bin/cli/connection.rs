@@ -0,0 +1,124 @@
+use poorly::grpc::proto::{self, database_client::DatabaseClient};
+
+use colored::Colorize;
+use tonic::{transport::Channel, Code, Request, Status};
+
+/// A client of the server that reconnects when the server went away, e.g.
+/// because it was restarted
+#[derive(Debug)]
+pub struct Connection {
+    address: String,
+    client: DatabaseClient<Channel>,
+}
+
+impl Connection {
+    pub async fn connect(address: String) -> Result<Self, tonic::transport::Error> {
+        let client = DatabaseClient::connect(address.clone()).await?;
+        Ok(Self { address, client })
+    }
+
+    /// Runs `query`, running it again once on a new connection if the
+    /// server couldn't be reached.
+    pub async fn execute(&mut self, query: proto::Query) -> Result<proto::Reply, Status> {
+        match self.client.execute(Request::new(query.clone())).await {
+            Err(status) if is_disconnected(&status) => {
+                println!("{}", "reconnecting...".yellow());
+                self.client = DatabaseClient::connect(self.address.clone())
+                    .await
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+                self.client.execute(Request::new(query)).await
+            }
+            reply => reply,
+        }
+        .map(|reply| reply.into_inner())
+    }
+}
+
+// Whether the request never got an answer from the server, as opposed to
+// the server answering with an error
+fn is_disconnected(status: &Status) -> bool {
+    status.code() == Code::Unavailable
+        || std::error::Error::source(status)
+            .is_some_and(|source| source.is::<tonic::transport::Error>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poorly::core::{database::DEFAULT_DB, DatabaseEng, Poorly};
+
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use tokio::sync::{oneshot, Mutex};
+
+    // Serves the database in `dir` from a thread of its own until told to
+    // stop. Stopping drops the server's runtime, and so all its connections.
+    fn server(dir: &tempfile::TempDir, port: u16) -> (oneshot::Sender<()>, JoinHandle<()>) {
+        let poorly = Poorly::open(dir.path().to_path_buf());
+        poorly.init().unwrap();
+        let db = Arc::new(Mutex::new(poorly)) as Arc<dyn DatabaseEng>;
+        let (stop, stopped) = oneshot::channel();
+        let thread = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                tokio::select! {
+                    served = poorly::grpc::serve(db, ([127, 0, 0, 1], port), None) => {
+                        served.unwrap()
+                    }
+                    _ = stopped => {}
+                }
+            });
+        });
+        (stop, thread)
+    }
+
+    async fn connect(port: u16) -> Connection {
+        for _ in 0..50 {
+            if let Ok(connection) = Connection::connect(format!("http://127.0.0.1:{}", port)).await
+            {
+                return connection;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("server did not start");
+    }
+
+    fn show_tables() -> proto::Query {
+        proto::Query {
+            query: Some(proto::query::Query::ShowTables(proto::ShowTables {
+                db: DEFAULT_DB.to_string(),
+            })),
+            params: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let (stop, first) = server(&dir, port);
+        let mut connection = connect(port).await;
+        connection.execute(show_tables()).await.unwrap();
+
+        stop.send(()).unwrap();
+        first.join().unwrap();
+        let down = connection.execute(show_tables()).await.unwrap_err();
+        assert_eq!(down.code(), Code::Unavailable);
+
+        let (stop, second) = server(&dir, port);
+        // Wait for the new server before the stale connection is used again
+        connect(port).await;
+        connection.execute(show_tables()).await.unwrap();
+
+        stop.send(()).unwrap();
+        second.join().unwrap();
+    }
+}
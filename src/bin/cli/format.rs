@@ -1,3 +1,7 @@
+use poorly::core::types::ColumnSet;
+
+use std::collections::BTreeSet;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Format {
     Json,
@@ -19,3 +23,69 @@ impl std::str::FromStr for Format {
         }
     }
 }
+
+/// Renders `rows` as an HTML table. Rows may hold different columns: the
+/// header has all of them, in order, and cells of missing columns are empty.
+pub fn html(rows: &[ColumnSet]) -> String {
+    let columns: BTreeSet<&String> = rows.iter().flat_map(|row| row.keys()).collect();
+
+    let mut html = String::from("<table>\n<thead>\n<tr>");
+    for column in &columns {
+        html += &format!("<th>{}</th>", escape(column));
+    }
+    html += "</tr>\n</thead>\n<tbody>\n";
+    for row in rows {
+        html += "<tr>";
+        for column in &columns {
+            let value = row.get(*column).map(ToString::to_string);
+            html += &format!("<td>{}</td>", escape(&value.unwrap_or_default()));
+        }
+        html += "</tr>\n";
+    }
+    html += "</tbody>\n</table>\n";
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poorly::core::types::TypedValue;
+
+    #[test]
+    fn html_table() {
+        let rows: Vec<ColumnSet> = vec![
+            [
+                ("name".into(), TypedValue::from("<b>Tom & Jerry</b>")),
+                ("age".into(), TypedValue::Int(7)),
+            ]
+            .into(),
+            [("name".into(), TypedValue::from("O'Hara"))].into(),
+        ];
+
+        assert_eq!(
+            html(&rows),
+            "<table>\n\
+             <thead>\n<tr><th>age</th><th>name</th></tr>\n</thead>\n\
+             <tbody>\n\
+             <tr><td>7</td><td>&lt;b&gt;Tom &amp; Jerry&lt;/b&gt;</td></tr>\n\
+             <tr><td></td><td>O&#39;Hara</td></tr>\n\
+             </tbody>\n\
+             </table>\n"
+        );
+    }
+}
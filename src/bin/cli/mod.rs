@@ -1,4 +1,5 @@
 mod command;
+mod connection;
 pub mod format;
 mod helpers;
 mod repl;
@@ -1,18 +1,19 @@
-use std::str::FromStr;
+use super::{
+    command::Command,
+    connection::Connection,
+    format::{self, Format},
+    helpers::PoorlyHelper,
+};
 
-use super::{command::Command, format::Format, helpers::PoorlyHelper};
-
-use poorly::core::types::ColumnSet;
-use poorly::grpc::proto::database_client::DatabaseClient;
+use poorly::core::types::{ColumnSet, TypedValue};
 
 use colored::Colorize;
 use prettytable::{csv, Row, Table as PrettyTable};
 use rustyline::Editor;
-use tonic::{transport::Channel, Request};
 
 #[derive(Debug)]
 pub struct Repl {
-    client: DatabaseClient<Channel>,
+    connection: Connection,
     editor: Editor<PoorlyHelper>,
     format: Format,
 }
@@ -22,7 +23,7 @@ impl Repl {
         let mut editor = Editor::<PoorlyHelper>::new().expect("Failed to init readline");
         editor.set_helper(Some(PoorlyHelper::default()));
         Self {
-            client: DatabaseClient::connect(address)
+            connection: Connection::connect(address)
                 .await
                 .expect("Failed to connect to server"),
             editor,
@@ -81,27 +82,37 @@ impl Repl {
                 Self::get_table(&rows).to_csv_writer(writer).unwrap();
             }
             Format::Html => {
-                let mut out = std::io::stdout();
-                Self::get_table(&rows).print_html(&mut out).unwrap();
+                print!("{}", format::html(&rows));
             }
         }
     }
 
-    pub async fn execute(&mut self, command: String) -> Result<Vec<ColumnSet>, String> {
-        // parse the command
-        // NOTE: this makes it impossible for strings to have whitespace inside -
-        // consider using `shlex` parser.
+    pub fn print_count(&self, rows: Vec<ColumnSet>) {
+        if let Some(count) = rows.first().and_then(|row| row.get("count")) {
+            println!("{}", count.to_string());
+        }
+    }
 
-        let command = Command::from_str(&command).map_err(|e| e.to_string())?;
+    pub async fn execute(
+        &mut self,
+        command: Command,
+        params: Option<Vec<TypedValue>>,
+    ) -> Result<Vec<ColumnSet>, String> {
+        let query = match params {
+            Some(params) => command.with_params(params),
+            None => command.into(),
+        };
 
         // execute the command
-        let response = self
-            .client
-            .execute(Request::new(command.into()))
+        let reply = self
+            .connection
+            .execute(query)
             .await
             .map_err(|e| format!("{} {}\n", "error:".red().bold(), e.message()))?;
 
-        Ok(response.into_inner().into())
+        reply
+            .try_into()
+            .map_err(|e: tonic::Status| format!("{} {}\n", "error:".red().bold(), e.message()))
     }
 
     pub async fn run(&mut self) {
@@ -112,8 +123,21 @@ impl Repl {
                 Ok(line) => {
                     self.editor.add_history_entry(line.as_str());
 
+                    // parse the command
+                    // NOTE: this makes it impossible for strings to have whitespace inside -
+                    // consider using `shlex` parser.
+                    let (command, params) = match Command::parse(&line) {
+                        Ok(parsed) => parsed,
+                        Err(e) => {
+                            println!("{}", e);
+                            continue;
+                        }
+                    };
+                    let is_count = matches!(command, Command::Count { .. });
+
                     // print the response
-                    match self.execute(line).await {
+                    match self.execute(command, params).await {
+                        Ok(response) if is_count => self.print_count(response),
                         Ok(response) => {
                             self.print_rows(response);
                             println!();
@@ -1,11 +1,19 @@
 use clap::Parser;
 use env_logger::Env;
 use poorly::{
-    core::{DatabaseEng, Poorly},
-    grpc, rest,
+    core::{
+        engine::{poorly::DEFAULT_MAX_ROWS, sqlite::Sqlite},
+        schema::{SchemaFileFormat, MAX_NAME_LENGTH},
+        table::row_store::GroupCommit,
+        DatabaseEng, Poorly,
+    },
+    grpc,
+    rate_limit::RateLimiter,
+    rest,
 };
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
 /// A database engine as poor as a house elf
@@ -31,6 +39,70 @@ struct Args {
     /// Use sqlite as the backend
     #[arg(long)]
     sqlite: bool,
+
+    /// Maximum number of rows a select or join may return
+    #[arg(long, default_value_t = DEFAULT_MAX_ROWS)]
+    max_rows: usize,
+
+    /// Match table and column names regardless of case
+    #[arg(long)]
+    case_insensitive: bool,
+
+    /// Longest name, in bytes, a table or column may be created with
+    #[arg(long, default_value_t = MAX_NAME_LENGTH)]
+    max_name_length: usize,
+
+    /// Layout to write schema files in, `text` or `json`. Existing schema
+    /// files are read in either and rewritten in this one when they change.
+    #[arg(long, value_name = "FORMAT")]
+    schema_format: Option<SchemaFileFormat>,
+
+    /// Refuse deletes without conditions unless they set a limit
+    #[arg(long)]
+    require_delete_limit: bool,
+
+    /// Encrypt schema and table files with a key derived from this passphrase
+    #[arg(long, env = "POORLY_ENCRYPTION_KEY", hide_env_values = true)]
+    encryption_key: Option<String>,
+
+    /// Requests a client may make per second, by address or by one of the
+    /// `--rate-limit-key` API keys
+    #[arg(long, value_name = "REQUESTS")]
+    rate_limit: Option<u32>,
+
+    /// Requests a client may make at once before the rate limit applies
+    /// [default: the rate limit]
+    #[arg(long, value_name = "BURST", requires = "rate_limit")]
+    rate_burst: Option<u32>,
+
+    /// API key that is rate limited on its own rather than by address.
+    /// May be repeated.
+    #[arg(long = "rate-limit-key", value_name = "KEY", requires = "rate_limit")]
+    rate_limit_keys: Vec<String>,
+
+    /// Write and sync inserts to table files in groups, holding each back
+    /// for at most this many milliseconds
+    #[arg(long, value_name = "MILLIS")]
+    group_commit_ms: Option<u64>,
+
+    /// Bytes of inserted rows that are written as soon as they are pending
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024, requires = "group_commit_ms")]
+    group_commit_bytes: usize,
+
+    /// Serve the databases for querying only, refusing every query that
+    /// would change them
+    #[arg(long)]
+    readonly: bool,
+
+    /// Leave out REST conditions with an empty value, like `?name=`, instead
+    /// of matching rows where the column is empty
+    #[arg(long)]
+    ignore_empty_conditions: bool,
+
+    /// Let REST updates and deletes without conditions change every row
+    /// without confirming it with the `X-All-Rows` header
+    #[arg(long)]
+    allow_unconditional_writes: bool,
 }
 
 #[tokio::main]
@@ -43,19 +115,75 @@ async fn main() {
         panic!("No server specified");
     }
 
-    let db = {
-        let db = Poorly::open(args.server_folder);
+    if args.sqlite {
+        let sqlite = Sqlite::open(args.server_folder.clone()).with_max_rows(args.max_rows);
+        sqlite.init().unwrap();
+        let db = Arc::new(Mutex::new(sqlite)) as Arc<dyn DatabaseEng>;
+        serve(&args, db).await;
+        return;
+    }
+
+    let poorly = {
+        let db = Poorly::open(args.server_folder.clone())
+            .with_max_rows(args.max_rows)
+            .with_case_insensitive(args.case_insensitive)
+            .with_max_name_length(args.max_name_length)
+            .with_require_delete_limit(args.require_delete_limit)
+            .with_read_only(args.readonly)
+            .with_group_commit(args.group_commit_ms.map(|millis| GroupCommit {
+                max_bytes: args.group_commit_bytes,
+                max_delay: Duration::from_millis(millis),
+            }));
+        let db = match args.schema_format {
+            Some(schema_format) => db.with_schema_format(schema_format),
+            None => db,
+        };
+        let db = match &args.encryption_key {
+            Some(passphrase) => db
+                .with_encryption_key(passphrase)
+                .expect("Failed to derive the encryption key"),
+            None => db,
+        };
         db.init().unwrap();
-        Arc::new(Mutex::new(db)) as Arc<dyn DatabaseEng>
+        Arc::new(Mutex::new(db))
     };
+    let db = Arc::clone(&poorly) as Arc<dyn DatabaseEng>;
+    serve(&args, db).await;
+
+    // The servers are gone with their handles to the engine, unless a
+    // request is still holding one
+    match Arc::try_unwrap(poorly) {
+        Ok(poorly) => {
+            if let Err(err) = poorly.into_inner().close().await {
+                log::error!(target: "poorly::server", "Failed to close the databases: {}", err);
+            }
+        }
+        Err(_) => {
+            log::warn!(target: "poorly::server", "Requests are still running, not closing the databases");
+        }
+    }
+}
+
+// Serves `db` until the servers stop or the process is interrupted
+async fn serve(args: &Args, db: Arc<dyn DatabaseEng>) {
+    let limiter = args.rate_limit.map(|rate| {
+        let limiter = RateLimiter::new(rate, args.rate_burst.unwrap_or(rate))
+            .with_api_keys(args.rate_limit_keys.clone());
+        Arc::new(limiter)
+    });
 
-    let rest_server = args
-        .rest
-        .map(|port| rest::serve(Arc::clone(&db), ([0, 0, 0, 0], port)));
+    let rest_server = args.rest.map(|port| {
+        let options = rest::Options {
+            limiter: limiter.clone(),
+            ignore_empty_conditions: args.ignore_empty_conditions,
+            allow_unconditional_writes: args.allow_unconditional_writes,
+        };
+        rest::serve(Arc::clone(&db), ([0, 0, 0, 0], port), options)
+    });
 
     let grpc_server = args
         .grpc
-        .map(|port| grpc::serve(Arc::clone(&db), ([0, 0, 0, 0], port)));
+        .map(|port| grpc::serve(Arc::clone(&db), ([0, 0, 0, 0], port), limiter.clone()));
 
     tokio::select! {
         _ = async { rest_server.unwrap().await }, if rest_server.is_some() => {},
@@ -0,0 +1,49 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use super::types;
+
+/// Source of the current time for column defaults like `now`
+pub trait Clock: Send + Sync + Debug {
+    /// Milliseconds since the Unix epoch
+    fn now_millis(&self) -> i64;
+}
+
+/// The server clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        types::now_millis()
+    }
+}
+
+/// A clock that only moves when told to, so tests can tell exactly which
+/// time a row was given
+#[derive(Debug, Default)]
+pub struct MockClock {
+    millis: AtomicI64,
+}
+
+impl MockClock {
+    pub fn new(millis: i64) -> Self {
+        Self {
+            millis: AtomicI64::new(millis),
+        }
+    }
+
+    pub fn set(&self, millis: i64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, millis: i64) {
+        self.millis.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> i64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
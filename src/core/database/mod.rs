@@ -1,37 +1,167 @@
-use tokio::sync::RwLock;
+use tokio::sync::{OwnedRwLockWriteGuard, RwLock};
 
-use super::schema::{Columns, Schema};
+use super::clock::{Clock, SystemClock};
+use super::schema::{Columns, Defaults, Schema, SchemaFileFormat};
+use super::table::encrypted::{EncryptedStore, EncryptionKey};
+use super::table::row_store::{
+    segment_paths, FileStore, Flusher, ReadOnlyStore, RowStore, SegmentedStore,
+};
 use super::table::Table;
-use super::types::PoorlyError;
+use super::types::{ColumnSet, PoorlyError, TypedValue};
 
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Cursor, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use serde::Serialize;
+
 #[cfg(test)]
 mod tests;
 
 pub const DEFAULT_DB: &'static str = "poorly";
 
+// Rows `Database::merge_tables` holds before appending them
+const MERGE_BATCH_ROWS: usize = 1024;
+
+/// What `Database::repair` found and fixed.
+#[derive(Debug, Default, Serialize)]
+pub struct RepairReport {
+    /// Tables listed in the schema with no file on disk
+    pub missing_files: Vec<String>,
+    /// Files in the database directory the schema doesn't know about
+    pub unknown_files: Vec<String>,
+    /// Tables whose serial counter was behind: (table, old, new)
+    pub serials: Vec<(String, u32, u32)>,
+    /// Tables that could not be read to the end
+    pub corrupted: Vec<(String, String)>,
+}
+
+impl RepairReport {
+    pub fn into_rows(self) -> Vec<ColumnSet> {
+        let row = |table: String, problem: String| -> ColumnSet {
+            [
+                ("table".to_string(), TypedValue::String(table)),
+                ("problem".to_string(), TypedValue::String(problem)),
+            ]
+            .into()
+        };
+
+        let missing = self
+            .missing_files
+            .into_iter()
+            .map(|table| row(table, "missing file".to_string()));
+        let unknown = self
+            .unknown_files
+            .into_iter()
+            .map(|file| row(file, "not in schema".to_string()));
+        let serials = self
+            .serials
+            .into_iter()
+            .map(|(table, old, new)| row(table, format!("serial moved from {} to {}", old, new)));
+        let corrupted = self
+            .corrupted
+            .into_iter()
+            .map(|(table, error)| row(table, format!("corrupted: {}", error)));
+
+        missing
+            .chain(unknown)
+            .chain(serials)
+            .chain(corrupted)
+            .collect()
+    }
+}
+
+/// What `Database::check_integrity` found, by table
+#[derive(Debug, Default, Serialize)]
+pub struct IntegrityReport {
+    pub problems: Vec<(String, String)>,
+}
+
+impl IntegrityReport {
+    pub fn into_rows(self) -> Vec<ColumnSet> {
+        self.problems
+            .into_iter()
+            .map(|(table, problem)| {
+                [
+                    ("table".to_string(), TypedValue::String(table)),
+                    ("problem".to_string(), TypedValue::String(problem)),
+                ]
+                .into()
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct Database {
     tables: HashMap<String, Arc<RwLock<Table>>>,
     schema: Schema,
-    path: PathBuf,
+    name: String,
+    // None for a database kept in memory only
+    path: Option<PathBuf>,
+    // Set if the schema and table files are encrypted
+    key: Option<EncryptionKey>,
+    // Tables are opened without write access and nothing may change
+    read_only: bool,
+    // Set if inserts into table files are written in groups
+    group_commit: Option<Flusher>,
+    // Passed on to every table opened, see `Table::with_clock`
+    clock: Arc<dyn Clock>,
+}
+
+fn load_schema(path: &Path, key: Option<&EncryptionKey>) -> Result<Schema, PoorlyError> {
+    match key {
+        Some(key) => {
+            let file = FileStore::open(path.join(".schema"))?;
+            let mut store = EncryptedStore::new(Box::new(file), key.clone())?;
+            store.seek(SeekFrom::Start(0))?;
+            Schema::read_from(store)
+        }
+        None => Schema::try_load(path),
+    }
+}
+
+fn dump_schema(schema: &Schema, path: &Path, key: Option<&EncryptionKey>) -> io::Result<()> {
+    match key {
+        Some(key) => {
+            let file = FileStore::open(path.join(".schema"))?;
+            let mut store = EncryptedStore::new(Box::new(file), key.clone())?;
+            store.set_len(0)?;
+            schema.write_to(store)
+        }
+        None => schema.dump(path),
+    }
+}
+
+// A table file, split into segments of `segment_size` if given
+fn open_file(path: PathBuf, segment_size: Option<u64>) -> io::Result<Box<dyn RowStore>> {
+    Ok(match segment_size {
+        Some(size) => Box::new(SegmentedStore::open(path, size)?),
+        None => Box::new(FileStore::open(path)?),
+    })
 }
 
 // TODO: add cleanup (remove all deleted entries)
 impl Database {
     pub async fn drop_table(&mut self, table_name: String) -> Result<(), PoorlyError> {
-        let result = self.schema.drop_table(table_name.clone());
-        if let Err(PoorlyError::TableNotFound(_)) = result {
-        } else {
-            return result;
-        }
-
-        drop(self.get_table(&table_name).await?);
+        self.check_writable()?;
+        self.schema.drop_table(table_name.clone())?;
         self.tables.remove(&table_name);
 
+        // A table that was never written to may have no file yet
+        if let Some(path) = &self.path {
+            let segments = segment_paths(&path.join(&table_name));
+            match std::fs::remove_file(path.join(&table_name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            for segment in segments.into_iter().skip(1) {
+                std::fs::remove_file(segment)?;
+            }
+        }
+
         Ok(())
     }
 
@@ -43,8 +173,68 @@ impl Database {
         &mut self,
         table_name: String,
         columns: Columns,
+        defaults: Defaults,
+        compressed: bool,
+        segment_size: Option<u64>,
+    ) -> Result<(), PoorlyError> {
+        self.check_writable()?;
+        if segment_size == Some(0) {
+            return Err(PoorlyError::InvalidOperation(
+                "segment size must be at least 1 byte".to_string(),
+            ));
+        }
+        self.schema.create_table(table_name.clone(), columns)?;
+        if compressed {
+            self.schema.compressed.insert(table_name.clone());
+        }
+        if let Some(size) = segment_size {
+            self.schema.segment_sizes.insert(table_name.clone(), size);
+        }
+        for (column, default) in defaults {
+            if let Err(e) = self.schema.set_default(&table_name, &column, default) {
+                self.schema.drop_table(table_name)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `create_table`, but a table that already exists with the same
+    /// columns, defaults, compression and segment size is left alone. Returns
+    /// whether the table was created.
+    pub fn create_table_if_not_exists(
+        &mut self,
+        table_name: String,
+        columns: Columns,
+        defaults: Defaults,
+        compressed: bool,
+        segment_size: Option<u64>,
+    ) -> Result<bool, PoorlyError> {
+        self.check_writable()?;
+        let existing = self.schema.resolve_table(&table_name);
+        if !self.schema.tables.contains_key(&existing) {
+            self.create_table(table_name, columns, defaults, compressed, segment_size)?;
+            return Ok(true);
+        }
+
+        if self.schema.has_table(&existing, &columns, &defaults)
+            && self.schema.compressed.contains(&existing) == compressed
+            && self.schema.segment_sizes.get(&existing).copied() == segment_size
+        {
+            Ok(false)
+        } else {
+            Err(PoorlyError::TableAlreadyExists(table_name))
+        }
+    }
+
+    pub fn comment(
+        &mut self,
+        table_name: &str,
+        column: Option<&str>,
+        text: String,
     ) -> Result<(), PoorlyError> {
-        self.schema.create_table(table_name, columns)
+        self.check_writable()?;
+        self.schema.set_comment(table_name, column, text)
     }
 
     pub async fn alter_table(
@@ -52,6 +242,7 @@ impl Database {
         table_name: String,
         rename: HashMap<String, String>,
     ) -> Result<(), PoorlyError> {
+        self.check_writable()?;
         self.schema.alter_table(table_name.clone(), rename)?;
 
         self.update_columns(table_name).await;
@@ -61,10 +252,16 @@ impl Database {
 
     async fn update_columns(&self, table_name: String) {
         let table = self.tables.get(&table_name).unwrap();
-        table.write().await.columns = self.schema.tables[&table_name].clone();
+        let mut table = table.write().await;
+        table.columns = self.schema.tables[&table_name].clone();
+        table.defaults = self.table_defaults(&table_name);
     }
 
-    pub fn create_db(db_name: String, mut path: PathBuf) -> Result<(), PoorlyError> {
+    pub fn create_db(
+        db_name: String,
+        mut path: PathBuf,
+        key: Option<&EncryptionKey>,
+    ) -> Result<(), PoorlyError> {
         path.push(db_name.clone());
 
         if path.exists() {
@@ -74,18 +271,21 @@ impl Database {
         std::fs::create_dir_all(&path)?;
 
         let schema = Schema::new_poorly(db_name);
-        schema.dump(path.as_path())?;
+        dump_schema(&schema, &path, key)?;
 
         Ok(())
     }
 
     pub fn drop_db(&mut self) -> Result<(), PoorlyError> {
-        if self.path.file_name().unwrap() != DEFAULT_DB {
-            std::fs::remove_dir_all(&self.path)?;
-        } else {
+        self.check_writable()?;
+        if self.name == DEFAULT_DB {
             return Err(PoorlyError::CannotDropDefaultDb);
         }
 
+        if let Some(path) = &self.path {
+            std::fs::remove_dir_all(path)?;
+        }
+
         Ok(())
     }
 
@@ -95,20 +295,353 @@ impl Database {
         }
 
         if !self.tables.contains_key(table_name) {
+            let name = table_name.to_string();
             let columns = self.schema.tables[table_name].clone();
-            let table = Arc::new(RwLock::new(Table::open(
-                table_name.to_string(),
-                columns,
-                &self.path,
-            )));
-            self.tables.insert(table_name.to_string(), table);
+            let defaults = self.table_defaults(table_name);
+            let format = self.schema.table_format(table_name);
+            let segment_size = self.schema.segment_sizes.get(table_name).copied();
+            let mut table = match (&self.path, &self.key) {
+                (path, key) if self.read_only => {
+                    let file = path.as_ref().map(|path| {
+                        let path = path.join(&name);
+                        match segment_size {
+                            Some(size) => SegmentedStore::open_read_only(path, size)
+                                .map(|file| Box::new(file) as Box<dyn RowStore>),
+                            None => FileStore::open_read_only(path)
+                                .map(|file| Box::new(file) as Box<dyn RowStore>),
+                        }
+                    });
+                    let store: Box<dyn RowStore> = match file {
+                        Some(Ok(file)) => file,
+                        Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                        // The table was never written to
+                        _ => Box::new(Cursor::new(Vec::new())),
+                    };
+                    let store: Box<dyn RowStore> = match key {
+                        Some(key) => Box::new(EncryptedStore::new(store, key.clone())?),
+                        None => store,
+                    };
+                    let store = Box::new(ReadOnlyStore(store));
+                    Table::with_store(name, columns, defaults, format, store)?
+                }
+                (Some(path), Some(key)) => {
+                    let file = open_file(path.join(&name), segment_size)?;
+                    let store = EncryptedStore::new(file, key.clone())?;
+                    Table::with_store(name, columns, defaults, format, Box::new(store))?
+                }
+                (Some(path), None) if segment_size.is_some() => {
+                    let file = open_file(path.join(&name), segment_size)?;
+                    Table::with_store(name, columns, defaults, format, file)?
+                }
+                (Some(path), None) => Table::open(name, columns, defaults, format, path),
+                (None, _) => Table::in_memory(name, columns, defaults, format),
+            }
+            .with_clock(self.clock.clone());
+            // Tables in memory have nothing to sync
+            let on_disk = self.path.is_some() && !self.read_only;
+            if let Some(flusher) = self.group_commit.as_ref().filter(|_| on_disk) {
+                table.buffer_writes(flusher)?;
+            }
+            self.tables
+                .insert(table_name.to_string(), Arc::new(RwLock::new(table)));
         }
 
         let tmp = self.tables.get(table_name).unwrap().clone();
         Ok(tmp)
     }
 
-    pub fn open(name: &str, mut path: PathBuf) -> Result<Self, PoorlyError> {
+    /// Appends the live rows of `src` to `dst`, which must have the same
+    /// columns, e.g. to gather shards of one table. With `dedup_key`, a row
+    /// whose value of that column is already in `dst`, or came earlier from
+    /// `src`, is skipped. Serial columns are numbered anew by `dst`. `src` is
+    /// dropped afterwards if `drop_src`. Returns the number of rows merged.
+    pub async fn merge_tables(
+        &mut self,
+        dst: &str,
+        src: &str,
+        dedup_key: Option<String>,
+        drop_src: bool,
+    ) -> Result<u64, PoorlyError> {
+        self.check_writable()?;
+        if dst == src {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "table {} can't be merged into itself",
+                dst
+            )));
+        }
+        let columns = |table: &str| {
+            let mut columns = self
+                .schema
+                .tables
+                .get(table)
+                .cloned()
+                .ok_or_else(|| PoorlyError::TableNotFound(table.to_string()))?;
+            columns.sort();
+            Ok::<_, PoorlyError>(columns)
+        };
+        let dst_columns = columns(dst)?;
+        if dst_columns != columns(src)? {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "tables {} and {} have different columns",
+                dst, src
+            )));
+        }
+        if let Some(key) = &dedup_key {
+            if !dst_columns.iter().any(|(column, _)| column == key) {
+                return Err(PoorlyError::ColumnNotFound(key.clone(), dst.to_string()));
+            }
+        }
+
+        // In name order, like joins, so the two can't deadlock each other
+        let (mut dst_table, mut src_table) = lock_pair(
+            (dst, self.get_table(dst).await?),
+            (src, self.get_table(src).await?),
+        )
+        .await;
+
+        let mut keys = HashSet::new();
+        if let Some(key) = &dedup_key {
+            for row in dst_table.iter_offsets()? {
+                let (_, row) = row?;
+                keys.insert(row[key].canonical_key());
+            }
+        }
+
+        let mut merged = 0;
+        let mut batch = Vec::new();
+        for row in src_table.iter_offsets()? {
+            let (_, row) = row?;
+            if let Some(key) = &dedup_key {
+                if !keys.insert(row[key].canonical_key()) {
+                    continue;
+                }
+            }
+            batch.push(row);
+            if batch.len() == MERGE_BATCH_ROWS {
+                merged += batch.len() as u64;
+                dst_table.insert_raw_many(std::mem::take(&mut batch))?;
+            }
+        }
+        merged += batch.len() as u64;
+        dst_table.insert_raw_many(batch)?;
+        drop((dst_table, src_table));
+
+        if drop_src {
+            self.drop_table(src.to_string()).await?;
+        }
+        Ok(merged)
+    }
+
+    /// Checks the schema against the files on disk and moves every table's
+    /// serial counter past the ids already stored. Tables have no indexes yet,
+    /// so there is nothing else to rebuild.
+    pub async fn repair(&mut self) -> Result<RepairReport, PoorlyError> {
+        self.check_writable()?;
+        let mut report = RepairReport::default();
+
+        if let Some(path) = &self.path {
+            for entry in std::fs::read_dir(path)? {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                // Segments after the first are named `table.1`, `table.2` and so on
+                let table = match name.rsplit_once('.') {
+                    Some((table, index)) if index.parse::<usize>().is_ok() => table,
+                    _ => &name,
+                };
+                if name != ".schema" && !self.schema.tables.contains_key(table) {
+                    report.unknown_files.push(name);
+                }
+            }
+        }
+
+        let mut tables = self.get_tables();
+        tables.sort();
+        for table_name in tables {
+            let missing = self
+                .path
+                .as_ref()
+                .is_some_and(|path| !path.join(&table_name).exists());
+            if !self.tables.contains_key(&table_name) && missing {
+                report.missing_files.push(table_name);
+                continue;
+            }
+
+            let table = self.get_table(&table_name).await?;
+            let mut table = table.write().await;
+            match table.repair_serial() {
+                Ok(Some((old, new))) => report.serials.push((table_name, old, new)),
+                Ok(None) => {}
+                Err(e) => report.corrupted.push((table_name, e.to_string())),
+            }
+        }
+
+        report.unknown_files.sort();
+        Ok(report)
+    }
+
+    /// Reads every table to the end and reports values that aren't valid
+    /// for their column, e.g. written by an older version, and serial
+    /// counters that would hand out stored serials again. Unlike `repair`
+    /// nothing is changed. There are no unique or foreign key constraints
+    /// yet, so there is nothing else to check.
+    pub async fn check_integrity(&mut self) -> Result<IntegrityReport, PoorlyError> {
+        let mut report = IntegrityReport::default();
+
+        let mut tables = self.get_tables();
+        tables.sort();
+        for table_name in tables {
+            let missing = self
+                .path
+                .as_ref()
+                .is_some_and(|path| !path.join(&table_name).exists());
+            if !self.tables.contains_key(&table_name) && missing {
+                report
+                    .problems
+                    .push((table_name, "missing file".to_string()));
+                continue;
+            }
+
+            let table = self.get_table(&table_name).await?;
+            let problems = table.write().await.check_integrity()?;
+            report.problems.extend(
+                problems
+                    .into_iter()
+                    .map(|problem| (table_name.clone(), problem)),
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Re-reads the `.schema` file, e.g. after it was edited by hand. Open
+    /// tables must keep the same column types in the same order, since their
+    /// rows are laid out by them; column names may change.
+    pub async fn reload_schema(&mut self) -> Result<(), PoorlyError> {
+        let Some(path) = &self.path else {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "database {} is in memory and has no schema file",
+                self.name
+            )));
+        };
+        let mut schema = load_schema(path, self.key.as_ref())?;
+        schema.case_insensitive = self.schema.case_insensitive;
+        schema.max_name_length = self.schema.max_name_length;
+        schema.file_format = self.schema.file_format;
+
+        for (name, table) in &self.tables {
+            let table = table.read().await;
+            let compatible = schema.tables.get(name).is_some_and(|columns| {
+                schema.table_format(name) == table.format
+                    && columns.len() == table.columns.len()
+                    && columns
+                        .iter()
+                        .zip(&table.columns)
+                        .all(|((_, new), (_, old))| new == old)
+            });
+            if !compatible {
+                return Err(PoorlyError::IncompatibleSchema(name.clone()));
+            }
+        }
+
+        log::info!("Reloaded schema at {:?}", path);
+        self.schema = schema;
+        for name in self.tables.keys() {
+            self.update_columns(name.clone()).await;
+        }
+        Ok(())
+    }
+
+    /// Writes every open table and the schema through to the disk, so the
+    /// database directory can be copied as it is. With `close` the tables are
+    /// closed too, and opened again when next used. Returns how many tables
+    /// were flushed.
+    pub async fn flush(&mut self, close: bool) -> Result<usize, PoorlyError> {
+        for table in self.tables.values() {
+            table.write().await.file.sync()?;
+        }
+        if let Some(path) = self.path.as_ref().filter(|_| !self.read_only) {
+            dump_schema(&self.schema, path, self.key.as_ref())?;
+            File::open(path.join(".schema"))?.sync_all()?;
+        }
+
+        let flushed = self.tables.len();
+        if close {
+            log::info!("Closing {} tables of database `{}`", flushed, self.name);
+            self.tables.clear();
+        }
+        Ok(flushed)
+    }
+
+    /// Flushes the database and closes its tables. Errors are returned,
+    /// where dropping it could only panic on them.
+    pub async fn close(mut self) -> Result<(), PoorlyError> {
+        self.flush(true).await?;
+        // The schema is dumped already, leave nothing for `drop` to write
+        self.path = None;
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), PoorlyError> {
+        if self.read_only {
+            Err(PoorlyError::InvalidOperation("read-only".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Opens tables without write access and refuses every change, e.g. to
+    /// serve a replica or a snapshot on a read-only file system
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Holds back inserts into table files to write and sync them in groups,
+    /// see `BufferedStore`
+    pub fn with_group_commit(mut self, group_commit: Option<Flusher>) -> Self {
+        self.group_commit = group_commit;
+        self
+    }
+
+    /// Bounds the length of names of tables and columns created from now on,
+    /// see `Schema::max_name_length`
+    pub fn with_max_name_length(mut self, max_name_length: usize) -> Self {
+        self.schema.max_name_length = max_name_length;
+        self
+    }
+
+    /// Writes the schema file in `file_format` from the next change on, if
+    /// given, else in the format it was read in
+    pub fn with_schema_format(mut self, file_format: Option<SchemaFileFormat>) -> Self {
+        if let Some(file_format) = file_format {
+            self.schema.file_format = file_format;
+        }
+        self
+    }
+
+    /// Gives tables opened from now on `clock` for their `now` defaults
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn table_defaults(&self, table_name: &str) -> Defaults {
+        self.schema
+            .defaults
+            .get(table_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn open(
+        name: &str,
+        mut path: PathBuf,
+        case_insensitive: bool,
+        key: Option<EncryptionKey>,
+    ) -> Result<Self, PoorlyError> {
         log::info!("Opening database `{}`", name);
         path.push(name);
 
@@ -118,22 +651,70 @@ impl Database {
 
         println!("Loading database at {:?}", path);
 
-        let schema = Schema::load(path.as_path());
+        let mut schema = match &key {
+            Some(key) => load_schema(&path, Some(key))?,
+            None => Schema::load(path.as_path()),
+        };
+        schema.case_insensitive = case_insensitive;
 
         log::info!("Database `{}` loaded", name);
 
         Ok(Self {
             tables: HashMap::new(),
             schema,
-            path: path.clone(),
+            name: name.to_string(),
+            path: Some(path),
+            key,
+            read_only: false,
+            group_commit: None,
+            clock: Arc::new(SystemClock),
         })
     }
+
+    /// Creates an empty database whose schema and tables are kept in memory
+    /// only and are gone once it is dropped.
+    pub fn in_memory(name: &str, case_insensitive: bool) -> Self {
+        log::info!("Creating in-memory database `{}`", name);
+        let mut schema = Schema::new_poorly(name.to_string());
+        schema.case_insensitive = case_insensitive;
+
+        Self {
+            tables: HashMap::new(),
+            schema,
+            name: name.to_string(),
+            path: None,
+            key: None,
+            read_only: false,
+            group_commit: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
 }
 
 impl Drop for Database {
     fn drop(&mut self) {
-        if self.path.exists() {
-            self.schema.dump(&self.path).expect("Failed to dump schema");
+        if self.read_only {
+            return;
+        }
+        if let Some(path) = self.path.as_ref().filter(|path| path.exists()) {
+            dump_schema(&self.schema, path, self.key.as_ref()).expect("Failed to dump schema");
         }
     }
 }
+
+/// Write-locks two distinct tables of a database in the order of their
+/// names, whatever order they are given in, so that operations locking the
+/// same pair can't deadlock each other. The guards are returned in the order
+/// the tables were given.
+pub(crate) async fn lock_pair(
+    first: (&str, Arc<RwLock<Table>>),
+    second: (&str, Arc<RwLock<Table>>),
+) -> (OwnedRwLockWriteGuard<Table>, OwnedRwLockWriteGuard<Table>) {
+    if first.0 <= second.0 {
+        let first = first.1.write_owned().await;
+        (first, second.1.write_owned().await)
+    } else {
+        let second = second.1.write_owned().await;
+        (first.1.write_owned().await, second)
+    }
+}
@@ -1,5 +1,6 @@
 use super::*;
-use crate::core::types::{DataType, TypedValue};
+use crate::core::types::{DataType, QueryStats, RowFormat, TypedValue};
+use std::io::Cursor;
 
 fn table() -> Table {
     Table {
@@ -8,8 +9,14 @@ fn table() -> Table {
             ("id".into(), DataType::Int),
             ("price".into(), DataType::Float),
         ],
-        file: tempfile::tempfile().unwrap(),
+        defaults: HashMap::new(),
+        format: RowFormat::CURRENT,
+        file: Box::new(Cursor::new(Vec::new())),
+        changes: 0,
         serial: 0,
+        stats: QueryStats::default(),
+        clock: Arc::new(SystemClock),
+        counter: None,
     }
 }
 
@@ -20,8 +27,14 @@ fn join(i: i32) -> Table {
             ("id".into(), DataType::Int),
             ("email".into(), DataType::Email),
         ],
-        file: tempfile::tempfile().unwrap(),
+        defaults: HashMap::new(),
+        format: RowFormat::CURRENT,
+        file: Box::new(Cursor::new(Vec::new())),
+        changes: 0,
         serial: 0,
+        stats: QueryStats::default(),
+        clock: Arc::new(SystemClock),
+        counter: None,
     }
 }
 
@@ -36,7 +49,7 @@ fn select() -> Result<(), PoorlyError> {
 
     table.insert(row.clone())?;
 
-    let rows = table.select(vec![], [].into())?;
+    let rows = table.select(vec![], [].into(), None)?;
     assert_eq!(rows.len(), 1);
     assert_eq!(rows[0], row);
 
@@ -95,7 +108,7 @@ fn test_join() -> Result<(), PoorlyError> {
     join_on.insert("join1.id".to_string(), "join2.id".to_string());
 
     let result = table1
-        .join(&mut table2, vec![], conditions, join_on)?
+        .join(&mut table2, vec![], conditions, join_on, None, None)?
         .remove(0);
 
     assert_eq!(result.get("join1.id"), Some(&TypedValue::Int(1)));
@@ -114,6 +127,244 @@ fn test_join() -> Result<(), PoorlyError> {
     Ok(())
 }
 
+#[test]
+fn join_wildcard() -> Result<(), PoorlyError> {
+    let mut table1 = join(1);
+    let mut table2 = join(2);
+    for table in [&mut table1, &mut table2] {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(1)),
+                ("email".into(), TypedValue::Email("a@gmail.com".to_string())),
+            ]
+            .into(),
+        )?;
+    }
+
+    let join_on: HashMap<_, _> = [("join1.id".to_string(), "join2.id".to_string())].into();
+    let columns = vec!["join1.*".to_string(), "join2.email".to_string()];
+    let result = table1.join(&mut table2, columns, HashMap::new(), join_on, None, None)?;
+
+    let mut keys: Vec<_> = result[0].keys().cloned().collect();
+    keys.sort();
+    assert_eq!(keys, vec!["join1.email", "join1.id", "join2.email"]);
+
+    Ok(())
+}
+
+#[test]
+fn join_order_is_stable() -> Result<(), PoorlyError> {
+    let mut table1 = join(1);
+    let mut table2 = join(2);
+    for (id, email) in [(3, "c@gmail.com"), (1, "a@gmail.com"), (2, "b@gmail.com")] {
+        table1.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("email".into(), TypedValue::Email(email.to_string())),
+            ]
+            .into(),
+        )?;
+    }
+    for (id, email) in [(2, "y@gmail.com"), (3, "z@gmail.com"), (1, "x@gmail.com")] {
+        table2.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("email".into(), TypedValue::Email(email.to_string())),
+            ]
+            .into(),
+        )?;
+    }
+
+    let join_on: HashMap<_, _> = [("join1.id".to_string(), "join2.id".to_string())].into();
+    let first = table1.join(&mut table2, vec![], [].into(), join_on.clone(), None, None)?;
+    let second = table1.join(&mut table2, vec![], [].into(), join_on, None, None)?;
+
+    assert_eq!(first, second);
+    let emails: Vec<_> = first.iter().map(|row| row["join2.email"].clone()).collect();
+    assert_eq!(
+        emails,
+        vec![
+            TypedValue::Email("x@gmail.com".to_string()),
+            TypedValue::Email("y@gmail.com".to_string()),
+            TypedValue::Email("z@gmail.com".to_string()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn join_limit() -> Result<(), PoorlyError> {
+    let mut table1 = join(1);
+    let mut table2 = join(2);
+    // The second table's file holds its rows in the reverse of the output order
+    for id in 1..=10 {
+        let email = TypedValue::Email(format!("{}@gmail.com", id));
+        for (table, id) in [(&mut table1, id), (&mut table2, 11 - id)] {
+            table.insert(
+                [
+                    ("id".into(), TypedValue::Int(id)),
+                    ("email".into(), email.clone()),
+                ]
+                .into(),
+            )?;
+        }
+    }
+
+    // The first rows of the output order, not of the file
+    let join_on: HashMap<_, _> = [("join1.id".to_string(), "join2.id".to_string())].into();
+    let all = table1.join(&mut table2, vec![], [].into(), join_on.clone(), None, None)?;
+    let limited = table1.join(
+        &mut table2,
+        vec![],
+        [].into(),
+        join_on.clone(),
+        None,
+        Some(3),
+    )?;
+    assert_eq!(limited, all[..3]);
+    let ids: Vec<_> = limited.iter().map(|row| row["join2.id"].clone()).collect();
+    assert_eq!(ids, (1..=3).map(TypedValue::Int).collect::<Vec<_>>());
+
+    // Conditions are checked before a row counts towards the limit
+    let conditions: HashMap<_, _> = [("join2.id".to_string(), TypedValue::Int(5))].into();
+    let limited = table1.join(
+        &mut table2,
+        vec![],
+        conditions,
+        join_on.clone(),
+        None,
+        Some(1),
+    )?;
+    assert_eq!(limited.len(), 1);
+    assert_eq!(limited[0]["join2.id"], TypedValue::Int(5));
+
+    // A limit within `max_rows` is fine however many rows are joined
+    let limited = table1.join(
+        &mut table2,
+        vec![],
+        [].into(),
+        join_on.clone(),
+        Some(3),
+        Some(3),
+    )?;
+    assert_eq!(limited, all[..3]);
+
+    let beyond = table1.join(
+        &mut table2,
+        vec![],
+        [].into(),
+        join_on.clone(),
+        None,
+        Some(20),
+    )?;
+    assert_eq!(all, beyond);
+    assert!(table1
+        .join(&mut table2, vec![], [].into(), join_on, None, Some(0))?
+        .is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn join_mixed_int_widths() -> Result<(), PoorlyError> {
+    let mut table1 = join(1);
+    let mut table2 = join(2);
+    table2.columns[0].1 = DataType::Int32;
+    for id in 1..=3 {
+        let email = TypedValue::Email(format!("{}@gmail.com", id));
+        table1.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("email".into(), email.clone()),
+            ]
+            .into(),
+        )?;
+        table2.insert(
+            [
+                ("id".into(), TypedValue::Int32(id as i32)),
+                ("email".into(), email),
+            ]
+            .into(),
+        )?;
+    }
+
+    // An `int` and an `int32` holding the same number join with or without a limit
+    let join_on: HashMap<_, _> = [("join1.id".to_string(), "join2.id".to_string())].into();
+    let all = table1.join(&mut table2, vec![], [].into(), join_on.clone(), None, None)?;
+    let limited = table1.join(&mut table2, vec![], [].into(), join_on, None, Some(3))?;
+    assert_eq!(all.len(), 3);
+    assert_eq!(all, limited);
+
+    Ok(())
+}
+
+#[test]
+fn join_max_rows_result() -> Result<(), PoorlyError> {
+    let mut table1 = join(1);
+    let mut table2 = join(2);
+    for id in 1..=10 {
+        table1.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                (
+                    "email".into(),
+                    TypedValue::Email(format!("{}@gmail.com", id)),
+                ),
+            ]
+            .into(),
+        )?;
+    }
+    for id in [1, 2] {
+        table2.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                (
+                    "email".into(),
+                    TypedValue::Email(format!("{}@gmail.com", id)),
+                ),
+            ]
+            .into(),
+        )?;
+    }
+
+    // The first table has more rows than `max_rows`, the join doesn't
+    let join_on: HashMap<_, _> = [("join1.id".to_string(), "join2.id".to_string())].into();
+    let joined = table1.join(
+        &mut table2,
+        vec![],
+        [].into(),
+        join_on.clone(),
+        Some(2),
+        None,
+    )?;
+    assert_eq!(joined.len(), 2);
+
+    let result = table1.join(
+        &mut table2,
+        vec![],
+        [].into(),
+        join_on.clone(),
+        Some(1),
+        None,
+    );
+    assert!(matches!(result, Err(PoorlyError::ResultTooLarge(1))));
+
+    // Conditions on the first table are met before joining
+    let joined = table1.join(
+        &mut table2,
+        vec![],
+        [("join1.id".to_string(), TypedValue::Int(2))].into(),
+        join_on,
+        Some(1),
+        None,
+    )?;
+    assert_eq!(joined.len(), 1);
+    assert_eq!(joined[0]["join2.id"], TypedValue::Int(2));
+
+    Ok(())
+}
+
 #[test]
 fn project() -> Result<(), PoorlyError> {
     let mut table = table();
@@ -125,7 +376,7 @@ fn project() -> Result<(), PoorlyError> {
 
     table.insert(row.clone())?;
 
-    let rows = table.select(vec!["price".into()], [].into())?;
+    let rows = table.select(vec!["price".into()], [].into(), None)?;
     assert_eq!(rows.len(), 1);
 
     row.remove("id");
@@ -153,7 +404,7 @@ fn filter() -> Result<(), PoorlyError> {
 
     table.insert(row.clone())?;
 
-    let rows = table.select(vec![], [("id".into(), TypedValue::Int(2))].into())?;
+    let rows = table.select(vec![], [("id".into(), TypedValue::Int(2))].into(), None)?;
     assert_eq!(rows.len(), 1);
     assert_eq!(rows[0], row);
 
@@ -175,7 +426,7 @@ fn update() -> Result<(), PoorlyError> {
         [].into(),
     )?;
 
-    let rows = table.select(vec![], [].into())?;
+    let rows = table.select(vec![], [].into(), None)?;
     assert_eq!(rows.len(), 1);
     assert_eq!(rows[0]["price"], TypedValue::Float(123.45));
 
@@ -194,8 +445,306 @@ fn delete() -> Result<(), PoorlyError> {
     table.insert(row)?;
     table.delete([].into())?;
 
-    let rows = table.select(vec![], [].into())?;
+    let rows = table.select(vec![], [].into(), None)?;
     assert!(rows.is_empty());
 
     Ok(())
 }
+
+#[tokio::test]
+async fn repair_serial() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    Database::create_db("test".into(), dir.path().to_path_buf(), None)?;
+
+    let mut db = Database::open("test", dir.path().to_path_buf(), false, None)?;
+    db.create_table(
+        "users".into(),
+        vec![("id".into(), DataType::Serial)],
+        HashMap::new(),
+        false,
+        None,
+    )?;
+    {
+        let table = db.get_table("users").await?;
+        let mut table = table.write().await;
+        for _ in 0..3 {
+            table.insert(HashMap::new())?;
+        }
+    }
+    drop(db);
+
+    // Pretend a crash left the header behind the stored ids
+    let path = dir.path().join("test").join("users");
+    let mut bytes = std::fs::read(&path)?;
+    bytes[..4].copy_from_slice(&1u32.to_le_bytes());
+    std::fs::write(&path, bytes)?;
+
+    let mut db = Database::open("test", dir.path().to_path_buf(), false, None)?;
+    let report = db.repair().await?;
+    assert_eq!(report.serials, vec![("users".to_string(), 1, 3)]);
+    assert!(report.missing_files.is_empty());
+    assert!(report.corrupted.is_empty());
+
+    let table = db.get_table("users").await?;
+    let mut table = table.write().await;
+    table.insert(HashMap::new())?;
+    let rows = table.select(vec![], [].into(), None)?;
+    let ids: Vec<_> = rows.iter().map(|row| row["id"].clone()).collect();
+    assert_eq!(ids.last(), Some(&TypedValue::Serial(3)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reload_schema() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    Database::create_db("test".into(), dir.path().to_path_buf(), None)?;
+    let path = dir.path().join("test");
+
+    let mut db = Database::open("test", dir.path().to_path_buf(), false, None)?;
+    db.create_table(
+        "users".into(),
+        vec![("id".into(), DataType::Int)],
+        HashMap::new(),
+        false,
+        None,
+    )?;
+    let users = db.get_table("users").await?;
+    db.schema().dump(&path)?;
+
+    // Another tool adds a table and renames a column of the open one
+    let schema = std::fs::read_to_string(path.join(".schema"))?;
+    let schema = schema.replace("users#id:int", "users#uid:int");
+    std::fs::write(
+        path.join(".schema"),
+        format!("{}orders#id:int,total:float\n", schema),
+    )?;
+
+    db.reload_schema().await?;
+    let mut tables = db.get_tables();
+    tables.sort();
+    assert_eq!(tables, vec!["orders".to_string(), "users".to_string()]);
+    assert_eq!(
+        users.read().await.columns,
+        vec![("uid".to_string(), DataType::Int)]
+    );
+
+    // Changing the type of an open table's column would misread its rows
+    let schema = std::fs::read_to_string(path.join(".schema"))?;
+    std::fs::write(
+        path.join(".schema"),
+        schema.replace("users#uid:int", "users#uid:string"),
+    )?;
+    let result = db.reload_schema().await;
+    assert!(matches!(result, Err(PoorlyError::IncompatibleSchema(table)) if table == "users"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn drop_table_removes_file() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    Database::create_db("test".into(), dir.path().to_path_buf(), None)?;
+    let path = dir.path().join("test").join("users");
+
+    let mut db = Database::open("test", dir.path().to_path_buf(), false, None)?;
+    let create = |db: &mut Database| {
+        db.create_table(
+            "users".into(),
+            vec![("id".into(), DataType::Int)],
+            HashMap::new(),
+            false,
+            None,
+        )
+    };
+    create(&mut db)?;
+    {
+        let table = db.get_table("users").await?;
+        let mut table = table.write().await;
+        table.insert([("id".into(), TypedValue::Int(1))].into())?;
+    }
+    assert!(path.exists());
+
+    db.drop_table("users".into()).await?;
+    assert!(!path.exists());
+
+    create(&mut db)?;
+    let table = db.get_table("users").await?;
+    let mut table = table.write().await;
+    assert!(table.select(vec![], [].into(), None)?.is_empty());
+    drop(table);
+
+    // Dropping a table whose file was never created is fine too
+    db.drop_table("users".into()).await?;
+    create(&mut db)?;
+    db.drop_table("users".into()).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn check_integrity() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    Database::create_db("test".into(), dir.path().to_path_buf(), None)?;
+
+    let mut db = Database::open("test", dir.path().to_path_buf(), false, None)?;
+    db.create_table(
+        "users".into(),
+        vec![
+            ("email".into(), DataType::Email),
+            ("id".into(), DataType::Serial),
+        ],
+        HashMap::new(),
+        false,
+        None,
+    )?;
+    {
+        let table = db.get_table("users").await?;
+        let mut table = table.write().await;
+        table.insert([("email".into(), TypedValue::from("a@b.com"))].into())?;
+        assert!(table.check_integrity()?.is_empty());
+
+        // A row from a buggy writer, with a serial the counter hasn't reached
+        // and an email that doesn't validate
+        let format = table.format;
+        let mut row = vec![0];
+        row.extend(TypedValue::Email("nope".into()).into_bytes(format));
+        row.extend(TypedValue::Serial(7).into_bytes(format));
+        table.file.seek(SeekFrom::End(0))?;
+        table.file.write_all(&row)?;
+    }
+
+    let report = db.check_integrity().await?;
+    let problems: Vec<_> = report.problems.iter().map(|(_, p)| p.as_str()).collect();
+    assert_eq!(problems.len(), 2, "{:?}", problems);
+    assert!(problems[0].contains("email"));
+    assert_eq!(problems[1], "serial counter 1 is not past stored serial 7");
+    assert!(report.problems.iter().all(|(table, _)| table == "users"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn segmented_table() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    Database::create_db("test".into(), dir.path().to_path_buf(), None)?;
+    let path = dir.path().join("test");
+
+    let mut db = Database::open("test", dir.path().to_path_buf(), false, None)?;
+    db.create_table(
+        "events".into(),
+        vec![("id".into(), DataType::Serial)],
+        HashMap::new(),
+        false,
+        Some(64),
+    )?;
+    {
+        let table = db.get_table("events").await?;
+        let mut table = table.write().await;
+        for _ in 0..50 {
+            table.insert(HashMap::new())?;
+        }
+    }
+    drop(db);
+
+    // The segment size is kept in the schema, so a reopened table keeps
+    // reading and rolling over its segments
+    let mut db = Database::open("test", dir.path().to_path_buf(), false, None)?;
+    assert_eq!(db.schema().segment_sizes["events"], 64);
+    assert!(path.join("events.1").exists());
+    let report = db.repair().await?;
+    assert!(report.unknown_files.is_empty());
+    let count = db
+        .get_table("events")
+        .await?
+        .write()
+        .await
+        .count([].into())?;
+    assert_eq!(count, 50);
+
+    db.drop_table("events".into()).await?;
+    assert!(!path.join("events").exists());
+    assert!(!path.join("events.1").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn merge_tables() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    Database::create_db("test".into(), dir.path().to_path_buf(), None)?;
+    let mut db = Database::open("test", dir.path().to_path_buf(), false, None)?;
+
+    let columns = vec![
+        ("serial".into(), DataType::Serial),
+        ("email".into(), DataType::Email),
+        ("visits".into(), DataType::Int),
+    ];
+    for (table, emails) in [
+        ("shard1", ["a@x.com", "b@x.com"]),
+        ("shard2", ["b@x.com", "c@x.com"]),
+        ("shard3", ["c@x.com", "c@x.com"]),
+    ] {
+        db.create_table(table.into(), columns.clone(), HashMap::new(), false, None)?;
+        let table = db.get_table(table).await?;
+        let mut table = table.write().await;
+        for (visits, email) in emails.into_iter().enumerate() {
+            table.insert(
+                [
+                    ("email".into(), TypedValue::Email(email.into())),
+                    ("visits".into(), TypedValue::Int(visits as i64)),
+                ]
+                .into(),
+            )?;
+        }
+    }
+    let emails = |rows: Vec<ColumnSet>| {
+        rows.into_iter()
+            .map(|row| match &row["email"] {
+                TypedValue::Email(email) => email.clone(),
+                other => panic!("not an email: {:?}", other),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // `b@x.com` is already there, and `c@x.com` only counts once
+    let merged = db
+        .merge_tables("shard1", "shard2", Some("email".into()), false)
+        .await?;
+    assert_eq!(merged, 1);
+    let merged = db
+        .merge_tables("shard1", "shard3", Some("email".into()), true)
+        .await?;
+    assert_eq!(merged, 0);
+    assert!(matches!(
+        db.get_table("shard3").await,
+        Err(PoorlyError::TableNotFound(_))
+    ));
+
+    let table = db.get_table("shard1").await?;
+    let rows = table.write().await.select(vec![], [].into(), None)?;
+    assert_eq!(emails(rows.clone()), ["a@x.com", "b@x.com", "c@x.com"]);
+    // Serials are handed out by the table merged into
+    assert_eq!(rows[2]["serial"], TypedValue::Serial(2));
+    assert_eq!(rows[2]["visits"], TypedValue::Int(1));
+
+    // Without a key every row is merged
+    let merged = db.merge_tables("shard1", "shard2", None, false).await?;
+    assert_eq!(merged, 2);
+
+    db.create_table(
+        "other".into(),
+        vec![("email".into(), DataType::Email)],
+        HashMap::new(),
+        false,
+        None,
+    )?;
+    let different = db.merge_tables("shard1", "other", None, false).await;
+    assert!(matches!(different, Err(PoorlyError::InvalidOperation(_))));
+    let missing_key = db
+        .merge_tables("shard1", "shard2", Some("name".into()), false)
+        .await;
+    assert!(matches!(missing_key, Err(PoorlyError::ColumnNotFound(..))));
+
+    Ok(())
+}
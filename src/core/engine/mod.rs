@@ -1,21 +1,107 @@
-use super::types::{ColumnSet, PoorlyError, Query};
+use super::schema::Schema;
+use super::types::{ColumnSet, DataType, PoorlyError, Query, QueryStats};
 use async_trait::async_trait;
-use tokio::sync::Mutex;
+use std::collections::HashMap;
+use tokio::sync::{broadcast, Mutex};
 
 pub mod poorly;
+pub mod sqlite;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A row written by an insert, update or delete, as sent to subscribers.
+/// Updated rows hold their new values, deleted ones their last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub db: String,
+    pub table: String,
+    pub kind: ChangeKind,
+    pub row: ColumnSet,
+}
 
 #[async_trait]
 pub trait DatabaseEng: Send + Sync {
-    async fn execute(&self, query: Query) -> Result<Vec<ColumnSet>, PoorlyError>;
+    async fn execute(&self, query: Query) -> Result<Vec<ColumnSet>, PoorlyError> {
+        self.execute_with_stats(query).await.map(|(rows, _)| rows)
+    }
+
+    async fn execute_with_stats(
+        &self,
+        query: Query,
+    ) -> Result<(Vec<ColumnSet>, QueryStats), PoorlyError>;
+
+    /// Declared types of the columns the rows of a `Select` have
+    async fn column_types(&self, query: Query) -> Result<HashMap<String, DataType>, PoorlyError>;
+
+    /// Current schema of database `db`
+    async fn schema(&self, db: &str) -> Result<Schema, PoorlyError>;
+
+    /// Changes made from now on, in the order they were made. A receiver
+    /// that falls too far behind misses the oldest and is told how many.
+    async fn subscribe(&self) -> broadcast::Receiver<Change>;
 }
 
 #[async_trait]
 impl DatabaseEng for Mutex<poorly::Poorly> {
-    async fn execute(&self, query: Query) -> Result<Vec<ColumnSet>, PoorlyError> {
+    async fn execute_with_stats(
+        &self,
+        query: Query,
+    ) -> Result<(Vec<ColumnSet>, QueryStats), PoorlyError> {
         let mut lock = self.lock().await;
 
         let tmp = lock.execute(query).await;
+        let stats = lock.stats();
+        let groups = lock.take_groups();
+        drop(lock);
+
+        let rows = tmp?;
+        poorly::Poorly::synced(groups).await?;
+        Ok((rows, stats))
+    }
+
+    async fn column_types(&self, query: Query) -> Result<HashMap<String, DataType>, PoorlyError> {
+        self.lock().await.column_types(query).await
+    }
+
+    async fn schema(&self, db: &str) -> Result<Schema, PoorlyError> {
+        self.lock().await.schema(db).await
+    }
+
+    async fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.lock().await.subscribe()
+    }
+}
+
+#[async_trait]
+impl DatabaseEng for Mutex<sqlite::Sqlite> {
+    async fn execute_with_stats(
+        &self,
+        query: Query,
+    ) -> Result<(Vec<ColumnSet>, QueryStats), PoorlyError> {
+        let mut lock = self.lock().await;
+
+        let tmp = lock.execute(query);
+
+        tmp.map(|rows| (rows, lock.stats()))
+    }
+
+    async fn column_types(&self, query: Query) -> Result<HashMap<String, DataType>, PoorlyError> {
+        self.lock().await.column_types(query)
+    }
+
+    async fn schema(&self, db: &str) -> Result<Schema, PoorlyError> {
+        self.lock().await.schema(db)
+    }
 
-        tmp
+    async fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.lock().await.subscribe()
     }
 }
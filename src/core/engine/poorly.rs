@@ -1,68 +1,329 @@
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{broadcast, Mutex, RwLock};
 
 use crate::core::{
-    database::{Database, DEFAULT_DB},
-    schema::Columns,
-    table::Table,
-    types::TypedValue,
+    clock::{Clock, SystemClock},
+    database::{lock_pair, Database, DEFAULT_DB},
+    predicate::{self, Predicate},
+    schema::{Columns, Defaults, Schema, SchemaFileFormat, SchemaKind, MAX_NAME_LENGTH},
+    table::{
+        encrypted::EncryptionKey,
+        row_store::{Flusher, Group, GroupCommit},
+        Table, COUNT_ONLY, DELETED_COLUMN, OFFSET_COLUMN,
+    },
+    types::{DataType, TypedValue},
 };
-use std::{collections::HashMap, hash::Hash};
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::core::types::{ColumnSet, OrderBy, PoorlyError, Query, QueryStats};
 
-use crate::core::types::{ColumnSet, PoorlyError, Query};
+use super::{sqlite, Change, ChangeKind};
+
+pub const DEFAULT_MAX_ROWS: usize = 1_000_000;
+// Changes held for subscribers before the slowest starts missing them
+const CHANGE_CAPACITY: usize = 1024;
+// Snapshots that may be open at once
+const MAX_SNAPSHOTS: usize = 1024;
 
 #[derive(Debug)]
 pub struct Poorly {
     databases: HashMap<String, RwLock<Database>>,
-    path: PathBuf,
+    // None when nothing is written to disk, see `Poorly::in_memory`
+    path: Option<PathBuf>,
+    max_rows: usize,
+    // Statistics of the last executed query
+    stats: QueryStats,
+    case_insensitive: bool,
+    // Refuse deletes without conditions unless they have a limit
+    require_delete_limit: bool,
+    // Set if schema and table files are encrypted
+    key: Option<EncryptionKey>,
+    // Open databases without write access
+    read_only: bool,
+    // Set if inserts are written to table files in groups
+    group_commit: Option<Flusher>,
+    max_name_length: usize,
+    // Open snapshots by id, oldest first, see `Query::Snapshot`
+    snapshots: BTreeMap<u64, Snapshot>,
+    next_snapshot: u64,
+    // Layout schema files are written in, or the one they were read in
+    schema_format: Option<SchemaFileFormat>,
+    // Time of `now` defaults in every database
+    clock: Arc<dyn Clock>,
+    // Rows written by inserts, updates and deletes, see `Poorly::subscribe`
+    changes: broadcast::Sender<Change>,
+}
+
+// Serial counters of the tables of a database when a snapshot was opened
+#[derive(Debug)]
+struct Snapshot {
+    db: String,
+    serials: HashMap<String, u32>,
 }
 
 impl Poorly {
     pub async fn execute(&mut self, query: Query) -> Result<Vec<ColumnSet>, PoorlyError> {
+        self.stats = QueryStats::default();
+        if self.read_only && query.mutates() {
+            return Err(PoorlyError::InvalidOperation("read-only".to_string()));
+        }
+        let query = if self.case_insensitive {
+            self.resolve_names(query).await?
+        } else {
+            query
+        };
+        self.validate(&query).await?;
+
         match query {
             Query::Select {
                 db,
                 from,
                 columns,
                 conditions,
-            } => self
-                .get_table(&db, &from)
-                .await?
-                .write()
+                predicates,
+                order_by,
+                distinct_on,
+                max_rows,
+                since_serial,
+                post_filter,
+                include_deleted,
+                snapshot,
+                in_select,
+            } => {
+                let max_rows = max_rows.unwrap_or(self.max_rows);
+                let mut predicates = predicate::merge(conditions, predicates)?;
+                for (column, select) in in_select {
+                    let values = Predicate::In(self.select_values(select).await?);
+                    let predicate = match predicates.remove(&column) {
+                        Some(predicate) => Predicate::All(vec![predicate, values]),
+                        None => values,
+                    };
+                    predicates.insert(column, predicate);
+                }
+                let watermark = match snapshot {
+                    Some(snapshot) => Some(self.snapshot_serial(snapshot, &db, &from)?),
+                    None => None,
+                };
+                self.scan(&db, &from, move |table| {
+                    if let Some(serial) = since_serial {
+                        let column = table.serial_column()?.to_string();
+                        if predicates.contains_key(&column) {
+                            return Err(PoorlyError::InvalidOperation(format!(
+                                "column {} has both a predicate and since_serial",
+                                column
+                            )));
+                        }
+                        predicates.insert(column, Predicate::Gt(TypedValue::Serial(serial)));
+                    }
+                    if let Some(serial) = watermark {
+                        let column = table.serial_column()?.to_string();
+                        let before = Predicate::Lt(TypedValue::Serial(serial));
+                        let predicate = match predicates.remove(&column) {
+                            Some(predicate) => Predicate::All(vec![predicate, before]),
+                            None => before,
+                        };
+                        predicates.insert(column, predicate);
+                    }
+                    let select = match include_deleted {
+                        true => Table::select_with_deleted,
+                        false => Table::select_where,
+                    };
+                    let rows = select(
+                        table,
+                        columns,
+                        predicates,
+                        &order_by,
+                        &distinct_on,
+                        Some(max_rows),
+                    )?;
+                    predicate::filter_rows(rows, &post_filter)
+                })
                 .await
-                .select(columns, conditions),
-            Query::Insert { db, into, values } => self
-                .get_table(&db, &into)
-                .await?
-                .write()
+            }
+            Query::SelectOne {
+                db,
+                from,
+                conditions,
+            } => {
+                self.scan(&db, &from, |table| {
+                    table.select_one(conditions).map(|row| vec![row])
+                })
                 .await
-                .insert(values)
-                .map(|v| vec![v]),
+            }
+            Query::Count {
+                db,
+                from,
+                conditions,
+            } => {
+                self.scan(&db, &from, |table| {
+                    table.count(conditions).map(|count| {
+                        vec![[("count".to_string(), TypedValue::Int(count as i64))].into()]
+                    })
+                })
+                .await
+            }
+            Query::Sample { db, table, n } => {
+                self.scan(&db, &table, move |table| table.sample(n as usize))
+                    .await
+            }
+            Query::First {
+                db,
+                from,
+                predicates,
+                order_by,
+            } => {
+                self.scan(&db, &from, move |table| {
+                    table.first(predicates, &order_by).map(Vec::from_iter)
+                })
+                .await
+            }
+            Query::SelectDeleted {
+                db,
+                from,
+                conditions,
+            } => {
+                self.scan(&db, &from, |table| table.select_deleted(conditions))
+                    .await
+            }
+            Query::Undelete { db, table, offset } => {
+                let rows = self
+                    .scan(&db, &table, move |table| {
+                        table.undelete(offset).map(|row| vec![row])
+                    })
+                    .await?;
+                self.publish(&db, &table, ChangeKind::Insert, &rows);
+                Ok(rows)
+            }
+            Query::Insert {
+                db,
+                into,
+                values,
+                with_offset,
+                returning,
+            } => {
+                let table = self.get_table(&db, &into).await?;
+                let mut table = table.write().await;
+                // Checked up front, so a bad name doesn't leave the row inserted
+                for column in &returning {
+                    let exists = table.columns.iter().any(|(name, _)| name == column)
+                        || with_offset && column == OFFSET_COLUMN;
+                    if !exists {
+                        return Err(PoorlyError::ColumnNotFound(
+                            column.clone(),
+                            table.name.clone(),
+                        ));
+                    }
+                }
+                let mut row = if with_offset {
+                    table.insert_with_offset(values)?
+                } else {
+                    table.insert(values)?
+                };
+                drop(table);
+                self.publish(&db, &into, ChangeKind::Insert, std::slice::from_ref(&row));
+                if !returning.is_empty() {
+                    row.retain(|column, _| returning.contains(column));
+                }
+                Ok(vec![row])
+            }
             Query::Update {
                 db,
                 table,
                 set,
+                expressions,
                 conditions,
-            } => self
-                .get_table(&db, &table)
-                .await?
-                .write()
-                .await
-                .update(set, conditions),
+                predicates,
+                with_offset,
+                limit,
+            } => {
+                let predicates = predicate::merge(conditions, predicates)?;
+                let rows = self
+                    .scan(&db, &table, move |table| {
+                        if with_offset {
+                            table.update_with_offsets(set, expressions, predicates, limit)
+                        } else {
+                            table.update_where(set, expressions, predicates, limit)
+                        }
+                    })
+                    .await?;
+                self.publish(&db, &table, ChangeKind::Update, &rows);
+                Ok(rows)
+            }
             Query::Delete {
                 db,
                 from,
                 conditions,
+                predicates,
+                limit,
+                compact,
+            } => {
+                let predicates = predicate::merge(conditions, predicates)?;
+                if self.require_delete_limit && predicates.is_empty() && limit.is_none() {
+                    return Err(PoorlyError::InvalidOperation(
+                        "deleting every row of a table needs a limit".to_string(),
+                    ));
+                }
+                let rows = self
+                    .scan(&db, &from, move |table| {
+                        if compact {
+                            table.delete_compacting(predicates, limit)
+                        } else {
+                            table.delete_where(predicates, limit)
+                        }
+                    })
+                    .await?;
+                self.publish(&db, &from, ChangeKind::Delete, &rows);
+                Ok(rows)
+            }
+            Query::Create {
+                db,
+                table,
+                columns,
+                defaults,
+                compressed,
+                segment_size,
+                if_not_exists: false,
             } => self
-                .get_table(&db, &from)
-                .await?
-                .write()
+                .create_table(db, table, columns, defaults, compressed, segment_size)
                 .await
-                .delete(conditions),
-            Query::Create { db, table, columns } => {
-                self.create_table(db, table, columns).await.map(|_| vec![])
+                .map(|_| vec![]),
+            Query::Create {
+                db,
+                table,
+                columns,
+                defaults,
+                compressed,
+                segment_size,
+                if_not_exists: true,
+            } => {
+                let mut db = self.get_database(&db).await?.write().await;
+                let created = db.create_table_if_not_exists(
+                    table,
+                    columns,
+                    defaults,
+                    compressed,
+                    segment_size,
+                )?;
+                Ok(vec![[(
+                    "created".to_string(),
+                    TypedValue::Int(created as i64),
+                )]
+                .into()])
             }
-            Query::Drop { db, table } => self.drop_table(db, table).await.map(|_| vec![]),
+            Query::Drop {
+                db,
+                table,
+                if_exists,
+            } => match self.drop_table(db, table).await {
+                Err(PoorlyError::TableNotFound(_)) if if_exists => Ok(vec![]),
+                result => result.map(|_| vec![]),
+            },
             Query::DropDb { name } => {
                 self.drop_db(name).await?;
                 Ok(vec![])
@@ -71,10 +332,33 @@ impl Poorly {
                 self.create_db(name)?;
                 Ok(vec![])
             }
+            Query::ConvertDb {
+                name,
+                to_kind,
+                swap,
+            } => {
+                let db = self.convert_db(name, to_kind, swap).await?;
+                Ok(vec![[("db".to_string(), TypedValue::String(db))].into()])
+            }
             Query::Alter { db, table, rename } => {
                 self.alter_table(db, table, rename).await?;
                 Ok(vec![])
             }
+            Query::ReloadSchema { db } => {
+                let db = self.get_database(&db).await?;
+                db.write().await.reload_schema().await?;
+                Ok(vec![])
+            }
+            Query::Repair { db } => {
+                let db = self.get_database(&db).await?;
+                let report = db.write().await.repair().await?;
+                Ok(report.into_rows())
+            }
+            Query::CheckIntegrity { db } => {
+                let db = self.get_database(&db).await?;
+                let report = db.write().await.check_integrity().await?;
+                Ok(report.into_rows())
+            }
             Query::ShowTables { db } => {
                 let db = self.get_database(&db).await?;
                 let tables: ColumnSet = db
@@ -87,6 +371,140 @@ impl Poorly {
 
                 Ok(vec![tables])
             }
+            Query::ShowCreate { db, table } => {
+                let db = self.get_database(&db).await?;
+                let ddl = db.read().await.schema().ddl(&table)?;
+                Ok(vec![[("ddl".to_string(), TypedValue::String(ddl))].into()])
+            }
+            Query::DescribeDb { db } => {
+                let db = self.get_database(&db).await?.read().await;
+                let schema = db.schema();
+                let mut tables: Vec<&String> = schema.tables.keys().collect();
+                tables.sort();
+
+                let mut rows = Vec::new();
+                for table in tables {
+                    for (position, (column, data_type)) in schema.tables[table].iter().enumerate() {
+                        let mut row: ColumnSet = [
+                            ("table".to_string(), TypedValue::String(table.clone())),
+                            ("column".to_string(), TypedValue::String(column.clone())),
+                            (
+                                "type".to_string(),
+                                TypedValue::String(format!("{:?}", data_type)),
+                            ),
+                            ("position".to_string(), TypedValue::Int(position as i64)),
+                        ]
+                        .into();
+                        if let Some(default) =
+                            schema.defaults.get(table).and_then(|d| d.get(column))
+                        {
+                            row.insert(
+                                "default".to_string(),
+                                TypedValue::String(default.attribute().to_string()),
+                            );
+                        }
+                        if let Some(comment) = schema.comment(table, Some(column)) {
+                            row.insert(
+                                "comment".to_string(),
+                                TypedValue::String(comment.to_string()),
+                            );
+                        }
+                        rows.push(row);
+                    }
+                }
+                Ok(rows)
+            }
+            Query::Comment {
+                db,
+                table,
+                column,
+                text,
+            } => {
+                let db = self.get_database(&db).await?;
+                db.write().await.comment(&table, column.as_deref(), text)?;
+                Ok(vec![])
+            }
+            Query::CopyInto {
+                db,
+                src,
+                dst,
+                conditions,
+                column_map,
+            } => self.copy_into(db, src, dst, conditions, column_map).await,
+            Query::DeleteKeys {
+                db,
+                from,
+                column,
+                keys,
+            } => {
+                let deleted = self
+                    .scan(&db, &from, move |table| table.delete_keys(&column, keys))
+                    .await?;
+                Ok(vec![[(
+                    "deleted".to_string(),
+                    TypedValue::Int(deleted as i64),
+                )]
+                .into()])
+            }
+            Query::Snapshot { db } => {
+                // Snapshots opened without a session are never released on
+                // their own, so a client forgetting them can't take up more
+                if self.snapshots.len() >= MAX_SNAPSHOTS {
+                    return Err(PoorlyError::InvalidOperation(format!(
+                        "{} snapshots are open already, release some first",
+                        MAX_SNAPSHOTS
+                    )));
+                }
+                let tables = self.get_database(&db).await?.read().await.get_tables();
+                let mut serials = HashMap::new();
+                for table in tables {
+                    let serial = self.get_table(&db, &table).await?.read().await.serial;
+                    serials.insert(table, serial);
+                }
+                let id = self.next_snapshot;
+                self.next_snapshot += 1;
+                self.snapshots.insert(id, Snapshot { db, serials });
+                Ok(vec![
+                    [("snapshot".to_string(), TypedValue::Int(id as i64))].into()
+                ])
+            }
+            Query::ReleaseSnapshot { snapshot } => match self.snapshots.remove(&snapshot) {
+                Some(_) => Ok(vec![]),
+                None => Err(PoorlyError::InvalidOperation(format!(
+                    "no snapshot {}",
+                    snapshot
+                ))),
+            },
+            Query::Flush { db, close } => {
+                let mut db = self.get_database(&db).await?.write().await;
+                let flushed = db.flush(close).await?;
+                Ok(vec![[(
+                    "flushed".to_string(),
+                    TypedValue::Int(flushed as i64),
+                )]
+                .into()])
+            }
+            Query::Vacuum { db, table } => {
+                let table = self.get_table(&db, &table).await?;
+                // Queries run one at a time under the engine lock, so nothing
+                // reads or writes the table between the copy and the swap
+                let compacted = table.read().await.compact()?;
+                let mut table = table.write().await;
+                let removed = match table.swap(compacted)? {
+                    Some(removed) => removed,
+                    // Written to in between, which the engine lock rules out
+                    None => table.vacuum()?,
+                };
+                Ok(vec![[(
+                    "removed".to_string(),
+                    TypedValue::Int(removed as i64),
+                )]
+                .into()])
+            }
+            Query::CreateAs { db, table, select } => {
+                self.create_as(db, table, *select).await?;
+                Ok(vec![])
+            }
             Query::Join {
                 db,
                 table1,
@@ -94,16 +512,31 @@ impl Poorly {
                 columns,
                 conditions,
                 join_on,
+                using,
+                max_rows,
+                post_filter,
+                limit,
             } => {
+                let max_rows = max_rows.unwrap_or(self.max_rows);
+                // The table can keep just the first rows only when every row it
+                // joins is returned, otherwise they are cut after the filter
+                let pushed = limit.filter(|_| post_filter.is_empty());
                 let result = self
-                    .join(db, table1, table2, columns, conditions, join_on)
+                    .join(
+                        db, table1, table2, columns, conditions, join_on, using, max_rows, pushed,
+                    )
                     .await?;
 
-                Ok(result)
+                let mut rows = predicate::filter_rows(result, &post_filter)?;
+                if let Some(limit) = limit {
+                    rows.truncate(limit);
+                }
+                Ok(rows)
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn join(
         &mut self,
         db: String,
@@ -111,17 +544,854 @@ impl Poorly {
         table2: String,
         columns: Vec<String>,
         conditions: HashMap<String, TypedValue>,
-        join_on: HashMap<String, String>,
+        mut join_on: HashMap<String, String>,
+        using: Vec<String>,
+        max_rows: usize,
+        limit: Option<usize>,
     ) -> Result<Vec<ColumnSet>, PoorlyError> {
-        let t1 = self.get_table(&db, &table1).await?;
-        let mut t1 = t1.write().await;
+        // A `using` column stands for the one of the first table, which is
+        // equal to the one of the second
+        let first = |column: String| match using.contains(&column) {
+            true => format!("{}.{}", table1, column),
+            false => column,
+        };
+        let columns: Vec<String> = columns.into_iter().map(first).collect();
+        let conditions: HashMap<String, TypedValue> = conditions
+            .into_iter()
+            .map(|(column, value)| (first(column), value))
+            .collect();
+        for column in &using {
+            join_on.insert(
+                format!("{}.{}", table1, column),
+                format!("{}.{}", table2, column),
+            );
+        }
 
+        let t1 = self.get_table(&db, &table1).await?;
         let t2 = self.get_table(&db, &table2).await?;
-        let mut t2 = t2.write().await;
+        if Arc::ptr_eq(&t1, &t2) {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "table {} can't be joined with itself",
+                table1
+            )));
+        }
+        let (mut t1, mut t2) = lock_pair((&table1, t1), (&table2, t2)).await;
+
+        let (result, stats) = blocking(move || {
+            let result = t1.join(&mut t2, columns, conditions, join_on, Some(max_rows), limit);
+            (result, t1.stats)
+        })
+        .await;
+        self.stats = stats;
+
+        let mut rows = result?;
+        for row in &mut rows {
+            for column in &using {
+                row.remove(&format!("{}.{}", table2, column));
+                if let Some(value) = row.remove(&format!("{}.{}", table1, column)) {
+                    row.insert(column.clone(), value);
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Runs `scan` on the table on the blocking thread pool, so reading a big
+    /// table doesn't hold up the other tasks of the runtime. The table stays
+    /// locked meanwhile, and the stats of the scan become the query's.
+    async fn scan<T, F>(&mut self, db: &str, table: &str, scan: F) -> Result<T, PoorlyError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Table) -> Result<T, PoorlyError> + Send + 'static,
+    {
+        let table = self.get_table(db, table).await?;
+        let mut table = table.write_owned().await;
+        let (result, stats) = blocking(move || {
+            let result = scan(&mut table);
+            (result, table.stats)
+        })
+        .await;
+        self.stats = stats;
+        result
+    }
+
+    /// Inserts the rows of `src` matching `conditions` into `dst`, renaming
+    /// columns by `column_map`. Returns the inserted rows.
+    pub async fn copy_into(
+        &mut self,
+        db: String,
+        src: String,
+        dst: String,
+        conditions: ColumnSet,
+        column_map: HashMap<String, String>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let src_table = self.get_table(&db, &src).await?;
+        let dst_table = self.get_table(&db, &dst).await?;
+        let dst_columns = dst_table.read().await.columns.clone();
+
+        // `src` and `dst` may be the same table, so it is only locked once at a time
+        let rows = {
+            let mut src_table = src_table.write().await;
+            let column_map = if column_map.is_empty() {
+                src_table
+                    .columns
+                    .iter()
+                    .filter(|(name, _)| {
+                        dst_columns
+                            .iter()
+                            .any(|(dst, _type)| dst == name && _type != &DataType::Serial)
+                    })
+                    .map(|(name, _)| (name.clone(), name.clone()))
+                    .collect()
+            } else {
+                column_map
+            };
+
+            for (from, to) in &column_map {
+                if !src_table.columns.iter().any(|(name, _)| name == from) {
+                    return Err(PoorlyError::ColumnNotFound(from.clone(), src.clone()));
+                }
+                match dst_columns.iter().find(|(name, _)| name == to) {
+                    None => return Err(PoorlyError::ColumnNotFound(to.clone(), dst.clone())),
+                    Some((_, DataType::Serial)) => {
+                        return Err(PoorlyError::InvalidOperation(format!(
+                            "serial column {}.{} can't be copied into",
+                            dst, to
+                        )))
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            let columns = column_map.keys().cloned().collect();
+            let rows = src_table.select(columns, conditions, None)?;
+            self.stats = src_table.stats;
+            rows.into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|(column, value)| (column_map[&column].clone(), value))
+                        .collect()
+                })
+                .collect()
+        };
+
+        let result = dst_table.write().await.insert_many(rows);
+        result
+    }
+
+    /// Creates `table` with the columns of the result of `select` and inserts
+    /// its rows. Joined `table.column` names become `table_column`, and serial
+    /// columns are copied as plain ints. The rows come out of tables of the
+    /// same column types, so they are appended without being checked again.
+    pub async fn create_as(
+        &mut self,
+        db: String,
+        table: String,
+        select: Query,
+    ) -> Result<(), PoorlyError> {
+        {
+            let db = self.get_database(&db).await?.read().await;
+            let table = db.schema().resolve_table(&table);
+            if db.get_tables().contains(&table) {
+                return Err(PoorlyError::TableAlreadyExists(table));
+            }
+        }
+
+        let no_using = vec![];
+        let (sources, projection, using) = match &select {
+            Query::Select {
+                db, from, columns, ..
+            } => (vec![(db, from, false)], columns, &no_using),
+            Query::Join {
+                db,
+                table1,
+                table2,
+                columns,
+                using,
+                ..
+            } => (vec![(db, table1, true), (db, table2, true)], columns, using),
+            _ => {
+                return Err(PoorlyError::InvalidOperation(
+                    "a table can only be created from a select or a join".to_string(),
+                ))
+            }
+        };
+        let mut columns = Columns::new();
+        let mut names = HashMap::new();
+        for (i, (db, from, prefixed)) in sources.into_iter().enumerate() {
+            let source = self.get_table(db, from).await?.read().await.columns.clone();
+            for (column, _type) in source {
+                // A `using` column comes once, unprefixed, from the first table
+                let shared = using.contains(&column);
+                if shared && i > 0 {
+                    continue;
+                }
+                let plain = column.clone();
+                let column = if prefixed && !shared {
+                    format!("{}.{}", from, column)
+                } else {
+                    column
+                };
+                let wildcard = format!("{}.*", from);
+                let selected = projection.is_empty()
+                    || projection.contains(&column)
+                    || (shared && projection.contains(&format!("{}.{}", from, plain)))
+                    || (prefixed && projection.contains(&wildcard));
+                if !selected {
+                    continue;
+                }
+                let name = column.replace('.', "_");
+                if columns.iter().any(|(other, _)| other == &name) {
+                    return Err(PoorlyError::InvalidOperation(format!(
+                        "column {} appears twice in the result",
+                        name
+                    )));
+                }
+                let _type = match _type {
+                    DataType::Serial => DataType::Int,
+                    _type => _type,
+                };
+                columns.push((name.clone(), _type));
+                names.insert(column, name);
+            }
+        }
+
+        let rows = Box::pin(self.execute(select))
+            .await?
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|(column, value)| {
+                        let value = match value {
+                            TypedValue::Serial(serial) => TypedValue::Int(serial as i64),
+                            value => value,
+                        };
+                        (names[&column].clone(), value)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        self.create_table(
+            db.clone(),
+            table.clone(),
+            columns,
+            Defaults::new(),
+            false,
+            None,
+        )
+        .await?;
+        let inserted = self
+            .get_table(&db, &table)
+            .await?
+            .write()
+            .await
+            .insert_raw_many(rows);
+        if let Err(e) = inserted {
+            self.drop_table(db, table).await?;
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        let result = t1.join(&mut t2, columns, conditions, join_on)?;
+    /// Declared types of the columns a `Select` returns, by the names the
+    /// rows have them under
+    pub async fn column_types(
+        &mut self,
+        query: Query,
+    ) -> Result<HashMap<String, DataType>, PoorlyError> {
+        let query = if self.case_insensitive {
+            self.resolve_names(query).await?
+        } else {
+            query
+        };
+        let Query::Select {
+            db, from, columns, ..
+        } = query
+        else {
+            return Err(PoorlyError::InvalidOperation(
+                "only a select has column types".to_string(),
+            ));
+        };
 
-        Ok(result)
+        let table = self.get_table(&db, &from).await?;
+        let table = table.read().await;
+        Ok(table
+            .columns
+            .iter()
+            .filter(|(name, _)| columns.is_empty() || columns.contains(name))
+            .map(|(name, data_type)| (name.clone(), *data_type))
+            .collect())
+    }
+
+    // Checks by the schema that the tables and columns `query` names exist,
+    // before any table file is opened or locked for it
+    async fn validate(&mut self, query: &Query) -> Result<(), PoorlyError> {
+        let db_name = match query {
+            Query::Select { db, .. }
+            | Query::SelectOne { db, .. }
+            | Query::Count { db, .. }
+            | Query::SelectDeleted { db, .. }
+            | Query::Sample { db, .. }
+            | Query::First { db, .. }
+            | Query::Undelete { db, .. }
+            | Query::Insert { db, .. }
+            | Query::Update { db, .. }
+            | Query::Delete { db, .. }
+            | Query::CopyInto { db, .. }
+            | Query::DeleteKeys { db, .. }
+            | Query::Join { db, .. } => db,
+            _ => return Ok(()),
+        };
+        let db = self.get_database(db_name).await?.read().await;
+        let schema = db.schema();
+
+        let table = |table: &String| {
+            schema
+                .tables
+                .get(table)
+                .map(|_| ())
+                .ok_or_else(|| PoorlyError::TableNotFound(table.clone()))
+        };
+        fn columns<'a>(
+            schema: &Schema,
+            table: &str,
+            columns: impl IntoIterator<Item = &'a String>,
+        ) -> Result<(), PoorlyError> {
+            let Some(existing) = schema.tables.get(table) else {
+                return Err(PoorlyError::TableNotFound(table.to_string()));
+            };
+            for column in columns {
+                if !existing.iter().any(|(name, _)| name == column) {
+                    return Err(PoorlyError::ColumnNotFound(
+                        column.clone(),
+                        table.to_string(),
+                    ));
+                }
+            }
+            Ok(())
+        }
+
+        // An `in_select` runs on its own before the outer select, so it
+        // can't name columns of the outer table as `table.column`
+        fn subquery(outer: &str, select: &Query) -> Result<(), PoorlyError> {
+            let error = |what: &str| Err(PoorlyError::InvalidOperation(what.to_string()));
+            let Query::Select {
+                columns,
+                conditions,
+                predicates,
+                order_by,
+                distinct_on,
+                post_filter,
+                ..
+            } = select
+            else {
+                return error("a subquery must be a select");
+            };
+            if columns.len() != 1 {
+                return error("a subquery must select exactly one column");
+            }
+            let mut named = columns
+                .iter()
+                .chain(conditions.keys())
+                .chain(predicates.keys())
+                .chain(order_by.iter().map(|order| &order.column))
+                .chain(distinct_on)
+                .chain(post_filter.keys());
+            let correlated = named.any(|column| {
+                column
+                    .split_once('.')
+                    .is_some_and(|(table, _)| table.eq_ignore_ascii_case(outer))
+            });
+            if correlated {
+                return error("correlated subqueries are not supported");
+            }
+            Ok(())
+        }
+
+        match query {
+            Query::Select {
+                from,
+                columns: projection,
+                conditions,
+                predicates,
+                order_by,
+                distinct_on,
+                include_deleted,
+                in_select,
+                ..
+            } => {
+                for select in in_select.values() {
+                    subquery(from, select)?;
+                }
+                columns(
+                    schema,
+                    from,
+                    projection
+                        .iter()
+                        .filter(|column| *column != COUNT_ONLY)
+                        .chain(conditions.keys())
+                        .chain(predicates.keys())
+                        .filter(|column| !(*include_deleted && *column == DELETED_COLUMN))
+                        .chain(order_by.iter().map(|order| &order.column))
+                        .chain(distinct_on)
+                        .chain(in_select.keys()),
+                )
+            }
+            Query::SelectOne {
+                from, conditions, ..
+            }
+            | Query::Count {
+                from, conditions, ..
+            }
+            | Query::SelectDeleted {
+                from, conditions, ..
+            } => columns(schema, from, conditions.keys()),
+            Query::Sample { table: name, .. } | Query::Undelete { table: name, .. } => table(name),
+            Query::First {
+                from,
+                predicates,
+                order_by,
+                ..
+            } => columns(schema, from, predicates.keys().chain([&order_by.column])),
+            Query::Insert {
+                into,
+                values,
+                with_offset,
+                returning,
+                ..
+            } => columns(
+                schema,
+                into,
+                values.keys().chain(
+                    returning
+                        .iter()
+                        .filter(|column| !(*with_offset && *column == OFFSET_COLUMN)),
+                ),
+            ),
+            Query::Update {
+                table: name,
+                set,
+                expressions,
+                conditions,
+                predicates,
+                ..
+            } => columns(
+                schema,
+                name,
+                set.keys()
+                    .chain(expressions.keys())
+                    .chain(conditions.keys())
+                    .chain(predicates.keys()),
+            ),
+            Query::Delete {
+                from,
+                conditions,
+                predicates,
+                ..
+            } => columns(schema, from, conditions.keys().chain(predicates.keys())),
+            Query::CopyInto {
+                src,
+                dst,
+                conditions,
+                ..
+            } => {
+                table(dst)?;
+                columns(schema, src, conditions.keys())
+            }
+            Query::DeleteKeys { from, column, .. } => columns(schema, from, [column]),
+            Query::Join {
+                table1,
+                table2,
+                using,
+                ..
+            } => {
+                columns(schema, table1, using)?;
+                columns(schema, table2, using)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Rewrites table and column names of `query` into the case they are stored with
+    async fn resolve_names(&mut self, query: Query) -> Result<Query, PoorlyError> {
+        if let Query::CreateAs { db, table, select } = query {
+            let select = Box::pin(self.resolve_names(*select)).await?;
+            return Ok(Query::CreateAs {
+                db,
+                table,
+                select: Box::new(select),
+            });
+        }
+
+        fn columns(schema: &Schema, table: &str, columns: Vec<String>) -> Vec<String> {
+            columns
+                .into_iter()
+                .map(|column| schema.resolve_column(table, &column))
+                .collect()
+        }
+
+        fn column_set<T>(
+            schema: &Schema,
+            table: &str,
+            set: HashMap<String, T>,
+        ) -> HashMap<String, T> {
+            set.into_iter()
+                .map(|(column, value)| (schema.resolve_column(table, &column), value))
+                .collect()
+        }
+
+        // Join columns are prefixed with their table name: `table.column`
+        fn prefixed(schema: &Schema, column: String) -> String {
+            match column.split_once('.') {
+                Some((table, column)) => {
+                    let table = schema.resolve_table(table);
+                    let column = schema.resolve_column(&table, column);
+                    format!("{}.{}", table, column)
+                }
+                None => column,
+            }
+        }
+
+        let db_name = match &query {
+            Query::CreateDb { .. }
+            | Query::DropDb { .. }
+            | Query::ConvertDb { .. }
+            | Query::CreateAs { .. }
+            | Query::ReleaseSnapshot { .. } => return Ok(query),
+            Query::Select { db, .. }
+            | Query::SelectOne { db, .. }
+            | Query::Count { db, .. }
+            | Query::Sample { db, .. }
+            | Query::First { db, .. }
+            | Query::Insert { db, .. }
+            | Query::Update { db, .. }
+            | Query::Delete { db, .. }
+            | Query::Create { db, .. }
+            | Query::Drop { db, .. }
+            | Query::Alter { db, .. }
+            | Query::ShowTables { db }
+            | Query::ShowCreate { db, .. }
+            | Query::DescribeDb { db }
+            | Query::Comment { db, .. }
+            | Query::Repair { db }
+            | Query::CheckIntegrity { db }
+            | Query::ReloadSchema { db }
+            | Query::SelectDeleted { db, .. }
+            | Query::Undelete { db, .. }
+            | Query::CopyInto { db, .. }
+            | Query::Vacuum { db, .. }
+            | Query::Flush { db, .. }
+            | Query::Snapshot { db }
+            | Query::DeleteKeys { db, .. }
+            | Query::Join { db, .. } => db.clone(),
+        };
+        let db = self.get_database(&db_name).await?.read().await;
+        let schema = db.schema();
+
+        let query = match query {
+            Query::Select {
+                db,
+                from,
+                columns: projection,
+                conditions,
+                predicates,
+                order_by,
+                distinct_on,
+                max_rows,
+                since_serial,
+                post_filter,
+                include_deleted,
+                snapshot,
+                in_select,
+            } => {
+                let from = schema.resolve_table(&from);
+                Query::Select {
+                    db,
+                    columns: columns(schema, &from, projection),
+                    conditions: column_set(schema, &from, conditions),
+                    predicates: column_set(schema, &from, predicates),
+                    order_by: order_by
+                        .into_iter()
+                        .map(|order| OrderBy {
+                            column: schema.resolve_column(&from, &order.column),
+                            ..order
+                        })
+                        .collect(),
+                    distinct_on: columns(schema, &from, distinct_on),
+                    post_filter: column_set(schema, &from, post_filter),
+                    // The selects resolve their own names when they run
+                    in_select: column_set(schema, &from, in_select),
+                    from,
+                    max_rows,
+                    since_serial,
+                    include_deleted,
+                    snapshot,
+                }
+            }
+            Query::SelectOne {
+                db,
+                from,
+                conditions,
+            } => {
+                let from = schema.resolve_table(&from);
+                Query::SelectOne {
+                    db,
+                    conditions: column_set(schema, &from, conditions),
+                    from,
+                }
+            }
+            Query::Count {
+                db,
+                from,
+                conditions,
+            } => {
+                let from = schema.resolve_table(&from);
+                Query::Count {
+                    db,
+                    conditions: column_set(schema, &from, conditions),
+                    from,
+                }
+            }
+            Query::SelectDeleted {
+                db,
+                from,
+                conditions,
+            } => {
+                let from = schema.resolve_table(&from);
+                Query::SelectDeleted {
+                    db,
+                    conditions: column_set(schema, &from, conditions),
+                    from,
+                }
+            }
+            Query::Sample { db, table, n } => Query::Sample {
+                db,
+                table: schema.resolve_table(&table),
+                n,
+            },
+            Query::First {
+                db,
+                from,
+                predicates,
+                order_by,
+            } => {
+                let from = schema.resolve_table(&from);
+                Query::First {
+                    db,
+                    predicates: column_set(schema, &from, predicates),
+                    order_by: OrderBy {
+                        column: schema.resolve_column(&from, &order_by.column),
+                        ..order_by
+                    },
+                    from,
+                }
+            }
+            Query::Undelete { db, table, offset } => Query::Undelete {
+                db,
+                table: schema.resolve_table(&table),
+                offset,
+            },
+            Query::Insert {
+                db,
+                into,
+                values,
+                with_offset,
+                returning,
+            } => {
+                let into = schema.resolve_table(&into);
+                Query::Insert {
+                    db,
+                    values: column_set(schema, &into, values),
+                    returning: columns(schema, &into, returning),
+                    into,
+                    with_offset,
+                }
+            }
+            Query::Update {
+                db,
+                table,
+                set,
+                expressions,
+                conditions,
+                predicates,
+                with_offset,
+                limit,
+            } => {
+                let table = schema.resolve_table(&table);
+                let resolve = |column: String| schema.resolve_column(&table, &column);
+                let expressions = column_set(schema, &table, expressions)
+                    .into_iter()
+                    .map(|(column, expression)| (column, expression.rename_columns(&resolve)))
+                    .collect();
+                Query::Update {
+                    db,
+                    set: column_set(schema, &table, set),
+                    expressions,
+                    conditions: column_set(schema, &table, conditions),
+                    predicates: column_set(schema, &table, predicates),
+                    table,
+                    with_offset,
+                    limit,
+                }
+            }
+            Query::Delete {
+                db,
+                from,
+                conditions,
+                predicates,
+                limit,
+                compact,
+            } => {
+                let from = schema.resolve_table(&from);
+                Query::Delete {
+                    db,
+                    conditions: column_set(schema, &from, conditions),
+                    predicates: column_set(schema, &from, predicates),
+                    from,
+                    limit,
+                    compact,
+                }
+            }
+            Query::Drop {
+                db,
+                table,
+                if_exists,
+            } => Query::Drop {
+                db,
+                table: schema.resolve_table(&table),
+                if_exists,
+            },
+            Query::Vacuum { db, table } => Query::Vacuum {
+                db,
+                table: schema.resolve_table(&table),
+            },
+            Query::ShowCreate { db, table } => Query::ShowCreate {
+                db,
+                table: schema.resolve_table(&table),
+            },
+            Query::Comment {
+                db,
+                table,
+                column,
+                text,
+            } => {
+                let table = schema.resolve_table(&table);
+                Query::Comment {
+                    db,
+                    column: column.map(|column| schema.resolve_column(&table, &column)),
+                    table,
+                    text,
+                }
+            }
+            Query::DeleteKeys {
+                db,
+                from,
+                column,
+                keys,
+            } => {
+                let from = schema.resolve_table(&from);
+                Query::DeleteKeys {
+                    db,
+                    column: schema.resolve_column(&from, &column),
+                    from,
+                    keys,
+                }
+            }
+            Query::Alter { db, table, rename } => {
+                let table = schema.resolve_table(&table);
+                Query::Alter {
+                    db,
+                    rename: rename
+                        .into_iter()
+                        .map(|(from, to)| (schema.resolve_column(&table, &from), to))
+                        .collect(),
+                    table,
+                }
+            }
+            Query::CopyInto {
+                db,
+                src,
+                dst,
+                conditions,
+                column_map,
+            } => {
+                let src = schema.resolve_table(&src);
+                let dst = schema.resolve_table(&dst);
+                Query::CopyInto {
+                    db,
+                    conditions: column_set(schema, &src, conditions),
+                    column_map: column_map
+                        .into_iter()
+                        .map(|(from, to)| {
+                            (
+                                schema.resolve_column(&src, &from),
+                                schema.resolve_column(&dst, &to),
+                            )
+                        })
+                        .collect(),
+                    src,
+                    dst,
+                }
+            }
+            Query::Join {
+                db,
+                table1,
+                table2,
+                columns,
+                conditions,
+                join_on,
+                using,
+                max_rows,
+                post_filter,
+                limit,
+            } => {
+                let table1 = schema.resolve_table(&table1);
+                Query::Join {
+                    db,
+                    table2: schema.resolve_table(&table2),
+                    columns: columns
+                        .into_iter()
+                        .map(|column| prefixed(schema, column))
+                        .collect(),
+                    conditions: conditions
+                        .into_iter()
+                        .map(|(column, value)| (prefixed(schema, column), value))
+                        .collect(),
+                    join_on: join_on
+                        .into_iter()
+                        .map(|(left, right)| (prefixed(schema, left), prefixed(schema, right)))
+                        .collect(),
+                    using: using
+                        .into_iter()
+                        .map(|column| schema.resolve_column(&table1, &column))
+                        .collect(),
+                    table1,
+                    max_rows,
+                    post_filter: post_filter
+                        .into_iter()
+                        .map(|(column, predicate)| (prefixed(schema, column), predicate))
+                        .collect(),
+                    limit,
+                }
+            }
+            // New tables keep the name they are created with
+            query @ (Query::Create { .. }
+            | Query::ShowTables { .. }
+            | Query::Repair { .. }
+            | Query::CheckIntegrity { .. }
+            | Query::ReloadSchema { .. }
+            | Query::Flush { .. }
+            | Query::Snapshot { .. }
+            | Query::DescribeDb { .. }) => query,
+            Query::CreateDb { .. }
+            | Query::DropDb { .. }
+            | Query::ConvertDb { .. }
+            | Query::CreateAs { .. }
+            | Query::ReleaseSnapshot { .. } => unreachable!(),
+        };
+
+        Ok(query)
     }
 
     pub async fn drop_table(&mut self, db: String, table_name: String) -> Result<(), PoorlyError> {
@@ -143,6 +1413,150 @@ impl Poorly {
         Ok(())
     }
 
+    /// Copies database `name` into a new one stored by the `to_kind` engine
+    /// and returns the name of the copy, see `Query::ConvertDb`. Column
+    /// types map to SQLite ones by `DataType::to_sql`, but SQLite has no
+    /// defaults, so a table with any can't be converted to it.
+    pub async fn convert_db(
+        &mut self,
+        name: String,
+        to_kind: SchemaKind,
+        swap: bool,
+    ) -> Result<String, PoorlyError> {
+        let Some(path) = self.path.clone() else {
+            return Err(PoorlyError::InvalidOperation(
+                "an in-memory server has no files to convert".to_string(),
+            ));
+        };
+        // Schemas are encrypted there, and converted databases would not be
+        if self.key.is_some() {
+            return Err(PoorlyError::InvalidOperation(
+                "databases can't be converted on a server with an encryption key".to_string(),
+            ));
+        }
+        if !path.join(&name).exists() {
+            return Err(PoorlyError::DatabaseNotFound(name));
+        }
+        // Write the database through, so its files have every row
+        if let Some(db) = self.databases.remove(&name) {
+            db.into_inner().close().await?;
+        }
+        let schema = Schema::try_load(&path.join(&name))?;
+        let from_kind = schema.kind();
+        if from_kind == to_kind {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "database {} is a {} database already",
+                name, to_kind
+            )));
+        }
+        let copy = format!("{}_{}", name, to_kind);
+        let original = format!("{}_{}", name, from_kind);
+        for taken in [Some(&copy), Some(&original).filter(|_| swap)]
+            .into_iter()
+            .flatten()
+        {
+            if path.join(taken).exists() {
+                return Err(PoorlyError::DatabaseAlreadyExists(taken.clone()));
+            }
+        }
+
+        let converted = match to_kind {
+            SchemaKind::Sqlite => self.convert_to_sqlite(&name, &copy).await,
+            SchemaKind::Poorly => self.convert_to_poorly(&name, &schema, &copy).await,
+        };
+        // Drop the copy as the swap would, and leave no half-written one behind
+        if let Some(db) = self.databases.remove(&copy) {
+            db.into_inner().close().await?;
+        }
+        if let Err(e) = converted {
+            if path.join(&copy).exists() {
+                std::fs::remove_dir_all(path.join(&copy))?;
+            }
+            return Err(e);
+        }
+        log::info!("Database {} converted to {} as {}", name, to_kind, copy);
+        if !swap {
+            return Ok(copy);
+        }
+
+        rename_db(&path, &name, &original)?;
+        if let Err(e) = rename_db(&path, &copy, &name) {
+            // Put the original back under its name, the copy stays aside
+            rename_db(&path, &original, &name)?;
+            return Err(e);
+        }
+        log::info!(
+            "Database {} is now {}, the original is {}",
+            copy,
+            name,
+            original
+        );
+        Ok(name)
+    }
+
+    async fn convert_to_sqlite(&mut self, name: &str, copy: &str) -> Result<(), PoorlyError> {
+        let path = self.path.clone().unwrap();
+        let mut db = self.get_database(name).await?.write().await;
+        let schema = db.schema().clone();
+        for (table, defaults) in &schema.defaults {
+            if let Some(column) = defaults.keys().next() {
+                return Err(PoorlyError::InvalidOperation(format!(
+                    "column {} of table {} has a default, which sqlite databases don't have",
+                    column, table
+                )));
+            }
+        }
+
+        sqlite::create_db(&path, copy)?;
+        let dst = path.join(copy);
+        let mut converted = Schema::try_load(&dst)?;
+        for (table, columns) in &schema.tables {
+            sqlite::create_table(&dst, &mut converted, table.clone(), columns.clone())?;
+            let rows = db
+                .get_table(table)
+                .await?
+                .write()
+                .await
+                .select(vec![], [].into(), None)?;
+            sqlite::insert_rows(&dst, table, columns, rows)?;
+        }
+        converted.table_comments = schema.table_comments;
+        converted.column_comments = schema.column_comments;
+        converted.dump(&dst)?;
+        Ok(())
+    }
+
+    async fn convert_to_poorly(
+        &mut self,
+        name: &str,
+        schema: &Schema,
+        copy: &str,
+    ) -> Result<(), PoorlyError> {
+        let path = self.path.clone().unwrap();
+        let src = path.join(name);
+        Database::create_db(copy.to_string(), path, None)?;
+        let mut db = self.get_database(copy).await?.write().await;
+        for (table, columns) in &schema.tables {
+            db.create_table(table.clone(), columns.clone(), Defaults::new(), false, None)?;
+            for column in [None]
+                .into_iter()
+                .chain(columns.iter().map(|(c, _)| Some(c)))
+            {
+                if let Some(comment) = schema.comment(table, column.map(String::as_str)) {
+                    db.comment(table, column.map(String::as_str), comment.to_string())?;
+                }
+            }
+            // Values of another type than their column's fail to read
+            let rows = sqlite::select_rows(&src, table, columns, &ColumnSet::new())?;
+            let table = db.get_table(table).await?;
+            let mut table = table.write().await;
+            for row in rows {
+                table.insert(row)?;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn alter_table(
         &mut self,
         db: String,
@@ -159,14 +1573,71 @@ impl Poorly {
         db: String,
         table_name: String,
         columns: Columns,
+        defaults: Defaults,
+        compressed: bool,
+        segment_size: Option<u64>,
     ) -> Result<(), PoorlyError> {
         let mut db = self.get_database(&db).await?.write().await;
-        db.create_table(table_name, columns)
+        db.create_table(table_name, columns, defaults, compressed, segment_size)
+    }
+
+    pub async fn schema(&mut self, db: &str) -> Result<Schema, PoorlyError> {
+        let db = self.get_database(db).await?.read().await;
+        Ok(db.schema().clone())
+    }
+
+    // Runs a subquery of `Select::in_select`, which `validate` made sure
+    // selects a single column, and takes its values
+    async fn select_values(&mut self, select: Query) -> Result<Vec<TypedValue>, PoorlyError> {
+        let rows = Box::pin(self.execute(select)).await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| row.into_values().next())
+            .collect())
+    }
+
+    // Serial counter `table` had when `snapshot` was opened. Rows with a
+    // serial from it on were inserted after.
+    fn snapshot_serial(&self, snapshot: u64, db: &str, table: &str) -> Result<u32, PoorlyError> {
+        let Some(opened) = self.snapshots.get(&snapshot) else {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "no snapshot {}",
+                snapshot
+            )));
+        };
+        if opened.db != db {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "snapshot {} is of database {}",
+                snapshot, opened.db
+            )));
+        }
+        // Tables created since weren't there yet
+        opened
+            .serials
+            .get(table)
+            .copied()
+            .ok_or_else(|| PoorlyError::TableNotFound(table.to_string()))
     }
 
     async fn get_database(&mut self, db_name: &str) -> Result<&RwLock<Database>, PoorlyError> {
         if !self.databases.contains_key(db_name) {
-            let db = Database::open(db_name, self.path.clone())?;
+            let db = match &self.path {
+                Some(path) => Database::open(
+                    db_name,
+                    path.clone(),
+                    self.case_insensitive,
+                    self.key.clone(),
+                )?,
+                None if db_name == DEFAULT_DB => {
+                    Database::in_memory(db_name, self.case_insensitive)
+                }
+                None => return Err(PoorlyError::DatabaseNotFound(db_name.to_string())),
+            }
+            .with_read_only(self.read_only)
+            .with_group_commit(self.group_commit.clone())
+            .with_max_name_length(self.max_name_length)
+            .with_schema_format(self.schema_format)
+            .with_clock(self.clock.clone());
             self.databases.insert(db_name.to_string(), RwLock::new(db));
         };
 
@@ -183,21 +1654,212 @@ impl Poorly {
 
         Poorly {
             databases: HashMap::new(),
-            path,
+            path: Some(path),
+            max_rows: DEFAULT_MAX_ROWS,
+            stats: QueryStats::default(),
+            case_insensitive: false,
+            require_delete_limit: false,
+            read_only: false,
+            key: None,
+            group_commit: None,
+            max_name_length: MAX_NAME_LENGTH,
+            schema_format: None,
+            snapshots: BTreeMap::new(),
+            next_snapshot: 0,
+            clock: Arc::new(SystemClock),
+            changes: broadcast::channel(CHANGE_CAPACITY).0,
+        }
+    }
+
+    /// An engine that keeps all databases in memory and never touches the
+    /// disk, for tests and throwaway data. The default database is always
+    /// there, others are created with `CreateDb` as usual.
+    pub fn in_memory() -> Self {
+        log::info!("Opening in-memory server");
+        Poorly {
+            databases: HashMap::new(),
+            path: None,
+            max_rows: DEFAULT_MAX_ROWS,
+            stats: QueryStats::default(),
+            case_insensitive: false,
+            require_delete_limit: false,
+            read_only: false,
+            key: None,
+            group_commit: None,
+            max_name_length: MAX_NAME_LENGTH,
+            schema_format: None,
+            snapshots: BTreeMap::new(),
+            next_snapshot: 0,
+            clock: Arc::new(SystemClock),
+            changes: broadcast::channel(CHANGE_CAPACITY).0,
         }
     }
 
+    /// Makes table and column names case-insensitive. Names keep the case
+    /// they were created with, but names differing only in case collide.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Sets the longest name, in bytes, a table or column may be created
+    /// with. Longer names fail with `InvalidName`.
+    pub fn with_max_name_length(mut self, max_name_length: usize) -> Self {
+        self.max_name_length = max_name_length;
+        self
+    }
+
+    /// Writes schema files as `schema_format`, migrating a database in the
+    /// other format the next time its schema changes. Both are read anyway.
+    pub fn with_schema_format(mut self, schema_format: SchemaFileFormat) -> Self {
+        self.schema_format = Some(schema_format);
+        self
+    }
+
+    /// Takes the time of `now` and `auto_update` defaults from `clock`, e.g.
+    /// a `MockClock` in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Makes deletes without any conditions fail unless they set a limit,
+    /// so a mistyped delete can't wipe a whole table.
+    pub fn with_require_delete_limit(mut self, require_delete_limit: bool) -> Self {
+        self.require_delete_limit = require_delete_limit;
+        self
+    }
+
+    /// Opens every database read-only: table files are opened without write
+    /// access, and every query that `Query::mutates` fails with
+    /// `InvalidOperation` before it touches them.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Holds back inserts to write them to table files, and sync them, in
+    /// groups: once `max_bytes` are pending or the oldest is `max_delay` old.
+    /// A query made through `DatabaseEng` returns once the groups of its rows
+    /// are synced. Calling `execute` directly, wait with `take_groups` and
+    /// `synced`, or a crash may lose the rows of the pending groups.
+    pub fn with_group_commit(mut self, group_commit: Option<GroupCommit>) -> Self {
+        self.group_commit = group_commit.map(Flusher::new);
+        self
+    }
+
+    /// Groups holding the rows written by queries since the last call, see
+    /// `with_group_commit`
+    pub fn take_groups(&self) -> Vec<Arc<Group>> {
+        self.group_commit
+            .as_ref()
+            .map(Flusher::take_groups)
+            .unwrap_or_default()
+    }
+
+    /// Waits until `groups` are synced. Waiting without holding the engine
+    /// lets other queries add their rows to the same groups meanwhile.
+    pub async fn synced(groups: Vec<Arc<Group>>) -> Result<(), PoorlyError> {
+        for group in groups {
+            blocking(move || group.wait()).await?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts schema and table files with a key derived from `passphrase`.
+    /// The salt is kept in a `.salt` file in the server folder, created on
+    /// first use. Files written without encryption can't be read with it on.
+    pub fn with_encryption_key(mut self, passphrase: &str) -> Result<Self, PoorlyError> {
+        let Some(path) = &self.path else {
+            return Err(PoorlyError::InvalidOperation(
+                "an in-memory server has no files to encrypt".to_string(),
+            ));
+        };
+
+        let salt_path = path.join(".salt");
+        let salt = match std::fs::read(&salt_path) {
+            Ok(salt) => salt,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let salt = EncryptionKey::generate_salt();
+                std::fs::create_dir_all(path)?;
+                std::fs::write(&salt_path, salt)?;
+                salt.to_vec()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.key = Some(EncryptionKey::derive(passphrase, &salt)?);
+        Ok(self)
+    }
+
+    /// Rows written by inserts, updates and deletes from now on, of every
+    /// database. Bulk writes like `CopyInto` or `DeleteKeys` aren't sent.
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    fn publish(&self, db: &str, table: &str, kind: ChangeKind, rows: &[ColumnSet]) {
+        if self.changes.receiver_count() == 0 {
+            return;
+        }
+        for row in rows {
+            // Fails only if every subscriber is gone meanwhile
+            let _ = self.changes.send(Change {
+                db: db.to_string(),
+                table: table.to_string(),
+                kind,
+                row: row.clone(),
+            });
+        }
+    }
+
+    pub fn stats(&self) -> QueryStats {
+        self.stats
+    }
+
+    /// Sets the maximum number of rows a buffered select or join may return
+    /// unless the query overrides it.
+    pub fn with_max_rows(mut self, max_rows: usize) -> Self {
+        self.max_rows = max_rows;
+        self
+    }
+
     pub fn init(&self) -> Result<(), PoorlyError> {
-        if self.path.join(DEFAULT_DB).exists() {
-            return Ok(());
+        match &self.path {
+            Some(path) if !self.read_only && !path.join(DEFAULT_DB).exists() => {
+                log::info!("Creating database {} at {:?}", DEFAULT_DB, path);
+                Database::create_db(DEFAULT_DB.to_string(), path.clone(), self.key.as_ref())
+            }
+            _ => Ok(()),
         }
+    }
 
-        self.create_db(DEFAULT_DB.to_string())
+    /// Writes every open database through to the disk and closes it, for a
+    /// clean shutdown that doesn't depend on what is dropped when
+    pub async fn close(mut self) -> Result<(), PoorlyError> {
+        for (name, db) in self.databases.drain() {
+            log::info!("Closing database {}", name);
+            db.into_inner().close().await?;
+        }
+        Ok(())
     }
 
-    pub fn create_db(&self, name: String) -> Result<(), PoorlyError> {
-        log::info!("Creating database {} at {:?}", name, self.path);
-        Database::create_db(name, self.path.clone())
+    pub fn create_db(&mut self, name: String) -> Result<(), PoorlyError> {
+        if self.read_only {
+            return Err(PoorlyError::InvalidOperation("read-only".to_string()));
+        }
+        let Some(path) = &self.path else {
+            if name == DEFAULT_DB || self.databases.contains_key(&name) {
+                return Err(PoorlyError::DatabaseAlreadyExists(name));
+            }
+            let db = Database::in_memory(&name, self.case_insensitive)
+                .with_max_name_length(self.max_name_length)
+                .with_clock(self.clock.clone());
+            self.databases.insert(name, RwLock::new(db));
+            return Ok(());
+        };
+
+        log::info!("Creating database {} at {:?}", name, path);
+        Database::create_db(name, path.clone(), self.key.as_ref())
     }
 
     async fn get_table(&mut self, db: &str, name: &str) -> Result<Arc<RwLock<Table>>, PoorlyError> {
@@ -207,3 +1869,30 @@ impl Poorly {
         tmp
     }
 }
+
+// Renames database folder `from` to `to` along with the name in its schema
+// Moves database `from` to `to`, or leaves it as it was if that fails
+fn rename_db(path: &Path, from: &str, to: &str) -> Result<(), PoorlyError> {
+    std::fs::rename(path.join(from), path.join(to))?;
+    let renamed = Schema::try_load(&path.join(to)).and_then(|mut schema| {
+        schema.rename(to.to_string());
+        Ok(schema.dump(&path.join(to))?)
+    });
+    if let Err(e) = renamed {
+        std::fs::rename(path.join(to), path.join(from))?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Runs `f` on the blocking thread pool. A panic in `f` is passed on, as if
+// `f` had been called directly.
+async fn blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
@@ -0,0 +1,390 @@
+use rusqlite::{params_from_iter, types::ValueRef, Connection};
+use tokio::sync::broadcast;
+
+use crate::core::{
+    database::DEFAULT_DB,
+    schema::{Columns, Schema},
+    types::{ColumnSet, DataType, PoorlyError, Query, QueryStats, TypedValue},
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use super::{Change, ChangeKind};
+
+/// File holding the tables of an sqlite database, in its folder next to
+/// the `.schema`
+pub const SQLITE_FILE: &str = "db.sqlite";
+
+// Changes held for subscribers before the slowest starts missing them
+const CHANGE_CAPACITY: usize = 1024;
+
+/// An engine keeping each database in an SQLite file. The `.schema` of the
+/// database, of the sqlite kind, has the column types, as SQLite only keeps
+/// the broader ones of `DataType::to_sql`. Tables can be created, inserted
+/// into, selected from by equality and counted; other queries fail with
+/// `InvalidOperation`.
+#[derive(Debug)]
+pub struct Sqlite {
+    path: PathBuf,
+    max_rows: usize,
+    // Statistics of the last executed query
+    stats: QueryStats,
+    // Rows written by inserts, see `Sqlite::subscribe`
+    changes: broadcast::Sender<Change>,
+}
+
+impl Sqlite {
+    pub fn open(path: PathBuf) -> Self {
+        log::info!("Opening sqlite server folder at {:?}", path);
+        Sqlite {
+            path,
+            max_rows: super::poorly::DEFAULT_MAX_ROWS,
+            stats: QueryStats::default(),
+            changes: broadcast::channel(CHANGE_CAPACITY).0,
+        }
+    }
+
+    /// Sets the maximum number of rows a select may return unless the
+    /// query overrides it.
+    pub fn with_max_rows(mut self, max_rows: usize) -> Self {
+        self.max_rows = max_rows;
+        self
+    }
+
+    pub fn init(&self) -> Result<(), PoorlyError> {
+        if self.path.join(DEFAULT_DB).exists() {
+            return Ok(());
+        }
+        log::info!("Creating database {} at {:?}", DEFAULT_DB, self.path);
+        create_db(&self.path, DEFAULT_DB)
+    }
+
+    pub fn execute(&mut self, query: Query) -> Result<Vec<ColumnSet>, PoorlyError> {
+        self.stats = QueryStats::default();
+        match query {
+            Query::CreateDb { name } => {
+                create_db(&self.path, &name)?;
+                Ok(vec![])
+            }
+            Query::DropDb { name } => {
+                if name == DEFAULT_DB {
+                    return Err(PoorlyError::CannotDropDefaultDb);
+                }
+                self.schema(&name)?;
+                std::fs::remove_dir_all(self.path.join(&name))?;
+                log::info!("Database {} dropped", name);
+                Ok(vec![])
+            }
+            Query::Create {
+                db,
+                table,
+                columns,
+                defaults,
+                compressed,
+                segment_size,
+                if_not_exists,
+            } => {
+                if !defaults.is_empty() || compressed || segment_size.is_some() {
+                    return Err(unsupported("defaults, compression and segments"));
+                }
+                let mut schema = self.schema(&db)?;
+                let created = !(if_not_exists && schema.has_table(&table, &columns, &defaults));
+                if created {
+                    create_table(&self.path.join(&db), &mut schema, table, columns)?;
+                }
+                Ok(vec![[(
+                    "created".to_string(),
+                    TypedValue::Int(created as i64),
+                )]
+                .into()])
+            }
+            Query::Insert {
+                db,
+                into,
+                values,
+                with_offset,
+                returning,
+            } => {
+                if with_offset {
+                    return Err(unsupported("offsets"));
+                }
+                let schema = self.schema(&db)?;
+                let columns = table_columns(&schema, &into)?;
+                let mut row = coerce(&into, columns, values)?;
+                insert_rows(&self.path.join(&db), &into, columns, [row.clone()])?;
+                self.stats.rows_matched = 1;
+                // Fails only if there are no subscribers
+                let _ = self.changes.send(Change {
+                    db,
+                    table: into,
+                    kind: ChangeKind::Insert,
+                    row: row.clone(),
+                });
+                row.retain(|column, _| returning.is_empty() || returning.contains(column));
+                Ok(vec![row])
+            }
+            Query::Select {
+                db,
+                from,
+                columns,
+                conditions,
+                predicates,
+                order_by,
+                distinct_on,
+                max_rows,
+                since_serial,
+                post_filter,
+                include_deleted,
+                snapshot,
+                in_select,
+            } => {
+                if !predicates.is_empty()
+                    || !order_by.is_empty()
+                    || !distinct_on.is_empty()
+                    || since_serial.is_some()
+                    || !post_filter.is_empty()
+                    || include_deleted
+                    || snapshot.is_some()
+                    || !in_select.is_empty()
+                {
+                    return Err(unsupported("selects by anything but equality"));
+                }
+                let schema = self.schema(&db)?;
+                let table_columns = table_columns(&schema, &from)?;
+                let conditions = coerce(&from, table_columns, conditions)?;
+                let mut rows =
+                    select_rows(&self.path.join(&db), &from, table_columns, &conditions)?;
+                let max_rows = max_rows.unwrap_or(self.max_rows);
+                if rows.len() > max_rows {
+                    return Err(PoorlyError::ResultTooLarge(max_rows));
+                }
+                self.stats.rows_matched = rows.len() as u64;
+                for row in &mut rows {
+                    row.retain(|column, _| columns.is_empty() || columns.contains(column));
+                }
+                Ok(rows)
+            }
+            Query::Count {
+                db,
+                from,
+                conditions,
+            } => {
+                let schema = self.schema(&db)?;
+                let columns = table_columns(&schema, &from)?;
+                let conditions = coerce(&from, columns, conditions)?;
+                let count = select_rows(&self.path.join(&db), &from, columns, &conditions)?.len();
+                self.stats.rows_matched = count as u64;
+                Ok(vec![
+                    [("count".to_string(), TypedValue::Int(count as i64))].into()
+                ])
+            }
+            Query::ShowTables { db } => {
+                let tables: ColumnSet = self
+                    .schema(&db)?
+                    .tables
+                    .into_keys()
+                    .map(|t| (t, TypedValue::String("".to_string())))
+                    .collect();
+                Ok(vec![tables])
+            }
+            _ => Err(unsupported("this query")),
+        }
+    }
+
+    /// Declared types of the columns the rows of a `Select` have
+    pub fn column_types(&self, query: Query) -> Result<HashMap<String, DataType>, PoorlyError> {
+        let Query::Select {
+            db, from, columns, ..
+        } = query
+        else {
+            return Err(PoorlyError::InvalidOperation(
+                "column types are only known for selects".to_string(),
+            ));
+        };
+        let schema = self.schema(&db)?;
+        Ok(table_columns(&schema, &from)?
+            .iter()
+            .filter(|(name, _)| columns.is_empty() || columns.contains(name))
+            .map(|(name, data_type)| (name.clone(), *data_type))
+            .collect())
+    }
+
+    /// Schema of sqlite database `db`
+    pub fn schema(&self, db: &str) -> Result<Schema, PoorlyError> {
+        let path = self.path.join(db);
+        if !path.exists() {
+            return Err(PoorlyError::DatabaseNotFound(db.to_string()));
+        }
+        let schema = Schema::try_load(&path)?;
+        if !schema.is_sqlite() {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "database {} is not an sqlite database",
+                db
+            )));
+        }
+        Ok(schema)
+    }
+
+    /// Rows inserted from now on, of every database
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    pub fn stats(&self) -> QueryStats {
+        self.stats
+    }
+}
+
+fn unsupported(what: &str) -> PoorlyError {
+    PoorlyError::InvalidOperation(format!("{} not supported by the sqlite engine", what))
+}
+
+fn table_columns<'a>(schema: &'a Schema, table: &str) -> Result<&'a Columns, PoorlyError> {
+    schema
+        .tables
+        .get(table)
+        .ok_or_else(|| PoorlyError::TableNotFound(table.to_string()))
+}
+
+// Coerces `values` to the types of the `columns` of `table` they are for
+fn coerce(table: &str, columns: &Columns, values: ColumnSet) -> Result<ColumnSet, PoorlyError> {
+    values
+        .into_iter()
+        .map(|(column, value)| {
+            let Some((_, data_type)) = columns.iter().find(|(name, _)| *name == column) else {
+                return Err(PoorlyError::ColumnNotFound(column, table.to_string()));
+            };
+            let value = value.coerce(*data_type)?;
+            value.validate()?;
+            Ok((column, value))
+        })
+        .collect()
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn connect(path: &Path) -> Result<Connection, PoorlyError> {
+    Ok(Connection::open(path.join(SQLITE_FILE))?)
+}
+
+/// Creates sqlite database `name` in the server folder at `path`
+pub(crate) fn create_db(path: &Path, name: &str) -> Result<(), PoorlyError> {
+    let path = path.join(name);
+    if path.exists() {
+        return Err(PoorlyError::DatabaseAlreadyExists(name.to_string()));
+    }
+    std::fs::create_dir_all(&path)?;
+    Schema::new_sqlite(name.to_string()).dump(&path)?;
+    connect(&path)?;
+    Ok(())
+}
+
+/// Adds `table` to the schema of the sqlite database at `path` and to its file
+pub(crate) fn create_table(
+    path: &Path,
+    schema: &mut Schema,
+    table: String,
+    columns: Columns,
+) -> Result<(), PoorlyError> {
+    schema.create_table(table.clone(), columns.clone())?;
+    let columns = columns
+        .iter()
+        .map(|(column, data_type)| format!("{} {}", quote(column), data_type.to_sql()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    connect(path)?.execute(&format!("CREATE TABLE {} ({})", quote(&table), columns), [])?;
+    schema.dump(path)?;
+    Ok(())
+}
+
+/// Writes `rows` into `table` of the sqlite database at `path`, all or none
+/// of them. Every row must have a value for each of the `columns`.
+pub(crate) fn insert_rows(
+    path: &Path,
+    table: &str,
+    columns: &Columns,
+    rows: impl IntoIterator<Item = ColumnSet>,
+) -> Result<usize, PoorlyError> {
+    let mut connection = connect(path)?;
+    let transaction = connection.transaction()?;
+    let mut inserted = 0;
+    {
+        let names: Vec<String> = columns.iter().map(|(column, _)| quote(column)).collect();
+        let placeholders = vec!["?"; columns.len()].join(", ");
+        let mut statement = transaction.prepare(&format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote(table),
+            names.join(", "),
+            placeholders
+        ))?;
+        for row in rows {
+            let values = columns
+                .iter()
+                .map(|(column, _)| {
+                    row.get(column)
+                        .ok_or_else(|| PoorlyError::IncompleteData(column.clone(), table.into()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            statement.execute(params_from_iter(values))?;
+            inserted += 1;
+        }
+    }
+    transaction.commit()?;
+    Ok(inserted)
+}
+
+/// Rows of `table` of the sqlite database at `path` equal to `conditions`,
+/// with values of the types of `columns`
+pub(crate) fn select_rows(
+    path: &Path,
+    table: &str,
+    columns: &Columns,
+    conditions: &ColumnSet,
+) -> Result<Vec<ColumnSet>, PoorlyError> {
+    let connection = connect(path)?;
+    let names: Vec<String> = columns.iter().map(|(column, _)| quote(column)).collect();
+    let mut sql = format!("SELECT {} FROM {}", names.join(", "), quote(table));
+    let conditions: Vec<(&String, &TypedValue)> = conditions.iter().collect();
+    if !conditions.is_empty() {
+        let tests: Vec<String> = conditions
+            .iter()
+            .map(|(column, _)| format!("{} = ?", quote(column)))
+            .collect();
+        sql.push_str(&format!(" WHERE {}", tests.join(" AND ")));
+    }
+
+    let mut statement = connection.prepare(&sql)?;
+    let mut rows = statement.query(params_from_iter(conditions.iter().map(|(_, value)| value)))?;
+    let mut selected = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut values = ColumnSet::new();
+        for (i, (column, data_type)) in columns.iter().enumerate() {
+            values.insert(column.clone(), from_sql(row.get_ref(i)?, *data_type)?);
+        }
+        selected.push(values);
+    }
+    Ok(selected)
+}
+
+// Reads a value SQLite keeps as the type `DataType::to_sql` maps `data_type`
+// to back into `data_type`. SQLite doesn't enforce column types, so a value
+// of another type fails with `InvalidValue`.
+fn from_sql(value: ValueRef, data_type: DataType) -> Result<TypedValue, PoorlyError> {
+    let value = match value {
+        ValueRef::Integer(i) => TypedValue::Int(i),
+        ValueRef::Real(f) => TypedValue::Float(f),
+        ValueRef::Text(text) => TypedValue::String(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Null | ValueRef::Blob(_) => {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "{:?} value in a column of type {:?}",
+                value.data_type(),
+                data_type
+            )))
+        }
+    };
+    value.coerce(data_type)
+}
@@ -0,0 +1,2188 @@
+use super::{poorly::Poorly, DatabaseEng};
+use crate::core::clock::MockClock;
+use crate::core::database::DEFAULT_DB;
+use crate::core::predicate::Predicate;
+use crate::core::schema::ColumnDefault;
+use crate::core::types::{DataType, OrderBy, PoorlyError, Query, TypedValue};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex;
+
+fn poorly(dir: &tempfile::TempDir) -> Poorly {
+    let poorly = Poorly::open(dir.path().to_path_buf());
+    poorly.init().unwrap();
+    poorly
+}
+
+#[tokio::test]
+async fn case_insensitive() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir).with_case_insensitive(true);
+
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "Users".into(),
+            columns: vec![("Id".into(), DataType::Int)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    poorly
+        .execute(Query::Insert {
+            db: DEFAULT_DB.into(),
+            into: "USERS".into(),
+            values: [("id".into(), TypedValue::Int(1))].into(),
+            with_offset: false,
+            returning: vec![],
+        })
+        .await?;
+
+    let rows = poorly
+        .execute(Query::Select {
+            db: DEFAULT_DB.into(),
+            from: "users".into(),
+            columns: vec!["ID".into()],
+            conditions: [("iD".into(), TypedValue::Int(1))].into(),
+            predicates: HashMap::new(),
+            order_by: vec![],
+            distinct_on: vec![],
+            max_rows: None,
+            since_serial: None,
+            post_filter: HashMap::new(),
+            include_deleted: false,
+            snapshot: None,
+            in_select: HashMap::new(),
+        })
+        .await?;
+    assert_eq!(rows, vec![[("Id".into(), TypedValue::Int(1))].into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn copy_into() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "orders".into(),
+            columns: vec![
+                ("item".into(), DataType::String),
+                ("qty".into(), DataType::Int),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "archive".into(),
+            columns: vec![
+                ("id".into(), DataType::Serial),
+                ("name".into(), DataType::String),
+                ("amount".into(), DataType::Float),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for (item, qty) in [("apple", 1), ("pear", 2), ("plum", 2)] {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "orders".into(),
+                values: [
+                    ("item".into(), TypedValue::String(item.into())),
+                    ("qty".into(), TypedValue::Int(qty)),
+                ]
+                .into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+
+    let copy = |column_map: HashMap<String, String>| Query::CopyInto {
+        db: DEFAULT_DB.into(),
+        src: "orders".into(),
+        dst: "archive".into(),
+        conditions: [("qty".into(), TypedValue::Int(2))].into(),
+        column_map,
+    };
+    assert!(matches!(
+        poorly
+            .execute(copy([("item".into(), "id".into())].into()))
+            .await,
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+
+    let column_map = [
+        ("item".into(), "name".into()),
+        ("qty".into(), "amount".into()),
+    ];
+    assert_eq!(poorly.execute(copy(column_map.into())).await?.len(), 2);
+
+    let mut rows = poorly
+        .execute(Query::Select {
+            db: DEFAULT_DB.into(),
+            from: "archive".into(),
+            columns: vec![],
+            conditions: HashMap::new(),
+            predicates: HashMap::new(),
+            order_by: vec![],
+            distinct_on: vec![],
+            max_rows: None,
+            since_serial: None,
+            post_filter: HashMap::new(),
+            include_deleted: false,
+            snapshot: None,
+            in_select: HashMap::new(),
+        })
+        .await?;
+    rows.sort_by_key(|row| row["id"].to_string());
+    let names: Vec<_> = rows.iter().map(|row| row["name"].clone()).collect();
+    assert_eq!(
+        names,
+        vec![
+            TypedValue::String("pear".into()),
+            TypedValue::String("plum".into())
+        ]
+    );
+    assert!(rows
+        .iter()
+        .all(|row| row["amount"] == TypedValue::Float(2.0)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn create_as() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "orders".into(),
+            columns: vec![
+                ("id".into(), DataType::Serial),
+                ("item".into(), DataType::String),
+                ("qty".into(), DataType::Int),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for (item, qty) in [("apple", 1), ("pear", 2), ("plum", 2)] {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "orders".into(),
+                values: [
+                    ("item".into(), TypedValue::String(item.into())),
+                    ("qty".into(), TypedValue::Int(qty)),
+                ]
+                .into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+
+    let select = |from: &str, columns: Vec<String>, qty: Option<i64>| Query::Select {
+        db: DEFAULT_DB.into(),
+        from: from.into(),
+        columns,
+        conditions: qty
+            .map(|qty| ("qty".to_string(), TypedValue::Int(qty)))
+            .into_iter()
+            .collect(),
+        predicates: HashMap::new(),
+        order_by: vec![],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+    let create_as = |table: &str| Query::CreateAs {
+        db: DEFAULT_DB.into(),
+        table: table.into(),
+        select: Box::new(select("orders", vec!["id".into(), "item".into()], Some(2))),
+    };
+    poorly.execute(create_as("pairs")).await?;
+    assert!(matches!(
+        poorly.execute(create_as("orders")).await,
+        Err(PoorlyError::TableAlreadyExists(_))
+    ));
+
+    let mut rows = poorly.execute(select("pairs", vec![], None)).await?;
+    rows.sort_by_key(|row| row["id"].to_string());
+    // The serial is copied into a plain int column
+    assert_eq!(
+        rows,
+        vec![
+            [
+                ("id".into(), TypedValue::Int(1)),
+                ("item".into(), TypedValue::String("pear".into())),
+            ]
+            .into(),
+            [
+                ("id".into(), TypedValue::Int(2)),
+                ("item".into(), TypedValue::String("plum".into())),
+            ]
+            .into(),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn vacuum_keeps_live_rows() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut db = poorly(&dir);
+
+    db.execute(Query::Create {
+        db: DEFAULT_DB.into(),
+        table: "items".into(),
+        columns: vec![("n".into(), DataType::Int)],
+        defaults: HashMap::new(),
+        compressed: false,
+        segment_size: None,
+        if_not_exists: false,
+    })
+    .await?;
+    for n in 0..200 {
+        db.execute(Query::Insert {
+            db: DEFAULT_DB.into(),
+            into: "items".into(),
+            values: [("n".into(), TypedValue::Int(n))].into(),
+            with_offset: false,
+            returning: vec![],
+        })
+        .await?;
+    }
+    db.execute(Query::Delete {
+        db: DEFAULT_DB.into(),
+        from: "items".into(),
+        conditions: HashMap::new(),
+        predicates: [("n".into(), Predicate::Lt(TypedValue::Int(100)))].into(),
+        limit: None,
+        compact: false,
+    })
+    .await?;
+
+    let select = Query::Select {
+        db: DEFAULT_DB.into(),
+        from: "items".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        predicates: HashMap::new(),
+        order_by: vec![OrderBy {
+            column: "n".into(),
+            descending: false,
+        }],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+    let expected = db.execute(select.clone()).await?;
+    assert_eq!(expected.len(), 100);
+
+    let removed = db
+        .execute(Query::Vacuum {
+            db: DEFAULT_DB.into(),
+            table: "items".into(),
+        })
+        .await?;
+    assert_eq!(removed[0]["removed"], TypedValue::Int(100));
+    assert_eq!(db.execute(select).await?, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn require_delete_limit() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir).with_require_delete_limit(true);
+
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "items".into(),
+            columns: vec![("n".into(), DataType::Int)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for n in 0..5 {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "items".into(),
+                values: [("n".into(), TypedValue::Int(n))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+
+    let delete = |limit| Query::Delete {
+        db: DEFAULT_DB.into(),
+        from: "items".into(),
+        conditions: HashMap::new(),
+        predicates: HashMap::new(),
+        limit,
+        compact: false,
+    };
+    assert!(matches!(
+        poorly.execute(delete(None)).await,
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+    assert_eq!(poorly.execute(delete(Some(3))).await?.len(), 3);
+    assert_eq!(poorly.execute(delete(Some(3))).await?.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn clock() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let clock = Arc::new(MockClock::new(86_400_000));
+    let mut poorly = poorly(&dir).with_clock(clock.clone());
+
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "events".into(),
+            columns: vec![
+                ("n".into(), DataType::Int),
+                ("at".into(), DataType::Timestamp),
+            ],
+            defaults: [("at".into(), ColumnDefault::Now)].into(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for n in 0..3 {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "events".into(),
+                values: [("n".into(), TypedValue::Int(n))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+        clock.advance(1_000);
+    }
+
+    let rows = poorly
+        .execute(Query::Select {
+            db: DEFAULT_DB.into(),
+            from: "events".into(),
+            columns: vec!["n".into()],
+            conditions: HashMap::new(),
+            predicates: [(
+                "at".into(),
+                Predicate::Le(TypedValue::Timestamp(86_401_000)),
+            )]
+            .into(),
+            order_by: vec![OrderBy {
+                column: "n".into(),
+                descending: false,
+            }],
+            distinct_on: vec![],
+            max_rows: None,
+            since_serial: None,
+            post_filter: HashMap::new(),
+            include_deleted: false,
+            snapshot: None,
+            in_select: HashMap::new(),
+        })
+        .await?;
+    let ns: Vec<_> = rows.iter().map(|row| row["n"].clone()).collect();
+    assert_eq!(ns, vec![TypedValue::Int(0), TypedValue::Int(1)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn in_select() -> Result<(), PoorlyError> {
+    let mut poorly = Poorly::in_memory();
+    for (table, columns) in [
+        (
+            "customers",
+            [("id", DataType::Int), ("name", DataType::String)],
+        ),
+        (
+            "orders",
+            [("customer_id", DataType::Int), ("total", DataType::Float)],
+        ),
+    ] {
+        poorly
+            .execute(Query::Create {
+                db: DEFAULT_DB.into(),
+                table: table.into(),
+                columns: columns
+                    .into_iter()
+                    .map(|(name, data_type)| (name.into(), data_type))
+                    .collect(),
+                defaults: HashMap::new(),
+                compressed: false,
+                segment_size: None,
+                if_not_exists: false,
+            })
+            .await?;
+    }
+    let insert = |into: &str, values| Query::Insert {
+        db: DEFAULT_DB.into(),
+        into: into.into(),
+        values,
+        with_offset: false,
+        returning: vec![],
+    };
+    for (id, name) in [(1, "ann"), (2, "bob"), (3, "cid")] {
+        poorly
+            .execute(insert(
+                "customers",
+                crate::row! { "id" => id, "name" => name },
+            ))
+            .await?;
+    }
+    for (customer_id, total) in [(1, 5.0), (3, 20.0), (3, 30.0), (4, 50.0)] {
+        poorly
+            .execute(insert(
+                "orders",
+                crate::row! { "customer_id" => customer_id, "total" => total },
+            ))
+            .await?;
+    }
+
+    let select = |from: &str, columns: Vec<String>, predicates, in_select| Query::Select {
+        db: DEFAULT_DB.into(),
+        from: from.into(),
+        columns,
+        conditions: HashMap::new(),
+        predicates,
+        order_by: vec![],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select,
+    };
+    // Customers with an order over 10
+    let big_orders = select(
+        "orders",
+        vec!["customer_id".into()],
+        [("total".into(), Predicate::Gt(TypedValue::Float(10.0)))].into(),
+        HashMap::new(),
+    );
+    let rows = poorly
+        .execute(select(
+            "customers",
+            vec!["name".into()],
+            HashMap::new(),
+            [("id".into(), big_orders.clone())].into(),
+        ))
+        .await?;
+    assert_eq!(rows, vec![crate::row! { "name" => "cid" }]);
+
+    // Along with the predicates of the outer select
+    let rows = poorly
+        .execute(select(
+            "customers",
+            vec!["name".into()],
+            [("id".into(), Predicate::Lt(TypedValue::Int(3)))].into(),
+            [("id".into(), big_orders)].into(),
+        ))
+        .await?;
+    assert!(rows.is_empty());
+
+    let rejected = [
+        select("orders", vec![], HashMap::new(), HashMap::new()),
+        select(
+            "orders",
+            vec!["customer_id".into(), "total".into()],
+            HashMap::new(),
+            HashMap::new(),
+        ),
+        select(
+            "orders",
+            vec!["customer_id".into()],
+            [("customers.id".into(), Predicate::Gt(TypedValue::Int(0)))].into(),
+            HashMap::new(),
+        ),
+        Query::Count {
+            db: DEFAULT_DB.into(),
+            from: "orders".into(),
+            conditions: HashMap::new(),
+        },
+    ];
+    for subquery in rejected {
+        let result = poorly
+            .execute(select(
+                "customers",
+                vec![],
+                HashMap::new(),
+                [("id".into(), subquery)].into(),
+            ))
+            .await;
+        assert!(
+            matches!(result, Err(PoorlyError::InvalidOperation(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn in_memory() -> Result<(), PoorlyError> {
+    let mut poorly = Poorly::in_memory();
+
+    poorly
+        .execute(Query::CreateDb {
+            name: "scratch".into(),
+        })
+        .await?;
+    poorly
+        .execute(Query::Create {
+            db: "scratch".into(),
+            table: "items".into(),
+            columns: vec![("n".into(), DataType::Int)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for n in 0..3 {
+        poorly
+            .execute(Query::Insert {
+                db: "scratch".into(),
+                into: "items".into(),
+                values: [("n".into(), TypedValue::Int(n))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+    poorly
+        .execute(Query::Delete {
+            db: "scratch".into(),
+            from: "items".into(),
+            conditions: [("n".into(), TypedValue::Int(0))].into(),
+            predicates: HashMap::new(),
+            limit: None,
+            compact: false,
+        })
+        .await?;
+    let removed = poorly
+        .execute(Query::Vacuum {
+            db: "scratch".into(),
+            table: "items".into(),
+        })
+        .await?;
+    assert_eq!(removed[0]["removed"], TypedValue::Int(1));
+
+    let select = Query::Select {
+        db: "scratch".into(),
+        from: "items".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        predicates: HashMap::new(),
+        order_by: vec![OrderBy {
+            column: "n".into(),
+            descending: false,
+        }],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+    let rows = poorly.execute(select.clone()).await?;
+    let ns: Vec<_> = rows.iter().map(|row| row["n"].clone()).collect();
+    assert_eq!(ns, vec![TypedValue::Int(1), TypedValue::Int(2)]);
+
+    // There is no schema file to reload, and dropped databases are gone
+    assert!(matches!(
+        poorly
+            .execute(Query::ReloadSchema {
+                db: "scratch".into()
+            })
+            .await,
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+    poorly
+        .execute(Query::DropDb {
+            name: "scratch".into(),
+        })
+        .await?;
+    assert!(matches!(
+        poorly.execute(select).await,
+        Err(PoorlyError::DatabaseNotFound(_))
+    ));
+
+    // The default database exists from the start and can't be dropped
+    assert!(matches!(
+        poorly
+            .execute(Query::CreateDb {
+                name: DEFAULT_DB.into()
+            })
+            .await,
+        Err(PoorlyError::DatabaseAlreadyExists(_))
+    ));
+    assert!(matches!(
+        poorly
+            .execute(Query::DropDb {
+                name: DEFAULT_DB.into()
+            })
+            .await,
+        Err(PoorlyError::CannotDropDefaultDb)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypted() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let open = |passphrase| -> Result<Poorly, PoorlyError> {
+        let poorly = Poorly::open(dir.path().to_path_buf()).with_encryption_key(passphrase)?;
+        poorly.init()?;
+        Ok(poorly)
+    };
+    let select = Query::Select {
+        db: DEFAULT_DB.into(),
+        from: "accounts".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        predicates: HashMap::new(),
+        order_by: vec![],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+
+    let mut poorly = open("passphrase")?;
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "accounts".into(),
+            columns: vec![("owner".into(), DataType::String)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    poorly
+        .execute(Query::Insert {
+            db: DEFAULT_DB.into(),
+            into: "accounts".into(),
+            values: [("owner".into(), TypedValue::String("alice".into()))].into(),
+            with_offset: false,
+            returning: vec![],
+        })
+        .await?;
+    drop(poorly);
+
+    // Neither the schema nor the rows are stored in plain text
+    for file in [".schema", "accounts"] {
+        let bytes = std::fs::read(dir.path().join(DEFAULT_DB).join(file))?;
+        assert!(!bytes.windows(5).any(|window| window == b"alice"));
+        assert!(!bytes.windows(8).any(|window| window == b"accounts"));
+    }
+
+    let mut poorly = open("passphrase")?;
+    let rows = poorly.execute(select.clone()).await?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["owner"], TypedValue::String("alice".into()));
+
+    let mut poorly = open("wrong")?;
+    assert!(poorly.execute(select).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn create_if_not_exists() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    let create = |columns: Vec<(String, DataType)>, if_not_exists| Query::Create {
+        db: DEFAULT_DB.into(),
+        table: "users".into(),
+        columns,
+        defaults: HashMap::new(),
+        compressed: false,
+        segment_size: None,
+        if_not_exists,
+    };
+    let columns = vec![
+        ("name".into(), DataType::String),
+        ("id".into(), DataType::Int),
+    ];
+
+    let created = poorly.execute(create(columns.clone(), true)).await?;
+    assert_eq!(created[0]["created"], TypedValue::Int(1));
+
+    // The same columns in another order are the same table
+    let mut reordered = columns.clone();
+    reordered.reverse();
+    let created = poorly.execute(create(reordered, true)).await?;
+    assert_eq!(created[0]["created"], TypedValue::Int(0));
+    assert!(matches!(
+        poorly.execute(create(columns, false)).await,
+        Err(PoorlyError::TableAlreadyExists(_))
+    ));
+
+    let conflicting = vec![
+        ("name".into(), DataType::String),
+        ("id".into(), DataType::String),
+    ];
+    assert!(matches!(
+        poorly.execute(create(conflicting, true)).await,
+        Err(PoorlyError::TableAlreadyExists(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn drop_if_exists() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    let drop = |if_exists| Query::Drop {
+        db: DEFAULT_DB.into(),
+        table: "users".into(),
+        if_exists,
+    };
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "users".into(),
+            columns: vec![("name".into(), DataType::String)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+
+    poorly.execute(drop(false)).await?;
+    assert!(poorly.execute(drop(true)).await?.is_empty());
+    assert!(matches!(
+        poorly.execute(drop(false)).await,
+        Err(PoorlyError::TableNotFound(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_only() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    {
+        let mut poorly = poorly(&dir);
+        poorly
+            .execute(Query::Create {
+                db: DEFAULT_DB.into(),
+                table: "users".into(),
+                columns: vec![("name".into(), DataType::String)],
+                defaults: HashMap::new(),
+                compressed: false,
+                segment_size: None,
+                if_not_exists: false,
+            })
+            .await?;
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "users".into(),
+                values: [("name".into(), TypedValue::from("ann"))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+
+    let mut poorly = Poorly::open(dir.path().to_path_buf()).with_read_only(true);
+    let rows = poorly
+        .execute(Query::Select {
+            db: DEFAULT_DB.into(),
+            from: "users".into(),
+            columns: vec![],
+            conditions: HashMap::new(),
+            predicates: HashMap::new(),
+            order_by: vec![],
+            distinct_on: vec![],
+            max_rows: None,
+            since_serial: None,
+            post_filter: HashMap::new(),
+            include_deleted: false,
+            snapshot: None,
+            in_select: HashMap::new(),
+        })
+        .await?;
+    assert_eq!(rows.len(), 1);
+
+    let insert = poorly
+        .execute(Query::Insert {
+            db: DEFAULT_DB.into(),
+            into: "users".into(),
+            values: [("name".into(), TypedValue::from("bob"))].into(),
+            with_offset: false,
+            returning: vec![],
+        })
+        .await;
+    assert!(matches!(insert, Err(PoorlyError::InvalidOperation(_))));
+    let drop = poorly
+        .execute(Query::Drop {
+            db: DEFAULT_DB.into(),
+            table: "users".into(),
+            if_exists: false,
+        })
+        .await;
+    assert!(matches!(drop, Err(PoorlyError::InvalidOperation(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn validated_before_opening_tables() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    poorly(&dir)
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "users".into(),
+            columns: vec![("name".into(), DataType::String)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    // The table file is only created once the table is opened. Opening it
+    // now fails, so only a query checked before that gets to tell what is
+    // wrong with it.
+    std::fs::create_dir(dir.path().join(DEFAULT_DB).join("users"))?;
+
+    let mut poorly = poorly(&dir);
+    let select = |from: &str, column: &str| Query::Select {
+        db: DEFAULT_DB.into(),
+        from: from.into(),
+        columns: vec![column.into()],
+        conditions: HashMap::new(),
+        predicates: HashMap::new(),
+        order_by: vec![],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+    let result = poorly.execute(select("users", "age")).await;
+    assert!(matches!(result, Err(PoorlyError::ColumnNotFound(column, _)) if column == "age"));
+    let result = poorly.execute(select("people", "name")).await;
+    assert!(matches!(result, Err(PoorlyError::TableNotFound(_))));
+    let insert = poorly
+        .execute(Query::Insert {
+            db: DEFAULT_DB.into(),
+            into: "users".into(),
+            values: [("age".into(), TypedValue::Int(42))].into(),
+            with_offset: false,
+            returning: vec![],
+        })
+        .await;
+    assert!(matches!(insert, Err(PoorlyError::ColumnNotFound(_, _))));
+    let delete = poorly
+        .execute(Query::Delete {
+            db: DEFAULT_DB.into(),
+            from: "users".into(),
+            conditions: HashMap::new(),
+            predicates: [("age".into(), Predicate::Gt(TypedValue::Int(1)))].into(),
+            limit: None,
+            compact: false,
+        })
+        .await;
+    assert!(matches!(delete, Err(PoorlyError::ColumnNotFound(_, _))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn scan_does_not_block_runtime() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "numbers".into(),
+            columns: vec![("n".into(), DataType::Int)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for n in 0..2000 {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "numbers".into(),
+                values: [("n".into(), TypedValue::Int(n))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+
+    let poorly = Arc::new(Mutex::new(poorly));
+    let scan = tokio::spawn(async move {
+        poorly
+            .execute(Query::Select {
+                db: DEFAULT_DB.into(),
+                from: "numbers".into(),
+                columns: vec![],
+                conditions: HashMap::new(),
+                predicates: HashMap::new(),
+                order_by: vec![],
+                distinct_on: vec![],
+                max_rows: None,
+                since_serial: None,
+                post_filter: HashMap::new(),
+                include_deleted: false,
+                snapshot: None,
+                in_select: HashMap::new(),
+            })
+            .await
+    });
+
+    // The test runtime has a single thread: had the scan run on it, this
+    // task would only get to run again once the scan was over.
+    let mut ticks = 0;
+    while !scan.is_finished() {
+        tokio::task::yield_now().await;
+        ticks += 1;
+    }
+    assert!(ticks > 1);
+    assert_eq!(scan.await.unwrap()?.len(), 2000);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn since_serial() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "events".into(),
+            columns: vec![
+                ("id".into(), DataType::Serial),
+                ("name".into(), DataType::String),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    let insert = |name: &str| Query::Insert {
+        db: DEFAULT_DB.into(),
+        into: "events".into(),
+        values: [("name".into(), TypedValue::from(name))].into(),
+        with_offset: false,
+        returning: vec![],
+    };
+    let select = |since_serial| Query::Select {
+        db: DEFAULT_DB.into(),
+        from: "events".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        predicates: HashMap::new(),
+        order_by: vec![OrderBy {
+            column: "id".into(),
+            descending: false,
+        }],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+
+    poorly.execute(insert("first")).await?;
+    poorly.execute(insert("second")).await?;
+    let rows = poorly.execute(select(None)).await?;
+    let TypedValue::Serial(watermark) = rows.last().unwrap()["id"] else {
+        panic!("id is not a serial");
+    };
+
+    poorly.execute(insert("third")).await?;
+    poorly.execute(insert("fourth")).await?;
+    let rows = poorly.execute(select(Some(watermark))).await?;
+    let names: Vec<_> = rows.iter().map(|row| row["name"].clone()).collect();
+    assert_eq!(
+        names,
+        [TypedValue::from("third"), TypedValue::from("fourth")]
+    );
+
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "plain".into(),
+            columns: vec![("name".into(), DataType::String)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    let mut plain = select(Some(0));
+    if let Query::Select { from, order_by, .. } = &mut plain {
+        *from = "plain".into();
+        order_by.clear();
+    }
+    assert!(matches!(
+        poorly.execute(plain).await,
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn insert_returning() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "users".into(),
+            columns: vec![
+                ("id".into(), DataType::Serial),
+                ("name".into(), DataType::String),
+                ("bio".into(), DataType::String),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    let insert = |returning: &[&str]| Query::Insert {
+        db: DEFAULT_DB.into(),
+        into: "users".into(),
+        values: [
+            ("name".into(), TypedValue::from("ann")),
+            ("bio".into(), TypedValue::from("a rather long story")),
+        ]
+        .into(),
+        with_offset: false,
+        returning: returning.iter().map(|column| column.to_string()).collect(),
+    };
+
+    let rows = poorly.execute(insert(&["id"])).await?;
+    assert_eq!(rows, vec![[("id".into(), TypedValue::Serial(0))].into()]);
+    let rows = poorly.execute(insert(&[])).await?;
+    assert_eq!(rows[0].len(), 3);
+
+    assert!(matches!(
+        poorly.execute(insert(&["age"])).await,
+        Err(PoorlyError::ColumnNotFound(..))
+    ));
+    let count = poorly
+        .execute(Query::Count {
+            db: DEFAULT_DB.into(),
+            from: "users".into(),
+            conditions: HashMap::new(),
+        })
+        .await?;
+    assert_eq!(count[0]["count"], TypedValue::Int(2));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn join_lock_order() -> Result<(), PoorlyError> {
+    use crate::core::database::lock_pair;
+    use crate::core::table::Table;
+    use crate::core::types::RowFormat;
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    let table = |name: &str| {
+        let columns = vec![("id".into(), DataType::Int)];
+        let table = Table::in_memory(name.into(), columns, HashMap::new(), RowFormat::CURRENT);
+        Arc::new(RwLock::new(table))
+    };
+    let (a, b) = (table("a"), table("b"));
+
+    // Half of the tasks lock `a` and `b`, the other half `b` and `a`
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let (a, b) = (("a", Arc::clone(&a)), ("b", Arc::clone(&b)));
+            tokio::spawn(async move {
+                let (first, second) = if i % 2 == 0 { (a, b) } else { (b, a) };
+                let names = (first.0, second.0);
+                let (first, second) = lock_pair(first, second).await;
+                assert_eq!((first.name.as_str(), second.name.as_str()), names);
+                tokio::task::yield_now().await;
+            })
+        })
+        .collect();
+    let all = async {
+        for task in tasks {
+            task.await.unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), all)
+        .await
+        .expect("joins deadlocked");
+
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "a".into(),
+            columns: vec![("id".into(), DataType::Int)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    let self_join = poorly
+        .join(
+            DEFAULT_DB.into(),
+            "a".into(),
+            "a".into(),
+            vec![],
+            HashMap::new(),
+            [("a.id".into(), "a.id".into())].into(),
+            vec![],
+            10,
+            None,
+        )
+        .await;
+    assert!(matches!(self_join, Err(PoorlyError::InvalidOperation(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn post_filter() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+
+    for (table, column) in [("users", "name"), ("orders", "total")] {
+        poorly
+            .execute(Query::Create {
+                db: DEFAULT_DB.into(),
+                table: table.into(),
+                columns: vec![
+                    ("user_id".into(), DataType::Int),
+                    (column.into(), DataType::Int),
+                ],
+                defaults: HashMap::new(),
+                compressed: false,
+                segment_size: None,
+                if_not_exists: false,
+            })
+            .await?;
+    }
+    for (table, values) in [
+        ("users", [("user_id", 1), ("name", 10)]),
+        ("users", [("user_id", 2), ("name", 20)]),
+        ("orders", [("user_id", 1), ("total", 5)]),
+        ("orders", [("user_id", 2), ("total", 70)]),
+    ] {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: table.into(),
+                values: values
+                    .into_iter()
+                    .map(|(column, value)| (column.to_string(), TypedValue::Int(value)))
+                    .collect(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+    let join = |post_filter| Query::Join {
+        db: DEFAULT_DB.into(),
+        table1: "users".into(),
+        table2: "orders".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        join_on: [("users.user_id".into(), "orders.user_id".into())].into(),
+        using: vec![],
+        max_rows: None,
+        post_filter,
+        limit: None,
+    };
+
+    let rows = poorly.execute(join(HashMap::new())).await?;
+    assert_eq!(rows.len(), 2);
+
+    // The filter sees the prefixed columns of the joined row
+    let rows = poorly
+        .execute(join(
+            [("orders.total".into(), Predicate::Gt(TypedValue::Int(60)))].into(),
+        ))
+        .await?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["users.name"], TypedValue::Int(20));
+
+    let missing = poorly
+        .execute(join(
+            [("total".into(), Predicate::Gt(TypedValue::Int(0)))].into(),
+        ))
+        .await;
+    assert!(matches!(missing, Err(PoorlyError::InvalidOperation(_))));
+
+    // The limit applies to the filtered rows, not the ones the table joins
+    let filter: HashMap<_, _> =
+        [("orders.total".into(), Predicate::Gt(TypedValue::Int(60)))].into();
+    let rows = poorly
+        .execute(Query::Join {
+            db: DEFAULT_DB.into(),
+            table1: "users".into(),
+            table2: "orders".into(),
+            columns: vec![],
+            conditions: HashMap::new(),
+            join_on: [("users.user_id".into(), "orders.user_id".into())].into(),
+            using: vec![],
+            max_rows: None,
+            post_filter: filter,
+            limit: Some(1),
+        })
+        .await?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["users.name"], TypedValue::Int(20));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn join_limit_with_post_filter() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+
+    for table in ["users", "orders"] {
+        poorly
+            .execute(Query::Create {
+                db: DEFAULT_DB.into(),
+                table: table.into(),
+                columns: vec![("user_id".into(), DataType::Int)],
+                defaults: HashMap::new(),
+                compressed: false,
+                segment_size: None,
+                if_not_exists: false,
+            })
+            .await?;
+    }
+    // Orders are stored in the reverse of the output order
+    for (table, user_id) in [("users", 1), ("users", 2), ("orders", 2), ("orders", 1)] {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: table.into(),
+                values: [("user_id".into(), TypedValue::Int(user_id))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+    let join = |post_filter| Query::Join {
+        db: DEFAULT_DB.into(),
+        table1: "users".into(),
+        table2: "orders".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        join_on: [("users.user_id".into(), "orders.user_id".into())].into(),
+        using: vec![],
+        max_rows: None,
+        post_filter,
+        limit: Some(1),
+    };
+
+    // A filter every row passes doesn't change which rows are first
+    let unfiltered = poorly.execute(join(HashMap::new())).await?;
+    let filtered = poorly
+        .execute(join(
+            [("orders.user_id".into(), Predicate::Gt(TypedValue::Int(0)))].into(),
+        ))
+        .await?;
+    assert_eq!(unfiltered, filtered);
+    assert_eq!(unfiltered.len(), 1);
+    assert_eq!(unfiltered[0]["orders.user_id"], TypedValue::Int(1));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn join_using() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+
+    for (table, column) in [("users", "name"), ("orders", "total")] {
+        poorly
+            .execute(Query::Create {
+                db: DEFAULT_DB.into(),
+                table: table.into(),
+                columns: vec![("id".into(), DataType::Int), (column.into(), DataType::Int)],
+                defaults: HashMap::new(),
+                compressed: false,
+                segment_size: None,
+                if_not_exists: false,
+            })
+            .await?;
+    }
+    for (table, values) in [
+        ("users", [("id", 1), ("name", 10)]),
+        ("users", [("id", 2), ("name", 20)]),
+        ("orders", [("id", 2), ("total", 70)]),
+    ] {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: table.into(),
+                values: values
+                    .into_iter()
+                    .map(|(column, value)| (column.to_string(), TypedValue::Int(value)))
+                    .collect(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+    let join = |using: &str| Query::Join {
+        db: DEFAULT_DB.into(),
+        table1: "users".into(),
+        table2: "orders".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        join_on: HashMap::new(),
+        using: vec![using.into()],
+        max_rows: None,
+        post_filter: HashMap::new(),
+        limit: None,
+    };
+
+    let rows = poorly.execute(join("id")).await?;
+    assert_eq!(
+        rows,
+        vec![[
+            ("id".into(), TypedValue::Int(2)),
+            ("users.name".into(), TypedValue::Int(20)),
+            ("orders.total".into(), TypedValue::Int(70)),
+        ]
+        .into()]
+    );
+
+    // The column is taken from both tables
+    let missing = poorly.execute(join("name")).await;
+    assert!(matches!(missing, Err(PoorlyError::ColumnNotFound(..))));
+
+    poorly
+        .execute(Query::CreateAs {
+            db: DEFAULT_DB.into(),
+            table: "sales".into(),
+            select: Box::new(join("id")),
+        })
+        .await?;
+    let schema = poorly.schema(DEFAULT_DB).await?;
+    assert_eq!(
+        schema.tables["sales"],
+        vec![
+            ("id".into(), DataType::Int),
+            ("users_name".into(), DataType::Int),
+            ("orders_total".into(), DataType::Int),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn join_using_case_insensitive() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir).with_case_insensitive(true);
+
+    for table in ["Users", "Orders"] {
+        poorly
+            .execute(Query::Create {
+                db: DEFAULT_DB.into(),
+                table: table.into(),
+                columns: vec![("Id".into(), DataType::Int)],
+                defaults: HashMap::new(),
+                compressed: false,
+                segment_size: None,
+                if_not_exists: false,
+            })
+            .await?;
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: table.into(),
+                values: [("Id".into(), TypedValue::Int(1))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+
+    // `using` columns are looked up in the table as it is named in the schema
+    let rows = poorly
+        .execute(Query::Join {
+            db: DEFAULT_DB.into(),
+            table1: "users".into(),
+            table2: "orders".into(),
+            columns: vec![],
+            conditions: HashMap::new(),
+            join_on: HashMap::new(),
+            using: vec!["ID".into()],
+            max_rows: None,
+            post_filter: HashMap::new(),
+            limit: None,
+        })
+        .await?;
+    assert_eq!(rows, vec![[("Id".into(), TypedValue::Int(1))].into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn flush() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "notes".into(),
+            columns: vec![("text".into(), DataType::String)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    poorly
+        .execute(Query::Insert {
+            db: DEFAULT_DB.into(),
+            into: "notes".into(),
+            values: [("text".into(), TypedValue::from("flushed to disk"))].into(),
+            with_offset: false,
+            returning: vec![],
+        })
+        .await?;
+
+    let flush = |close| Query::Flush {
+        db: DEFAULT_DB.into(),
+        close,
+    };
+    let rows = poorly.execute(flush(true)).await?;
+    assert_eq!(rows[0]["flushed"], TypedValue::Int(1));
+
+    // The row and the table are on disk, as a copy of the directory would see them
+    let path = dir.path().join(DEFAULT_DB);
+    let bytes = std::fs::read(path.join("notes"))?;
+    assert!(bytes.windows(15).any(|window| window == b"flushed to disk"));
+    let schema = std::fs::read_to_string(path.join(".schema"))?;
+    assert!(schema.contains("notes"));
+
+    // Closed tables are opened again when used
+    let rows = poorly.execute(flush(false)).await?;
+    assert_eq!(rows[0]["flushed"], TypedValue::Int(0));
+    let count = poorly
+        .execute(Query::Count {
+            db: DEFAULT_DB.into(),
+            from: "notes".into(),
+            conditions: HashMap::new(),
+        })
+        .await?;
+    assert_eq!(count[0]["count"], TypedValue::Int(1));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn group_commit() -> Result<(), PoorlyError> {
+    use crate::core::table::row_store::GroupCommit;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir()?;
+    let group_commit = GroupCommit {
+        max_bytes: 4096,
+        max_delay: Duration::from_millis(20),
+    };
+    let db = Arc::new(Mutex::new(
+        poorly(&dir).with_group_commit(Some(group_commit)),
+    ));
+    db.execute(Query::Create {
+        db: DEFAULT_DB.into(),
+        table: "events".into(),
+        columns: vec![("id".into(), DataType::Serial), ("n".into(), DataType::Int)],
+        defaults: HashMap::new(),
+        compressed: false,
+        segment_size: None,
+        if_not_exists: false,
+    })
+    .await?;
+
+    let tasks: Vec<_> = (0..200)
+        .map(|n| {
+            let db = Arc::clone(&db);
+            tokio::spawn(async move {
+                db.execute(Query::Insert {
+                    db: DEFAULT_DB.into(),
+                    into: "events".into(),
+                    values: [("n".into(), TypedValue::Int(n))].into(),
+                    with_offset: false,
+                    returning: vec![],
+                })
+                .await
+            })
+        })
+        .collect();
+    let mut ids = HashSet::new();
+    for task in tasks {
+        let rows = task.await.unwrap()?;
+        assert!(ids.insert(rows[0]["id"].canonical_key()));
+    }
+    assert_eq!(ids.len(), 200);
+
+    // Inserts return once their group is on disk, the last one included
+    let path = dir.path().join(DEFAULT_DB).join("events");
+    let written = std::fs::metadata(&path)?.len();
+    db.execute(Query::Flush {
+        db: DEFAULT_DB.into(),
+        close: true,
+    })
+    .await?;
+    assert_eq!(std::fs::metadata(&path)?.len(), written);
+
+    let rows = poorly(&dir)
+        .execute(Query::Select {
+            db: DEFAULT_DB.into(),
+            from: "events".into(),
+            columns: vec!["id".into()],
+            conditions: HashMap::new(),
+            predicates: HashMap::new(),
+            order_by: vec![],
+            distinct_on: vec![],
+            max_rows: None,
+            since_serial: None,
+            post_filter: HashMap::new(),
+            include_deleted: false,
+            snapshot: None,
+            in_select: HashMap::new(),
+        })
+        .await?;
+    let stored: HashSet<_> = rows.iter().map(|row| row["id"].canonical_key()).collect();
+    assert_eq!(stored, ids);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn close() -> Result<(), PoorlyError> {
+    use crate::core::schema::Schema;
+    use crate::core::table::row_store::GroupCommit;
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir()?;
+    // Inserts are held back for far longer than the test takes
+    let mut poorly = poorly(&dir).with_group_commit(Some(GroupCommit {
+        max_bytes: 1 << 20,
+        max_delay: Duration::from_secs(3600),
+    }));
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "users".into(),
+            columns: vec![("name".into(), DataType::String)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for name in ["ann", "bob"] {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "users".into(),
+                values: [("name".into(), TypedValue::from(name))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+    poorly
+        .execute(Query::Comment {
+            db: DEFAULT_DB.into(),
+            table: "users".into(),
+            column: None,
+            text: "People".into(),
+        })
+        .await?;
+    poorly.close().await?;
+
+    let schema = Schema::try_load(&dir.path().join(DEFAULT_DB))?;
+    assert_eq!(schema.comment("users", None), Some("People"));
+    let file = std::fs::read(dir.path().join(DEFAULT_DB).join("users"))?;
+    let names = String::from_utf8_lossy(&file);
+    assert!(names.contains("ann") && names.contains("bob"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn include_deleted() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "users".into(),
+            columns: vec![("name".into(), DataType::String)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for name in ["ann", "bob"] {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "users".into(),
+                values: [("name".into(), TypedValue::from(name))].into(),
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+    poorly
+        .execute(Query::Delete {
+            db: DEFAULT_DB.into(),
+            from: "users".into(),
+            conditions: [("name".into(), TypedValue::from("bob"))].into(),
+            predicates: HashMap::new(),
+            limit: None,
+            compact: false,
+        })
+        .await?;
+
+    let select = |include_deleted, predicates| Query::Select {
+        db: DEFAULT_DB.into(),
+        from: "users".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        predicates,
+        order_by: vec![],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+    let rows = poorly.execute(select(false, HashMap::new())).await?;
+    assert_eq!(
+        rows,
+        vec![[("name".into(), TypedValue::from("ann"))].into()]
+    );
+
+    let rows = poorly.execute(select(true, HashMap::new())).await?;
+    let rows: Vec<_> = rows
+        .iter()
+        .map(|row| (row["name"].clone(), row["_deleted"].clone()))
+        .collect();
+    assert_eq!(
+        rows,
+        vec![
+            (TypedValue::from("ann"), TypedValue::Int(0)),
+            (TypedValue::from("bob"), TypedValue::Int(1)),
+        ]
+    );
+
+    let deleted = [("_deleted".into(), Predicate::Eq(TypedValue::Int(1)))].into();
+    let rows = poorly.execute(select(true, deleted)).await?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["name"], TypedValue::from("bob"));
+    let deleted = [("_deleted".into(), Predicate::Eq(TypedValue::Int(1)))].into();
+    assert!(matches!(
+        poorly.execute(select(false, deleted)).await,
+        Err(PoorlyError::ColumnNotFound(_, _))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn snapshot() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let db: Arc<dyn DatabaseEng> = Arc::new(Mutex::new(poorly(&dir)));
+    db.execute(Query::Create {
+        db: DEFAULT_DB.into(),
+        table: "events".into(),
+        columns: vec![("id".into(), DataType::Serial), ("n".into(), DataType::Int)],
+        defaults: HashMap::new(),
+        compressed: false,
+        segment_size: None,
+        if_not_exists: false,
+    })
+    .await?;
+    let insert = |n| Query::Insert {
+        db: DEFAULT_DB.into(),
+        into: "events".into(),
+        values: [("n".into(), TypedValue::Int(n))].into(),
+        with_offset: false,
+        returning: vec![],
+    };
+    for n in 0..2 {
+        db.execute(insert(n)).await?;
+    }
+
+    let opened = db
+        .execute(Query::Snapshot {
+            db: DEFAULT_DB.into(),
+        })
+        .await?;
+    let TypedValue::Int(id) = opened[0]["snapshot"] else {
+        panic!("no snapshot id in {:?}", opened);
+    };
+    let select = |snapshot, predicates| Query::Select {
+        db: DEFAULT_DB.into(),
+        from: "events".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        predicates,
+        order_by: vec![],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot,
+        in_select: HashMap::new(),
+    };
+
+    let writer = {
+        let db = Arc::clone(&db);
+        tokio::spawn(async move {
+            for n in 2..20 {
+                db.execute(insert(n)).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        })
+    };
+    while !writer.is_finished() {
+        let rows = db.execute(select(Some(id as u64), HashMap::new())).await?;
+        assert_eq!(rows.len(), 2);
+        tokio::task::yield_now().await;
+    }
+    writer.await.unwrap();
+
+    assert_eq!(db.execute(select(None, HashMap::new())).await?.len(), 20);
+    assert_eq!(
+        db.execute(select(Some(id as u64), HashMap::new()))
+            .await?
+            .len(),
+        2
+    );
+    // A predicate on the serial column is kept along with the snapshot's
+    let first = [("n".into(), Predicate::Eq(TypedValue::Int(0)))].into();
+    assert_eq!(db.execute(select(Some(id as u64), first)).await?.len(), 1);
+    let id_past = [("id".into(), Predicate::Ge(TypedValue::Serial(1)))].into();
+    assert_eq!(db.execute(select(Some(id as u64), id_past)).await?.len(), 1);
+
+    db.execute(Query::ReleaseSnapshot {
+        snapshot: id as u64,
+    })
+    .await?;
+    let released = db.execute(select(Some(id as u64), HashMap::new())).await;
+    assert!(matches!(released, Err(PoorlyError::InvalidOperation(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn snapshots_are_capped() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    let open = || Query::Snapshot {
+        db: DEFAULT_DB.into(),
+    };
+    for _ in 0..1024 {
+        poorly.execute(open()).await?;
+    }
+
+    // No snapshot is dropped to make room, a new one is refused instead
+    assert!(matches!(
+        poorly.execute(open()).await,
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+    poorly
+        .execute(Query::ReleaseSnapshot { snapshot: 0 })
+        .await?;
+    let reply = poorly.execute(open()).await?;
+    assert_eq!(reply[0]["snapshot"], TypedValue::Int(1024));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn convert_db() -> Result<(), PoorlyError> {
+    use super::sqlite::Sqlite;
+    use crate::core::schema::SchemaKind;
+
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::CreateDb {
+            name: "shop".into(),
+        })
+        .await?;
+    poorly
+        .execute(Query::Create {
+            db: "shop".into(),
+            table: "orders".into(),
+            columns: vec![
+                ("id".into(), DataType::Int16),
+                ("email".into(), DataType::Email),
+                ("price".into(), DataType::Float),
+                ("size".into(), DataType::Char),
+                ("placed".into(), DataType::Timestamp),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for (id, email, size) in [(1, "ann@gmail.com", 'S'), (2, "bob@gmail.com", 'L')] {
+        poorly
+            .execute(Query::Insert {
+                db: "shop".into(),
+                into: "orders".into(),
+                values: crate::row! {
+                    "id" => TypedValue::Int16(id),
+                    "email" => TypedValue::Email(email.into()),
+                    "price" => 2.5,
+                    "size" => TypedValue::Char(size),
+                    "placed" => TypedValue::Timestamp(1_700_000_000_000),
+                },
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+    let convert = |to_kind, swap| Query::ConvertDb {
+        name: "shop".into(),
+        to_kind,
+        swap,
+    };
+
+    let reply = poorly.execute(convert(SchemaKind::Sqlite, false)).await?;
+    assert_eq!(reply[0]["db"], TypedValue::from("shop_sqlite"));
+
+    // The copy is read back through the sqlite engine with the same types
+    let sqlite = Mutex::new(Sqlite::open(dir.path().to_path_buf()));
+    let select = |db: &str| Query::Select {
+        db: db.into(),
+        from: "orders".into(),
+        columns: vec![],
+        conditions: [("id".into(), TypedValue::Int(2))].into(),
+        predicates: HashMap::new(),
+        order_by: vec![],
+        distinct_on: vec![],
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+    let rows = sqlite.execute(select("shop_sqlite")).await?;
+    assert_eq!(rows, poorly.execute(select("shop")).await?);
+    assert_eq!(rows[0]["email"], TypedValue::Email("bob@gmail.com".into()));
+    assert_eq!(rows[0]["size"], TypedValue::Char('L'));
+
+    // Back to poorly, and a database that is of the kind already
+    let reply = poorly
+        .execute(Query::ConvertDb {
+            name: "shop_sqlite".into(),
+            to_kind: SchemaKind::Poorly,
+            swap: false,
+        })
+        .await?;
+    assert_eq!(reply[0]["db"], TypedValue::from("shop_sqlite_poorly"));
+    assert_eq!(
+        poorly.execute(select("shop_sqlite_poorly")).await?,
+        poorly.execute(select("shop")).await?
+    );
+    assert!(matches!(
+        poorly.execute(convert(SchemaKind::Poorly, false)).await,
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+    assert!(matches!(
+        poorly.execute(convert(SchemaKind::Sqlite, false)).await,
+        Err(PoorlyError::DatabaseAlreadyExists(_))
+    ));
+
+    // With a swap the copy takes the name and the original is kept aside
+    sqlite
+        .execute(Query::DropDb {
+            name: "shop_sqlite".into(),
+        })
+        .await?;
+    let expected = poorly.execute(select("shop")).await?;
+    let reply = poorly.execute(convert(SchemaKind::Sqlite, true)).await?;
+    assert_eq!(reply[0]["db"], TypedValue::from("shop"));
+    assert_eq!(sqlite.execute(select("shop")).await?, expected);
+    assert_eq!(poorly.execute(select("shop_poorly")).await?, expected);
+    assert!(sqlite.schema("shop").await?.is_sqlite());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn convert_db_with_defaults() -> Result<(), PoorlyError> {
+    use crate::core::schema::SchemaKind;
+
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "events".into(),
+            columns: vec![("at".into(), DataType::Timestamp)],
+            defaults: [("at".into(), ColumnDefault::Now)].into(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+
+    // SQLite has no defaults, so nothing is converted
+    let result = poorly
+        .execute(Query::ConvertDb {
+            name: DEFAULT_DB.into(),
+            to_kind: SchemaKind::Sqlite,
+            swap: false,
+        })
+        .await;
+    assert!(matches!(result, Err(PoorlyError::InvalidOperation(_))));
+    assert!(!dir.path().join(format!("{}_sqlite", DEFAULT_DB)).exists());
+
+    Ok(())
+}
+
+#[test]
+fn sqlite_engine() -> Result<(), PoorlyError> {
+    use super::sqlite::Sqlite;
+
+    let dir = tempfile::tempdir()?;
+    let mut sqlite = Sqlite::open(dir.path().to_path_buf()).with_max_rows(2);
+    sqlite.init()?;
+    sqlite.execute(Query::CreateDb {
+        name: "shop".into(),
+    })?;
+    sqlite.execute(Query::Create {
+        db: "shop".into(),
+        table: "orders".into(),
+        columns: vec![
+            ("id".into(), DataType::Int16),
+            ("email".into(), DataType::Email),
+        ],
+        defaults: HashMap::new(),
+        compressed: false,
+        segment_size: None,
+        if_not_exists: false,
+    })?;
+
+    // Values are coerced to their column's type, and only `returning` comes back
+    for (id, email) in [
+        (1, "ann@gmail.com"),
+        (2, "bob@gmail.com"),
+        (3, "ann@gmail.com"),
+    ] {
+        let reply = sqlite.execute(Query::Insert {
+            db: "shop".into(),
+            into: "orders".into(),
+            values: crate::row! { "id" => id, "email" => email },
+            with_offset: false,
+            returning: vec!["id".into()],
+        })?;
+        assert_eq!(reply, vec![crate::row! { "id" => TypedValue::Int16(id) }]);
+    }
+
+    let select = |conditions, max_rows| Query::Select {
+        db: "shop".into(),
+        from: "orders".into(),
+        columns: vec!["id".into()],
+        conditions,
+        predicates: HashMap::new(),
+        order_by: vec![],
+        distinct_on: vec![],
+        max_rows,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+    let ann = crate::row! { "email" => TypedValue::Email("ann@gmail.com".into()) };
+    let mut rows = sqlite.execute(select(ann.clone(), None))?;
+    rows.sort_by_key(|row| row["id"].as_int());
+    assert_eq!(
+        rows,
+        vec![
+            crate::row! { "id" => TypedValue::Int16(1) },
+            crate::row! { "id" => TypedValue::Int16(3) },
+        ]
+    );
+    assert!(matches!(
+        sqlite.execute(select(HashMap::new(), None)),
+        Err(PoorlyError::ResultTooLarge(2))
+    ));
+    assert_eq!(sqlite.execute(select(HashMap::new(), Some(3)))?.len(), 3);
+
+    let count = sqlite.execute(Query::Count {
+        db: "shop".into(),
+        from: "orders".into(),
+        conditions: ann,
+    })?;
+    assert_eq!(count, vec![crate::row! { "count" => TypedValue::Int(2) }]);
+
+    let tables = sqlite.execute(Query::ShowTables { db: "shop".into() })?;
+    assert_eq!(tables[0].keys().collect::<Vec<_>>(), vec!["orders"]);
+
+    assert!(matches!(
+        sqlite.execute(Query::DropDb {
+            name: DEFAULT_DB.into(),
+        }),
+        Err(PoorlyError::CannotDropDefaultDb)
+    ));
+    sqlite.execute(Query::DropDb {
+        name: "shop".into(),
+    })?;
+    assert!(!dir.path().join("shop").exists());
+    assert!(matches!(
+        sqlite.execute(Query::ShowTables { db: "shop".into() }),
+        Err(PoorlyError::DatabaseNotFound(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn distinct_on() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut poorly = poorly(&dir);
+    poorly
+        .execute(Query::Create {
+            db: DEFAULT_DB.into(),
+            table: "visits".into(),
+            columns: vec![
+                ("user".into(), DataType::String),
+                ("page".into(), DataType::String),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })
+        .await?;
+    for (user, page) in [("ann", "home"), ("bob", "cart"), ("ann", "shop")] {
+        poorly
+            .execute(Query::Insert {
+                db: DEFAULT_DB.into(),
+                into: "visits".into(),
+                values: crate::row! { "user" => user, "page" => page },
+                with_offset: false,
+                returning: vec![],
+            })
+            .await?;
+    }
+    let select = |distinct_on: Vec<String>| Query::Select {
+        db: DEFAULT_DB.into(),
+        from: "visits".into(),
+        columns: vec![],
+        conditions: HashMap::new(),
+        predicates: HashMap::new(),
+        order_by: vec![],
+        distinct_on,
+        max_rows: None,
+        since_serial: None,
+        post_filter: HashMap::new(),
+        include_deleted: false,
+        snapshot: None,
+        in_select: HashMap::new(),
+    };
+
+    // One whole row per user, the first one in the table
+    let mut rows = poorly.execute(select(vec!["user".into()])).await?;
+    rows.sort_by_key(|row| row["user"].to_string());
+    assert_eq!(
+        rows,
+        vec![
+            crate::row! { "user" => "ann", "page" => "home" },
+            crate::row! { "user" => "bob", "page" => "cart" },
+        ]
+    );
+
+    assert!(matches!(
+        poorly.execute(select(vec!["missing".into()])).await,
+        Err(PoorlyError::ColumnNotFound(..))
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+
+use super::types::{ColumnSet, PoorlyError, TypedValue};
+
+use std::collections::HashMap;
+
+#[cfg(test)]
+mod tests;
+
+/// A value computed from the row being updated, like `stock - 1`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expression {
+    // The current value of a column
+    Column(String),
+    Literal(TypedValue),
+    Add(Box<Expression>, Box<Expression>),
+    Sub(Box<Expression>, Box<Expression>),
+    Mul(Box<Expression>, Box<Expression>),
+    Div(Box<Expression>, Box<Expression>),
+    // Remainder of dividing the left by the right, with the sign of the left
+    Mod(Box<Expression>, Box<Expression>),
+}
+
+pub type Expressions = HashMap<String, Expression>;
+
+impl Expression {
+    /// The columns the expression reads
+    pub fn columns(&self) -> Vec<&String> {
+        match self {
+            Expression::Column(column) => vec![column],
+            Expression::Literal(_) => vec![],
+            Expression::Add(left, right)
+            | Expression::Sub(left, right)
+            | Expression::Mul(left, right)
+            | Expression::Div(left, right)
+            | Expression::Mod(left, right) => {
+                let mut columns = left.columns();
+                columns.extend(right.columns());
+                columns
+            }
+        }
+    }
+
+    /// Renames the columns the expression reads with `rename`
+    pub fn rename_columns(self, rename: &impl Fn(String) -> String) -> Self {
+        let both =
+            |operator: fn(Box<Self>, Box<Self>) -> Self, left: Box<Self>, right: Box<Self>| {
+                operator(
+                    Box::new(left.rename_columns(rename)),
+                    Box::new(right.rename_columns(rename)),
+                )
+            };
+        match self {
+            Expression::Column(column) => Expression::Column(rename(column)),
+            Expression::Literal(value) => Expression::Literal(value),
+            Expression::Add(left, right) => both(Expression::Add, left, right),
+            Expression::Sub(left, right) => both(Expression::Sub, left, right),
+            Expression::Mul(left, right) => both(Expression::Mul, left, right),
+            Expression::Div(left, right) => both(Expression::Div, left, right),
+            Expression::Mod(left, right) => both(Expression::Mod, left, right),
+        }
+    }
+
+    /// Evaluates the expression on `row`. Integers of any width are computed
+    /// as `Int`, anything with a float as `Float`, and overflowing or dividing
+    /// by zero is an error. Dividing integers truncates toward zero, so
+    /// `7 / 2` is `3` and `-7 % 2` is `-1`, while `7 / 2.0` is `3.5`.
+    pub fn eval(&self, row: &ColumnSet) -> Result<TypedValue, PoorlyError> {
+        let (op, left, right) = match self {
+            Expression::Column(column) => {
+                return row.get(column).cloned().ok_or_else(|| {
+                    PoorlyError::InvalidOperation(format!("no column {} in the row", column))
+                })
+            }
+            Expression::Literal(value) => return Ok(value.clone()),
+            Expression::Add(left, right) => ('+', left, right),
+            Expression::Sub(left, right) => ('-', left, right),
+            Expression::Mul(left, right) => ('*', left, right),
+            Expression::Div(left, right) => ('/', left, right),
+            Expression::Mod(left, right) => ('%', left, right),
+        };
+        let (left, right) = (left.eval(row)?, right.eval(row)?);
+        let error = |what: &str| PoorlyError::InvalidOperation(what.to_string());
+
+        if let (Some(l), Some(r)) = (left.as_int(), right.as_int()) {
+            let result = match op {
+                '+' => l.checked_add(r),
+                '-' => l.checked_sub(r),
+                '*' => l.checked_mul(r),
+                _ if r == 0 => return Err(error("division by zero")),
+                '/' => l.checked_div(r),
+                _ => l.checked_rem(r),
+            };
+            return result
+                .map(TypedValue::Int)
+                .ok_or_else(|| error("integer overflow"));
+        }
+        match (as_float(&left), as_float(&right)) {
+            (Some(l), Some(r)) => {
+                let result = match op {
+                    '+' => l + r,
+                    '-' => l - r,
+                    '*' => l * r,
+                    _ if r == 0.0 => return Err(error("division by zero")),
+                    '/' => l / r,
+                    _ => l % r,
+                };
+                if result.is_finite() {
+                    Ok(TypedValue::Float(result))
+                } else {
+                    Err(error("float overflow"))
+                }
+            }
+            _ => Err(PoorlyError::InvalidOperation(format!(
+                "can't compute {:?} {} {:?}",
+                left, op, right
+            ))),
+        }
+    }
+}
+
+fn as_float(value: &TypedValue) -> Option<f64> {
+    match value {
+        TypedValue::Float(f) => Some(*f),
+        _ => value.as_int().map(|i| i as f64),
+    }
+}
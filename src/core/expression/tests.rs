@@ -0,0 +1,66 @@
+use super::*;
+
+fn eval(
+    operator: fn(Box<Expression>, Box<Expression>) -> Expression,
+    left: TypedValue,
+    right: TypedValue,
+) -> Result<TypedValue, PoorlyError> {
+    let literal = |value| Box::new(Expression::Literal(value));
+    operator(literal(left), literal(right)).eval(&HashMap::new())
+}
+
+#[test]
+fn division_by_zero() {
+    for operator in [Expression::Div, Expression::Mod] {
+        for (left, right) in [
+            (TypedValue::Int(7), TypedValue::Int(0)),
+            (TypedValue::Int32(7), TypedValue::Int16(0)),
+            (TypedValue::Float(7.0), TypedValue::Int(0)),
+            (TypedValue::Int(7), TypedValue::Float(0.0)),
+        ] {
+            match eval(operator, left, right) {
+                Err(PoorlyError::InvalidOperation(message)) => {
+                    assert_eq!(message, "division by zero")
+                }
+                other => panic!("expected division by zero, got {:?}", other),
+            }
+        }
+    }
+}
+
+#[test]
+fn integer_truncation() -> Result<(), PoorlyError> {
+    let int = TypedValue::Int;
+    assert_eq!(eval(Expression::Div, int(7), int(2))?, int(3));
+    assert_eq!(eval(Expression::Div, int(-7), int(2))?, int(-3));
+    assert_eq!(eval(Expression::Div, int(7), int(-2))?, int(-3));
+    assert_eq!(eval(Expression::Mod, int(7), int(2))?, int(1));
+    assert_eq!(eval(Expression::Mod, int(-7), int(2))?, int(-1));
+    assert_eq!(eval(Expression::Mod, int(7), int(-2))?, int(1));
+    // Narrower integers are widened, not promoted to floats
+    assert_eq!(
+        eval(Expression::Div, TypedValue::Int16(9), TypedValue::Int32(4))?,
+        int(2)
+    );
+
+    // The one quotient that doesn't fit
+    for operator in [Expression::Div, Expression::Mod] {
+        assert!(matches!(
+            eval(operator, int(i64::MIN), int(-1)),
+            Err(PoorlyError::InvalidOperation(_))
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn float_promotion() -> Result<(), PoorlyError> {
+    let (int, float) = (TypedValue::Int, TypedValue::Float);
+    assert_eq!(eval(Expression::Div, int(7), float(2.0))?, float(3.5));
+    assert_eq!(eval(Expression::Div, float(7.0), int(2))?, float(3.5));
+    assert_eq!(eval(Expression::Mod, float(7.5), int(2))?, float(1.5));
+    assert_eq!(eval(Expression::Mod, int(-7), float(2.0))?, float(-1.0));
+
+    Ok(())
+}
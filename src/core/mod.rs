@@ -1,5 +1,8 @@
+pub mod clock;
 pub mod database;
 pub mod engine;
+pub mod expression;
+pub mod predicate;
 pub mod schema;
 pub mod table;
 pub mod types;
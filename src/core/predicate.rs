@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+
+use super::types::{ColumnSet, DataType, PoorlyError, TypedValue};
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+#[cfg(test)]
+mod tests;
+
+/// A test on a single column. Plain `conditions` are `Eq` predicates.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Predicate {
+    Eq(TypedValue),
+    Ne(TypedValue),
+    Lt(TypedValue),
+    Le(TypedValue),
+    Gt(TypedValue),
+    Ge(TypedValue),
+    // Inclusive on both ends
+    Between(TypedValue, TypedValue),
+    In(Vec<TypedValue>),
+    // SQL pattern: `%` matches any run of characters, `_` a single one
+    Like(String),
+    // Holds when every one of them does, for several tests on one column
+    All(Vec<Predicate>),
+    // Tests the length of a string, in characters, like `Len(Gt(10))`
+    Len(Box<Predicate>),
+}
+
+pub type Predicates = HashMap<String, Predicate>;
+
+impl Predicate {
+    /// Coerces the operands to the type of the column they are tested against.
+    pub fn coerce(self, to: DataType) -> Result<Self, PoorlyError> {
+        let predicate = match self {
+            Predicate::Eq(value) => Predicate::Eq(value.coerce(to)?),
+            Predicate::Ne(value) => Predicate::Ne(value.coerce(to)?),
+            Predicate::Lt(value) => Predicate::Lt(value.coerce(to)?),
+            Predicate::Le(value) => Predicate::Le(value.coerce(to)?),
+            Predicate::Gt(value) => Predicate::Gt(value.coerce(to)?),
+            Predicate::Ge(value) => Predicate::Ge(value.coerce(to)?),
+            Predicate::Between(low, high) => Predicate::Between(low.coerce(to)?, high.coerce(to)?),
+            Predicate::In(values) => Predicate::In(
+                values
+                    .into_iter()
+                    .map(|value| value.coerce(to))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Predicate::Like(pattern) => match to {
+                DataType::String | DataType::Email | DataType::Char => Predicate::Like(pattern),
+                _ => {
+                    return Err(PoorlyError::InvalidOperation(format!(
+                        "like can't be used on a {:?} column",
+                        to
+                    )))
+                }
+            },
+            Predicate::All(predicates) => Predicate::All(
+                predicates
+                    .into_iter()
+                    .map(|predicate| predicate.coerce(to))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Predicate::Len(predicate) => match to {
+                DataType::String | DataType::Email | DataType::Char => {
+                    Predicate::Len(Box::new(predicate.coerce(DataType::Int)?))
+                }
+                _ => {
+                    return Err(PoorlyError::InvalidOperation(format!(
+                        "len can't be used on a {:?} column",
+                        to
+                    )))
+                }
+            },
+        };
+        Ok(predicate)
+    }
+
+    pub fn matches(&self, value: &TypedValue) -> bool {
+        let cmp = |other: &TypedValue| value.partial_cmp(other);
+        match self {
+            Predicate::Eq(other) => value == other,
+            Predicate::Ne(other) => value != other,
+            Predicate::Lt(other) => cmp(other) == Some(Ordering::Less),
+            Predicate::Le(other) => matches!(cmp(other), Some(Ordering::Less | Ordering::Equal)),
+            Predicate::Gt(other) => cmp(other) == Some(Ordering::Greater),
+            Predicate::Ge(other) => {
+                matches!(cmp(other), Some(Ordering::Greater | Ordering::Equal))
+            }
+            Predicate::Between(low, high) => {
+                Predicate::Ge(low.clone()).matches(value)
+                    && Predicate::Le(high.clone()).matches(value)
+            }
+            Predicate::In(values) => values.contains(value),
+            Predicate::Like(pattern) => {
+                let value: Vec<char> = value.to_string().chars().collect();
+                let pattern: Vec<char> = pattern.chars().collect();
+                like(&value, &pattern)
+            }
+            Predicate::All(predicates) => predicates.iter().all(|p| p.matches(value)),
+            Predicate::Len(predicate) => {
+                let len = match value {
+                    TypedValue::String(s) | TypedValue::Email(s) => s.chars().count(),
+                    TypedValue::Char(_) => 1,
+                    _ => return false,
+                };
+                predicate.matches(&TypedValue::Int(len as i64))
+            }
+        }
+    }
+}
+
+fn like(value: &[char], pattern: &[char]) -> bool {
+    match pattern.split_first() {
+        None => value.is_empty(),
+        Some(('%', rest)) => (0..=value.len()).any(|skip| like(&value[skip..], rest)),
+        Some((c, rest)) => match value.split_first() {
+            Some((v, value)) => (*c == '_' || c == v) && like(value, rest),
+            None => false,
+        },
+    }
+}
+
+/// Adds `predicate` on `column` to `predicates`. A column that has one
+/// already has to pass both.
+pub fn and(predicates: &mut Predicates, column: String, predicate: Predicate) {
+    let predicate = match predicates.remove(&column) {
+        None => predicate,
+        Some(Predicate::All(mut all)) => {
+            all.push(predicate);
+            Predicate::All(all)
+        }
+        Some(existing) => Predicate::All(vec![existing, predicate]),
+    };
+    predicates.insert(column, predicate);
+}
+
+/// Combines equality `conditions` with `predicates`. A column can only be
+/// constrained by one of them.
+pub fn merge(conditions: ColumnSet, mut predicates: Predicates) -> Result<Predicates, PoorlyError> {
+    for (column, value) in conditions {
+        if predicates.contains_key(&column) {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "column {} has both a condition and a predicate",
+                column
+            )));
+        }
+        predicates.insert(column, Predicate::Eq(value));
+    }
+    Ok(predicates)
+}
+
+/// Keeps the `rows` matching all `predicates`, e.g. to filter the result of a
+/// query on columns that only exist after projection.
+pub fn filter_rows(
+    rows: Vec<ColumnSet>,
+    predicates: &Predicates,
+) -> Result<Vec<ColumnSet>, PoorlyError> {
+    if predicates.is_empty() {
+        return Ok(rows);
+    }
+
+    let mut filtered = Vec::with_capacity(rows.len());
+    for row in rows {
+        let mut keep = true;
+        for (column, predicate) in predicates {
+            let value = row.get(column).ok_or_else(|| {
+                PoorlyError::InvalidOperation(format!("column {} is not in the result", column))
+            })?;
+            if !predicate.clone().coerce(value.data_type())?.matches(value) {
+                keep = false;
+                break;
+            }
+        }
+        if keep {
+            filtered.push(row);
+        }
+    }
+    Ok(filtered)
+}
@@ -0,0 +1,68 @@
+use super::*;
+
+#[test]
+fn like() {
+    let matches = |pattern: &str, value: &str| {
+        Predicate::Like(pattern.to_string()).matches(&TypedValue::String(value.to_string()))
+    };
+
+    assert!(matches("ab%", "abc"));
+    assert!(matches("%@gmail.com", "test@gmail.com"));
+    assert!(matches("a_c", "abc"));
+    assert!(matches("%", ""));
+    assert!(!matches("a_c", "ac"));
+    assert!(!matches("ab", "abc"));
+}
+
+#[test]
+fn merge_conflict() {
+    let conditions = [("id".to_string(), TypedValue::Int(1))].into();
+    let predicates = [("id".to_string(), Predicate::Gt(TypedValue::Int(0)))].into();
+
+    assert!(merge(conditions, predicates).is_err());
+}
+
+#[test]
+fn and_predicates() {
+    let mut predicates = Predicates::new();
+    and(
+        &mut predicates,
+        "price".to_string(),
+        Predicate::Gt(TypedValue::Int(10)),
+    );
+    and(
+        &mut predicates,
+        "price".to_string(),
+        Predicate::Lt(TypedValue::Int(20)),
+    );
+    and(
+        &mut predicates,
+        "price".to_string(),
+        Predicate::Ne(TypedValue::Int(15)),
+    );
+
+    let price = &predicates["price"];
+    assert!(matches!(price, Predicate::All(all) if all.len() == 3));
+    assert!(price.matches(&TypedValue::Int(11)));
+    assert!(!price.matches(&TypedValue::Int(15)));
+    assert!(!price.matches(&TypedValue::Int(20)));
+}
+
+#[test]
+fn len() -> Result<(), PoorlyError> {
+    let longer_than = |n| Predicate::Len(Box::new(Predicate::Gt(TypedValue::Int(n))));
+
+    // Five characters in eight bytes
+    let word = TypedValue::String("żółwi".to_string());
+    assert_eq!(word.to_string().len(), 8);
+    assert!(longer_than(4).coerce(DataType::String)?.matches(&word));
+    assert!(!longer_than(5).coerce(DataType::String)?.matches(&word));
+
+    let email = TypedValue::Email("ab@c.de".to_string());
+    assert!(longer_than(6).coerce(DataType::Email)?.matches(&email));
+
+    let result = longer_than(1).coerce(DataType::Int);
+    assert!(matches!(result, Err(PoorlyError::InvalidOperation(_))));
+
+    Ok(())
+}
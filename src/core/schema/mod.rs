@@ -1,31 +1,329 @@
+use super::clock::Clock;
+use super::table::COUNT_ONLY;
 use super::types::DataType;
 use super::types::PoorlyError;
+use super::types::RowFormat;
+use super::types::TypedValue;
 
-use serde::Serialize;
-use std::collections::{hash_map::Entry, HashMap};
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::fs::File;
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
 use std::path::Path;
 
 #[cfg(test)]
 mod tests;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize)]
+/// Default `Schema::max_name_length`
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Layout of the `.schema` file. Both are read; a schema is written back in
+/// the layout it was read in unless switched, so setting `Json` on a text
+/// schema migrates it on the next dump.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum SchemaFileFormat {
+    // A `name:kind:version:byte order` header, then a line per table
+    #[default]
+    Text,
+    // A `SchemaFile` object
+    Json,
+}
+
+impl std::str::FromStr for SchemaFileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(SchemaFileFormat::Text),
+            "json" => Ok(SchemaFileFormat::Json),
+            _ => Err(format!(
+                "unknown schema format `{}`, expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+// Version of the JSON schema file. The text format, which has none, counts
+// as version 1.
+const SCHEMA_FILE_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct SchemaFile {
+    version: u32,
+    name: String,
+    kind: SchemaKind,
+    row_format: u32,
+    byte_order: String,
+    tables: Vec<TableFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableFile {
+    name: String,
+    columns: Vec<ColumnFile>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    compressed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    segment_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ColumnFile {
+    name: String,
+    data_type: DataType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default: Option<ColumnDefault>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+/// Engine a database is stored by: table files of its own, or an SQLite
+/// file read by `engine::sqlite::Sqlite`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
-enum SchemaKind {
+pub enum SchemaKind {
     Poorly,
     Sqlite,
 }
 
+impl std::str::FromStr for SchemaKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poorly" => Ok(SchemaKind::Poorly),
+            "sqlite" => Ok(SchemaKind::Sqlite),
+            _ => Err(format!(
+                "unknown database kind `{}`, expected poorly or sqlite",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SchemaKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+// Byte order of numbers in table files, written to the schema header so the
+// layout is explicit. Reading uses `from_le_bytes`, so files stay portable to
+// big-endian machines.
+const BYTE_ORDER: &str = "le";
+
 pub type Column = (String, DataType);
 pub type Columns = Vec<Column>;
 
+// Value used for a column omitted on insert
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnDefault {
+    // Current time, only for timestamp columns
+    Now,
+    // Current time, set again by every update that changes the row. Only
+    // for timestamp columns.
+    AutoUpdate,
+    // One past the highest value inserted so far, from a counter kept in
+    // the table file. Only for int columns, and one per table.
+    AutoIncrement,
+}
+
+// Column defaults of a table
+pub type Defaults = HashMap<String, ColumnDefault>;
+
+impl ColumnDefault {
+    /// The value of the default for a row inserted now. `AutoIncrement` has
+    /// none, the table fills it in from its counter as the row is written.
+    pub fn value(&self, clock: &dyn Clock) -> Option<TypedValue> {
+        match self {
+            ColumnDefault::Now | ColumnDefault::AutoUpdate => {
+                Some(TypedValue::Timestamp(clock.now_millis()))
+            }
+            ColumnDefault::AutoIncrement => None,
+        }
+    }
+
+    fn allowed_for(&self, data_type: DataType) -> bool {
+        match self {
+            ColumnDefault::Now | ColumnDefault::AutoUpdate => data_type == DataType::Timestamp,
+            ColumnDefault::AutoIncrement => data_type == DataType::Int,
+        }
+    }
+
+    /// How the default follows the column type in the schema file
+    pub fn attribute(&self) -> &'static str {
+        match self {
+            ColumnDefault::Now => "default=now",
+            ColumnDefault::AutoUpdate => "auto_update",
+            ColumnDefault::AutoIncrement => "auto_increment",
+        }
+    }
+
+    pub fn from_attribute(attribute: &str) -> Option<Self> {
+        match attribute {
+            "default=now" => Some(ColumnDefault::Now),
+            "auto_update" => Some(ColumnDefault::AutoUpdate),
+            "auto_increment" => Some(ColumnDefault::AutoIncrement),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct Schema {
     #[serde(serialize_with = "serialize_tables")]
     pub tables: HashMap<String, Columns>,
+    // Defaults by table, only tables having any are present
+    pub defaults: HashMap<String, Defaults>,
+    // Tables storing their strings compressed
+    pub compressed: HashSet<String>,
+    // Tables split into segment files, by the size a segment grows to
+    pub segment_sizes: HashMap<String, u64>,
+    // Freeform descriptions of tables
+    pub table_comments: HashMap<String, String>,
+    // Column comments by table, only tables having any are present
+    pub column_comments: HashMap<String, HashMap<String, String>>,
     name: String,
     kind: SchemaKind,
+    pub format: RowFormat,
+    // Match table and column names regardless of case, keeping the case
+    // they were created with for display
+    #[serde(skip)]
+    pub case_insensitive: bool,
+    // Longest table or column name that may be created, in bytes
+    #[serde(skip)]
+    pub max_name_length: usize,
+    // Layout the schema file is written in
+    #[serde(skip)]
+    pub file_format: SchemaFileFormat,
+}
+
+/// Differences between two schemas, as returned by `Schema::diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SchemaDiff {
+    pub added_tables: Vec<String>,
+    pub removed_tables: Vec<String>,
+    // Tables present in both schemas whose columns differ
+    pub changed_tables: Vec<TableDiff>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TableDiff {
+    pub table: String,
+    pub added_columns: Columns,
+    pub removed_columns: Columns,
+    // Old and new name
+    pub renamed_columns: Vec<(String, String)>,
+    // Column name, old and new type
+    pub retyped_columns: Vec<(String, DataType, DataType)>,
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_tables.is_empty()
+            && self.removed_tables.is_empty()
+            && self.changed_tables.is_empty()
+    }
+}
+
+impl TableDiff {
+    fn new(table: &str, old: &Columns, new: &Columns) -> Self {
+        let find = |columns: &Columns, name: &str| {
+            columns
+                .iter()
+                .find(|(column, _)| column == name)
+                .map(|(_, data_type)| *data_type)
+        };
+
+        let mut diff = TableDiff {
+            table: table.to_string(),
+            ..Default::default()
+        };
+        for (column, data_type) in old {
+            match find(new, column) {
+                None => diff.removed_columns.push((column.clone(), *data_type)),
+                Some(new_type) if new_type != *data_type => {
+                    diff.retyped_columns
+                        .push((column.clone(), *data_type, new_type));
+                }
+                Some(_) => {}
+            }
+        }
+        for (column, data_type) in new {
+            if find(old, column).is_none() {
+                diff.added_columns.push((column.clone(), *data_type));
+            }
+        }
+
+        // A column can't be told apart from a removed one plus an added one, so
+        // only the unambiguous case is taken as a rename: a single removed and
+        // a single added column of the same type.
+        let only = |columns: &Columns, data_type: DataType| {
+            let mut of_type = columns.iter().filter(|(_, other)| *other == data_type);
+            match (of_type.next(), of_type.next()) {
+                (Some((column, _)), None) => Some(column.clone()),
+                _ => None,
+            }
+        };
+        let renamed: Vec<(String, String)> = diff
+            .removed_columns
+            .iter()
+            .filter_map(|(_, data_type)| {
+                Some((
+                    only(&diff.removed_columns, *data_type)?,
+                    only(&diff.added_columns, *data_type)?,
+                ))
+            })
+            .collect();
+        for (from, to) in &renamed {
+            diff.removed_columns.retain(|(column, _)| column != from);
+            diff.added_columns.retain(|(column, _)| column != to);
+        }
+        diff.renamed_columns = renamed;
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added_columns.is_empty()
+            && self.removed_columns.is_empty()
+            && self.renamed_columns.is_empty()
+            && self.retyped_columns.is_empty()
+    }
+}
+
+// Values are sent as `TypedValue` serializes them, so timestamps are
+// milliseconds since the epoch
+fn json_schema_type(data_type: DataType) -> serde_json::Value {
+    match data_type {
+        DataType::Int | DataType::Timestamp => {
+            serde_json::json!({ "type": "integer", "format": "int64" })
+        }
+        DataType::Int32 => serde_json::json!({ "type": "integer", "format": "int32" }),
+        DataType::Int16 => serde_json::json!({
+            "type": "integer",
+            "minimum": i16::MIN,
+            "maximum": i16::MAX,
+        }),
+        DataType::Serial => serde_json::json!({
+            "type": "integer",
+            "minimum": 0,
+            "maximum": u32::MAX,
+            "readOnly": true,
+        }),
+        DataType::Float => serde_json::json!({ "type": "number", "format": "double" }),
+        DataType::Char => serde_json::json!({
+            "type": "string",
+            "minLength": 1,
+            "maxLength": 1,
+        }),
+        DataType::String => serde_json::json!({ "type": "string" }),
+        DataType::Email => serde_json::json!({ "type": "string", "format": "email" }),
+    }
 }
 
 fn serialize_tables<S: serde::Serializer>(
@@ -45,16 +343,34 @@ impl Schema {
     pub fn new_sqlite(name: String) -> Self {
         Schema {
             tables: HashMap::new(),
+            defaults: HashMap::new(),
+            compressed: HashSet::new(),
+            segment_sizes: HashMap::new(),
+            table_comments: HashMap::new(),
+            column_comments: HashMap::new(),
             name,
             kind: SchemaKind::Sqlite,
+            format: RowFormat::CURRENT,
+            case_insensitive: false,
+            max_name_length: MAX_NAME_LENGTH,
+            file_format: SchemaFileFormat::Text,
         }
     }
 
     pub fn new_poorly(name: String) -> Self {
         Schema {
             tables: HashMap::new(),
+            defaults: HashMap::new(),
+            compressed: HashSet::new(),
+            segment_sizes: HashMap::new(),
+            table_comments: HashMap::new(),
+            column_comments: HashMap::new(),
             name,
             kind: SchemaKind::Poorly,
+            format: RowFormat::CURRENT,
+            case_insensitive: false,
+            max_name_length: MAX_NAME_LENGTH,
+            file_format: SchemaFileFormat::Text,
         }
     }
 
@@ -66,73 +382,310 @@ impl Schema {
         self.kind == SchemaKind::Poorly
     }
 
+    pub fn kind(&self) -> SchemaKind {
+        self.kind
+    }
+
+    // For a database whose folder was renamed, see `Query::ConvertDb`
+    pub(crate) fn rename(&mut self, name: String) {
+        self.name = name;
+    }
+
     pub fn load(path: &Path) -> Schema {
+        Self::try_load(path).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_load(path: &Path) -> Result<Schema, PoorlyError> {
         log::info!("Loading schema...");
-        let file = File::open(path.join(".schema")).expect("Schema file not found");
+        Self::read_from(File::open(path.join(".schema"))?)
+    }
+
+    /// Parses a schema in either format of the `.schema` file
+    pub fn read_from(mut file: impl Read) -> Result<Schema, PoorlyError> {
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        // A text schema starts with the database name, so one named like
+        // `{x` is read as text once it turns out not to be JSON
+        match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => {
+                Self::read_json(&bytes).or_else(|err| Self::read_text(&bytes[..]).map_err(|_| err))
+            }
+            _ => Self::read_text(&bytes[..]),
+        }
+    }
+
+    fn read_json(bytes: &[u8]) -> Result<Schema, PoorlyError> {
+        let file: SchemaFile = serde_json::from_slice(bytes)
+            .map_err(|e| PoorlyError::SchemaCorrupted(format!("Schema file corrupted: {}", e)))?;
+        if file.version != SCHEMA_FILE_VERSION {
+            return Err(PoorlyError::SchemaCorrupted(format!(
+                "Unsupported schema version {}",
+                file.version
+            )));
+        }
+        if file.byte_order != BYTE_ORDER {
+            return Err(PoorlyError::SchemaCorrupted(format!(
+                "Unsupported byte order `{}`",
+                file.byte_order
+            )));
+        }
+
+        let mut schema = Schema::new_poorly(file.name);
+        schema.kind = file.kind;
+        schema.format = RowFormat::try_from(file.row_format)
+            .map_err(|_| PoorlyError::SchemaCorrupted("Schema file corrupted".to_string()))?;
+        schema.file_format = SchemaFileFormat::Json;
+        for table in file.tables {
+            let mut columns = Columns::new();
+            for column in table.columns {
+                if let Some(default) = column.default {
+                    schema
+                        .defaults
+                        .entry(table.name.clone())
+                        .or_default()
+                        .insert(column.name.clone(), default);
+                }
+                if let Some(comment) = column.comment {
+                    schema
+                        .column_comments
+                        .entry(table.name.clone())
+                        .or_default()
+                        .insert(column.name.clone(), comment);
+                }
+                columns.push((column.name, column.data_type));
+            }
+            if table.compressed {
+                schema.compressed.insert(table.name.clone());
+            }
+            if let Some(size) = table.segment_size {
+                schema.segment_sizes.insert(table.name.clone(), size);
+            }
+            if let Some(comment) = table.comment {
+                schema.table_comments.insert(table.name.clone(), comment);
+            }
+            schema.tables.insert(table.name, columns);
+        }
+        Ok(schema)
+    }
+
+    fn read_text(file: impl Read) -> Result<Schema, PoorlyError> {
+        let corrupted = || PoorlyError::SchemaCorrupted("Schema file corrupted".to_string());
+
         let mut reader = io::BufReader::new(file).lines();
         let mut tables = HashMap::new();
+        let mut defaults: HashMap<String, Defaults> = HashMap::new();
+        let mut compressed = HashSet::new();
+        let mut segment_sizes = HashMap::new();
+        let mut table_comments = HashMap::new();
+        let mut column_comments: HashMap<String, HashMap<String, String>> = HashMap::new();
         let header = reader
             .next()
-            .expect("Schema file is empty")
-            .expect("Failed to read schema file");
-        let (name, kind) = header.split_once(':').expect("Schema file corrupted");
+            .ok_or_else(|| PoorlyError::SchemaCorrupted("Schema file is empty".to_string()))??;
+        // name:kind[:version[:byte order]]
+        let mut header = header.split(':');
+        let name = header.next().ok_or_else(corrupted)?;
+        let kind = header.next().ok_or_else(corrupted)?;
+        // Schemas written before the row format was versioned have no version
+        let format = match header.next() {
+            Some(version) => version
+                .parse::<u32>()
+                .ok()
+                .and_then(|version| RowFormat::try_from(version).ok())
+                .ok_or_else(corrupted)?,
+            None => RowFormat::V1,
+        };
+        // Rows have always been little-endian, older schemas just don't say so
+        if let Some(byte_order) = header.next() {
+            if byte_order != BYTE_ORDER {
+                return Err(PoorlyError::SchemaCorrupted(format!(
+                    "Unsupported byte order `{}`",
+                    byte_order
+                )));
+            }
+        }
         for line in reader {
-            let line = line.expect("Failed to read schema file");
-            let (table, columns) = line.split_once('#').expect("Schema file corrupted");
+            let line = line?;
+            let (table, columns) = line.split_once('#').ok_or_else(corrupted)?;
+            // table[:compressed][:segment=bytes][:comment=text]
+            let mut attributes = table.split(':');
+            let table = attributes.next().ok_or_else(corrupted)?;
+            let table = unescape(table).ok_or_else(corrupted)?;
+            let table = table.as_str();
+            for attribute in attributes {
+                if attribute == "compressed" {
+                    compressed.insert(table.to_string());
+                } else if let Some(size) = attribute.strip_prefix("segment=") {
+                    let size = size.parse().map_err(|_| corrupted())?;
+                    segment_sizes.insert(table.to_string(), size);
+                } else if let Some(comment) = attribute.strip_prefix("comment=") {
+                    let comment = unescape(comment).ok_or_else(corrupted)?;
+                    table_comments.insert(table.to_string(), comment);
+                } else {
+                    return Err(corrupted());
+                }
+            }
             for column in columns.split(',') {
-                let (column, data_type) = column.split_once(':').expect("Schema file corrupted");
+                // name:type[:attribute]*
+                let mut parts = column.split(':');
+                let column = parts.next().ok_or_else(corrupted)?;
+                let column = unescape(column).ok_or_else(corrupted)?;
+                let column = column.as_str();
+                let data_type = parts.next().ok_or_else(corrupted)?;
                 tables
                     .entry(table.to_string())
                     .or_insert_with(Vec::new)
                     .push((
                         column.to_string(),
-                        data_type.try_into().expect("Schema file corrupted"),
+                        data_type.try_into().map_err(|_| corrupted())?,
                     ));
+                for attribute in parts {
+                    if let Some(comment) = attribute.strip_prefix("comment=") {
+                        column_comments
+                            .entry(table.to_string())
+                            .or_default()
+                            .insert(column.to_string(), unescape(comment).ok_or_else(corrupted)?);
+                        continue;
+                    }
+                    let default = ColumnDefault::from_attribute(attribute).ok_or_else(corrupted)?;
+                    defaults
+                        .entry(table.to_string())
+                        .or_default()
+                        .insert(column.to_string(), default);
+                }
             }
         }
-        let kind = match kind {
-            "poorly" => SchemaKind::Poorly,
-            "sqlite" => SchemaKind::Sqlite,
-            _ => panic!("Schema file corrupted"),
-        };
-        Schema {
+        let kind: SchemaKind = kind.parse().map_err(|_| corrupted())?;
+        Ok(Schema {
             tables,
+            defaults,
+            compressed,
+            segment_sizes,
+            table_comments,
+            column_comments,
             name: name.into(),
             kind,
-        }
+            format,
+            case_insensitive: false,
+            max_name_length: MAX_NAME_LENGTH,
+            file_format: SchemaFileFormat::Text,
+        })
     }
 
     pub fn dump(&self, path: &Path) -> Result<(), io::Error> {
         log::info!("Dumping schema...");
-        let mut file = File::create(path.join(".schema"))?;
+        self.write_to(File::create(path.join(".schema"))?)
+    }
+
+    /// Writes the schema as the `.schema` file, in `file_format`
+    pub fn write_to(&self, file: impl Write) -> Result<(), io::Error> {
+        match self.file_format {
+            SchemaFileFormat::Text => self.write_text(file),
+            SchemaFileFormat::Json => self.write_json(file),
+        }
+    }
+
+    fn write_json(&self, file: impl Write) -> Result<(), io::Error> {
+        let mut names: Vec<&String> = self.tables.keys().collect();
+        names.sort();
+        let tables = names
+            .into_iter()
+            .map(|table| TableFile {
+                name: table.clone(),
+                columns: self.tables[table]
+                    .iter()
+                    .map(|(column, data_type)| ColumnFile {
+                        name: column.clone(),
+                        data_type: *data_type,
+                        default: self
+                            .defaults
+                            .get(table)
+                            .and_then(|d| d.get(column))
+                            .copied(),
+                        comment: self.comment(table, Some(column)).map(str::to_string),
+                    })
+                    .collect(),
+                compressed: self.compressed.contains(table),
+                segment_size: self.segment_sizes.get(table).copied(),
+                comment: self.comment(table, None).map(str::to_string),
+            })
+            .collect();
+        let schema = SchemaFile {
+            version: SCHEMA_FILE_VERSION,
+            name: self.name.clone(),
+            kind: self.kind,
+            row_format: self.format.version(),
+            byte_order: BYTE_ORDER.to_string(),
+            tables,
+        };
+
+        let mut file = io::BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut file, &schema)?;
+        file.write_all(b"\n")?;
+        file.flush()
+    }
+
+    fn write_text(&self, file: impl Write) -> Result<(), io::Error> {
+        let mut file = io::BufWriter::new(file);
         file.write_all(self.name.as_bytes())?;
-        file.write_all(format!(":{:?}", self.kind).to_lowercase().as_bytes())?;
+        file.write_all(format!(":{}", self.kind).as_bytes())?;
+        file.write_all(format!(":{}:{}", self.format.version(), BYTE_ORDER).as_bytes())?;
         file.write_all(b"\n")?;
         for (table, columns) in &self.tables {
+            let defaults = self.defaults.get(table);
+            let comments = self.column_comments.get(table);
             let table_schema: String = columns
                 .iter()
-                .map(|(column, data_type)| format!("{}:{:?}", column, data_type))
+                .map(|(column, data_type)| {
+                    let mut column_schema = format!("{}:{:?}", escape(column), data_type);
+                    if let Some(default) = defaults.and_then(|d| d.get(column)) {
+                        column_schema.push(':');
+                        column_schema.push_str(default.attribute());
+                    }
+                    if let Some(comment) = comments.and_then(|c| c.get(column)) {
+                        column_schema.push_str(":comment=");
+                        column_schema.push_str(&escape(comment));
+                    }
+                    column_schema
+                })
                 .collect::<Vec<_>>()
                 .join(",");
-            file.write_all(format!("{}#{}\n", table, table_schema).as_bytes())?;
+            let mut attributes = String::new();
+            if self.compressed.contains(table) {
+                attributes.push_str(":compressed");
+            }
+            if let Some(size) = self.segment_sizes.get(table) {
+                attributes.push_str(&format!(":segment={}", size));
+            }
+            if let Some(comment) = self.table_comments.get(table) {
+                attributes.push_str(":comment=");
+                attributes.push_str(&escape(comment));
+            }
+            let table = escape(table);
+            file.write_all(format!("{}{}#{}\n", table, attributes, table_schema).as_bytes())?;
         }
-        Ok(())
+        file.flush()
     }
 
+    /// Adds `table_name` with `columns`, in the order they are given
     pub fn create_table(
         &mut self,
         table_name: String,
-        mut columns: Columns,
+        columns: Columns,
     ) -> Result<(), PoorlyError> {
-        Self::validate_name(&table_name)?;
+        Self::validate_name(&table_name, self.max_name_length)?;
         if columns.is_empty() {
             return Err(PoorlyError::NoColumns);
         }
+        if self.resolve_table(&table_name) != table_name {
+            return Err(PoorlyError::TableAlreadyExists(table_name));
+        }
+        let case_insensitive = self.case_insensitive;
+        let max_name_length = self.max_name_length;
         if let Entry::Vacant(entry) = self.tables.entry(table_name.clone()) {
-            columns.sort();
-            for (i, (column, _)) in columns.iter().enumerate() {
-                Self::validate_name(column)?;
-                if i > 0 && column == &columns[i - 1].0 {
+            let mut names = HashSet::new();
+            for (column, _) in &columns {
+                Self::validate_column_name(column, max_name_length)?;
+                if !names.insert(Self::fold_case(case_insensitive, column)) {
                     return Err(PoorlyError::ColumnAlreadyExists(column.clone(), table_name));
                 }
             }
@@ -143,9 +696,225 @@ impl Schema {
         }
     }
 
+    /// Tells if `table` exists with exactly these columns and defaults,
+    /// the order of the columns aside.
+    pub fn has_table(&self, table: &str, columns: &Columns, defaults: &Defaults) -> bool {
+        let Some(existing) = self.tables.get(table) else {
+            return false;
+        };
+        let fold = |column: &str| Self::fold_case(self.case_insensitive, column);
+        let sorted = |columns: &Columns| {
+            let mut columns = columns.clone();
+            columns.sort_by_key(|(column, _)| fold(column));
+            columns
+        };
+        let (columns, existing) = (sorted(columns), sorted(existing));
+        let same_columns = columns.len() == existing.len()
+            && columns
+                .iter()
+                .zip(&existing)
+                .all(|((a, a_type), (b, b_type))| fold(a) == fold(b) && a_type == b_type);
+
+        let existing_defaults = self.defaults.get(table);
+        let same_defaults = defaults.len() == existing_defaults.map_or(0, |d| d.len())
+            && defaults.iter().all(|(column, default)| {
+                existing_defaults
+                    .and_then(|d| d.iter().find(|(c, _)| fold(c) == fold(column)))
+                    .is_some_and(|(_, existing)| existing == default)
+            });
+
+        same_columns && same_defaults
+    }
+
+    /// The CLI `Create` command that creates `table` as it is, like
+    /// `Create shop users created=timestamp:default=now,name=string compressed`,
+    /// followed by a `Comment` command on its own line for every comment
+    pub fn ddl(&self, table: &str) -> Result<String, PoorlyError> {
+        let columns = self
+            .tables
+            .get(table)
+            .ok_or_else(|| PoorlyError::TableNotFound(table.to_string()))?;
+        let defaults = self.defaults.get(table);
+        let columns = columns
+            .iter()
+            .map(|(column, data_type)| {
+                let name = quote_identifier(column);
+                match defaults.and_then(|d| d.get(column)) {
+                    Some(default) => {
+                        format!("{}={:?}:{}", name, data_type, default.attribute())
+                    }
+                    None => format!("{}={:?}", name, data_type),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let name = quote_identifier(table);
+        let mut ddl = format!("Create {} {} {}", self.name, name, columns);
+        if self.compressed.contains(table) {
+            ddl.push_str(" compressed");
+        }
+        if let Some(size) = self.segment_sizes.get(table) {
+            ddl.push_str(&format!(" segment={}", size));
+        }
+        if let Some(comment) = self.table_comments.get(table) {
+            ddl.push_str(&format!("\nComment {} {} {}", self.name, name, comment));
+        }
+        if let Some(comments) = self.column_comments.get(table) {
+            for (column, _) in &self.tables[table] {
+                if let Some(comment) = comments.get(column) {
+                    ddl.push_str(&format!(
+                        "\nComment {} {}.{} {}",
+                        self.name,
+                        name,
+                        quote_identifier(column),
+                        comment
+                    ));
+                }
+            }
+        }
+        Ok(ddl)
+    }
+
+    /// JSON Schemas of the rows of every table, by table name. Columns are
+    /// listed in the order they are defined and comments become descriptions.
+    pub fn to_json_schema(&self) -> serde_json::Value {
+        let tables = self
+            .tables
+            .iter()
+            .map(|(table, columns)| {
+                let properties: serde_json::Map<String, serde_json::Value> = columns
+                    .iter()
+                    .map(|(column, data_type)| {
+                        let mut property = json_schema_type(*data_type);
+                        if let Some(comment) = self.comment(table, Some(column)) {
+                            property["description"] = comment.into();
+                        }
+                        (column.clone(), property)
+                    })
+                    .collect();
+                let mut schema = serde_json::json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": columns.iter().map(|(column, _)| column).collect::<Vec<_>>(),
+                });
+                if let Some(comment) = self.comment(table, None) {
+                    schema["description"] = comment.into();
+                }
+                (table.clone(), schema)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::Value::Object(tables)
+    }
+
+    /// Sets the comment of `table`, or of its `column` if given. An empty
+    /// text removes the comment.
+    pub fn set_comment(
+        &mut self,
+        table: &str,
+        column: Option<&str>,
+        text: String,
+    ) -> Result<(), PoorlyError> {
+        let columns = self
+            .tables
+            .get(table)
+            .ok_or_else(|| PoorlyError::TableNotFound(table.to_string()))?;
+        let Some(column) = column else {
+            if text.is_empty() {
+                self.table_comments.remove(table);
+            } else {
+                self.table_comments.insert(table.to_string(), text);
+            }
+            return Ok(());
+        };
+
+        if !columns.iter().any(|(name, _)| name == column) {
+            return Err(PoorlyError::ColumnNotFound(
+                column.to_string(),
+                table.to_string(),
+            ));
+        }
+        let comments = self.column_comments.entry(table.to_string()).or_default();
+        if text.is_empty() {
+            comments.remove(column);
+            if comments.is_empty() {
+                self.column_comments.remove(table);
+            }
+        } else {
+            comments.insert(column.to_string(), text);
+        }
+        Ok(())
+    }
+
+    pub fn comment(&self, table: &str, column: Option<&str>) -> Option<&str> {
+        match column {
+            None => self.table_comments.get(table),
+            Some(column) => self
+                .column_comments
+                .get(table)
+                .and_then(|comments| comments.get(column)),
+        }
+        .map(String::as_str)
+    }
+
+    /// Layout of the rows of `table`, which is the database's unless the
+    /// table is compressed
+    pub fn table_format(&self, table: &str) -> RowFormat {
+        if self.compressed.contains(table) {
+            RowFormat::V3
+        } else {
+            self.format
+        }
+    }
+
+    pub fn set_default(
+        &mut self,
+        table: &str,
+        column: &str,
+        default: ColumnDefault,
+    ) -> Result<(), PoorlyError> {
+        let columns = self
+            .tables
+            .get(table)
+            .ok_or_else(|| PoorlyError::TableNotFound(table.to_string()))?;
+        let (_, data_type) = columns
+            .iter()
+            .find(|(name, _)| name == column)
+            .ok_or_else(|| PoorlyError::ColumnNotFound(column.to_string(), table.to_string()))?;
+        if !default.allowed_for(*data_type) {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "Default {:?} is not allowed for {:?} column {}",
+                default, data_type, column
+            )));
+        }
+        let defaults = self.defaults.get(table);
+        let counted = defaults.is_some_and(|defaults| {
+            defaults
+                .iter()
+                .any(|(other, default)| *default == ColumnDefault::AutoIncrement && other != column)
+        });
+        if default == ColumnDefault::AutoIncrement && counted {
+            return Err(PoorlyError::InvalidOperation(format!(
+                "table {} already has an auto_increment column",
+                table
+            )));
+        }
+
+        self.defaults
+            .entry(table.to_string())
+            .or_default()
+            .insert(column.to_string(), default);
+        Ok(())
+    }
+
     pub fn drop_table(&mut self, name: String) -> Result<(), PoorlyError> {
         if let Entry::Occupied(entry) = self.tables.entry(name.clone()) {
             entry.remove();
+            self.defaults.remove(&name);
+            self.compressed.remove(&name);
+            self.segment_sizes.remove(&name);
+            self.table_comments.remove(&name);
+            self.column_comments.remove(&name);
             Ok(())
         } else {
             Err(PoorlyError::TableNotFound(name))
@@ -157,17 +926,25 @@ impl Schema {
         table: String,
         mut rename: HashMap<String, String>,
     ) -> Result<(), PoorlyError> {
+        let case_insensitive = self.case_insensitive;
+        let max_name_length = self.max_name_length;
         if let Entry::Occupied(mut entry) = self.tables.entry(table.clone()) {
-            let mut new_columns = Vec::new();
+            let mut new_columns: Columns = Vec::new();
+            let mut renamed = Vec::new();
 
             for (column, data_type) in entry.get().iter() {
                 let new_column = if rename.contains_key(column) {
-                    Self::validate_name(&rename[column])?;
-                    rename.remove(column).unwrap()
+                    Self::validate_column_name(&rename[column], max_name_length)?;
+                    let new_column = rename.remove(column).unwrap();
+                    renamed.push((column.clone(), new_column.clone()));
+                    new_column
                 } else {
                     column.clone()
                 };
-                if new_columns.iter().any(|(c, _)| c == &new_column) {
+                if new_columns.iter().any(|(c, _)| {
+                    Self::fold_case(case_insensitive, c)
+                        == Self::fold_case(case_insensitive, &new_column)
+                }) {
                     return Err(PoorlyError::ColumnAlreadyExists(new_column, table));
                 }
                 new_columns.push((new_column, *data_type));
@@ -180,6 +957,20 @@ impl Schema {
                 ))
             } else {
                 entry.insert(new_columns);
+                if let Some(defaults) = self.defaults.get_mut(&table) {
+                    let moved: Vec<_> = renamed
+                        .iter()
+                        .filter_map(|(from, to)| defaults.remove(from).map(|d| (to.clone(), d)))
+                        .collect();
+                    defaults.extend(moved);
+                }
+                if let Some(comments) = self.column_comments.get_mut(&table) {
+                    let moved: Vec<_> = renamed
+                        .iter()
+                        .filter_map(|(from, to)| comments.remove(from).map(|c| (to.clone(), c)))
+                        .collect();
+                    comments.extend(moved);
+                }
                 Ok(())
             }
         } else {
@@ -187,11 +978,159 @@ impl Schema {
         }
     }
 
-    fn validate_name(name: &str) -> Result<(), PoorlyError> {
-        if name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+    /// Returns the stored name of `table`, which differs from `table` only
+    /// in case-insensitive mode. Unknown tables are returned as is.
+    /// Lists what changed going from `self` to `other`. Names are sorted so
+    /// the same pair of schemas always gives the same diff.
+    pub fn diff(&self, other: &Schema) -> SchemaDiff {
+        let mut diff = SchemaDiff::default();
+        for (table, columns) in &self.tables {
+            match other.tables.get(table) {
+                None => diff.removed_tables.push(table.clone()),
+                Some(other_columns) => {
+                    let table_diff = TableDiff::new(table, columns, other_columns);
+                    if !table_diff.is_empty() {
+                        diff.changed_tables.push(table_diff);
+                    }
+                }
+            }
+        }
+        diff.added_tables = other
+            .tables
+            .keys()
+            .filter(|table| !self.tables.contains_key(*table))
+            .cloned()
+            .collect();
+
+        diff.added_tables.sort();
+        diff.removed_tables.sort();
+        diff.changed_tables.sort_by(|a, b| a.table.cmp(&b.table));
+        diff
+    }
+
+    pub fn resolve_table(&self, table: &str) -> String {
+        if !self.case_insensitive || self.tables.contains_key(table) {
+            return table.to_string();
+        }
+
+        let folded = table.to_lowercase();
+        self.tables
+            .keys()
+            .find(|name| name.to_lowercase() == folded)
+            .cloned()
+            .unwrap_or_else(|| table.to_string())
+    }
+
+    /// Returns the stored name of `column` in `table` (which must already be
+    /// resolved). Unknown columns are returned as is.
+    pub fn resolve_column(&self, table: &str, column: &str) -> String {
+        if !self.case_insensitive {
+            return column.to_string();
+        }
+
+        let folded = column.to_lowercase();
+        self.tables
+            .get(table)
+            .and_then(|columns| {
+                columns
+                    .iter()
+                    .find(|(name, _)| name.to_lowercase() == folded)
+            })
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| column.to_string())
+    }
+
+    fn fold_case(case_insensitive: bool, name: &str) -> String {
+        if case_insensitive {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+
+    // Names may hold any character but those telling a table from its
+    // columns (`.`) or making a bad file name. Names that aren't just
+    // letters, digits and `_` are quoted in commands, see `quote_identifier`.
+    // Every name is written to the schema file and to error messages, so
+    // its length is bounded too.
+    fn validate_name(name: &str, max_length: usize) -> Result<(), PoorlyError> {
+        if name.len() > max_length {
+            let start: String = name.chars().take(32).collect();
+            return Err(PoorlyError::InvalidName(format!(
+                "{}... ({} bytes, at most {})",
+                start,
+                name.len(),
+                max_length
+            )));
+        }
+        let invalid = |c: char| c.is_control() || ['.', '/', '\\'].contains(&c);
+        if !name.is_empty() && !name.chars().any(invalid) {
             Ok(())
         } else {
             Err(PoorlyError::InvalidName(name.to_string()))
         }
     }
+
+    // Selecting `COUNT_ONLY` asks for a count, so no column may be named so
+    fn validate_column_name(name: &str, max_length: usize) -> Result<(), PoorlyError> {
+        if name == COUNT_ONLY {
+            return Err(PoorlyError::InvalidName(name.to_string()));
+        }
+        Self::validate_name(name, max_length)
+    }
+}
+
+/// Writes `name` so a command reads it back as one identifier: as it is if
+/// it's only letters, digits and `_`, else in double quotes with the quotes
+/// in it doubled, like `"first name"`
+pub fn quote_identifier(name: &str) -> String {
+    if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+/// Reads back a name written by `quote_identifier`. Text that isn't a
+/// single quoted identifier is returned as it is.
+pub fn unquote_identifier(text: &str) -> String {
+    let quoted = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        // Quotes inside must come in pairs, else the text is more than one name
+        .filter(|inner| inner.replace("\"\"", "").find('"').is_none());
+    match quoted {
+        Some(inner) => inner.replace("\"\"", "\""),
+        None => text.to_string(),
+    }
+}
+
+// Comments and names are kept percent-encoded in the schema file so they
+// can't contain its delimiters
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '%' | ':' | ',' | '#' | '\n' | '\r' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &str) -> Option<String> {
+    let mut text = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            let code: String = chars.by_ref().take(2).collect();
+            if code.len() != 2 {
+                return None;
+            }
+            text.push(u8::from_str_radix(&code, 16).ok()? as char);
+        } else {
+            text.push(c);
+        }
+    }
+    Some(text)
 }
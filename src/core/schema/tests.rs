@@ -4,8 +4,17 @@ use super::*;
 fn create() -> Result<(), PoorlyError> {
     let mut schema = Schema {
         tables: HashMap::new(),
+        defaults: HashMap::new(),
+        compressed: HashSet::new(),
+        segment_sizes: HashMap::new(),
+        table_comments: HashMap::new(),
+        column_comments: HashMap::new(),
         name: "".into(),
         kind: SchemaKind::Poorly,
+        format: RowFormat::CURRENT,
+        case_insensitive: false,
+        max_name_length: MAX_NAME_LENGTH,
+        file_format: SchemaFileFormat::Text,
     };
     let table_schema = vec![("column".into(), DataType::String)];
 
@@ -20,8 +29,17 @@ fn create() -> Result<(), PoorlyError> {
 fn drop() -> Result<(), PoorlyError> {
     let mut schema = Schema {
         tables: HashMap::new(),
+        defaults: HashMap::new(),
+        compressed: HashSet::new(),
+        segment_sizes: HashMap::new(),
+        table_comments: HashMap::new(),
+        column_comments: HashMap::new(),
         name: "".into(),
         kind: SchemaKind::Poorly,
+        format: RowFormat::CURRENT,
+        case_insensitive: false,
+        max_name_length: MAX_NAME_LENGTH,
+        file_format: SchemaFileFormat::Text,
     };
     let table_schema = vec![("column".into(), DataType::String)];
 
@@ -36,8 +54,17 @@ fn drop() -> Result<(), PoorlyError> {
 fn alter() -> Result<(), PoorlyError> {
     let mut schema = Schema {
         tables: HashMap::new(),
+        defaults: HashMap::new(),
+        compressed: HashSet::new(),
+        segment_sizes: HashMap::new(),
+        table_comments: HashMap::new(),
+        column_comments: HashMap::new(),
         name: "".into(),
         kind: SchemaKind::Poorly,
+        format: RowFormat::CURRENT,
+        case_insensitive: false,
+        max_name_length: MAX_NAME_LENGTH,
+        file_format: SchemaFileFormat::Text,
     };
     let table_schema = vec![("column".into(), DataType::String)];
 
@@ -54,3 +81,419 @@ fn alter() -> Result<(), PoorlyError> {
     );
     Ok(())
 }
+
+#[test]
+fn format_version() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+
+    std::fs::write(
+        dir.path().join(".schema"),
+        "old:poorly\ntest_table#column:string\n",
+    )?;
+    let schema = Schema::load(dir.path());
+    assert_eq!(schema.format, RowFormat::V1);
+
+    schema.dump(dir.path())?;
+    assert_eq!(Schema::load(dir.path()).format, RowFormat::V1);
+
+    let schema = Schema::new_poorly("new".into());
+    schema.dump(dir.path())?;
+    assert_eq!(Schema::load(dir.path()).format, RowFormat::CURRENT);
+
+    Ok(())
+}
+
+#[test]
+fn compressed_tables() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut schema = Schema::new_poorly("test".into());
+    let columns = vec![("column".into(), DataType::String)];
+    schema.create_table("plain".into(), columns.clone())?;
+    schema.create_table("packed".into(), columns)?;
+    schema.compressed.insert("packed".into());
+
+    schema.dump(dir.path())?;
+    let mut schema = Schema::load(dir.path());
+    assert_eq!(schema.table_format("plain"), RowFormat::CURRENT);
+    assert_eq!(schema.table_format("packed"), RowFormat::V3);
+
+    schema.drop_table("packed".into())?;
+    assert!(schema.compressed.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn comments() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut schema = Schema::new_poorly("test".into());
+    schema.create_table(
+        "users".into(),
+        vec![
+            ("id".into(), DataType::Int),
+            ("seen".into(), DataType::Timestamp),
+        ],
+    )?;
+    schema.set_default("users", "seen", ColumnDefault::AutoUpdate)?;
+    // Delimiters of the schema file must survive
+    let text = "People: admins, guests # and 100% more\nnext line";
+    schema.set_comment("users", None, text.into())?;
+    schema.set_comment("users", Some("seen"), "Last activity".into())?;
+    assert!(matches!(
+        schema.set_comment("users", Some("name"), "x".into()),
+        Err(PoorlyError::ColumnNotFound(_, _))
+    ));
+
+    schema.dump(dir.path())?;
+    let mut schema = Schema::load(dir.path());
+    assert_eq!(schema.comment("users", None), Some(text));
+    assert_eq!(schema.comment("users", Some("seen")), Some("Last activity"));
+    assert_eq!(schema.comment("users", Some("id")), None);
+    assert_eq!(schema.defaults["users"]["seen"], ColumnDefault::AutoUpdate);
+    assert!(schema
+        .ddl("users")?
+        .ends_with("\nComment test users.seen Last activity"));
+
+    schema.alter_table("users".into(), [("seen".into(), "active".into())].into())?;
+    assert_eq!(
+        schema.comment("users", Some("active")),
+        Some("Last activity")
+    );
+    schema.set_comment("users", Some("active"), String::new())?;
+    assert!(schema.column_comments.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn case_insensitive() -> Result<(), PoorlyError> {
+    let mut schema = Schema::new_poorly("".into());
+    schema.case_insensitive = true;
+
+    schema.create_table(
+        "Users".to_string(),
+        vec![
+            ("Id".into(), DataType::Int),
+            ("name".into(), DataType::String),
+        ],
+    )?;
+
+    assert_eq!(schema.resolve_table("users"), "Users");
+    assert_eq!(schema.resolve_column("Users", "ID"), "Id");
+    assert!(matches!(
+        schema.create_table("USERS".to_string(), vec![("id".into(), DataType::Int)]),
+        Err(PoorlyError::TableAlreadyExists(_))
+    ));
+    assert!(matches!(
+        schema.create_table(
+            "other".to_string(),
+            vec![("id".into(), DataType::Int), ("ID".into(), DataType::Int)]
+        ),
+        Err(PoorlyError::ColumnAlreadyExists(_, _))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn default_now() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut schema = Schema::new_poorly("".into());
+    schema.create_table(
+        "test_table".to_string(),
+        vec![
+            ("created".into(), DataType::Timestamp),
+            ("name".into(), DataType::String),
+            ("updated".into(), DataType::Timestamp),
+        ],
+    )?;
+    schema.set_default("test_table", "created", ColumnDefault::Now)?;
+    schema.set_default("test_table", "updated", ColumnDefault::AutoUpdate)?;
+    assert!(matches!(
+        schema.set_default("test_table", "name", ColumnDefault::Now),
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+
+    schema.dump(dir.path())?;
+    let schema = Schema::load(dir.path());
+    assert_eq!(
+        schema.defaults["test_table"],
+        [
+            ("created".to_string(), ColumnDefault::Now),
+            ("updated".to_string(), ColumnDefault::AutoUpdate),
+        ]
+        .into()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn auto_increment() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut schema = Schema::new_poorly("".into());
+    schema.create_table(
+        "orders".to_string(),
+        vec![
+            ("id".into(), DataType::Int),
+            ("other".into(), DataType::Int),
+            ("small".into(), DataType::Int32),
+        ],
+    )?;
+    schema.set_default("orders", "id", ColumnDefault::AutoIncrement)?;
+    // Setting the same one again is fine, a second column is not
+    schema.set_default("orders", "id", ColumnDefault::AutoIncrement)?;
+    for column in ["other", "small"] {
+        assert!(matches!(
+            schema.set_default("orders", column, ColumnDefault::AutoIncrement),
+            Err(PoorlyError::InvalidOperation(_))
+        ));
+    }
+
+    schema.dump(dir.path())?;
+    let schema = Schema::load(dir.path());
+    assert_eq!(
+        schema.defaults["orders"],
+        [("id".to_string(), ColumnDefault::AutoIncrement)].into()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn byte_order() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    Schema::new_poorly("test".into()).dump(dir.path())?;
+
+    let schema = std::fs::read_to_string(dir.path().join(".schema"))?;
+    assert_eq!(schema.lines().next(), Some("test:poorly:2:le"));
+
+    Ok(())
+}
+
+#[test]
+fn diff() -> Result<(), PoorlyError> {
+    let mut old = Schema::new_poorly("".into());
+    old.create_table(
+        "users".into(),
+        vec![
+            ("id".into(), DataType::Serial),
+            ("name".into(), DataType::String),
+            ("age".into(), DataType::Int),
+            ("nick".into(), DataType::String),
+        ],
+    )?;
+    old.create_table("logs".into(), vec![("line".into(), DataType::String)])?;
+    assert!(old.diff(&old).is_empty());
+
+    let mut new = old.clone();
+    new.create_table("orders".into(), vec![("id".into(), DataType::Serial)])?;
+    new.tables.insert(
+        "users".into(),
+        vec![
+            ("id".into(), DataType::Serial),
+            ("name".into(), DataType::String),
+            ("age".into(), DataType::Float),
+            ("email".into(), DataType::Email),
+        ],
+    );
+    new.drop_table("logs".into())?;
+
+    assert_eq!(
+        old.diff(&new),
+        SchemaDiff {
+            added_tables: vec!["orders".into()],
+            removed_tables: vec!["logs".into()],
+            changed_tables: vec![TableDiff {
+                table: "users".into(),
+                added_columns: vec![("email".into(), DataType::Email)],
+                removed_columns: vec![("nick".into(), DataType::String)],
+                renamed_columns: vec![],
+                retyped_columns: vec![("age".into(), DataType::Int, DataType::Float)],
+            }],
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn diff_rename() -> Result<(), PoorlyError> {
+    let mut old = Schema::new_poorly("".into());
+    old.create_table(
+        "users".into(),
+        vec![
+            ("name".into(), DataType::String),
+            ("age".into(), DataType::Int),
+        ],
+    )?;
+    let mut new = old.clone();
+    new.alter_table("users".into(), [("name".into(), "login".into())].into())?;
+
+    let diff = old.diff(&new);
+    assert_eq!(diff.changed_tables.len(), 1);
+    assert_eq!(
+        diff.changed_tables[0].renamed_columns,
+        vec![("name".into(), "login".into())]
+    );
+    assert!(diff.changed_tables[0].added_columns.is_empty());
+    assert!(diff.changed_tables[0].removed_columns.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn quoted_names() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut schema = Schema::new_poorly("test".into());
+    let columns = vec![
+        ("first name".into(), DataType::String),
+        ("a:b,c#d%".into(), DataType::Int),
+    ];
+    schema.create_table("the people".into(), columns.clone())?;
+    assert!(schema.create_table("a.b".into(), columns.clone()).is_err());
+    assert!(schema.create_table("".into(), columns.clone()).is_err());
+    let reserved = vec![(COUNT_ONLY.into(), DataType::Int)];
+    assert!(schema.create_table("counts".into(), reserved).is_err());
+
+    // Delimiters of the schema file in names survive
+    schema.dump(dir.path())?;
+    let schema = Schema::load(dir.path());
+    assert_eq!(schema.tables["the people"], columns);
+
+    assert_eq!(quote_identifier("plain_name1"), "plain_name1");
+    assert_eq!(quote_identifier(r#"say "hi""#), r#""say ""hi""""#);
+    assert_eq!(unquote_identifier(r#""say ""hi""""#), r#"say "hi""#);
+    assert_eq!(unquote_identifier(r#""a","b""#), r#""a","b""#);
+
+    Ok(())
+}
+
+#[test]
+fn json_schema() -> Result<(), PoorlyError> {
+    let mut schema = Schema::new_poorly("test".into());
+    let columns = vec![
+        ("id".into(), DataType::Serial),
+        ("email".into(), DataType::Email),
+        ("grade".into(), DataType::Char),
+    ];
+    schema.create_table("users".into(), columns)?;
+    schema.set_comment("users", None, "People who signed up".into())?;
+    schema.set_comment("users", Some("grade"), "A to F".into())?;
+
+    let json = schema.to_json_schema();
+    let users = &json["users"];
+    assert_eq!(users["description"], "People who signed up");
+    assert_eq!(
+        users["required"],
+        serde_json::json!(["id", "email", "grade"])
+    );
+    let properties = &users["properties"];
+    assert_eq!(properties["id"]["type"], "integer");
+    assert_eq!(properties["id"]["readOnly"], true);
+    assert_eq!(properties["email"]["format"], "email");
+    assert_eq!(properties["grade"]["maxLength"], 1);
+    assert_eq!(properties["grade"]["description"], "A to F");
+    assert!(properties["email"].get("description").is_none());
+
+    Ok(())
+}
+
+#[test]
+fn name_length() -> Result<(), PoorlyError> {
+    let mut schema = Schema::new_poorly("test".into());
+    let at_limit = "n".repeat(MAX_NAME_LENGTH);
+    let over_limit = "n".repeat(MAX_NAME_LENGTH + 1);
+    let columns = |name: &str| vec![(name.to_string(), DataType::Int)];
+
+    schema.create_table(at_limit.clone(), columns(&at_limit))?;
+    let table = schema.create_table(over_limit.clone(), columns("id"));
+    assert!(matches!(table, Err(PoorlyError::InvalidName(_))));
+    let column = schema.create_table("other".into(), columns(&over_limit));
+    assert!(matches!(column, Err(PoorlyError::InvalidName(_))));
+    let renamed = schema.alter_table(at_limit.clone(), [(at_limit, over_limit)].into());
+    assert!(matches!(renamed, Err(PoorlyError::InvalidName(_))));
+
+    // The limit is configurable, and the error doesn't repeat the whole name
+    schema.max_name_length = 4;
+    match schema.create_table("users".repeat(1000), columns("id")) {
+        Err(PoorlyError::InvalidName(name)) => assert!(name.len() < 100),
+        other => panic!("expected InvalidName, got {:?}", other),
+    }
+    schema.create_table("user".into(), columns("id"))?;
+
+    Ok(())
+}
+
+#[test]
+fn json_file() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let mut schema = Schema::new_poorly("test".into());
+    schema.create_table(
+        "users".into(),
+        vec![
+            ("id".into(), DataType::Serial),
+            ("created".into(), DataType::Timestamp),
+            ("a:b,c#d%".into(), DataType::String),
+        ],
+    )?;
+    schema.create_table("events".into(), vec![("at".into(), DataType::Timestamp)])?;
+    schema.set_default("users", "created", ColumnDefault::Now)?;
+    schema.set_default("events", "at", ColumnDefault::AutoUpdate)?;
+    schema.set_comment("users", None, "People: admins\nand guests".into())?;
+    schema.set_comment("users", Some("a:b,c#d%"), "odd # one".into())?;
+    schema.compressed.insert("users".into());
+    schema.segment_sizes.insert("events".into(), 4096);
+    schema.file_format = SchemaFileFormat::Json;
+
+    schema.dump(dir.path())?;
+    let text = std::fs::read_to_string(dir.path().join(".schema"))?;
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(json["version"], 2);
+
+    let loaded = Schema::load(dir.path());
+    assert_eq!(loaded.file_format, SchemaFileFormat::Json);
+    assert_eq!(loaded.tables, schema.tables);
+    assert_eq!(loaded.defaults, schema.defaults);
+    assert_eq!(loaded.compressed, schema.compressed);
+    assert_eq!(loaded.segment_sizes, schema.segment_sizes);
+    assert_eq!(loaded.table_comments, schema.table_comments);
+    assert_eq!(loaded.column_comments, schema.column_comments);
+    assert_eq!(loaded.format, schema.format);
+    assert!(loaded.is_poorly());
+
+    let unknown = text.replace("\"version\": 2", "\"version\": 3");
+    std::fs::write(dir.path().join(".schema"), unknown)?;
+    assert!(matches!(
+        Schema::try_load(dir.path()),
+        Err(PoorlyError::SchemaCorrupted(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn text_to_json_migration() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(
+        dir.path().join(".schema"),
+        "old:poorly:2:le\nusers:segment=1024#id:serial,seen:timestamp:auto_update\n",
+    )?;
+    let mut schema = Schema::load(dir.path());
+    assert_eq!(schema.file_format, SchemaFileFormat::Text);
+
+    schema.file_format = SchemaFileFormat::Json;
+    schema.dump(dir.path())?;
+    let migrated = Schema::load(dir.path());
+    assert_eq!(migrated.file_format, SchemaFileFormat::Json);
+    assert_eq!(migrated.tables, schema.tables);
+    assert_eq!(migrated.defaults, schema.defaults);
+    assert_eq!(migrated.segment_sizes["users"], 1024);
+    assert_eq!(migrated.format, RowFormat::V2);
+
+    // A database named like JSON is still a text schema
+    std::fs::write(dir.path().join(".schema"), "{odd:poorly\n")?;
+    assert_eq!(Schema::load(dir.path()).file_format, SchemaFileFormat::Text);
+
+    Ok(())
+}
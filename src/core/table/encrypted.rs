@@ -0,0 +1,376 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+
+use super::row_store::RowStore;
+use crate::core::types::PoorlyError;
+
+use std::any::Any;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// Plaintext bytes per block. Every block is sealed on its own, so a write
+// only re-encrypts the block it lands in.
+const BLOCK: u64 = 4096;
+const NONCE: u64 = 12;
+const TAG: u64 = 16;
+// Size of a full block on disk
+const STRIDE: u64 = NONCE + BLOCK + TAG;
+// Random id of the file its blocks are sealed with, stored before them
+const HEADER: u64 = 16;
+
+/// AES-256-GCM key derived from a passphrase with argon2
+#[derive(Clone)]
+pub struct EncryptionKey(Aes256Gcm);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn derive(passphrase: &str, salt: &[u8]) -> Result<Self, PoorlyError> {
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| PoorlyError::InvalidOperation(format!("key derivation failed: {}", e)))?;
+        Ok(EncryptionKey(Aes256Gcm::new(&key.into())))
+    }
+
+    /// Random bytes to derive a key with, stored next to the data
+    pub fn generate_salt() -> [u8; 16] {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    // Seals `plaintext` as nonce followed by the ciphertext and tag. The id
+    // of the file, the block index and whether it is the last block are
+    // authenticated too, so blocks can't be moved around, taken from other
+    // files or cut off the end.
+    fn seal(&self, id: &[u8], index: u64, last: bool, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = associated_data(id, index, last);
+        let sealed = self
+            .0
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    fn open(&self, id: &[u8], index: u64, last: bool, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if (sealed.len() as u64) < NONCE + TAG {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated block",
+            ));
+        }
+        let (nonce, msg) = sealed.split_at(NONCE as usize);
+        let aad = associated_data(id, index, last);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), Payload { msg, aad: &aad })
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "block {} can't be decrypted, wrong key or corrupted data",
+                        index
+                    ),
+                )
+            })
+    }
+}
+
+fn associated_data(id: &[u8], index: u64, last: bool) -> Vec<u8> {
+    [id, &index.to_le_bytes(), &[last as u8]].concat()
+}
+
+/// Encrypts everything written to the inner store. The data is kept in
+/// blocks sealed separately with fresh nonces, so it can still be read and
+/// written at any position. Only the last block may be shorter than the
+/// others, which is how the length of the data is known, and it is sealed
+/// as the last one so the file can't be cut short unnoticed.
+///
+/// Every file gets a random id of its own in a header, which its blocks are
+/// sealed with, and a rewrite gets a new one. A block changed in place can
+/// still be put back to an older version of itself from the same file.
+#[derive(Debug)]
+pub struct EncryptedStore {
+    inner: Box<dyn RowStore>,
+    key: EncryptionKey,
+    id: [u8; HEADER as usize],
+    // The header is written along with the first block of a new file
+    has_header: bool,
+    pos: u64,
+    len: u64,
+    // The last block read or written, decrypted
+    cached: Option<(u64, Vec<u8>)>,
+}
+
+impl EncryptedStore {
+    pub fn new(mut inner: Box<dyn RowStore>, key: EncryptionKey) -> io::Result<Self> {
+        let size = inner.seek(SeekFrom::End(0))?;
+        let mut id = [0u8; HEADER as usize];
+        if size == 0 {
+            OsRng.fill_bytes(&mut id);
+        } else if size < HEADER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated header",
+            ));
+        } else {
+            inner.seek(SeekFrom::Start(0))?;
+            inner.read_exact(&mut id)?;
+        }
+        let blocks = size.saturating_sub(HEADER);
+        let len = match blocks % STRIDE {
+            0 => blocks / STRIDE * BLOCK,
+            rest if rest > NONCE + TAG => blocks / STRIDE * BLOCK + rest - NONCE - TAG,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated block",
+                ))
+            }
+        };
+        Ok(EncryptedStore {
+            inner,
+            key,
+            id,
+            has_header: size > 0,
+            pos: 0,
+            len,
+            cached: None,
+        })
+    }
+
+    // Index of the last block of `len` bytes of data
+    fn last_block(len: u64) -> Option<u64> {
+        len.checked_sub(1).map(|end| end / BLOCK)
+    }
+
+    fn block(&mut self, index: u64) -> io::Result<&mut Vec<u8>> {
+        if self
+            .cached
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != index)
+        {
+            let start = index * BLOCK;
+            let block = if start < self.len {
+                let size = (self.len - start).min(BLOCK) + NONCE + TAG;
+                let mut sealed = vec![0; size as usize];
+                self.inner.seek(SeekFrom::Start(HEADER + index * STRIDE))?;
+                self.inner.read_exact(&mut sealed)?;
+                let last = Self::last_block(self.len) == Some(index);
+                self.key.open(&self.id, index, last, &sealed)?
+            } else {
+                vec![]
+            };
+            self.cached = Some((index, block));
+        }
+        Ok(&mut self.cached.as_mut().unwrap().1)
+    }
+
+    fn write_block(&mut self, index: u64, block: Vec<u8>) -> io::Result<()> {
+        if !self.has_header {
+            self.inner.seek(SeekFrom::Start(0))?;
+            self.inner.write_all(&self.id)?;
+            self.has_header = true;
+        }
+        let len = self.len.max(index * BLOCK + block.len() as u64);
+        // The block that was last isn't any more, so it is sealed again
+        if let Some(previous) = Self::last_block(self.len).filter(|previous| *previous < index) {
+            let previous_block = self.block(previous)?.clone();
+            self.seal_at(previous, false, &previous_block)?;
+        }
+        self.seal_at(index, Self::last_block(len) == Some(index), &block)?;
+        self.len = len;
+        self.cached = Some((index, block));
+        Ok(())
+    }
+
+    fn seal_at(&mut self, index: u64, last: bool, block: &[u8]) -> io::Result<()> {
+        let sealed = self.key.seal(&self.id, index, last, block)?;
+        self.inner.seek(SeekFrom::Start(HEADER + index * STRIDE))?;
+        self.inner.write_all(&sealed)
+    }
+}
+
+impl Read for EncryptedStore {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let offset = (self.pos % BLOCK) as usize;
+        let block = self.block(self.pos / BLOCK)?;
+        let n = buf.len().min(block.len() - offset);
+        buf[..n].copy_from_slice(&block[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for EncryptedStore {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos > self.len {
+            self.set_len(self.pos)?;
+        }
+        let index = self.pos / BLOCK;
+        let offset = (self.pos % BLOCK) as usize;
+        let mut block = self.block(index)?.clone();
+        let n = buf.len().min(BLOCK as usize - offset);
+        block.resize(block.len().max(offset + n), 0);
+        block[offset..offset + n].copy_from_slice(&buf[..n]);
+        self.write_block(index, block)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for EncryptedStore {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl RowStore for EncryptedStore {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if len > self.len {
+            let pos = self.pos;
+            self.pos = self.len;
+            let zeros = vec![0; (len - self.len) as usize];
+            self.write_all(&zeros)?;
+            self.pos = pos;
+        } else if len < self.len {
+            match Self::last_block(len) {
+                // The new last block is sealed again as the last one
+                Some(index) => {
+                    let mut block = self.block(index)?.clone();
+                    block.truncate((len - index * BLOCK) as usize);
+                    self.cached = None;
+                    self.inner
+                        .set_len(HEADER + index * STRIDE + NONCE + block.len() as u64 + TAG)?;
+                    self.len = len;
+                    self.write_block(index, block)?;
+                }
+                None => {
+                    self.cached = None;
+                    self.inner
+                        .set_len(if self.has_header { HEADER } else { 0 })?;
+                    self.len = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        let mut inner = self.inner.reader()?;
+        io::copy(&mut (&mut inner).take(HEADER), &mut io::sink())?;
+        Ok(Box::new(DecryptingReader {
+            inner,
+            key: self.key.clone(),
+            id: self.id,
+            index: 0,
+            ahead: None,
+            block: vec![],
+            offset: 0,
+        }))
+    }
+
+    fn rewrite(&self) -> io::Result<Box<dyn RowStore>> {
+        let inner = self.inner.rewrite()?;
+        Ok(Box::new(EncryptedStore::new(inner, self.key.clone())?))
+    }
+
+    fn commit(&mut self, rewritten: Box<dyn RowStore>) -> io::Result<()> {
+        let rewritten: Box<dyn Any> = rewritten;
+        let rewritten = rewritten
+            .downcast::<EncryptedStore>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not an encrypted rewrite"))?;
+        self.inner.commit(rewritten.inner)?;
+        self.id = rewritten.id;
+        self.has_header = rewritten.has_header;
+        self.pos = 0;
+        self.len = rewritten.len;
+        self.cached = None;
+        Ok(())
+    }
+
+    fn remove(self: Box<Self>) -> io::Result<()> {
+        self.inner.remove()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+// Decrypts the blocks of a store one after another, see `RowStore::reader`
+struct DecryptingReader {
+    inner: Box<dyn Read + Send>,
+    key: EncryptionKey,
+    id: [u8; HEADER as usize],
+    index: u64,
+    // The sealed block after the current one, read to tell if it is the last
+    ahead: Option<Vec<u8>>,
+    block: Vec<u8>,
+    offset: usize,
+}
+
+impl DecryptingReader {
+    fn read_sealed(&mut self) -> io::Result<Vec<u8>> {
+        let mut sealed = Vec::with_capacity(STRIDE as usize);
+        (&mut self.inner).take(STRIDE).read_to_end(&mut sealed)?;
+        Ok(sealed)
+    }
+}
+
+impl Read for DecryptingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset == self.block.len() {
+            let sealed = match self.ahead.take() {
+                Some(sealed) => sealed,
+                None => self.read_sealed()?,
+            };
+            if sealed.is_empty() {
+                return Ok(0);
+            }
+            let last = (sealed.len() as u64) < STRIDE || {
+                let ahead = self.read_sealed()?;
+                let last = ahead.is_empty();
+                self.ahead = Some(ahead);
+                last
+            };
+            self.block = self.key.open(&self.id, self.index, last, &sealed)?;
+            self.index += 1;
+            self.offset = 0;
+        }
+        let n = buf.len().min(self.block.len() - self.offset);
+        buf[..n].copy_from_slice(&self.block[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,106 @@
+use serde::Serialize;
+
+use super::Table;
+use crate::core::types::{PoorlyError, TypedValue};
+
+use std::io::Write;
+
+/// How `Table::stream_to_writer` writes rows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    // A header of column names and then a line for every row. Fields with
+    // commas, quotes or line breaks are quoted, with quotes doubled.
+    Csv,
+    // A JSON object for every row, one per line
+    JsonLines,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            _ => Err(format!(
+                "unknown export format `{}`, expected csv or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+impl Table {
+    /// Writes every live row to `writer` as it is read, in file order and
+    /// with the columns in table order, so nothing but the current row is
+    /// held in memory. Returns how many rows were written.
+    pub fn stream_to_writer(
+        &mut self,
+        format: ExportFormat,
+        mut writer: impl Write,
+    ) -> Result<u64, PoorlyError> {
+        let columns: Vec<_> = self.columns.iter().map(|(name, _)| name.clone()).collect();
+        let mut line = String::new();
+        if format == ExportFormat::Csv {
+            let header: Vec<_> = columns.iter().map(|column| csv_field(column)).collect();
+            writeln!(writer, "{}", header.join(","))?;
+        }
+
+        let mut written = 0;
+        for row in self.iter_offsets()? {
+            let (_, row) = row?;
+            line.clear();
+            match format {
+                ExportFormat::Csv => {
+                    let fields: Vec<_> = columns
+                        .iter()
+                        .map(|column| csv_field(&csv_text(&row[column])))
+                        .collect();
+                    line.push_str(&fields.join(","));
+                }
+                ExportFormat::JsonLines => {
+                    line.push('{');
+                    for (i, column) in columns.iter().enumerate() {
+                        if i > 0 {
+                            line.push(',');
+                        }
+                        line.push_str(&json(column));
+                        line.push(':');
+                        line.push_str(&json(&row[column]));
+                    }
+                    line.push('}');
+                }
+            }
+            line.push('\n');
+            writer.write_all(line.as_bytes())?;
+            written += 1;
+        }
+        writer.flush()?;
+        Ok(written)
+    }
+}
+
+// Timestamps are written as milliseconds, like everywhere else
+fn csv_text(value: &TypedValue) -> String {
+    match value {
+        TypedValue::Int(i) | TypedValue::Timestamp(i) => i.to_string(),
+        TypedValue::Int32(i) => i.to_string(),
+        TypedValue::Int16(i) => i.to_string(),
+        TypedValue::Serial(i) => i.to_string(),
+        TypedValue::Float(f) => f.to_string(),
+        TypedValue::Char(c) => c.to_string(),
+        TypedValue::String(s) | TypedValue::Email(s) => s.clone(),
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn json(value: &impl Serialize) -> String {
+    serde_json::to_string(value).expect("Names and values are valid JSON")
+}
@@ -1,14 +1,26 @@
-use joinable::JoinableGrouped;
 use rusqlite::types::Type;
 
-use super::schema::Columns;
-use super::types::{ColumnSet, DataType, PoorlyError, TableMethod, TypedValue};
+use super::clock::{Clock, SystemClock};
+use super::expression::{Expression, Expressions};
+use super::predicate::{self, Predicate, Predicates};
+use super::schema::{ColumnDefault, Columns, Defaults};
+use super::types::{
+    self, ColumnLayout, ColumnSet, DataType, OrderBy, PoorlyError, QueryStats, RowFormat,
+    TableMethod, TypedValue,
+};
 
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::sync::Arc;
 
+use self::row_store::{BufferedStore, FileStore, Flusher, RowStore};
+
+pub mod encrypted;
+pub mod export;
+pub mod row_store;
 #[cfg(test)]
 mod tests;
 
@@ -16,8 +28,27 @@ mod tests;
 pub struct Table {
     pub name: String,
     pub columns: Columns,
+    pub defaults: Defaults,
+    pub format: RowFormat,
     pub serial: u32,
-    pub file: File,
+    pub file: Box<dyn RowStore>,
+    // Bumped on every write to the file, so a vacuum can tell if its copy is stale
+    pub changes: u64,
+    // Statistics of the last select, join, update or delete
+    pub stats: QueryStats,
+    // Time given to `now` and `auto_update` columns
+    pub clock: Arc<dyn Clock>,
+    // Highest value of the `auto_increment` column so far, if the table has
+    // one. Kept in the file header after the serial.
+    pub counter: Option<i64>,
+}
+
+/// Live rows of a table copied into a new storage by `Table::compact`
+#[derive(Debug)]
+pub struct Compacted {
+    storage: Box<dyn RowStore>,
+    changes: u64,
+    removed: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -26,68 +57,286 @@ struct Row {
     offset: u64,
 }
 
-// TODO: add cleanup (remove all deleted entries)
+// Flag byte ahead of a row deleted by a query
+const DELETED: u8 = 1;
+// Flag byte ahead of the old version of an updated row, the new one being
+// appended to the file. Readers take any non-zero flag for a deleted row.
+const UPDATED: u8 = 2;
+
+/// Synthetic column carrying a row's position in the table file
+pub const OFFSET_COLUMN: &str = "_offset";
+
+/// Synthetic column of rows selected along with deleted ones, 1 for a
+/// deleted row and 0 for a live one
+pub const DELETED_COLUMN: &str = "_deleted";
+
+/// Where a column is in the rows of a table, see `Table::columns_bytes_layout`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnBytes {
+    pub column: String,
+    /// Bytes from the start of the row, which is known up to the first
+    /// column of variable width
+    pub offset: Option<u64>,
+    pub layout: ColumnLayout,
+}
+
+/// Projection of a select that builds no rows and returns only how many
+/// match, as a single `{"count": n}`. No column may have this name.
+pub const COUNT_ONLY: &str = "__count__";
+
 impl Table {
-    fn next_row(&mut self) -> Option<Result<Row, io::Error>> {
+    // Reads the row at the current position along with its "deleted" flag.
+    // Rows don't record how many values they hold, so a row written with
+    // fewer columns than the table has now is noticed by reading it running
+    // past the end of the file, into bytes that aren't a valid value, or to
+    // where the next row can't start as no flag byte is there.
+    fn read_row(&mut self) -> Option<Result<(Row, bool), PoorlyError>> {
         let mut row = HashMap::new();
         let mut deleted = [0];
-        let mut offset;
-        loop {
-            offset = self.file.stream_position().unwrap();
-            self.file.read_exact(&mut deleted).ok()?;
+        let offset = self.file.stream_position().unwrap();
+        self.file.read_exact(&mut deleted).ok()?;
+        self.stats.rows_scanned += 1;
+        if !Self::is_flag(deleted[0]) {
+            return Some(Err(PoorlyError::SchemaRowMismatch(offset)));
+        }
 
-            for (column, data_type) in &self.columns {
-                match TypedValue::read(*data_type, &mut self.file) {
-                    Ok(value) => row.insert(column.clone(), value),
-                    Err(e) => return Some(Err(e)),
-                };
+        for (column, data_type) in &self.columns {
+            match TypedValue::read(*data_type, &mut self.file, self.format) {
+                Ok(value) => row.insert(column.clone(), value),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => {
+                        return Some(Err(PoorlyError::SchemaRowMismatch(offset)))
+                    }
+                    _ => return Some(Err(PoorlyError::IoError(e))),
+                },
+            };
+        }
+
+        match self.next_flag() {
+            Ok(true) => {}
+            Ok(false) => return Some(Err(PoorlyError::SchemaRowMismatch(offset))),
+            Err(e) => return Some(Err(PoorlyError::IoError(e))),
+        }
+
+        let stored = self.file.stream_position().unwrap() - offset;
+        self.stats.bytes_stored += stored;
+        self.stats.bytes_raw += match self.format {
+            RowFormat::V3 => {
+                let values = row
+                    .values()
+                    .map(|value| value.clone().into_bytes(RowFormat::V2));
+                1 + values.map(|bytes| bytes.len() as u64).sum::<u64>()
             }
+            _ => stored,
+        };
 
-            if deleted[0] == 0 {
-                break;
+        Some(Ok((Row { offset, row }, deleted[0] != 0)))
+    }
+
+    fn is_flag(byte: u8) -> bool {
+        matches!(byte, 0 | DELETED | UPDATED)
+    }
+
+    // Tells if the end of the file or the flag of another row follows,
+    // keeping the current position. This is a best-effort check: the values
+    // of a misread row may still end where a byte that looks like a flag is.
+    fn next_flag(&mut self) -> io::Result<bool> {
+        let mut flag = [0];
+        match self.file.read(&mut flag)? {
+            0 => Ok(true),
+            _ => {
+                self.file.seek(SeekFrom::Current(-1))?;
+                Ok(Self::is_flag(flag[0]))
             }
         }
+    }
 
-        Some(Ok(Row { offset, row }))
+    fn next_row(&mut self) -> Option<Result<Row, PoorlyError>> {
+        loop {
+            match self.read_row()? {
+                Ok((row, false)) => return Some(Ok(row)),
+                Ok((_, true)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
     }
 
     fn delete_at(&mut self, offset: u64) -> Result<(), io::Error> {
+        self.tombstone_at(offset, DELETED)
+    }
+
+    fn tombstone_at(&mut self, offset: u64, flag: u8) -> Result<(), io::Error> {
+        self.changes += 1;
         self.file.seek(SeekFrom::Start(offset))?;
-        self.file.write_all(&[1])?;
+        self.file.write_all(&[flag])?;
         self.file.seek(SeekFrom::Current(-1))?;
         Ok(())
     }
 
-    pub fn open(name: String, columns: Columns, path: &Path) -> Self {
+    // Reads the flag byte of the row at `offset`, keeping the current position
+    fn flag_at(&mut self, offset: u64) -> Result<u8, io::Error> {
+        let position = self.file.stream_position()?;
+        let mut flag = [0];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut flag)?;
+        self.file.seek(SeekFrom::Start(position))?;
+        Ok(flag[0])
+    }
+
+    /// Yields the offset and values of every live row, in file order, for
+    /// tools keeping their own indexes. The offsets are those reported in
+    /// `OFFSET_COLUMN`.
+    pub fn iter_offsets(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<(u64, ColumnSet), PoorlyError>> + '_, PoorlyError> {
+        self.rewind().map_err(PoorlyError::IoError)?;
+        Ok(std::iter::from_fn(move || {
+            self.next_row()
+                .map(|row| row.map(|Row { row, offset }| (offset, row)))
+        }))
+    }
+
+    pub fn open(
+        name: String,
+        columns: Columns,
+        defaults: Defaults,
+        format: RowFormat,
+        path: &Path,
+    ) -> Self {
         log::info!("Opening table `{}`", name);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path.join(name.clone()))
-            .expect("Failed to open table");
+        let file = FileStore::open(path.join(&name)).expect("Failed to open table");
+        Self::with_store(name, columns, defaults, format, Box::new(file))
+            .expect("Failed to open table")
+    }
 
+    /// Creates a table kept in memory only, for databases that never touch the disk
+    pub fn in_memory(
+        name: String,
+        columns: Columns,
+        defaults: Defaults,
+        format: RowFormat,
+    ) -> Self {
+        log::info!("Creating in-memory table `{}`", name);
+        let file = Cursor::new(Vec::new());
+        Self::with_store(name, columns, defaults, format, Box::new(file))
+            .expect("Failed to write to table")
+    }
+
+    /// Creates a table over any `RowStore`, reading its serial or writing a
+    /// new one if the store is empty.
+    pub fn with_store(
+        name: String,
+        columns: Columns,
+        defaults: Defaults,
+        format: RowFormat,
+        mut file: Box<dyn RowStore>,
+    ) -> io::Result<Self> {
         let mut serial = 0u32;
 
         let mut buf = [0u8; 4];
-        let tmp = file.read_exact(&mut buf);
-        if let Err(e) = tmp {
-            if e.kind() == io::ErrorKind::UnexpectedEof {
+        match file.read_exact(&mut buf) {
+            Ok(()) => {
+                serial = u32::from_le_bytes(buf);
+                log::debug!("Read serial `{}` from table `{}`", serial, name)
+            }
+            // A read-only table without a header has no rows either
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && file.read_only() => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                 log::debug!("Writing serial `{}` to table `{}`", serial, name);
-                file.write_all(serial.to_le_bytes().as_ref())
-                    .expect("Failed to write to table");
-            } else {
+                file.write_all(serial.to_le_bytes().as_ref())?;
             }
-        } else {
-            serial = u32::from_le_bytes(buf);
-            log::debug!("Read serial `{}` from table `{}`", serial, name)
+            Err(e) => return Err(e),
         }
+        let counted = defaults
+            .values()
+            .any(|d| *d == ColumnDefault::AutoIncrement);
+        let counter = match counted {
+            true => Some(Self::read_counter(&name, &mut file)?),
+            false => None,
+        };
 
-        Self {
+        Ok(Self {
             name,
             columns,
+            defaults,
+            format,
             file,
             serial,
+            changes: 0,
+            stats: QueryStats::default(),
+            clock: Arc::new(SystemClock),
+            counter,
+        })
+    }
+
+    // Reads the counter of the `auto_increment` column following the
+    // serial, or writes a new one if the store has none yet
+    fn read_counter(name: &str, file: &mut Box<dyn RowStore>) -> io::Result<i64> {
+        let mut buf = [0u8; 8];
+        match file.read_exact(&mut buf) {
+            Ok(()) => {
+                let counter = i64::from_le_bytes(buf);
+                log::debug!("Read counter `{}` from table `{}`", counter, name);
+                Ok(counter)
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && file.read_only() => Ok(0),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                file.write_all(&0i64.to_le_bytes())?;
+                Ok(0)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // The serial, then the `auto_increment` counter if there is one. Rows
+    // start right after.
+    fn header(&self) -> Vec<u8> {
+        let mut header = self.serial.to_le_bytes().to_vec();
+        if let Some(counter) = self.counter {
+            header.extend_from_slice(&counter.to_le_bytes());
+        }
+        header
+    }
+
+    fn header_len(&self) -> u64 {
+        match self.counter {
+            Some(_) => 12,
+            None => 4,
+        }
+    }
+
+    // Moves to the first row
+    fn rewind(&mut self) -> io::Result<u64> {
+        let start = self.header_len();
+        self.file.seek(SeekFrom::Start(start))
+    }
+
+    fn auto_increment_column(&self) -> Option<&String> {
+        self.defaults
+            .iter()
+            .find(|(_, default)| **default == ColumnDefault::AutoIncrement)
+            .map(|(column, _)| column)
+    }
+
+    /// Takes the time of `now` and `auto_update` defaults from `clock`
+    /// instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Holds back inserts to write them in groups, see `BufferedStore`
+    pub fn buffer_writes(&mut self, flusher: &Flusher) -> Result<(), PoorlyError> {
+        let file = std::mem::replace(&mut self.file, Box::new(Cursor::new(Vec::new())));
+        self.file = Box::new(BufferedStore::new(file, flusher)?);
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), PoorlyError> {
+        if self.file.read_only() {
+            Err(PoorlyError::InvalidOperation("read-only".to_string()))
+        } else {
+            Ok(())
         }
     }
 
@@ -135,6 +384,67 @@ impl Table {
         }
     }
 
+    // Checks that the columns `expressions` set and read exist and that none
+    // is set by `set` too, pairing every expression with its column's type
+    fn check_expressions(
+        &self,
+        expressions: Expressions,
+        set: &ColumnSet,
+    ) -> Result<Vec<(String, Expression, DataType)>, PoorlyError> {
+        let data_type = |column: &String| {
+            self.columns
+                .iter()
+                .find(|(name, _)| name == column)
+                .map(|(_, data_type)| *data_type)
+                .ok_or_else(|| PoorlyError::ColumnNotFound(column.clone(), self.name.clone()))
+        };
+        let mut checked = Vec::new();
+        for (column, expression) in expressions {
+            let column_type = data_type(&column)?;
+            self.check_restrictions(column_type, &TableMethod::Update)?;
+            if set.contains_key(&column) {
+                return Err(PoorlyError::InvalidOperation(format!(
+                    "column {} is set both to a value and an expression",
+                    column
+                )));
+            }
+            for read in expression.columns() {
+                data_type(read)?;
+            }
+            checked.push((column, expression, column_type));
+        }
+        Ok(checked)
+    }
+
+    fn coerce_predicates(
+        &self,
+        mut predicates: Predicates,
+        table_method: TableMethod,
+    ) -> Result<Predicates, PoorlyError> {
+        let mut coerced = HashMap::new();
+        for (column, data_type) in &self.columns {
+            if let Some((column, predicate)) = predicates.remove_entry(column) {
+                self.check_restrictions(*data_type, &table_method)?;
+                let predicate = predicate.coerce(*data_type)?;
+                if let Predicate::Eq(value) = &predicate {
+                    value.validate()?;
+                }
+                coerced.insert(column, predicate);
+            }
+        }
+        match predicates.into_keys().next() {
+            None => Ok(coerced),
+            Some(column) => Err(PoorlyError::ColumnNotFound(column, self.name.clone())),
+        }
+    }
+
+    fn check_predicates(row: &ColumnSet, predicates: &Predicates) -> bool {
+        predicates.iter().all(|(column, predicate)| {
+            row.get(column)
+                .is_some_and(|value| predicate.matches(value))
+        })
+    }
+
     fn check_conditions(
         &self,
         row: &ColumnSet,
@@ -174,57 +484,315 @@ impl Table {
         Ok(result)
     }
 
+    /// Moves the serial counter past every serial stored in the table,
+    /// deleted rows included. Returns the old and new counter if it was behind.
+    pub fn repair_serial(&mut self) -> Result<Option<(u32, u32)>, PoorlyError> {
+        self.check_writable()?;
+        let mut next = None;
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.read_row() {
+            let (Row { row, .. }, _) = row?;
+            for value in row.values() {
+                if let TypedValue::Serial(serial) = value {
+                    next = next.max(Some(serial.saturating_add(1)));
+                }
+            }
+        }
+
+        match next {
+            Some(next) if next > self.serial => {
+                let old = self.serial;
+                self.serial = next;
+                self.changes += 1;
+                self.file.seek(SeekFrom::Start(0))?;
+                self.file.write_all(&self.header())?;
+                Ok(Some((old, next)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads every row, checking that live rows hold valid values and that
+    /// the serial counter is past every stored serial. Returns what is wrong;
+    /// reading stops at the first row that can't be read.
+    pub fn check_integrity(&mut self) -> Result<Vec<String>, PoorlyError> {
+        let mut problems = vec![];
+        let mut max_serial = None;
+        self.rewind()?;
+        while let Some(row) = self.read_row() {
+            let (Row { row, offset }, deleted) = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    problems.push(e.to_string());
+                    break;
+                }
+            };
+            for (column, _) in &self.columns {
+                let Some(value) = row.get(column) else {
+                    continue;
+                };
+                if let TypedValue::Serial(serial) = value {
+                    max_serial = max_serial.max(Some(*serial));
+                }
+                // Deleted rows only count for the serial counter
+                if deleted {
+                    continue;
+                }
+                if let Err(e) = value.validate() {
+                    problems.push(format!("row at offset {}: {}: {}", offset, column, e));
+                }
+            }
+        }
+
+        if let Some(max_serial) = max_serial.filter(|max_serial| *max_serial >= self.serial) {
+            problems.push(format!(
+                "serial counter {} is not past stored serial {}",
+                self.serial, max_serial
+            ));
+        }
+        Ok(problems)
+    }
+
     fn update_serial(&mut self) -> Result<(), PoorlyError> {
         self.file.seek(SeekFrom::Start(0))?;
         self.serial += 1;
-        self.file.write_all(&self.serial.to_le_bytes())?;
-        self.file.seek(SeekFrom::Start(4))?;
+        self.changes += 1;
+        self.file.write_all(&self.header())?;
+        self.rewind()?;
         Ok(())
     }
 
     pub fn insert(&mut self, values: ColumnSet) -> Result<ColumnSet, PoorlyError> {
+        let mut values = self.prepare_insert(values)?;
+        self.append(&mut values)?;
+        Ok(values)
+    }
+
+    /// Like `insert`, with the row's position in the file added as `_offset`.
+    /// The offset is only valid until the row is updated or the table is vacuumed.
+    pub fn insert_with_offset(&mut self, values: ColumnSet) -> Result<ColumnSet, PoorlyError> {
+        let mut values = self.prepare_insert(values)?;
+        let offset = self.append(&mut values)?;
+        values.insert(OFFSET_COLUMN.to_string(), TypedValue::Int(offset as i64));
+        Ok(values)
+    }
+
+    /// Inserts every row or, if any of them is invalid, none of them.
+    pub fn insert_many(&mut self, rows: Vec<ColumnSet>) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let mut rows = rows
+            .into_iter()
+            .map(|values| self.prepare_insert(values))
+            .collect::<Result<Vec<_>, _>>()?;
+        for values in &mut rows {
+            self.append(values)?;
+        }
+        Ok(rows)
+    }
+
+    /// Appends `rows` as they are in a single write, bumping the serial
+    /// counter once for all of them. Serial columns are numbered like `insert`
+    /// does, and the `auto_increment` counter moves up to the highest value
+    /// among them.
+    ///
+    /// Nothing is checked or coerced: every row must already hold a valid
+    /// value of the column's exact type for every other column, or the table
+    /// may be unreadable from the first bad row on. This is only for rows read
+    /// back from a table, like a restored dump, never for rows from a client.
+    pub(crate) fn insert_raw_many(&mut self, rows: Vec<ColumnSet>) -> Result<(), PoorlyError> {
+        self.check_writable()?;
+        let mut bytes = Vec::new();
+        let mut serial = self.serial;
+        let counted = self.auto_increment_column().cloned();
+        let mut counter = self.counter;
+        for mut values in rows {
+            bytes.push(0); // 0 - "not deleted"
+            for (name, _type) in &self.columns {
+                let value = match _type {
+                    DataType::Serial => TypedValue::Serial(serial),
+                    _ => values.remove(name).ok_or_else(|| {
+                        PoorlyError::IncompleteData(name.clone(), self.name.clone())
+                    })?,
+                };
+                if counted.as_ref() == Some(name) {
+                    counter = counter.max(value.as_int());
+                }
+                bytes.extend_from_slice(&value.into_bytes(self.format));
+            }
+            serial += 1;
+        }
+
+        self.serial = serial;
+        self.counter = counter;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&self.header())?;
+        self.changes += 1;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&bytes)?;
+        self.rewind()?;
+        Ok(())
+    }
+
+    // Fills in defaults and checks that `values` form a complete row
+    fn prepare_insert(&self, mut values: ColumnSet) -> Result<ColumnSet, PoorlyError> {
+        self.check_writable()?;
+        for (column, default) in &self.defaults {
+            if let Some(value) = default.value(self.clock.as_ref()) {
+                values.entry(column.clone()).or_insert(value);
+            }
+        }
         let values = self.check_and_coerce(values, TableMethod::Insert)?;
+        let counted = self.auto_increment_column();
+        for (name, _type) in &self.columns {
+            if _type != &DataType::Serial && !values.contains_key(name) && counted != Some(name) {
+                return Err(PoorlyError::IncompleteData(name.clone(), self.name.clone()));
+            }
+        }
+        Ok(values)
+    }
+
+    // Writes a row prepared by `prepare_insert` to the end of the file and
+    // returns its offset. The serial the row gets is added to `values`, and
+    // so is the `auto_increment` value if it was left out.
+    fn append(&mut self, values: &mut ColumnSet) -> Result<u64, PoorlyError> {
+        if let Some(column) = self.auto_increment_column().cloned() {
+            let counter = self.counter.unwrap_or_default();
+            let value = match values.get(&column).and_then(TypedValue::as_int) {
+                Some(given) => given,
+                None => counter.checked_add(1).ok_or_else(|| {
+                    PoorlyError::InvalidOperation(format!(
+                        "auto_increment column {} is out of values",
+                        column
+                    ))
+                })?,
+            };
+            self.counter = Some(counter.max(value));
+            values.insert(column, TypedValue::Int(value));
+        }
+
         let mut row = vec![0]; // 0 - "not deleted"
         for (name, _type) in &self.columns {
             if _type == &DataType::Serial {
-                row.extend_from_slice(&TypedValue::Serial(self.serial).into_bytes());
+                let serial = TypedValue::Serial(self.serial);
+                row.extend_from_slice(&serial.clone().into_bytes(self.format));
+                values.insert(name.clone(), serial);
                 continue;
             }
 
-            let value = values
-                .get(name)
-                .ok_or_else(|| PoorlyError::IncompleteData(name.clone(), self.name.clone()))?;
-
-            row.extend_from_slice(&value.clone().into_bytes());
+            row.extend_from_slice(&values[name].clone().into_bytes(self.format));
         }
 
         self.update_serial()?;
 
-        self.file
+        let offset = self
+            .file
             .seek(SeekFrom::End(0))
             .map_err(PoorlyError::IoError)?;
         self.file.write_all(&row).map_err(PoorlyError::IoError)?;
-        Ok(values)
+        Ok(offset)
     }
 
     pub fn select(
         &mut self,
         columns: Vec<String>,
         conditions: ColumnSet,
+        max_rows: Option<usize>,
     ) -> Result<Vec<ColumnSet>, PoorlyError> {
-        let conditions = self.check_and_coerce(conditions, TableMethod::Select)?;
+        let predicates = predicate::merge(conditions, HashMap::new())?;
+        self.select_where(columns, predicates, &[], &[], max_rows)
+    }
+
+    /// Selects the rows matching `predicates`. Rows are sorted by `order_by`
+    /// (ties keep their order in the file) and then, if `distinct_on` is given,
+    /// only the first row of every distinct value of those columns is kept.
+    /// Without `order_by` that is the first one in the file, whole rows are
+    /// kept either way.
+    pub fn select_where(
+        &mut self,
+        columns: Vec<String>,
+        predicates: Predicates,
+        order_by: &[OrderBy],
+        distinct_on: &[String],
+        max_rows: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        self.select_rows(columns, predicates, order_by, distinct_on, max_rows, false)
+    }
+
+    /// Like `select_where`, but deleted rows are selected too and every row
+    /// has a `DELETED_COLUMN` telling which it is, which `columns` and
+    /// `predicates` may name
+    pub fn select_with_deleted(
+        &mut self,
+        columns: Vec<String>,
+        predicates: Predicates,
+        order_by: &[OrderBy],
+        distinct_on: &[String],
+        max_rows: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        self.select_rows(columns, predicates, order_by, distinct_on, max_rows, true)
+    }
+
+    fn select_rows(
+        &mut self,
+        columns: Vec<String>,
+        mut predicates: Predicates,
+        order_by: &[OrderBy],
+        distinct_on: &[String],
+        max_rows: Option<usize>,
+        include_deleted: bool,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let deleted = match include_deleted {
+            true => predicates.remove(DELETED_COLUMN),
+            false => None,
+        };
+        let deleted = deleted.map(|p| p.coerce(DataType::Int)).transpose()?;
+        let mut predicates = self.coerce_predicates(predicates, TableMethod::Select)?;
+        predicates.extend(deleted.map(|predicate| (DELETED_COLUMN.to_string(), predicate)));
+        let order_columns = order_by.iter().map(|order| &order.column);
+        for column in order_columns.chain(distinct_on) {
+            if !self.columns.iter().any(|(name, _)| name == column) {
+                return Err(PoorlyError::ColumnNotFound(
+                    column.clone(),
+                    self.name.clone(),
+                ));
+            }
+        }
+        // Ordered results are only known after the whole scan, and so are the
+        // first rows of every distinct value among them. Unordered rows are
+        // deduplicated as they are read.
+        let buffered = !order_by.is_empty();
+        let distinct_later = buffered && !distinct_on.is_empty();
+        let count_only = columns == [COUNT_ONLY];
+        let mut seen = HashSet::new();
+        let mut counted = 0;
+
+        self.stats = QueryStats::default();
         let mut selected = Vec::new();
-        self.file
-            .seek(SeekFrom::Start(4))
-            .map_err(PoorlyError::IoError)?;
-        while let Some(row) = self.next_row() {
-            let Row { mut row, .. } = row.map_err(PoorlyError::IoError)?;
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.read_row() {
+            let (Row { mut row, .. }, deleted) = row?;
+            if deleted && !include_deleted {
+                continue;
+            }
+            if include_deleted {
+                row.insert(DELETED_COLUMN.to_string(), TypedValue::Int(deleted as i64));
+            }
 
-            if !self.check_conditions(&row, &conditions)? {
+            if !Self::check_predicates(&row, &predicates) {
+                continue;
+            }
+            self.stats.rows_matched += 1;
+            if !distinct_on.is_empty()
+                && !distinct_later
+                && !seen.insert(Self::distinct_key(&row, distinct_on))
+            {
+                continue;
+            }
+            if count_only && !distinct_later {
+                counted += 1;
                 continue;
             }
 
-            for column in &columns {
+            for column in columns.iter().filter(|_| !count_only) {
                 if !row.contains_key(column) {
                     return Err(PoorlyError::ColumnNotFound(
                         column.clone(),
@@ -233,138 +801,820 @@ impl Table {
                 }
             }
 
-            row.retain(|key, _| columns.is_empty() || columns.contains(key));
+            if !buffered && !count_only {
+                Self::check_result_size(selected.len(), max_rows)?;
+            }
             selected.push(row);
         }
+        if count_only && !distinct_later {
+            let count = TypedValue::Int(counted);
+            return Ok(vec![[("count".to_string(), count)].into()]);
+        }
+
+        if !order_by.is_empty() {
+            selected.sort_by(|a, b| Self::compare_ordered(a, b, order_by));
+        }
+        if distinct_later {
+            selected.retain(|row| seen.insert(Self::distinct_key(row, distinct_on)));
+        }
+        if count_only {
+            let count = TypedValue::Int(selected.len() as i64);
+            return Ok(vec![[("count".to_string(), count)].into()]);
+        }
+        match max_rows {
+            Some(max_rows) if buffered && selected.len() > max_rows => {
+                return Err(PoorlyError::ResultTooLarge(max_rows))
+            }
+            _ => {}
+        }
+
+        for row in &mut selected {
+            row.retain(|key, _| columns.is_empty() || columns.contains(key));
+        }
         Ok(selected)
     }
 
-    pub fn join(
+    // Identifies the rows `distinct_on` keeps one of
+    fn distinct_key(row: &ColumnSet, distinct_on: &[String]) -> Vec<types::CanonicalValue> {
+        distinct_on
+            .iter()
+            .map(|column| row[column].canonical_key())
+            .collect()
+    }
+
+    /// How the columns follow each other in a row, after the byte flagging
+    /// deleted rows, in the order `read_row` reads them
+    pub fn columns_bytes_layout(&self) -> Vec<ColumnBytes> {
+        let mut offset = Some(1);
+        self.columns
+            .iter()
+            .map(|(column, data_type)| {
+                let layout = data_type.layout(self.format);
+                let bytes = ColumnBytes {
+                    column: column.clone(),
+                    offset,
+                    layout,
+                };
+                offset = match layout {
+                    ColumnLayout::Fixed(width) => offset.map(|offset| offset + width as u64),
+                    ColumnLayout::Variable { .. } => None,
+                };
+                bytes
+            })
+            .collect()
+    }
+
+    /// The column rows are numbered in, for selecting what was inserted since
+    pub fn serial_column(&self) -> Result<&str, PoorlyError> {
+        self.columns
+            .iter()
+            .find(|(_, data_type)| *data_type == DataType::Serial)
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(|| {
+                PoorlyError::InvalidOperation(format!("table {} has no serial column", self.name))
+            })
+    }
+
+    /// Like `select_where`, but returns every row as the values of `columns`
+    /// in order, along with the column names once. Without `columns`, rows
+    /// hold all the columns of the table.
+    pub fn select_vectors(
         &mut self,
-        other_table: &mut Table,
         columns: Vec<String>,
-        conditions: ColumnSet,
-        join_on: HashMap<String, String>,
-    ) -> Result<Vec<ColumnSet>, PoorlyError> {
-        let get_rows = |table: &mut Table| -> Result<Vec<ColumnSet>, PoorlyError> {
-            let mut selected: Vec<ColumnSet> = Vec::new();
-            table
-                .file
-                .seek(SeekFrom::Start(4))
-                .map_err(PoorlyError::IoError)?;
-            while let Some(row) = table.next_row() {
-                let Row { row, .. } = row.map_err(PoorlyError::IoError)?;
+        predicates: Predicates,
+        order_by: &[OrderBy],
+        distinct_on: &[String],
+        max_rows: Option<usize>,
+    ) -> Result<(Vec<String>, Vec<Vec<TypedValue>>), PoorlyError> {
+        let header = if columns.is_empty() {
+            self.columns.iter().map(|(name, _)| name.clone()).collect()
+        } else {
+            columns.clone()
+        };
+        let rows = self.select_where(columns, predicates, order_by, distinct_on, max_rows)?;
+        let rows = types::row_vectors(&header, rows);
+        Ok((header, rows))
+    }
 
-                selected.push(
-                    row.into_iter()
-                        .map(|(k, v)| (format!("{}.{}", &table.name, &k), v))
-                        .collect(),
-                );
+    /// Returns the only row matching `conditions`, failing if there are
+    /// none or more than one.
+    pub fn select_one(&mut self, conditions: ColumnSet) -> Result<ColumnSet, PoorlyError> {
+        let conditions = self.check_and_coerce(conditions, TableMethod::Select)?;
+        self.stats = QueryStats::default();
+        let mut selected = None;
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.next_row() {
+            let Row { row, .. } = row?;
+
+            if !self.check_conditions(&row, &conditions)? {
+                continue;
             }
+            self.stats.rows_matched += 1;
 
-            return Ok(selected);
-        };
+            if selected.replace(row).is_some() {
+                return Err(PoorlyError::TooManyRows(self.name.clone()));
+            }
+        }
+        selected.ok_or_else(|| PoorlyError::RowNotFound(self.name.clone()))
+    }
 
-        let rows1 = get_rows(self)?;
-        let rows2 = get_rows(other_table)?;
+    pub fn count(&mut self, conditions: ColumnSet) -> Result<u64, PoorlyError> {
+        let conditions = self.check_and_coerce(conditions, TableMethod::Select)?;
+        self.stats = QueryStats::default();
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.next_row() {
+            let Row { row, .. } = row?;
 
-        let it = rows1.into_iter().inner_join_grouped(&rows2[..], |r1, r2| {
-            for (k1, k2) in &join_on {
-                let v1 = r1.get(k1);
-                let v2 = r2.get(k2);
+            if self.check_conditions(&row, &conditions)? {
+                self.stats.rows_matched += 1;
+            }
+        }
+        Ok(self.stats.rows_matched)
+    }
 
-                if let Some(ord) = v1.partial_cmp(&v2) {
-                    if ord != std::cmp::Ordering::Equal {
-                        return ord;
-                    }
+    /// The row matching `predicates` a select ordered by `order_by` would
+    /// return first, found in a single scan holding only the best row so far
+    pub fn first(
+        &mut self,
+        predicates: Predicates,
+        order_by: &OrderBy,
+    ) -> Result<Option<ColumnSet>, PoorlyError> {
+        self.extreme(predicates, order_by, false)
+    }
+
+    /// The row matching `predicates` a select ordered by `order_by` would
+    /// return last, like `first`
+    pub fn last(
+        &mut self,
+        predicates: Predicates,
+        order_by: &OrderBy,
+    ) -> Result<Option<ColumnSet>, PoorlyError> {
+        self.extreme(predicates, order_by, true)
+    }
+
+    // Of rows that order the same, the first in the file comes first when
+    // sorted and the last in the file comes last
+    fn extreme(
+        &mut self,
+        predicates: Predicates,
+        order_by: &OrderBy,
+        last: bool,
+    ) -> Result<Option<ColumnSet>, PoorlyError> {
+        if !self
+            .columns
+            .iter()
+            .any(|(name, _)| *name == order_by.column)
+        {
+            return Err(PoorlyError::ColumnNotFound(
+                order_by.column.clone(),
+                self.name.clone(),
+            ));
+        }
+        let predicates = self.coerce_predicates(predicates, TableMethod::Select)?;
+        let order_by = std::slice::from_ref(order_by);
+
+        self.stats = QueryStats::default();
+        let mut best: Option<ColumnSet> = None;
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.next_row() {
+            let Row { row, .. } = row?;
+            if !Self::check_predicates(&row, &predicates) {
+                continue;
+            }
+            self.stats.rows_matched += 1;
+
+            let better = best.as_ref().is_none_or(|best| {
+                let ordering = Self::compare_ordered(&row, best, order_by);
+                if last {
+                    ordering.is_ge()
                 } else {
-                    log::warn!("in inner_join_grouped None appeared");
-                    return std::cmp::Ordering::Less;
+                    ordering.is_lt()
                 }
+            });
+            if better {
+                best = Some(row);
             }
+        }
+        Ok(best)
+    }
 
-            std::cmp::Ordering::Equal
-        });
+    /// Returns up to `n` live rows picked at random, by reservoir sampling in
+    /// a single scan so only the sample is held in memory.
+    pub fn sample(&mut self, n: usize) -> Result<Vec<ColumnSet>, PoorlyError> {
+        self.stats = QueryStats::default();
+        let seed = RandomState::new();
+        let mut sample = Vec::with_capacity(n.min(1024));
+        self.rewind().map_err(PoorlyError::IoError)?;
+        let mut seen = 0u64;
+        while let Some(row) = self.next_row() {
+            let Row { row, .. } = row?;
+            seen += 1;
 
+            if sample.len() < n {
+                sample.push(row);
+                continue;
+            }
+            // The `seen`-th row replaces a sampled one with probability n / seen
+            let mut hasher = seed.build_hasher();
+            hasher.write_u64(seen);
+            let slot = (hasher.finish() % seen) as usize;
+            if slot < n {
+                sample[slot] = row;
+            }
+        }
+        self.stats.rows_matched = sample.len() as u64;
+        Ok(sample)
+    }
+
+    /// Returns the tombstoned rows matching `conditions`, each with its file
+    /// position in an extra `_offset` column that can be passed to `undelete`.
+    /// Old versions left behind by updates are not listed.
+    pub fn select_deleted(&mut self, conditions: ColumnSet) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let conditions = self.check_and_coerce(conditions, TableMethod::Select)?;
+        self.stats = QueryStats::default();
         let mut selected = Vec::new();
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.read_row() {
+            let (Row { offset, mut row }, deleted) = row?;
 
-        for (mut v1, v2) in it.into_iter() {
-            v2.into_iter().for_each(|map| v1.extend(map.clone()));
-            if !self.check_conditions_coerced(&v1, &conditions)? {
+            if !deleted || !self.check_conditions(&row, &conditions)? {
+                continue;
+            }
+            // Old versions of updated rows live on in their new ones
+            if self.flag_at(offset)? == UPDATED {
                 continue;
             }
-            v1.retain(|k, _| columns.is_empty() || columns.contains(k));
-            selected.push(v1);
+            self.stats.rows_matched += 1;
+
+            row.insert(OFFSET_COLUMN.to_string(), TypedValue::Int(offset as i64));
+            selected.push(row);
+        }
+        Ok(selected)
+    }
+
+    /// Clears the tombstone of the row at `offset` and returns the restored row.
+    /// Rows are only ever appended, so a tombstoned slot still holds the row
+    /// that was deleted from it; the offset is checked to be the start of a
+    /// deleted row so a stale or made-up one can't flip a byte inside another row.
+    /// The old version of an updated row is refused, restoring it would
+    /// duplicate the row. Tables written before updates were told apart from
+    /// deletes mark those old versions as deleted, so they can still be restored.
+    pub fn undelete(&mut self, offset: u64) -> Result<ColumnSet, PoorlyError> {
+        self.check_writable()?;
+        self.stats = QueryStats::default();
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.read_row() {
+            let (row, deleted) = row?;
+            if row.offset < offset {
+                continue;
+            }
+            if row.offset > offset || !deleted || self.flag_at(offset)? == UPDATED {
+                break;
+            }
+
+            self.stats.rows_matched += 1;
+            self.changes += 1;
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&[0])?;
+            return Ok(row.row);
+        }
+        Err(PoorlyError::InvalidOperation(format!(
+            "no deleted row at offset {} in table {}",
+            offset, self.name
+        )))
+    }
+
+    /// Joins `self` with `other_table`. The output is sorted by the `join_on`
+    /// columns and then by the remaining columns, so the same join over the
+    /// same data always returns rows in the same order.
+    ///
+    /// The rows of `self` meeting the conditions on its own columns are kept
+    /// in memory, while `other_table` is streamed past them. `max_rows` caps
+    /// the joined rows. With a `limit`, only the first that many rows of the
+    /// output order are returned. Every row is still joined, but no more than
+    /// twice the limit are held at once.
+    pub fn join(
+        &mut self,
+        other_table: &mut Table,
+        columns: Vec<String>,
+        conditions: ColumnSet,
+        join_on: HashMap<String, String>,
+        max_rows: Option<usize>,
+        limit: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        // `table.*` stands for all columns of one side of the join
+        let columns: Vec<String> = columns
+            .into_iter()
+            .flat_map(|column| {
+                let wildcard = [&*self, &*other_table]
+                    .into_iter()
+                    .find(|table| column == format!("{}.*", table.name));
+                match wildcard {
+                    Some(table) => table
+                        .columns
+                        .iter()
+                        .map(|(name, _)| format!("{}.{}", table.name, name))
+                        .collect(),
+                    None => vec![column],
+                }
+            })
+            .collect();
+
+        self.stats = QueryStats::default();
+        other_table.stats = QueryStats::default();
+        // Conditions on `self` alone are checked as it is read, so only the
+        // rows that can be joined are kept
+        let prefix = format!("{}.", self.name);
+        let (conditions1, conditions): (ColumnSet, ColumnSet) = conditions
+            .into_iter()
+            .partition(|(column, _)| column.starts_with(&prefix));
+        let mut rows1: Vec<ColumnSet> = Vec::new();
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.next_row() {
+            let Row { row, .. } = row?;
+            let row = Self::prefixed(&self.name, row);
+            if self.check_conditions_coerced(&row, &conditions1)? {
+                rows1.push(row);
+            }
+        }
+        // The hash join and `join_on` iteration don't give a stable order,
+        // so sort by the join keys first and then by every other column.
+        let mut order: Vec<String> = join_on
+            .iter()
+            .flat_map(|(k1, k2)| [k1.clone(), k2.clone()])
+            .collect();
+        order.sort();
+        order.dedup();
+        let mut rest: Vec<String> = [&*self, &*other_table]
+            .into_iter()
+            .flat_map(|table| {
+                table
+                    .columns
+                    .iter()
+                    .map(move |(name, _)| format!("{}.{}", table.name, name))
+            })
+            .filter(|k| !order.contains(k))
+            .collect();
+        rest.sort();
+        order.extend(rest);
+
+        let mut selected = self.join_rows(
+            rows1,
+            other_table,
+            &conditions,
+            &join_on,
+            &order,
+            limit,
+            max_rows,
+        )?;
+        self.stats.rows_scanned += other_table.stats.rows_scanned;
+        self.stats.bytes_stored += other_table.stats.bytes_stored;
+        self.stats.bytes_raw += other_table.stats.bytes_raw;
+        selected.sort_by(|a, b| Self::compare_rows(a, b, &order));
+
+        for row in &mut selected {
+            row.retain(|k, _| columns.is_empty() || columns.contains(k));
         }
 
         Ok(selected)
     }
 
+    // Names the columns of a row of `table` as `table.column`, as in joins
+    fn prefixed(table: &str, row: ColumnSet) -> ColumnSet {
+        row.into_iter()
+            .map(|(k, v)| (format!("{}.{}", table, k), v))
+            .collect()
+    }
+
+    // Joins rows of `other_table` as they are read to the `rows1` with the
+    // same `join_on` values. With a `limit`, only the first that many by
+    // `order` are returned.
+    #[allow(clippy::too_many_arguments)]
+    fn join_rows(
+        &mut self,
+        rows1: Vec<ColumnSet>,
+        other_table: &mut Table,
+        conditions: &ColumnSet,
+        join_on: &HashMap<String, String>,
+        order: &[String],
+        limit: Option<usize>,
+        max_rows: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let mut selected = Vec::new();
+        if limit == Some(0) {
+            return Ok(selected);
+        }
+        let (columns1, columns2): (Vec<_>, Vec<_>) = join_on.iter().unzip();
+        let key = |row: &ColumnSet, columns: &[&String]| -> Vec<_> {
+            columns
+                .iter()
+                .map(|column| row.get(*column).map(TypedValue::canonical_key))
+                .collect()
+        };
+        let mut by_key: HashMap<_, Vec<ColumnSet>> = HashMap::new();
+        for row in rows1 {
+            by_key.entry(key(&row, &columns1)).or_default().push(row);
+        }
+
+        let name = other_table.name.clone();
+        other_table.rewind()?;
+        while let Some(row) = other_table.next_row() {
+            let row2 = Self::prefixed(&name, row?.row);
+            for row1 in by_key.get(&key(&row2, &columns2)).into_iter().flatten() {
+                let mut joined = row1.clone();
+                joined.extend(row2.clone());
+                if !self.check_conditions_coerced(&joined, conditions)? {
+                    continue;
+                }
+                // Rows past the limit take the place of others, so only those
+                // up to it grow the result
+                let matched = self.stats.rows_matched as usize;
+                if limit.is_none_or(|n| matched < n) {
+                    Self::check_result_size(matched, max_rows)?;
+                }
+                self.stats.rows_matched += 1;
+                selected.push(joined);
+                // Keep the first `limit` rows of the output order seen so far
+                if let Some(limit) = limit.filter(|&n| selected.len() >= 2 * n) {
+                    selected.sort_by(|a, b| Self::compare_rows(a, b, order));
+                    selected.truncate(limit);
+                }
+            }
+        }
+        if let Some(limit) = limit {
+            selected.sort_by(|a, b| Self::compare_rows(a, b, order));
+            selected.truncate(limit);
+        }
+        Ok(selected)
+    }
+
+    fn check_result_size(selected: usize, max_rows: Option<usize>) -> Result<(), PoorlyError> {
+        match max_rows {
+            Some(max_rows) if selected >= max_rows => Err(PoorlyError::ResultTooLarge(max_rows)),
+            _ => Ok(()),
+        }
+    }
+
+    fn compare_ordered(a: &ColumnSet, b: &ColumnSet, order_by: &[OrderBy]) -> std::cmp::Ordering {
+        order_by
+            .iter()
+            .map(|order| {
+                let ordering = a[&order.column]
+                    .partial_cmp(&b[&order.column])
+                    .unwrap_or(std::cmp::Ordering::Equal);
+                if order.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|ord| ord.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    }
+
+    fn compare_rows(a: &ColumnSet, b: &ColumnSet, order: &[String]) -> std::cmp::Ordering {
+        order
+            .iter()
+            .map(|column| {
+                a.get(column)
+                    .partial_cmp(&b.get(column))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .find(|ord| ord.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    }
+
     pub fn update(
         &mut self,
         set: ColumnSet,
         conditions: ColumnSet,
     ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let predicates = predicate::merge(conditions, HashMap::new())?;
+        self.update_where(set, HashMap::new(), predicates, None)
+    }
+
+    /// Updates the rows matching `predicates`, at most `limit` of them.
+    /// Columns in `expressions` are set to the expression computed on the
+    /// row as it was before the update. Rows left unchanged don't count
+    /// towards the limit.
+    pub fn update_where(
+        &mut self,
+        set: ColumnSet,
+        expressions: Expressions,
+        predicates: Predicates,
+        limit: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let updated = self.update_rows(set, expressions, predicates, limit)?;
+        Ok(updated.into_iter().map(|(row, _)| row).collect())
+    }
+
+    /// Like `update_where`, with the new position of every updated row added
+    /// as `_offset`. Updated rows are moved to the end of the file, so an
+    /// offset is only valid until the row is updated again or the table is
+    /// vacuumed.
+    pub fn update_with_offsets(
+        &mut self,
+        set: ColumnSet,
+        expressions: Expressions,
+        predicates: Predicates,
+        limit: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let updated = self.update_rows(set, expressions, predicates, limit)?;
+        Ok(updated
+            .into_iter()
+            .map(|(mut row, offset)| {
+                row.insert(OFFSET_COLUMN.to_string(), TypedValue::Int(offset as i64));
+                row
+            })
+            .collect())
+    }
+
+    // Returns the updated rows along with the offsets they were moved to
+    fn update_rows(
+        &mut self,
+        set: ColumnSet,
+        expressions: Expressions,
+        predicates: Predicates,
+        limit: Option<usize>,
+    ) -> Result<Vec<(ColumnSet, u64)>, PoorlyError> {
+        self.check_writable()?;
         let set = self.check_and_coerce(set, TableMethod::Update)?;
-        let conditions = self.check_and_coerce(conditions, TableMethod::None)?;
-        let mut updated = Vec::new();
-        let eof = self
-            .file
-            .seek(SeekFrom::End(0))
-            .map_err(PoorlyError::IoError)?;
-        self.file
-            .seek(SeekFrom::Start(4))
-            .map_err(PoorlyError::IoError)?;
-        while let Some(row) = self.next_row() {
-            let Row { offset, mut row } = row.map_err(PoorlyError::IoError)?;
+        let expressions = self.check_expressions(expressions, &set)?;
+        let predicates = self.coerce_predicates(predicates, TableMethod::None)?;
+        self.stats = QueryStats::default();
 
-            if offset == eof {
+        // Changes are collected first and written after the scan, so the rows
+        // appended by the update are never scanned again and an invalid row
+        // fails the update before anything is written
+        let mut changes = Vec::new();
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while limit.is_none_or(|limit| changes.len() < limit) {
+            let Some(row) = self.next_row() else {
                 break;
-            }
+            };
+            let Row { offset, mut row } = row?;
 
-            if !self.check_conditions(&row, &conditions)? {
+            if !Self::check_predicates(&row, &predicates) {
                 continue;
             }
+            self.stats.rows_matched += 1;
+
+            let mut computed = Vec::new();
+            for (column, expression, data_type) in &expressions {
+                let value = expression.eval(&row)?.coerce(*data_type)?;
+                value.validate()?;
+                computed.push((column, value));
+            }
 
             let mut was_updated = false;
-            for (column, value) in &set {
+            let set_values = set.iter().map(|(column, value)| (column, value.clone()));
+            for (column, value) in set_values.chain(computed) {
                 if !row.contains_key(column) {
                     return Err(PoorlyError::ColumnNotFound(
                         column.clone(),
                         self.name.clone(),
                     ));
                 }
-                let old_value = row.insert(column.clone(), value.clone());
-                was_updated |= old_value != Some(value.clone());
+                let key = value.canonical_key();
+                let old_value = row.insert(column.clone(), value);
+                was_updated |= old_value.map(|old| old.canonical_key()) != Some(key);
             }
 
             if was_updated {
-                updated.push(row.clone());
-                self.insert(row)?;
-                self.delete_at(offset).map_err(PoorlyError::IoError)?;
+                // Only after `was_updated`, so touching the timestamp alone
+                // never counts as a change
+                for (column, default) in &self.defaults {
+                    let assigned = set.contains_key(column)
+                        || expressions.iter().any(|(target, ..)| target == column);
+                    if *default != ColumnDefault::AutoUpdate || assigned {
+                        continue;
+                    }
+                    if let Some(value) = default.value(self.clock.as_ref()) {
+                        row.insert(column.clone(), value);
+                    }
+                }
+                let values = self.prepare_insert(row.clone())?;
+                changes.push((offset, row, values));
             }
         }
+
+        let mut updated = Vec::new();
+        for (offset, row, mut values) in changes {
+            let new_offset = self.append(&mut values)?;
+            self.tombstone_at(offset, UPDATED)
+                .map_err(PoorlyError::IoError)?;
+            updated.push((row, new_offset));
+        }
         Ok(updated)
     }
 
     pub fn delete(&mut self, conditions: ColumnSet) -> Result<Vec<ColumnSet>, PoorlyError> {
-        let conditions = self.check_and_coerce(conditions, TableMethod::Delete)?;
+        let predicates = predicate::merge(conditions, HashMap::new())?;
+        self.delete_where(predicates, None)
+    }
+
+    /// Deletes the rows matching `predicates`, at most `limit` of them.
+    pub fn delete_where(
+        &mut self,
+        predicates: Predicates,
+        limit: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        self.check_writable()?;
+        let predicates = self.coerce_predicates(predicates, TableMethod::Delete)?;
+        self.stats = QueryStats::default();
         let mut deleted = Vec::new();
-        self.file
-            .seek(SeekFrom::Start(4))
-            .map_err(PoorlyError::IoError)?;
-        while let Some(row) = self.next_row() {
-            let Row { offset, row } = row.map_err(PoorlyError::IoError)?;
-            if !self.check_conditions(&row, &conditions)? {
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while limit.is_none_or(|limit| deleted.len() < limit) {
+            let Some(row) = self.next_row() else {
+                break;
+            };
+            let Row { offset, row } = row?;
+            if !Self::check_predicates(&row, &predicates) {
                 continue;
             }
+            self.stats.rows_matched += 1;
             deleted.push(row);
             self.delete_at(offset).map_err(PoorlyError::IoError)?;
         }
         Ok(deleted)
     }
 
+    /// Deletes the rows matching `predicates`, at most `limit` of them, by
+    /// copying the others into a new file like `vacuum` does, so the table is
+    /// left compact. Offsets of the remaining rows change and the deleted
+    /// rows can't be restored by `undelete`.
+    pub fn delete_compacting(
+        &mut self,
+        predicates: Predicates,
+        limit: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        self.check_writable()?;
+        let predicates = self.coerce_predicates(predicates, TableMethod::Delete)?;
+        self.stats = QueryStats::default();
+        self.rewrite_without(&predicates, limit)
+    }
+
+    fn rewrite_without(
+        &mut self,
+        predicates: &Predicates,
+        limit: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let mut storage = self.file.rewrite()?;
+        match self.copy_surviving(&mut storage, predicates, limit) {
+            Ok(deleted) => {
+                self.file.commit(storage)?;
+                self.changes += 1;
+                Ok(deleted)
+            }
+            Err(e) => {
+                storage.remove()?;
+                Err(e)
+            }
+        }
+    }
+
+    // Writes the rows not deleted to `storage` and returns the deleted ones
+    fn copy_surviving(
+        &mut self,
+        storage: &mut Box<dyn RowStore>,
+        predicates: &Predicates,
+        limit: Option<usize>,
+    ) -> Result<Vec<ColumnSet>, PoorlyError> {
+        let mut writer = BufWriter::new(storage);
+        writer.write_all(&self.header())?;
+
+        let mut deleted = Vec::new();
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.next_row() {
+            let Row { mut row, .. } = row?;
+            if limit.is_none_or(|limit| deleted.len() < limit)
+                && Self::check_predicates(&row, predicates)
+            {
+                self.stats.rows_matched += 1;
+                deleted.push(row);
+                continue;
+            }
+
+            let mut bytes = vec![0];
+            for (column, _) in &self.columns {
+                let value = row.remove(column).expect("rows are read with every column");
+                bytes.extend_from_slice(&value.into_bytes(self.format));
+            }
+            writer.write_all(&bytes)?;
+        }
+        writer.flush()?;
+        Ok(deleted)
+    }
+
+    /// Deletes every row whose `column` holds one of `keys`, in a single scan.
+    /// Returns how many rows were deleted.
+    pub fn delete_keys(&mut self, column: &str, keys: Vec<TypedValue>) -> Result<u64, PoorlyError> {
+        self.check_writable()?;
+        let data_type = match self.columns.iter().find(|(name, _)| name == column) {
+            Some((_, data_type)) => *data_type,
+            None => {
+                return Err(PoorlyError::ColumnNotFound(
+                    column.to_string(),
+                    self.name.clone(),
+                ))
+            }
+        };
+        // Keys are coerced to the column's type, so equal values share a representation
+        let keys = keys
+            .into_iter()
+            .map(|key| key.coerce(data_type).map(|key| key.canonical_key()))
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        self.stats = QueryStats::default();
+        self.rewind().map_err(PoorlyError::IoError)?;
+        while let Some(row) = self.next_row() {
+            let Row { offset, row } = row?;
+            if !keys.contains(&row[column].canonical_key()) {
+                continue;
+            }
+            self.stats.rows_matched += 1;
+            self.delete_at(offset).map_err(PoorlyError::IoError)?;
+        }
+        Ok(self.stats.rows_matched)
+    }
+
     pub fn drop(&mut self) -> Result<(), PoorlyError> {
+        self.check_writable()?;
+        self.changes += 1;
         self.file.set_len(0).map_err(PoorlyError::IoError)
     }
+
+    /// Removes deleted rows from the table file and returns how many there were.
+    /// Offsets of the remaining rows change, so deleted rows can't be restored
+    /// by `undelete` afterwards.
+    pub fn vacuum(&mut self) -> Result<u64, PoorlyError> {
+        let compacted = self.compact()?;
+        match self.swap(compacted)? {
+            Some(removed) => Ok(removed),
+            None => unreachable!("table changed during an exclusive vacuum"),
+        }
+    }
+
+    /// First half of a vacuum: copies the live rows into a new storage next to
+    /// the table. The table is read through a handle of its own, so this only
+    /// needs shared access and the table can still be read meanwhile.
+    pub fn compact(&self) -> Result<Compacted, PoorlyError> {
+        self.check_writable()?;
+        let mut storage = self.file.rewrite()?;
+        let mut reader = BufReader::new(self.file.reader()?);
+        let mut writer = BufWriter::new(&mut storage);
+        let header = self.header();
+        reader.read_exact(&mut vec![0; header.len()])?;
+        writer.write_all(&header)?;
+
+        let mut removed = 0;
+        let mut deleted = [0];
+        let mut offset = header.len() as u64;
+        loop {
+            match reader.read_exact(&mut deleted) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(PoorlyError::IoError(e)),
+            }
+            let mut row = vec![0];
+            for (_, data_type) in &self.columns {
+                match TypedValue::read(*data_type, &mut reader, self.format) {
+                    Ok(value) => row.extend_from_slice(&value.into_bytes(self.format)),
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => {
+                            return Err(PoorlyError::SchemaRowMismatch(offset))
+                        }
+                        _ => return Err(PoorlyError::IoError(e)),
+                    },
+                }
+            }
+            offset += row.len() as u64;
+            if deleted[0] != 0 {
+                removed += 1;
+            } else {
+                writer.write_all(&row)?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+
+        Ok(Compacted {
+            storage,
+            changes: self.changes,
+            removed,
+        })
+    }
+
+    /// Second half of a vacuum: replaces the table storage with the compacted one.
+    /// Returns `None`, and drops the copy, if the table was written to since
+    /// `compact`, in which case the vacuum has to be done again.
+    pub fn swap(&mut self, compacted: Compacted) -> Result<Option<u64>, PoorlyError> {
+        if compacted.changes != self.changes {
+            compacted.storage.remove()?;
+            return Ok(None);
+        }
+        self.file.commit(compacted.storage)?;
+        self.changes += 1;
+        Ok(Some(compacted.removed))
+    }
 }
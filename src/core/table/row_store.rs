@@ -0,0 +1,781 @@
+use std::any::Any;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+/// Where a table's bytes live: a file, or memory for databases that never
+/// touch the disk.
+pub trait RowStore: Any + Read + Write + Seek + Debug + Send + Sync {
+    /// Truncates or extends the data to `len` bytes
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Opens a reader over the data from the start. It has a position of its
+    /// own, so it doesn't need exclusive access to the store.
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Creates an empty store to write a replacement of the data into
+    fn rewrite(&self) -> io::Result<Box<dyn RowStore>>;
+
+    /// Replaces the data with `rewritten`, which came from `rewrite`
+    fn commit(&mut self, rewritten: Box<dyn RowStore>) -> io::Result<()>;
+
+    /// Throws away a store from `rewrite` that won't be committed
+    fn remove(self: Box<Self>) -> io::Result<()>;
+
+    /// Tells if every write will be refused
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Writes the data through to the disk, for stores that have one
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[derive(Debug)]
+pub struct FileStore {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        Ok(FileStore { file, path })
+    }
+
+    /// Opens an existing file without asking for write access, so it works
+    /// on read-only file systems too. Wrap it in a `ReadOnlyStore`.
+    pub fn open_read_only(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        Ok(FileStore { file, path })
+    }
+
+    // A rewrite is written next to the table file and renamed over it
+    fn rewrite_path(&self) -> PathBuf {
+        self.path.with_extension("vacuum")
+    }
+}
+
+impl Read for FileStore {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for FileStore {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FileStore {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl RowStore for FileStore {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(&self.path)?))
+    }
+
+    fn rewrite(&self) -> io::Result<Box<dyn RowStore>> {
+        let path = self.rewrite_path();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Box::new(FileStore { file, path }))
+    }
+
+    fn commit(&mut self, mut rewritten: Box<dyn RowStore>) -> io::Result<()> {
+        rewritten.flush()?;
+        drop(rewritten);
+        fs::rename(self.rewrite_path(), &self.path)?;
+        self.file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn remove(self: Box<Self>) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+/// Path of segment `index` of the table file at `path`: the first segment is
+/// the file itself, the next ones are `path.1`, `path.2` and so on.
+pub fn segment_path(path: &Path, index: usize) -> PathBuf {
+    match index {
+        0 => path.to_path_buf(),
+        _ => {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        }
+    }
+}
+
+/// The segments of the table file at `path` that exist on disk, in order
+pub fn segment_paths(path: &Path) -> Vec<PathBuf> {
+    (0..)
+        .map(|index| segment_path(path, index))
+        .take_while(|path| path.exists())
+        .collect()
+}
+
+/// A table file split into segments, read as if they were one file. A write
+/// at the end starts a new segment once the last one holds `max_size` bytes,
+/// and a segment only ever holds whole writes, so a row appended by a table
+/// never spans two of them. Rows keep their offsets, as they are counted from
+/// the start of the first segment.
+#[derive(Debug)]
+pub struct SegmentedStore {
+    path: PathBuf,
+    segments: Vec<File>,
+    // Length of every segment, in bytes
+    lens: Vec<u64>,
+    position: u64,
+    max_size: u64,
+}
+
+impl SegmentedStore {
+    pub fn open(path: PathBuf, max_size: u64) -> io::Result<Self> {
+        let mut segments = vec![FileStore::open(path.clone())?.file];
+        for path in segment_paths(&path).into_iter().skip(1) {
+            segments.push(OpenOptions::new().read(true).write(true).open(path)?);
+        }
+        Self::with_segments(path, segments, max_size)
+    }
+
+    /// Opens the existing segments without asking for write access, see
+    /// `FileStore::open_read_only`
+    pub fn open_read_only(path: PathBuf, max_size: u64) -> io::Result<Self> {
+        let mut segments = vec![File::open(&path)?];
+        for path in segment_paths(&path).into_iter().skip(1) {
+            segments.push(File::open(path)?);
+        }
+        Self::with_segments(path, segments, max_size)
+    }
+
+    fn with_segments(path: PathBuf, segments: Vec<File>, max_size: u64) -> io::Result<Self> {
+        let lens = segments
+            .iter()
+            .map(|segment| segment.metadata().map(|metadata| metadata.len()))
+            .collect::<io::Result<_>>()?;
+        Ok(SegmentedStore {
+            path,
+            segments,
+            lens,
+            position: 0,
+            max_size: max_size.max(1),
+        })
+    }
+
+    pub fn segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    fn len(&self) -> u64 {
+        self.lens.iter().sum()
+    }
+
+    // The segment `position` falls in and the position within it. Positions
+    // past the end fall in the last segment.
+    fn locate(&self, position: u64) -> (usize, u64) {
+        let mut start = 0;
+        for (index, len) in self.lens.iter().enumerate() {
+            if position < start + len || index + 1 == self.lens.len() {
+                return (index, position - start);
+            }
+            start += len;
+        }
+        unreachable!("a segmented store has at least one segment")
+    }
+
+    fn add_segment(&mut self) -> io::Result<()> {
+        let path = segment_path(&self.path, self.segments.len());
+        log::debug!("Starting segment {:?}", path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        self.segments.push(file);
+        self.lens.push(0);
+        Ok(())
+    }
+
+    // A rewrite is written into segments next to the table's and renamed
+    // over them
+    fn rewrite_path(&self) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(".vacuum");
+        PathBuf::from(name)
+    }
+
+    // Removes the segment files from `from` on
+    fn remove_segments(path: &Path, from: usize) -> io::Result<()> {
+        for path in segment_paths(path).into_iter().skip(from.max(1)) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for SegmentedStore {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (index, offset) = self.locate(self.position);
+        let remaining = self.lens[index].saturating_sub(offset);
+        let len = buf.len().min(remaining as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let segment = &mut self.segments[index];
+        segment.seek(SeekFrom::Start(offset))?;
+        let read = segment.read(&mut buf[..len])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for SegmentedStore {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let last = self.lens.len() - 1;
+        if self.position >= self.len() && self.lens[last] >= self.max_size {
+            self.add_segment()?;
+        }
+        let (index, offset) = self.locate(self.position);
+        // Only the last segment grows, a write over older data stops at the
+        // end of its segment
+        let len = if index + 1 == self.lens.len() {
+            buf.len()
+        } else {
+            buf.len().min((self.lens[index] - offset) as usize)
+        };
+        let segment = &mut self.segments[index];
+        segment.seek(SeekFrom::Start(offset))?;
+        segment.write_all(&buf[..len])?;
+        self.lens[index] = self.lens[index].max(offset + len as u64);
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for segment in &mut self.segments {
+            segment.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for SegmentedStore {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.len().checked_add_signed(delta),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+impl RowStore for SegmentedStore {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let (mut index, mut start) = (0, 0);
+        while index + 1 < self.lens.len() && start + self.lens[index] < len {
+            start += self.lens[index];
+            index += 1;
+        }
+        self.segments.truncate(index + 1);
+        self.lens.truncate(index + 1);
+        Self::remove_segments(&self.path, index + 1)?;
+        self.segments[index].set_len(len - start)?;
+        self.lens[index] = len - start;
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        let mut reader: Box<dyn Read + Send> = Box::new(io::empty());
+        for index in 0..self.segments.len() {
+            let segment = File::open(segment_path(&self.path, index))?;
+            reader = Box::new(reader.chain(segment));
+        }
+        Ok(reader)
+    }
+
+    fn rewrite(&self) -> io::Result<Box<dyn RowStore>> {
+        // Also drops the segments of a rewrite that was never committed
+        let mut rewritten = SegmentedStore::open(self.rewrite_path(), self.max_size)?;
+        rewritten.set_len(0)?;
+        Ok(Box::new(rewritten))
+    }
+
+    fn commit(&mut self, rewritten: Box<dyn RowStore>) -> io::Result<()> {
+        let rewritten: Box<dyn Any> = rewritten;
+        let mut rewritten = rewritten
+            .downcast::<SegmentedStore>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a segmented store"))?;
+        rewritten.flush()?;
+        let segments = rewritten.segments();
+        let path = rewritten.path.clone();
+        drop(rewritten);
+
+        for index in 0..segments {
+            fs::rename(segment_path(&path, index), segment_path(&self.path, index))?;
+        }
+        Self::remove_segments(&self.path, segments)?;
+        *self = SegmentedStore::open(self.path.clone(), self.max_size)?;
+        Ok(())
+    }
+
+    fn remove(self: Box<Self>) -> io::Result<()> {
+        for path in segment_paths(&self.path) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        for segment in &mut self.segments {
+            segment.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl RowStore for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(Cursor::new(self.get_ref().clone())))
+    }
+
+    fn rewrite(&self) -> io::Result<Box<dyn RowStore>> {
+        Ok(Box::new(Cursor::new(Vec::new())))
+    }
+
+    fn commit(&mut self, mut rewritten: Box<dyn RowStore>) -> io::Result<()> {
+        let mut data = Vec::new();
+        rewritten.seek(SeekFrom::Start(0))?;
+        rewritten.read_to_end(&mut data)?;
+        *self = Cursor::new(data);
+        Ok(())
+    }
+
+    fn remove(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Refuses every write to the inner store, for tables opened read-only
+#[derive(Debug)]
+pub struct ReadOnlyStore(pub Box<dyn RowStore>);
+
+fn read_only_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "read-only")
+}
+
+impl Read for ReadOnlyStore {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for ReadOnlyStore {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(read_only_error())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ReadOnlyStore {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl RowStore for ReadOnlyStore {
+    fn set_len(&mut self, _len: u64) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        self.0.reader()
+    }
+
+    fn rewrite(&self) -> io::Result<Box<dyn RowStore>> {
+        Err(read_only_error())
+    }
+
+    fn commit(&mut self, _rewritten: Box<dyn RowStore>) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn remove(self: Box<Self>) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+/// When a `BufferedStore` writes its held back appends through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCommit {
+    // Bytes of appended rows written in one go
+    pub max_bytes: usize,
+    // Longest an appended row waits to be written
+    pub max_delay: Duration,
+}
+
+/// Appends held back together, which their writers wait on until the group
+/// is written through and synced
+#[derive(Debug, Default)]
+pub struct Group {
+    // How the sync went, once it is done
+    synced: Mutex<Option<Result<(), (io::ErrorKind, String)>>>,
+    done: Condvar,
+}
+
+impl Group {
+    fn finish(&self, result: &io::Result<()>) {
+        let result = match result {
+            Ok(()) => Ok(()),
+            Err(e) => Err((e.kind(), e.to_string())),
+        };
+        *self.synced.lock().unwrap() = Some(result);
+        self.done.notify_all();
+    }
+
+    fn is_finished(&self) -> bool {
+        self.synced.lock().unwrap().is_some()
+    }
+
+    /// Blocks until the group is synced, and fails if writing it did
+    pub fn wait(&self) -> io::Result<()> {
+        let synced = self.synced.lock().unwrap();
+        let synced = self
+            .done
+            .wait_while(synced, |synced| synced.is_none())
+            .unwrap();
+        match synced.as_ref().unwrap() {
+            Ok(()) => Ok(()),
+            Err((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+        }
+    }
+}
+
+/// Writes out the groups of every `BufferedStore` made with it once they are
+/// due, from a single thread that stops once the flusher and its stores are
+/// dropped. Also tells which groups were written to since it was last asked,
+/// see `Flusher::take_groups`.
+#[derive(Debug, Clone)]
+pub struct Flusher {
+    shared: Arc<Flushed>,
+}
+
+#[derive(Debug)]
+struct Flushed {
+    commit: GroupCommit,
+    stores: Mutex<Vec<Weak<Mutex<Buffered>>>>,
+    // Groups written to that aren't synced yet or weren't taken
+    groups: Mutex<Vec<Arc<Group>>>,
+}
+
+impl Flusher {
+    pub fn new(commit: GroupCommit) -> Self {
+        let shared = Arc::new(Flushed {
+            commit,
+            stores: Mutex::new(Vec::new()),
+            groups: Mutex::new(Vec::new()),
+        });
+
+        let weak = Arc::downgrade(&shared);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(commit.max_delay.max(Duration::from_millis(1)));
+            let Some(shared) = weak.upgrade() else {
+                break;
+            };
+            shared.stores.lock().unwrap().retain(|store| {
+                let Some(store) = store.upgrade() else {
+                    return false;
+                };
+                let mut state = store.lock().unwrap();
+                let due = state
+                    .since
+                    .is_some_and(|since| since.elapsed() >= commit.max_delay);
+                if due {
+                    // The writers waiting on the group get the error
+                    let _ = state.write_through(true);
+                }
+                true
+            });
+        });
+        Flusher { shared }
+    }
+
+    pub fn commit(&self) -> GroupCommit {
+        self.shared.commit
+    }
+
+    /// Groups written to since the last call, for the writers to wait on.
+    /// Queries made one at a time can tell the groups of each this way.
+    pub fn take_groups(&self) -> Vec<Arc<Group>> {
+        std::mem::take(&mut *self.shared.groups.lock().unwrap())
+    }
+
+    fn written_to(&self, group: &Arc<Group>) {
+        let mut groups = self.shared.groups.lock().unwrap();
+        if !groups.iter().any(|taken| Arc::ptr_eq(taken, group)) {
+            // Nobody asked for the ones already synced
+            groups.retain(|group| !group.is_finished());
+            groups.push(Arc::clone(group));
+        }
+    }
+}
+
+/// Holds back appends, and rewrites of the header with the serial counter
+/// at the start of the store, to write them through in groups followed by a single sync:
+/// once `max_bytes` are pending or the oldest of them is `max_delay` old.
+/// The `Flusher` it was made with writes out groups nothing else comes along
+/// for. Writers wait on the `Group` of their appends to know they are on disk.
+/// Anything but another append or counter rewrite, reads included, writes
+/// the pending group through first, syncing it only when it is due.
+#[derive(Debug)]
+pub struct BufferedStore {
+    state: Arc<Mutex<Buffered>>,
+}
+
+#[derive(Debug)]
+struct Buffered {
+    inner: Box<dyn RowStore>,
+    flusher: Flusher,
+    // Length of `inner`, where `pending` goes
+    len: u64,
+    position: u64,
+    pending: Vec<u8>,
+    header: Option<Vec<u8>>,
+    // When the oldest write not synced yet was held back
+    since: Option<Instant>,
+    // Writes not synced yet, written through or not
+    group: Arc<Group>,
+}
+
+impl Buffered {
+    // Writes the held back group through, and syncs it to disk if `sync`.
+    // The group is done once synced, or if writing it fails.
+    fn write_through(&mut self, sync: bool) -> io::Result<()> {
+        if self.since.is_none() {
+            return Ok(());
+        }
+        let result = self.write_pending().and_then(|()| match sync {
+            true => self.inner.sync(),
+            false => self.inner.flush(),
+        });
+        if sync || result.is_err() {
+            self.since = None;
+            std::mem::take(&mut self.group).finish(&result);
+        }
+        result
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        if let Some(header) = self.header.take() {
+            self.inner.seek(SeekFrom::Start(0))?;
+            self.inner.write_all(&header)?;
+        }
+        if !self.pending.is_empty() {
+            self.inner.seek(SeekFrom::Start(self.len))?;
+            self.inner.write_all(&self.pending)?;
+            self.len += self.pending.len() as u64;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    fn hold_back(&mut self) -> io::Result<()> {
+        let since = *self.since.get_or_insert_with(Instant::now);
+        self.flusher.written_to(&self.group);
+        let commit = self.flusher.commit();
+        if self.pending.len() >= commit.max_bytes || since.elapsed() >= commit.max_delay {
+            self.write_through(true)?;
+        }
+        Ok(())
+    }
+}
+
+impl BufferedStore {
+    pub fn new(mut inner: Box<dyn RowStore>, flusher: &Flusher) -> io::Result<Self> {
+        let position = inner.stream_position()?;
+        let len = inner.seek(SeekFrom::End(0))?;
+        let state = Arc::new(Mutex::new(Buffered {
+            inner,
+            flusher: flusher.clone(),
+            len,
+            position,
+            pending: Vec::new(),
+            header: None,
+            since: None,
+            group: Arc::default(),
+        }));
+        flusher
+            .shared
+            .stores
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&state));
+        Ok(BufferedStore { state })
+    }
+
+    fn state(&self) -> MutexGuard<'_, Buffered> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Read for BufferedStore {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        state.write_through(false)?;
+        let position = state.position;
+        state.inner.seek(SeekFrom::Start(position))?;
+        let read = state.inner.read(buf)?;
+        state.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for BufferedStore {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        let end = state.len + state.pending.len() as u64;
+        // Only a header is ever rewritten in place at the start
+        if state.position == 0 && state.len >= buf.len() as u64 {
+            state.header = Some(buf.to_vec());
+        } else if state.position == end {
+            state.pending.extend_from_slice(buf);
+        } else {
+            state.write_through(false)?;
+            let position = state.position;
+            state.inner.seek(SeekFrom::Start(position))?;
+            let written = state.inner.write(buf)?;
+            state.position += written as u64;
+            state.len = state.len.max(state.position);
+            return Ok(written);
+        }
+        state.position += buf.len() as u64;
+        state.hold_back()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state().write_through(false)
+    }
+}
+
+impl Seek for BufferedStore {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut state = self.state();
+        let end = state.len + state.pending.len() as u64;
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => end.checked_add_signed(offset),
+            SeekFrom::Current(offset) => state.position.checked_add_signed(offset),
+        };
+        state.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(state.position)
+    }
+}
+
+impl RowStore for BufferedStore {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        state.write_through(false)?;
+        state.inner.set_len(len)?;
+        state.len = len;
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        let mut state = self.state();
+        state.write_through(false)?;
+        state.inner.reader()
+    }
+
+    fn rewrite(&self) -> io::Result<Box<dyn RowStore>> {
+        self.state().inner.rewrite()
+    }
+
+    fn commit(&mut self, rewritten: Box<dyn RowStore>) -> io::Result<()> {
+        let mut state = self.state();
+        state.write_through(false)?;
+        state.inner.commit(rewritten)?;
+        state.len = state.inner.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn remove(self: Box<Self>) -> io::Result<()> {
+        let mut state = self.state();
+        state.since = None;
+        std::mem::take(&mut state.group).finish(&Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the store was removed before its rows were synced",
+        )));
+        let inner = std::mem::replace(&mut state.inner, Box::new(Cursor::new(Vec::new())));
+        inner.remove()
+    }
+
+    fn read_only(&self) -> bool {
+        self.state().inner.read_only()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.state();
+        match state.since {
+            Some(_) => state.write_through(true),
+            None => state.inner.sync(),
+        }
+    }
+}
+
+impl Drop for BufferedStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.state().write_through(true) {
+            log::error!("Failed to write a group of rows through: {}", e);
+        }
+    }
+}
@@ -1,4 +1,8 @@
 use super::*;
+use crate::core::clock::MockClock;
+use crate::core::schema::ColumnDefault;
+use crate::core::table::row_store::{Flusher, GroupCommit, ReadOnlyStore};
+use std::time::Duration;
 
 fn table() -> Table {
     Table {
@@ -7,8 +11,14 @@ fn table() -> Table {
             ("id".into(), DataType::Int),
             ("price".into(), DataType::Float),
         ],
-        file: tempfile::tempfile().unwrap(),
+        defaults: HashMap::new(),
+        format: RowFormat::CURRENT,
+        file: Box::new(Cursor::new(Vec::new())),
+        changes: 0,
         serial: 0,
+        stats: QueryStats::default(),
+        clock: Arc::new(SystemClock),
+        counter: None,
     }
 }
 
@@ -23,7 +33,7 @@ fn select() -> Result<(), PoorlyError> {
 
     table.insert(row.clone())?;
 
-    let rows = table.select(vec![], [].into())?;
+    let rows = table.select(vec![], [].into(), None)?;
     assert_eq!(rows.len(), 1);
     assert_eq!(rows[0], row);
 
@@ -41,7 +51,7 @@ fn project() -> Result<(), PoorlyError> {
 
     table.insert(row.clone())?;
 
-    let rows = table.select(vec!["price".into()], [].into())?;
+    let rows = table.select(vec!["price".into()], [].into(), None)?;
     assert_eq!(rows.len(), 1);
 
     row.remove("id");
@@ -50,6 +60,133 @@ fn project() -> Result<(), PoorlyError> {
     Ok(())
 }
 
+#[test]
+fn count_only() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for id in [1, 2, 1, 3, 1] {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(1.23)),
+            ]
+            .into(),
+        )?;
+    }
+
+    let count = |rows: Vec<ColumnSet>| rows[0]["count"].clone();
+    let columns = vec![COUNT_ONLY.to_string()];
+    // The limit is on rows returned, and only the count is
+    let rows = table.select(columns.clone(), [].into(), Some(1))?;
+    assert_eq!(count(rows), TypedValue::Int(5));
+    let ones = [("id".into(), Predicate::Eq(TypedValue::Int(1)))].into();
+    let rows = table.select_where(columns.clone(), ones, &[], &[], None)?;
+    assert_eq!(count(rows), TypedValue::Int(3));
+    let rows = table.select_where(columns, [].into(), &[], &["id".into()], None)?;
+    assert_eq!(count(rows), TypedValue::Int(3));
+
+    Ok(())
+}
+
+#[test]
+fn columns_bytes_layout() -> Result<(), PoorlyError> {
+    let mut table = table();
+    table.columns = vec![
+        ("id".into(), DataType::Serial),
+        ("initial".into(), DataType::Char),
+        ("name".into(), DataType::String),
+        ("age".into(), DataType::Int16),
+    ];
+    table.format = RowFormat::V3;
+    let layout = table.columns_bytes_layout();
+    let layout: Vec<_> = layout
+        .iter()
+        .map(|bytes| (bytes.column.as_str(), bytes.offset, bytes.layout))
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            ("id", Some(1), ColumnLayout::Fixed(4)),
+            ("initial", Some(5), ColumnLayout::Fixed(1)),
+            (
+                "name",
+                Some(6),
+                ColumnLayout::Variable {
+                    prefix: 4,
+                    compression_flag: true
+                }
+            ),
+            ("age", None, ColumnLayout::Fixed(2)),
+        ]
+    );
+
+    // The fixed part of a row is where the layout says it is
+    table.insert(
+        [
+            ("initial".into(), TypedValue::Char('j')),
+            ("name".into(), TypedValue::from("joe")),
+            ("age".into(), TypedValue::Int16(42)),
+        ]
+        .into(),
+    )?;
+    let TypedValue::Serial(id) = table.select(vec![], [].into(), None)?[0]["id"] else {
+        panic!("expected a serial id");
+    };
+    let mut row = vec![];
+    table.file.seek(SeekFrom::Start(4))?;
+    table.file.read_to_end(&mut row)?;
+    assert_eq!(row[1..5], id.to_le_bytes());
+    assert_eq!(row[5], b'j');
+    assert_eq!(row[6..10], 3u32.to_le_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn first_and_last() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for (id, price) in [(3, 2.0), (1, 7.0), (4, 1.0), (2, 7.0), (5, 4.0)] {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(price)),
+            ]
+            .into(),
+        )?;
+    }
+    let by = |column: &str, descending| OrderBy {
+        column: column.into(),
+        descending,
+    };
+    let id = |row: Option<ColumnSet>| row.map(|row| row["id"].clone());
+
+    assert_eq!(
+        id(table.first([].into(), &by("id", false))?),
+        Some(1.into())
+    );
+    assert_eq!(id(table.last([].into(), &by("id", false))?), Some(5.into()));
+    assert_eq!(id(table.first([].into(), &by("id", true))?), Some(5.into()));
+    // Ties keep their order in the file, as when sorting
+    assert_eq!(
+        id(table.first([].into(), &by("price", true))?),
+        Some(1.into())
+    );
+    assert_eq!(
+        id(table.last([].into(), &by("price", false))?),
+        Some(2.into())
+    );
+
+    let cheap = [("price".into(), Predicate::Lt(TypedValue::Float(3.0)))].into();
+    assert_eq!(id(table.last(cheap, &by("id", false))?), Some(4.into()));
+    let none = [("id".into(), Predicate::Gt(TypedValue::Int(5)))].into();
+    assert_eq!(table.first(none, &by("id", false))?, None);
+    assert!(matches!(
+        table.first([].into(), &by("name", false)),
+        Err(PoorlyError::ColumnNotFound(_, _))
+    ));
+
+    Ok(())
+}
+
 #[test]
 fn filter() -> Result<(), PoorlyError> {
     let mut table = table();
@@ -69,7 +206,7 @@ fn filter() -> Result<(), PoorlyError> {
 
     table.insert(row.clone())?;
 
-    let rows = table.select(vec![], [("id".into(), TypedValue::Int(2))].into())?;
+    let rows = table.select(vec![], [("id".into(), TypedValue::Int(2))].into(), None)?;
     assert_eq!(rows.len(), 1);
     assert_eq!(rows[0], row);
 
@@ -91,7 +228,7 @@ fn update() -> Result<(), PoorlyError> {
         [].into(),
     )?;
 
-    let rows = table.select(vec![], [].into())?;
+    let rows = table.select(vec![], [].into(), None)?;
     assert_eq!(rows.len(), 1);
     assert_eq!(rows[0]["price"], TypedValue::Float(123.45));
 
@@ -110,8 +247,1328 @@ fn delete() -> Result<(), PoorlyError> {
     table.insert(row)?;
     table.delete([].into())?;
 
-    let rows = table.select(vec![], [].into())?;
+    let rows = table.select(vec![], [].into(), None)?;
     assert!(rows.is_empty());
 
     Ok(())
 }
+
+#[test]
+fn delete_compacting() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for id in 0..2000 {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(1.23)),
+            ]
+            .into(),
+        )?;
+    }
+    let size = |table: &mut Table| table.file.seek(SeekFrom::End(0)).unwrap();
+    let below = |id| [("id".to_string(), Predicate::Lt(TypedValue::Int(id)))].into();
+    let full = size(&mut table);
+
+    // Even most of a large table is only tombstoned, so it can be restored
+    let deleted = table.delete_where(
+        [("id".into(), Predicate::Ge(TypedValue::Int(100)))].into(),
+        None,
+    )?;
+    assert_eq!(deleted.len(), 1900);
+    assert_eq!(size(&mut table), full);
+    assert_eq!(table.count([].into())?, 100);
+
+    // Asked to compact, the file is rewritten without the deleted rows
+    table.delete_compacting(below(10), None)?;
+    assert!(size(&mut table) < full / 10);
+    assert_eq!(table.count([].into())?, 90);
+    assert_eq!(table.vacuum()?, 0);
+    table.delete_where(below(20), None)?;
+    assert_eq!(table.count([].into())?, 80);
+    assert_eq!(table.vacuum()?, 10);
+
+    Ok(())
+}
+
+#[test]
+fn result_too_large() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for id in 0..5 {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(1.23)),
+            ]
+            .into(),
+        )?;
+    }
+
+    let result = table.select(vec![], [].into(), Some(3));
+    assert!(matches!(result, Err(PoorlyError::ResultTooLarge(3))));
+
+    let rows = table.select(vec![], [].into(), Some(5))?;
+    assert_eq!(rows.len(), 5);
+
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for id in 0..3 {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(1.23)),
+            ]
+            .into(),
+        )?;
+    }
+    table.delete([("id".into(), TypedValue::Int(0))].into())?;
+
+    table.select(vec![], [("id".into(), TypedValue::Int(2))].into(), None)?;
+    assert_eq!(table.stats.rows_scanned, 3);
+    assert_eq!(table.stats.rows_matched, 1);
+
+    Ok(())
+}
+
+#[test]
+fn default_now() -> Result<(), PoorlyError> {
+    let mut table = table();
+    table.columns.push(("created".into(), DataType::Timestamp));
+    table.defaults.insert("created".into(), ColumnDefault::Now);
+
+    let before = TypedValue::now();
+    table.insert(
+        [
+            ("id".into(), TypedValue::Int(1)),
+            ("price".into(), TypedValue::Float(1.23)),
+        ]
+        .into(),
+    )?;
+    let after = TypedValue::now();
+
+    let rows = table.select(vec![], [].into(), None)?;
+    let created = &rows[0]["created"];
+    assert!(&before <= created && created <= &after);
+
+    Ok(())
+}
+
+#[test]
+fn select_one() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for (id, price) in [(1, 1.23), (2, 4.56), (2, 7.89)] {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(price)),
+            ]
+            .into(),
+        )?;
+    }
+
+    let row = table.select_one([("id".into(), TypedValue::Int(1))].into())?;
+    assert_eq!(row["price"], TypedValue::Float(1.23));
+
+    assert!(matches!(
+        table.select_one([("id".into(), TypedValue::Int(3))].into()),
+        Err(PoorlyError::RowNotFound(_))
+    ));
+    assert!(matches!(
+        table.select_one([("id".into(), TypedValue::Int(2))].into()),
+        Err(PoorlyError::TooManyRows(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn count() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for id in [1, 2, 2] {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(1.23)),
+            ]
+            .into(),
+        )?;
+    }
+
+    assert_eq!(table.count([].into())?, 3);
+    assert_eq!(table.count([("id".into(), TypedValue::Int(2))].into())?, 2);
+
+    Ok(())
+}
+
+#[test]
+fn sample() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for id in 0..50 {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(1.23)),
+            ]
+            .into(),
+        )?;
+    }
+    // Only even ids are left
+    table.delete_where(
+        [(
+            "id".into(),
+            Predicate::In((1..50).step_by(2).map(TypedValue::Int).collect()),
+        )]
+        .into(),
+        None,
+    )?;
+
+    let rows = table.sample(10)?;
+    assert_eq!(rows.len(), 10);
+    let mut ids = HashSet::new();
+    for row in &rows {
+        let TypedValue::Int(id) = row["id"] else {
+            panic!("id is not an int: {:?}", row["id"]);
+        };
+        assert_eq!(id % 2, 0);
+        ids.insert(id);
+    }
+    assert_eq!(ids.len(), 10);
+
+    // A sample larger than the table is all of it
+    assert_eq!(table.sample(100)?.len(), 25);
+    assert!(table.sample(0)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn insert_raw_many() -> Result<(), PoorlyError> {
+    let with_serial = || {
+        let mut table = table();
+        table.columns.push(("serial".into(), DataType::Serial));
+        table.columns.push(("email".into(), DataType::Email));
+        table
+    };
+    let rows: Vec<ColumnSet> = (0..5)
+        .map(|id| {
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(id as f64 / 4.0)),
+                ("email".into(), format!("user{}@example.com", id).into()),
+            ]
+            .into()
+        })
+        .collect();
+    let mut checked = with_serial();
+    checked.insert_many(rows)?;
+
+    // Restore what was stored, serials and all
+    let dump = checked.select(vec![], [].into(), None)?;
+    let mut restored = with_serial();
+    restored.insert_raw_many(dump)?;
+
+    let bytes = |table: &Table| {
+        let mut bytes = Vec::new();
+        table
+            .file
+            .reader()
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+        bytes
+    };
+    assert_eq!(restored.serial, checked.serial);
+    assert_eq!(bytes(&restored), bytes(&checked));
+
+    Ok(())
+}
+
+#[test]
+fn serial_conditions() -> Result<(), PoorlyError> {
+    let mut table = table();
+    table.columns.push(("serial".into(), DataType::Serial));
+    table.serial = 41;
+    for id in [1, 2] {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(1.23)),
+            ]
+            .into(),
+        )?;
+    }
+
+    let rows = table.select(vec![], [("serial".into(), "42".into())].into(), None)?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["id"], TypedValue::Int(2));
+
+    let result = table.select(vec![], [("serial".into(), "-1".into())].into(), None);
+    assert!(matches!(result, Err(PoorlyError::InvalidValue(_, _))));
+
+    let deleted = table.delete([("serial".into(), "41".into())].into())?;
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(table.count([].into())?, 1);
+
+    Ok(())
+}
+
+#[test]
+fn undelete() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for id in 1..=3 {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(1.0)),
+            ]
+            .into(),
+        )?;
+    }
+    table.delete([("id".into(), TypedValue::Int(2))].into())?;
+
+    let deleted = table.select_deleted([].into())?;
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0]["id"], TypedValue::Int(2));
+    let TypedValue::Int(offset) = deleted[0][OFFSET_COLUMN] else {
+        panic!("expected an offset, got {:?}", deleted[0][OFFSET_COLUMN]);
+    };
+
+    // Only the start of a tombstoned row can be undeleted
+    assert!(table.undelete(offset as u64 + 1).is_err());
+    assert!(table.undelete(4).is_err());
+
+    let row = table.undelete(offset as u64)?;
+    assert_eq!(row["id"], TypedValue::Int(2));
+    assert_eq!(table.count([].into())?, 3);
+    assert!(table.select_deleted([].into())?.is_empty());
+    assert!(table.undelete(offset as u64).is_err());
+
+    // The old version of an updated row is not offered for restoring
+    table.update(
+        [("price".into(), TypedValue::Float(2.0))].into(),
+        [("id".into(), TypedValue::Int(1))].into(),
+    )?;
+    assert!(table.select_deleted([].into())?.is_empty());
+    assert!(table.undelete(4).is_err());
+    assert_eq!(table.count([].into())?, 3);
+
+    Ok(())
+}
+
+fn priced(table: &mut Table, prices: &[f64]) -> Result<(), PoorlyError> {
+    for (id, price) in prices.iter().enumerate() {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id as i64)),
+                ("price".into(), TypedValue::Float(*price)),
+            ]
+            .into(),
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn delete_range() -> Result<(), PoorlyError> {
+    let mut table = table();
+    priced(&mut table, &[1.0, 5.0, 10.0, 50.0])?;
+
+    let deleted = table.delete_where(
+        [
+            ("price".into(), Predicate::Ge(TypedValue::Int(5))),
+            ("id".into(), Predicate::Lt(TypedValue::Int(3))),
+        ]
+        .into(),
+        None,
+    )?;
+    assert_eq!(deleted.len(), 2);
+
+    let ids: Vec<_> = table
+        .select(vec!["id".into()], [].into(), None)?
+        .into_iter()
+        .map(|row| row["id"].clone())
+        .collect();
+    assert_eq!(ids, vec![TypedValue::Int(0), TypedValue::Int(3)]);
+
+    Ok(())
+}
+
+#[test]
+fn update_between() -> Result<(), PoorlyError> {
+    let mut table = table();
+    priced(&mut table, &[1.0, 5.0, 10.0, 50.0])?;
+
+    let updated = table.update_where(
+        [("price".into(), TypedValue::Float(0.0))].into(),
+        HashMap::new(),
+        [(
+            "price".into(),
+            Predicate::Between(TypedValue::Int(5), TypedValue::Int(10)),
+        )]
+        .into(),
+        None,
+    )?;
+    assert_eq!(updated.len(), 2);
+
+    let zeroed = table.select_where(
+        vec!["id".into()],
+        [("price".into(), Predicate::Eq(TypedValue::Float(0.0)))].into(),
+        &[],
+        &[],
+        None,
+    )?;
+    let mut ids: Vec<_> = zeroed.into_iter().map(|row| row["id"].clone()).collect();
+    ids.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(ids, vec![TypedValue::Int(1), TypedValue::Int(2)]);
+    assert_eq!(table.count([].into())?, 4);
+
+    // Pattern matching only makes sense on text columns
+    assert!(table
+        .delete_where(
+            [("price".into(), Predicate::Like("1%".into()))].into(),
+            None
+        )
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn distinct_on() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for (id, price) in [(1, 3.0), (2, 1.0), (1, 7.0), (2, 4.0), (1, 5.0), (3, 2.0)] {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                ("price".into(), TypedValue::Float(price)),
+            ]
+            .into(),
+        )?;
+    }
+
+    // The highest price for every id
+    let order_by = [
+        OrderBy {
+            column: "id".into(),
+            descending: false,
+        },
+        OrderBy {
+            column: "price".into(),
+            descending: true,
+        },
+    ];
+    let rows = table.select_where(vec![], [].into(), &order_by, &["id".into()], None)?;
+    let rows: Vec<_> = rows
+        .into_iter()
+        .map(|row| (row["id"].clone(), row["price"].clone()))
+        .collect();
+    assert_eq!(
+        rows,
+        vec![
+            (TypedValue::Int(1), TypedValue::Float(7.0)),
+            (TypedValue::Int(2), TypedValue::Float(4.0)),
+            (TypedValue::Int(3), TypedValue::Float(2.0)),
+        ]
+    );
+
+    // The limit applies to the deduplicated rows
+    assert!(table
+        .select_where(vec![], [].into(), &order_by, &["id".into()], Some(3))
+        .is_ok());
+    assert!(table
+        .select_where(vec![], [].into(), &order_by, &[], Some(3))
+        .is_err());
+    assert!(table
+        .select_where(vec![], [].into(), &[], &["missing".into()], None)
+        .is_err());
+
+    // Without an order, the first row of every id in the file is kept whole
+    let rows = table.select_where(vec![], [].into(), &[], &["id".into()], Some(3))?;
+    let rows: Vec<_> = rows
+        .into_iter()
+        .map(|row| (row["id"].clone(), row["price"].clone()))
+        .collect();
+    assert_eq!(
+        rows,
+        vec![
+            (TypedValue::Int(1), TypedValue::Float(3.0)),
+            (TypedValue::Int(2), TypedValue::Float(1.0)),
+            (TypedValue::Int(3), TypedValue::Float(2.0)),
+        ]
+    );
+    let count = table.select_where(
+        vec![COUNT_ONLY.into()],
+        [].into(),
+        &[],
+        &["id".into()],
+        None,
+    )?;
+    assert_eq!(count[0]["count"], TypedValue::Int(3));
+
+    Ok(())
+}
+
+#[test]
+fn schema_row_mismatch() -> Result<(), PoorlyError> {
+    let mut table = table();
+    table.columns.pop();
+    table.insert([("id".into(), TypedValue::Int(1))].into())?;
+
+    // A column added without rewriting the rows already in the file
+    table.columns.push(("name".into(), DataType::String));
+    let result = table.select(vec![], [].into(), None);
+    assert!(matches!(result, Err(PoorlyError::SchemaRowMismatch(4))));
+
+    // A short row followed by others is read into the next one, which its
+    // values can hide. Its end then falls inside the next row, where no flag
+    // byte is.
+    let mut table = self::table();
+    table.columns.pop();
+    table.insert([("id".into(), TypedValue::Int(1))].into())?;
+    table.columns.push(("price".into(), DataType::Float));
+    table.insert(
+        [
+            ("id".into(), TypedValue::Int(-1)),
+            ("price".into(), TypedValue::Float(1.5)),
+        ]
+        .into(),
+    )?;
+    let result = table.select(vec![], [].into(), None);
+    assert!(matches!(result, Err(PoorlyError::SchemaRowMismatch(4))));
+
+    // A string length read from the wrong bytes is larger than what is left
+    let mut table = self::table();
+    table.format = RowFormat::V1;
+    table.columns.pop();
+    for id in [1, -1] {
+        table.insert([("id".into(), TypedValue::Int(id))].into())?;
+    }
+    table.columns.push(("name".into(), DataType::String));
+    let result = table.select(vec![], [].into(), None);
+    assert!(matches!(result, Err(PoorlyError::SchemaRowMismatch(_))));
+
+    Ok(())
+}
+
+#[test]
+fn vacuum() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let open = || {
+        let columns = table().columns;
+        Table::open(
+            "test".into(),
+            columns,
+            HashMap::new(),
+            RowFormat::CURRENT,
+            dir.path(),
+        )
+    };
+    let mut table = open();
+    priced(&mut table, &[1.0, 5.0, 10.0])?;
+    table.delete([("id".into(), TypedValue::Int(1))].into())?;
+    let size = table.file.seek(SeekFrom::End(0))?;
+
+    // A copy made before a write is stale and gets dropped
+    let compacted = table.compact()?;
+    table.delete([("id".into(), TypedValue::Int(2))].into())?;
+    assert_eq!(table.swap(compacted)?, None);
+
+    let rows = table.select(vec![], [].into(), None)?;
+    assert_eq!(table.vacuum()?, 2);
+    assert!(table.file.seek(SeekFrom::End(0))? < size);
+    assert_eq!(table.select(vec![], [].into(), None)?, rows);
+    assert!(table.select_deleted([].into())?.is_empty());
+
+    // The serial and the compacted rows survive reopening the table
+    let serial = table.serial;
+    let mut table = open();
+    assert_eq!(table.serial, serial);
+    assert_eq!(table.select(vec![], [].into(), None)?, rows);
+
+    Ok(())
+}
+
+#[test]
+fn auto_increment() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let open = || {
+        Table::open(
+            "test".into(),
+            table().columns,
+            [("id".into(), ColumnDefault::AutoIncrement)].into(),
+            RowFormat::CURRENT,
+            dir.path(),
+        )
+    };
+    let price = |price: f64| crate::row! { "price" => price };
+    let ids = |table: &mut Table| -> Result<Vec<TypedValue>, PoorlyError> {
+        let rows = table.select(vec!["id".into()], [].into(), None)?;
+        Ok(rows.into_iter().map(|row| row["id"].clone()).collect())
+    };
+
+    let mut table = open();
+    assert_eq!(table.insert(price(1.0))?["id"], TypedValue::Int(1));
+    assert_eq!(table.insert(price(2.0))?["id"], TypedValue::Int(2));
+    // A given value moves the counter up to it, but never down
+    table.insert(crate::row! { "id" => 10i64, "price" => 3.0 })?;
+    assert_eq!(table.insert(price(4.0))?["id"], TypedValue::Int(11));
+    table.insert(crate::row! { "id" => 5i64, "price" => 5.0 })?;
+    let rows = table.insert_many(vec![price(6.0), price(7.0)])?;
+    assert_eq!(rows[1]["id"], TypedValue::Int(13));
+    assert_eq!(table.counter, Some(13));
+
+    // The counter is in the file, and rows start after it
+    let mut table = open();
+    assert_eq!(table.counter, Some(13));
+    assert_eq!(table.insert(price(8.0))?["id"], TypedValue::Int(14));
+    let all: Vec<_> = [1, 2, 10, 11, 5, 12, 13, 14]
+        .into_iter()
+        .map(TypedValue::Int)
+        .collect();
+    assert_eq!(ids(&mut table)?, all);
+
+    // Vacuuming keeps it too, even past the removed highest value
+    table.delete([("id".into(), TypedValue::Int(14))].into())?;
+    table.vacuum()?;
+    let mut table = open();
+    assert_eq!(table.insert(price(9.0))?["id"], TypedValue::Int(15));
+    assert_eq!(ids(&mut table)?.len(), 8);
+
+    Ok(())
+}
+
+#[test]
+fn returned_offsets() -> Result<(), PoorlyError> {
+    let mut table = table();
+    priced(&mut table, &[1.0])?;
+    let inserted = table.insert_with_offset(
+        [
+            ("id".into(), TypedValue::Int(1)),
+            ("price".into(), TypedValue::Float(2.0)),
+        ]
+        .into(),
+    )?;
+    let updated = table.update_with_offsets(
+        [("price".into(), TypedValue::Float(3.0))].into(),
+        HashMap::new(),
+        [("id".into(), Predicate::Eq(TypedValue::Int(0)))].into(),
+        None,
+    )?;
+
+    let scanned = |table: &mut Table| -> Result<Vec<ColumnSet>, PoorlyError> {
+        table.file.seek(SeekFrom::Start(4))?;
+        let mut rows = Vec::new();
+        while let Some(row) = table.next_row() {
+            let Row { offset, mut row } = row?;
+            row.insert(OFFSET_COLUMN.into(), TypedValue::Int(offset as i64));
+            rows.push(row);
+        }
+        Ok(rows)
+    };
+    let rows = scanned(&mut table)?;
+    assert_eq!(updated.len(), 1);
+    for row in [inserted, updated[0].clone()] {
+        assert!(rows.contains(&row), "{:?} not in {:?}", row, rows);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn delete_keys() -> Result<(), PoorlyError> {
+    let mut table = table();
+    priced(&mut table, &[1.0, 2.0, 3.0, 4.0, 5.0])?;
+
+    // Keys are coerced to the column's type, and missing keys are ignored
+    let keys = vec![
+        TypedValue::Int(1),
+        TypedValue::String("3".into()),
+        TypedValue::Int(7),
+    ];
+    assert_eq!(table.delete_keys("id", keys)?, 2);
+
+    let mut ids: Vec<_> = table
+        .select(vec!["id".into()], [].into(), None)?
+        .into_iter()
+        .map(|row| row["id"].clone())
+        .collect();
+    ids.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(
+        ids,
+        vec![TypedValue::Int(0), TypedValue::Int(2), TypedValue::Int(4)]
+    );
+    assert!(table.delete_keys("missing", vec![]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn auto_update() -> Result<(), PoorlyError> {
+    let mut table = table();
+    table.columns.push(("updated".into(), DataType::Timestamp));
+    table
+        .defaults
+        .insert("updated".into(), ColumnDefault::AutoUpdate);
+    priced(&mut table, &[1.0, 2.0])?;
+    // Pretend the rows were written long ago
+    table.update(
+        [("updated".into(), TypedValue::Timestamp(0))].into(),
+        [].into(),
+    )?;
+
+    let before = TypedValue::now();
+    let updated = table.update(
+        [("price".into(), TypedValue::Float(5.0))].into(),
+        [("id".into(), TypedValue::Int(0))].into(),
+    )?;
+    assert!(updated[0]["updated"] >= before);
+
+    // Setting a value the row already has changes nothing, the timestamp included
+    let updated = table.update(
+        [("price".into(), TypedValue::Float(2.0))].into(),
+        [("id".into(), TypedValue::Int(1))].into(),
+    )?;
+    assert!(updated.is_empty());
+
+    let rows = table.select(vec![], [("id".into(), TypedValue::Int(1))].into(), None)?;
+    assert_eq!(rows[0]["updated"], TypedValue::Timestamp(0));
+
+    Ok(())
+}
+
+#[test]
+fn mock_clock() -> Result<(), PoorlyError> {
+    let clock = Arc::new(MockClock::new(1_000));
+    let mut table = table().with_clock(clock.clone());
+    table.columns.push(("created".into(), DataType::Timestamp));
+    table.columns.push(("updated".into(), DataType::Timestamp));
+    table.defaults.insert("created".into(), ColumnDefault::Now);
+    table
+        .defaults
+        .insert("updated".into(), ColumnDefault::AutoUpdate);
+
+    priced(&mut table, &[1.0])?;
+    clock.advance(500);
+    priced(&mut table, &[2.0])?;
+    let rows = table.select(vec![], [].into(), None)?;
+    assert_eq!(rows[0]["created"], TypedValue::Timestamp(1_000));
+    assert_eq!(rows[0]["updated"], TypedValue::Timestamp(1_000));
+    assert_eq!(rows[1]["created"], TypedValue::Timestamp(1_500));
+
+    clock.set(2_000);
+    let updated = table.update(
+        [("price".into(), TypedValue::Float(5.0))].into(),
+        [("price".into(), TypedValue::Float(1.0))].into(),
+    )?;
+    assert_eq!(updated[0]["created"], TypedValue::Timestamp(1_000));
+    assert_eq!(updated[0]["updated"], TypedValue::Timestamp(2_000));
+
+    // Rows compare by exactly the times the clock gave them
+    let since = |column: &str, millis| {
+        [(
+            column.to_string(),
+            Predicate::Ge(TypedValue::Timestamp(millis)),
+        )]
+        .into()
+    };
+    let rows = table.select_where(vec![], since("created", 1_500), &[], &[], None)?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["price"], TypedValue::Float(2.0));
+    let rows = table.select_where(vec![], since("updated", 1_501), &[], &[], None)?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["price"], TypedValue::Float(5.0));
+    let before = [(
+        "created".into(),
+        Predicate::Lt(TypedValue::Timestamp(1_000)),
+    )]
+    .into();
+    assert!(table
+        .select_where(vec![], before, &[], &[], None)?
+        .is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn stream_to_writer() -> Result<(), PoorlyError> {
+    use crate::core::table::export::ExportFormat;
+
+    let mut table = table();
+    table.columns.push(("name".into(), DataType::String));
+    let names = ["plain", "a, b", "say \"hi\"", "two\nlines", ""];
+    for (id, name) in names.iter().enumerate() {
+        table.insert(crate::row! { "id" => id as i64, "price" => 1.5, "name" => *name })?;
+    }
+    table.delete([("id".into(), TypedValue::Int(0))].into())?;
+
+    let mut csv = Vec::new();
+    assert_eq!(table.stream_to_writer(ExportFormat::Csv, &mut csv)?, 4);
+    let mut reader = prettytable::csv::Reader::from_reader(csv.as_slice());
+    assert_eq!(
+        reader.headers().unwrap().iter().collect::<Vec<_>>(),
+        ["id", "price", "name"]
+    );
+    let records: Vec<_> = reader.records().map(|record| record.unwrap()).collect();
+    for (record, (id, name)) in records.iter().zip(names.iter().enumerate().skip(1)) {
+        assert_eq!(
+            record.iter().collect::<Vec<_>>(),
+            [&id.to_string(), "1.5", name]
+        );
+    }
+
+    let mut jsonl = Vec::new();
+    assert_eq!(
+        table.stream_to_writer(ExportFormat::JsonLines, &mut jsonl)?,
+        4
+    );
+    let lines: Vec<_> = std::str::from_utf8(&jsonl).unwrap().lines().collect();
+    assert_eq!(lines.len(), 4);
+    for (line, (id, name)) in lines.iter().zip(names.iter().enumerate().skip(1)) {
+        let row: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(
+            row,
+            serde_json::json!({ "id": id, "price": 1.5, "name": name })
+        );
+    }
+    // Columns keep the order of the table
+    assert!(lines[0].starts_with(r#"{"id":1,"price":1.5,"name":"#));
+
+    Ok(())
+}
+
+#[test]
+fn limited() -> Result<(), PoorlyError> {
+    let mut table = table();
+    priced(&mut table, &[1.0, 1.0, 1.0, 2.0, 2.0])?;
+
+    let deleted = table.delete_where([].into(), Some(2))?;
+    assert_eq!(deleted.len(), 2);
+    assert_eq!(table.count([].into())?, 3);
+
+    // Rows already holding the new value don't use up the limit
+    let updated = table.update_where(
+        [("price".into(), TypedValue::Float(2.0))].into(),
+        HashMap::new(),
+        [].into(),
+        Some(1),
+    )?;
+    assert_eq!(updated.len(), 1);
+    assert_eq!(updated[0]["id"], TypedValue::Int(2));
+    assert_eq!(
+        table.count([("price".into(), TypedValue::Float(2.0))].into())?,
+        3
+    );
+
+    Ok(())
+}
+
+#[test]
+fn row_stores() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let columns = table().columns;
+    let stores = [
+        Table::open(
+            "test".into(),
+            columns.clone(),
+            HashMap::new(),
+            RowFormat::CURRENT,
+            dir.path(),
+        ),
+        Table::in_memory("test".into(), columns, HashMap::new(), RowFormat::CURRENT),
+    ];
+
+    let mut contents = vec![];
+    for mut table in stores {
+        priced(&mut table, &[1.0, 5.0, 10.0, 50.0])?;
+        table.update(
+            [("price".into(), TypedValue::Float(7.0))].into(),
+            [("id".into(), TypedValue::Int(1))].into(),
+        )?;
+        table.delete([("id".into(), TypedValue::Int(0))].into())?;
+        assert_eq!(table.vacuum()?, 2);
+        let mut rows = table.select(vec![], [].into(), None)?;
+        rows.sort_by_key(|row| row["id"].to_string());
+        assert_eq!(rows.len(), 3);
+
+        let mut bytes = vec![];
+        table.file.seek(SeekFrom::Start(0))?;
+        table.file.read_to_end(&mut bytes)?;
+        contents.push((rows, bytes));
+    }
+
+    // Both stores end up holding the same rows laid out the same way
+    assert_eq!(contents[0], contents[1]);
+    assert_eq!(std::fs::read(dir.path().join("test"))?, contents[0].1);
+
+    Ok(())
+}
+
+#[test]
+fn encrypted() -> Result<(), PoorlyError> {
+    use encrypted::{EncryptedStore, EncryptionKey};
+    use row_store::FileStore;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("secrets");
+    let key = EncryptionKey::derive("passphrase", b"some salt bytes!")?;
+    let open = |key: &EncryptionKey| -> io::Result<Table> {
+        let store = EncryptedStore::new(Box::new(FileStore::open(path.clone())?), key.clone())?;
+        let columns = vec![
+            ("id".into(), DataType::Int),
+            ("note".into(), DataType::String),
+        ];
+        Table::with_store(
+            "secrets".into(),
+            columns,
+            HashMap::new(),
+            RowFormat::CURRENT,
+            Box::new(store),
+        )
+    };
+
+    // Enough rows to span several blocks
+    let mut table = open(&key)?;
+    for id in 0..500 {
+        table.insert(
+            [
+                ("id".into(), TypedValue::Int(id)),
+                (
+                    "note".into(),
+                    TypedValue::String(format!("top secret {}", id)),
+                ),
+            ]
+            .into(),
+        )?;
+    }
+    table.delete_where(
+        [("id".into(), Predicate::Lt(TypedValue::Int(100)))].into(),
+        None,
+    )?;
+    assert_eq!(table.vacuum()?, 100);
+    let mut rows = table.select(vec![], [].into(), None)?;
+    drop(table);
+
+    let bytes = std::fs::read(&path)?;
+    assert!(!bytes.windows(10).any(|window| window == b"top secret"));
+
+    let mut table = open(&key)?;
+    let mut reopened = table.select(vec![], [].into(), None)?;
+    let by_id = |row: &ColumnSet| row["id"].to_string();
+    rows.sort_by_key(by_id);
+    reopened.sort_by_key(by_id);
+    assert_eq!(rows.len(), 400);
+    assert_eq!(reopened, rows);
+
+    let other = EncryptionKey::derive("wrong", b"some salt bytes!")?;
+    let error = open(&other).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    Ok(())
+}
+
+#[test]
+fn encrypted_blocks() -> Result<(), PoorlyError> {
+    use encrypted::{EncryptedStore, EncryptionKey};
+    use row_store::FileStore;
+
+    let dir = tempfile::tempdir()?;
+    let key = EncryptionKey::derive("passphrase", b"some salt bytes!")?;
+    let write = |name: &str| -> io::Result<Vec<u8>> {
+        let path = dir.path().join(name);
+        let mut store = EncryptedStore::new(Box::new(FileStore::open(path.clone())?), key.clone())?;
+        store.write_all(&[7; 3 * 4096])?;
+        drop(store);
+        std::fs::read(path)
+    };
+    let read = |bytes: &[u8]| -> io::Result<Vec<u8>> {
+        let path = dir.path().join("copy");
+        std::fs::write(&path, bytes)?;
+        let store = EncryptedStore::new(Box::new(FileStore::open(path)?), key.clone())?;
+        let mut data = vec![];
+        store.reader()?.read_to_end(&mut data)?;
+        Ok(data)
+    };
+    let bytes = write("first")?;
+    assert_eq!(read(&bytes)?, [7; 3 * 4096]);
+
+    // Header with the file id, then blocks of nonce, data and tag
+    let (header, stride) = (16, 12 + 4096 + 16);
+    let error = read(&bytes[..header + 2 * stride]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    // A block of another file sealed with the same key
+    let other = write("second")?;
+    let mut spliced = bytes.clone();
+    spliced[header..header + stride].copy_from_slice(&other[header..header + stride]);
+    let error = read(&spliced).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    Ok(())
+}
+
+#[test]
+fn compressed() -> Result<(), PoorlyError> {
+    let columns = vec![
+        ("id".into(), DataType::Int),
+        ("email".into(), DataType::Email),
+        ("note".into(), DataType::String),
+    ];
+    let mut plain = Table::in_memory(
+        "plain".into(),
+        columns.clone(),
+        HashMap::new(),
+        RowFormat::V2,
+    );
+    let mut packed = Table::in_memory("packed".into(), columns, HashMap::new(), RowFormat::V3);
+
+    for table in [&mut plain, &mut packed] {
+        for id in 0..50 {
+            table.insert(
+                [
+                    ("id".into(), TypedValue::Int(id)),
+                    (
+                        "email".into(),
+                        TypedValue::String(format!("user{}@example.com", id)),
+                    ),
+                    ("note".into(), TypedValue::String("lorem ipsum ".repeat(20))),
+                ]
+                .into(),
+            )?;
+        }
+    }
+
+    let plain_size = plain.file.seek(SeekFrom::End(0))?;
+    let packed_size = packed.file.seek(SeekFrom::End(0))?;
+    assert!(packed_size * 2 < plain_size);
+
+    let rows = packed.select(vec![], [].into(), None)?;
+    assert_eq!(rows, plain.select(vec![], [].into(), None)?);
+    assert_eq!(packed.stats.bytes_raw, plain.stats.bytes_stored);
+    assert!(packed.stats.compression_ratio().unwrap() > 2.0);
+    assert_eq!(plain.stats.compression_ratio(), Some(1.0));
+
+    Ok(())
+}
+
+#[test]
+fn row_macro() -> Result<(), PoorlyError> {
+    let manual: ColumnSet = [
+        ("id".into(), TypedValue::Int(1)),
+        ("price".into(), TypedValue::Float(1.23)),
+    ]
+    .into();
+    let row = crate::row! { "id" => 1, "price" => 1.23 };
+    assert_eq!(row, manual);
+    assert_eq!(crate::row! {}, ColumnSet::new());
+
+    let mut by_hand = table();
+    by_hand.insert(manual)?;
+    let mut by_macro = table();
+    by_macro.insert(row)?;
+    assert_eq!(
+        by_macro.select(vec![], [].into(), None)?,
+        by_hand.select(vec![], [].into(), None)?
+    );
+    // Stored by the column type, not the literal's
+    let rows = by_macro.select(vec![], [].into(), None)?;
+    assert!(matches!(rows[0]["id"], TypedValue::Int(1)));
+
+    Ok(())
+}
+
+#[test]
+fn read_only() -> Result<(), PoorlyError> {
+    let dir = tempfile::tempdir()?;
+    let columns = table().columns;
+    {
+        let mut table = Table::open(
+            "test".into(),
+            columns.clone(),
+            HashMap::new(),
+            RowFormat::CURRENT,
+            dir.path(),
+        );
+        priced(&mut table, &[1.0, 5.0])?;
+    }
+    let bytes = std::fs::read(dir.path().join("test"))?;
+
+    let file = FileStore::open_read_only(dir.path().join("test"))?;
+    let mut table = Table::with_store(
+        "test".into(),
+        columns,
+        HashMap::new(),
+        RowFormat::CURRENT,
+        Box::new(ReadOnlyStore(Box::new(file))),
+    )?;
+    assert_eq!(table.select(vec![], [].into(), None)?.len(), 2);
+
+    let read_only = |error: Option<PoorlyError>| matches!(error, Some(PoorlyError::InvalidOperation(message)) if message == "read-only");
+    let row = [
+        ("id".into(), TypedValue::Int(2)),
+        ("price".into(), TypedValue::Float(9.0)),
+    ];
+    assert!(read_only(table.insert(row.into()).err()));
+    assert!(read_only(table.delete([].into()).err()));
+    assert!(read_only(table.vacuum().err()));
+    assert_eq!(std::fs::read(dir.path().join("test"))?, bytes);
+
+    Ok(())
+}
+
+#[test]
+fn select_vectors() -> Result<(), PoorlyError> {
+    let mut table = table();
+    table.insert(crate::row! { "id" => 1, "price" => 1.5 })?;
+
+    let (header, rows) = table.select_vectors(
+        vec!["price".into(), "id".into()],
+        HashMap::new(),
+        &[],
+        &[],
+        None,
+    )?;
+    assert_eq!(header, ["price", "id"]);
+    assert_eq!(rows, [[TypedValue::Float(1.5), TypedValue::Int(1)]]);
+
+    let (header, rows) = table.select_vectors(vec![], HashMap::new(), &[], &[], None)?;
+    assert_eq!(header, ["id", "price"]);
+    assert_eq!(rows, [[TypedValue::Int(1), TypedValue::Float(1.5)]]);
+
+    Ok(())
+}
+
+#[test]
+fn iter_offsets() -> Result<(), PoorlyError> {
+    let mut table = table();
+    for id in 1..=3 {
+        table.insert(crate::row! { "id" => id, "price" => 1.0 })?;
+    }
+
+    let rows = table.iter_offsets()?.collect::<Result<Vec<_>, _>>()?;
+    let ids: Vec<_> = rows.iter().map(|(_, row)| row["id"].clone()).collect();
+    assert_eq!(
+        ids,
+        [TypedValue::Int(1), TypedValue::Int(2), TypedValue::Int(3)]
+    );
+
+    table.delete_at(rows[1].0)?;
+    let left = table.iter_offsets()?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(left, [rows[0].clone(), rows[2].clone()]);
+
+    Ok(())
+}
+
+#[test]
+fn update_expressions() -> Result<(), PoorlyError> {
+    use crate::core::expression::Expression;
+
+    let column = |name: &str| Box::new(Expression::Column(name.into()));
+    let literal = |value: TypedValue| Box::new(Expression::Literal(value));
+    let mut table = table();
+    table.insert(crate::row! { "id" => 5, "price" => 2.5 })?;
+    table.insert(crate::row! { "id" => 6, "price" => 4.0 })?;
+    let id_is = |id| [("id".into(), Predicate::Eq(TypedValue::Int(id)))].into();
+
+    // stock = stock - 1
+    let decrement = [(
+        "id".into(),
+        Expression::Sub(column("id"), literal(TypedValue::Int(1))),
+    )];
+    let updated = table.update_where(HashMap::new(), decrement.into(), id_is(5), None)?;
+    assert_eq!(updated[0]["id"], TypedValue::Int(4));
+
+    // Every expression reads the row as it was before the update
+    let multiply = [
+        (
+            "price".into(),
+            Expression::Mul(column("price"), column("id")),
+        ),
+        (
+            "id".into(),
+            Expression::Mul(column("id"), literal(TypedValue::Int(10))),
+        ),
+    ];
+    let updated = table.update_where(HashMap::new(), multiply.into(), id_is(6), None)?;
+    assert_eq!(updated[0]["price"], TypedValue::Float(24.0));
+    assert_eq!(updated[0]["id"], TypedValue::Int(60));
+
+    let divide = [(
+        "id".into(),
+        Expression::Div(column("id"), literal(TypedValue::Int(0))),
+    )];
+    assert!(matches!(
+        table.update_where(HashMap::new(), divide.into(), HashMap::new(), None),
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+    let overflow = [(
+        "id".into(),
+        Expression::Add(column("id"), literal(TypedValue::Int(i64::MAX))),
+    )];
+    assert!(matches!(
+        table.update_where(HashMap::new(), overflow.into(), HashMap::new(), None),
+        Err(PoorlyError::InvalidOperation(_))
+    ));
+    // A failed update changes nothing
+    let rows = table.select(vec!["id".into()], HashMap::new(), None)?;
+    let mut ids: Vec<_> = rows.iter().map(|row| row["id"].clone()).collect();
+    ids.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(ids, [TypedValue::Int(4), TypedValue::Int(60)]);
+
+    Ok(())
+}
+
+#[test]
+fn segments() -> Result<(), PoorlyError> {
+    use row_store::{segment_paths, SegmentedStore};
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("log");
+    let open = || -> io::Result<Table> {
+        let columns = vec![
+            ("id".into(), DataType::Serial),
+            ("line".into(), DataType::String),
+        ];
+        let store = SegmentedStore::open(path.clone(), 256)?;
+        Table::with_store(
+            "log".into(),
+            columns,
+            HashMap::new(),
+            RowFormat::CURRENT,
+            Box::new(store),
+        )
+    };
+    let line = |n: i64| -> ColumnSet {
+        [("line".into(), TypedValue::String(format!("line {}", n)))].into()
+    };
+
+    let mut table = open()?;
+    for n in 0..100 {
+        table.insert(line(n))?;
+    }
+    let segments = segment_paths(&path);
+    assert!(segments.len() > 1);
+    for segment in &segments {
+        assert!(std::fs::metadata(segment)?.len() < 256 + 32);
+    }
+
+    // Rows are read across segments in the order they were written, and
+    // serials keep counting after reopening
+    drop(table);
+    let mut table = open()?;
+    table.insert(line(100))?;
+    let rows = table.select(vec![], [].into(), None)?;
+    assert_eq!(rows.len(), 101);
+    for (n, row) in rows.iter().enumerate() {
+        assert_eq!(row["id"], TypedValue::Serial(n as u32));
+        assert_eq!(row["line"], TypedValue::String(format!("line {}", n)));
+    }
+
+    // Deletes reach rows in older segments and a vacuum drops the segments
+    // it no longer needs
+    table.delete_where(
+        [("id".into(), Predicate::Lt(TypedValue::Serial(90)))].into(),
+        None,
+    )?;
+    assert_eq!(table.vacuum()?, 90);
+    assert!(segment_paths(&path).len() < segments.len());
+    assert!(!dir.path().join("log.vacuum").exists());
+    drop(table);
+    let mut table = open()?;
+    let rows = table.select(vec!["id".into()], [].into(), None)?;
+    let ids: Vec<_> = rows.iter().map(|row| row["id"].clone()).collect();
+    assert_eq!(ids, (90..101).map(TypedValue::Serial).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+// Keeps the data in memory, but every sync fails as if the disk were gone
+#[derive(Debug)]
+struct FailingSync(Cursor<Vec<u8>>);
+
+impl Read for FailingSync {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for FailingSync {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FailingSync {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl RowStore for FailingSync {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        self.0.reader()
+    }
+
+    fn rewrite(&self) -> io::Result<Box<dyn RowStore>> {
+        self.0.rewrite()
+    }
+
+    fn commit(&mut self, rewritten: Box<dyn RowStore>) -> io::Result<()> {
+        self.0.commit(rewritten)
+    }
+
+    fn remove(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Err(io::Error::other("disk gone"))
+    }
+}
+
+#[test]
+fn group_commit() -> Result<(), PoorlyError> {
+    let flusher = Flusher::new(GroupCommit {
+        max_bytes: 1 << 20,
+        max_delay: Duration::from_millis(10),
+    });
+    let row = || -> ColumnSet {
+        [
+            ("id".into(), TypedValue::Int(1)),
+            ("price".into(), TypedValue::Float(1.5)),
+        ]
+        .into()
+    };
+
+    // Both rows are in one group, which the flusher syncs once it is due
+    let mut table = table();
+    table.buffer_writes(&flusher)?;
+    table.insert(row())?;
+    table.insert(row())?;
+    let groups = flusher.take_groups();
+    assert_eq!(groups.len(), 1);
+    groups[0].wait()?;
+    assert!(flusher.take_groups().is_empty());
+    assert_eq!(table.select(vec![], [].into(), None)?.len(), 2);
+
+    // A failed sync reaches the writers waiting on the group
+    let mut table = self::table();
+    table.file = Box::new(FailingSync(Cursor::new(Vec::new())));
+    table.buffer_writes(&flusher)?;
+    table.insert(row())?;
+    let groups = flusher.take_groups();
+    let err = groups[0].wait().unwrap_err();
+    assert_eq!(err.to_string(), "disk gone");
+
+    Ok(())
+}
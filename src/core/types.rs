@@ -1,15 +1,74 @@
 use std::collections::HashMap;
 use std::fmt;
-use std::io;
+use std::io::{self, Read};
 
 use rusqlite::types::ToSqlOutput;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use super::schema::Columns;
+use super::expression::Expressions;
+use super::predicate::Predicates;
+use super::schema::{ColumnDefault, Columns, SchemaKind};
+
+#[cfg(test)]
+mod tests;
 
 pub type ColumnSet = HashMap<String, TypedValue>;
 
+/// Builds a `ColumnSet` from `column => value` pairs, converting the values
+/// with `TypedValue::from`, e.g. `row! { "id" => 1, "price" => 1.23 }`.
+/// Values are coerced to the column types on insert as usual.
+#[macro_export]
+macro_rules! row {
+    ($($column:expr => $value:expr),* $(,)?) => {{
+        let row: $crate::core::types::ColumnSet = [$((
+            ::std::string::ToString::to_string(&$column),
+            $crate::core::types::TypedValue::from($value),
+        )),*]
+        .into();
+        row
+    }};
+}
+
+// Longest string (in bytes) that fits into the `V2` length prefix
+pub const MAX_STRING_LEN: usize = u32::MAX as usize;
+
+// On-disk layout of rows, recorded per database in the schema
+// V1 - strings and emails are prefixed with an 8-byte length
+// V2 - strings and emails are prefixed with a 4-byte length
+// V3 - like V2, with a byte after the length telling if the string is stored
+//      zstd-compressed, which it is when that makes it shorter
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum RowFormat {
+    V1 = 1,
+    V2 = 2,
+    V3 = 3,
+}
+
+impl RowFormat {
+    pub const CURRENT: RowFormat = RowFormat::V2;
+
+    pub fn version(&self) -> u32 {
+        *self as u32
+    }
+}
+
+impl TryFrom<u32> for RowFormat {
+    type Error = PoorlyError;
+
+    fn try_from(version: u32) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(RowFormat::V1),
+            2 => Ok(RowFormat::V2),
+            3 => Ok(RowFormat::V3),
+            _ => Err(PoorlyError::InvalidOperation(format!(
+                "Unsupported row format version {}",
+                version
+            ))),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum PoorlyError {
     #[error("Table {0} already exists")]
@@ -54,6 +113,24 @@ pub enum PoorlyError {
     #[error("Invalid operation: {0}")]
     InvalidOperation(String),
 
+    #[error("Result exceeds the limit of {0} rows")]
+    ResultTooLarge(usize),
+
+    #[error("No row in table {0} matches the conditions")]
+    RowNotFound(String),
+
+    #[error("More than one row in table {0} matches the conditions")]
+    TooManyRows(String),
+
+    #[error("{0}")]
+    SchemaCorrupted(String),
+
+    #[error("Reloaded schema changes the layout of open table {0}")]
+    IncompatibleSchema(String),
+
+    #[error("Row at offset {0} doesn't match the table's columns")]
+    SchemaRowMismatch(u64),
+
     #[error("IO Error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -61,12 +138,67 @@ pub enum PoorlyError {
     SqlError(#[from] rusqlite::Error),
 }
 
+// Work done by a single query, reported back to clients for debugging
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QueryStats {
+    // Rows read from the table files, including deleted ones
+    pub rows_scanned: u64,
+    // Rows that matched the query conditions
+    pub rows_matched: u64,
+    // Size of the rows read as stored, and as they would be uncompressed
+    pub bytes_stored: u64,
+    pub bytes_raw: u64,
+}
+
+impl QueryStats {
+    /// How many times smaller compression made the rows read, if any were
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.bytes_stored > 0).then(|| self.bytes_raw as f64 / self.bytes_stored as f64)
+    }
+}
+
+impl PoorlyError {
+    /// A stable name of the error kind for clients to branch on, unlike
+    /// the message which may change
+    pub fn code(&self) -> &'static str {
+        match self {
+            PoorlyError::TableAlreadyExists(_) => "TABLE_ALREADY_EXISTS",
+            PoorlyError::TableNotFound(_) => "TABLE_NOT_FOUND",
+            PoorlyError::DatabaseNotFound(_) => "DATABASE_NOT_FOUND",
+            PoorlyError::DatabaseAlreadyExists(_) => "DATABASE_ALREADY_EXISTS",
+            PoorlyError::CannotDropDefaultDb => "CANNOT_DROP_DEFAULT_DB",
+            PoorlyError::ColumnAlreadyExists(_, _) => "COLUMN_ALREADY_EXISTS",
+            PoorlyError::NoColumns => "NO_COLUMNS",
+            PoorlyError::ColumnNotFound(_, _) => "COLUMN_NOT_FOUND",
+            PoorlyError::InvalidName(_) => "INVALID_NAME",
+            PoorlyError::InvalidEmail => "INVALID_EMAIL",
+            PoorlyError::InvalidValue(_, _) => "INVALID_VALUE",
+            PoorlyError::IncompleteData(_, _) => "INCOMPLETE_DATA",
+            PoorlyError::InvalidDataType(_) => "INVALID_DATA_TYPE",
+            PoorlyError::InvalidOperation(_) => "INVALID_OPERATION",
+            PoorlyError::ResultTooLarge(_) => "RESULT_TOO_LARGE",
+            PoorlyError::RowNotFound(_) => "ROW_NOT_FOUND",
+            PoorlyError::TooManyRows(_) => "TOO_MANY_ROWS",
+            PoorlyError::SchemaCorrupted(_) => "SCHEMA_CORRUPTED",
+            PoorlyError::IncompatibleSchema(_) => "INCOMPATIBLE_SCHEMA",
+            PoorlyError::SchemaRowMismatch(_) => "SCHEMA_ROW_MISMATCH",
+            PoorlyError::IoError(_) => "IO_ERROR",
+            PoorlyError::SqlError(_) => "SQL_ERROR",
+        }
+    }
+}
+
 impl Serialize for PoorlyError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&self.to_string())
+        use serde::ser::SerializeStruct;
+
+        let mut error = serializer.serialize_struct("PoorlyError", 2)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
     }
 }
 
@@ -77,27 +209,95 @@ pub enum Query {
         from: String,
         columns: Vec<String>,
         conditions: ColumnSet,
+        // Range and pattern tests, on top of the equality `conditions`
+        predicates: Predicates,
+        order_by: Vec<OrderBy>,
+        // Keep only the first row, after ordering, for each value of these columns
+        distinct_on: Vec<String>,
+        // Overrides the server's row limit for this query
+        max_rows: Option<usize>,
+        // Only rows with a serial past this one, to fetch what was inserted
+        // since an earlier select
+        since_serial: Option<u32>,
+        // Tests on the selected columns, applied to the rows being returned
+        post_filter: Predicates,
+        // Deleted rows too, told apart by a `_deleted` column
+        include_deleted: bool,
+        // Only rows inserted before this snapshot was opened, see `Snapshot`
+        snapshot: Option<u64>,
+        // Only rows whose column holds one of the values selected by a
+        // `Select` of a single column, like `id IN (SELECT customer_id ...)`.
+        // The selects run first and can't refer to the outer table.
+        in_select: HashMap<String, Query>,
+    },
+    SelectOne {
+        db: String,
+        from: String,
+        conditions: ColumnSet,
+    },
+    Count {
+        db: String,
+        from: String,
+        conditions: ColumnSet,
+    },
+    // Up to `n` live rows of `table` picked at random
+    Sample {
+        db: String,
+        table: String,
+        n: u64,
+    },
+    // The row matching `predicates` that comes first by `order_by`, if any
+    First {
+        db: String,
+        from: String,
+        predicates: Predicates,
+        order_by: OrderBy,
     },
     Insert {
         db: String,
         into: String,
         values: ColumnSet,
+        // Adds the row's file offset to the reply as `_offset`
+        with_offset: bool,
+        // Columns of the inserted row to reply with, all of them if empty
+        returning: Vec<String>,
     },
     Update {
         db: String,
         table: String,
         set: ColumnSet,
+        // Columns set to a value computed from the row, on top of `set`
+        expressions: Expressions,
         conditions: ColumnSet,
+        predicates: Predicates,
+        // Adds the updated rows' new file offsets to the reply as `_offset`
+        with_offset: bool,
+        // Maximum number of rows to change
+        limit: Option<usize>,
     },
     Delete {
         db: String,
         from: String,
         conditions: ColumnSet,
+        predicates: Predicates,
+        // Maximum number of rows to delete
+        limit: Option<usize>,
+        // Rewrite the table without the deleted rows instead of tombstoning
+        // them. Deletes of most of a large table do so anyway.
+        compact: bool,
     },
     Create {
         db: String,
         table: String,
         columns: Columns,
+        defaults: HashMap<String, ColumnDefault>,
+        // Store the table's strings compressed, see `RowFormat::V3`
+        compressed: bool,
+        // Roll the table over to a new segment file once its last one holds
+        // this many bytes, see `SegmentedStore`
+        segment_size: Option<u64>,
+        // Succeed without creating anything if the same table already exists
+        if_not_exists: bool,
     },
     CreateDb {
         name: String,
@@ -105,10 +305,21 @@ pub enum Query {
     Drop {
         db: String,
         table: String,
+        // Succeed without doing anything if the table doesn't exist
+        if_exists: bool,
     },
     DropDb {
         name: String,
     },
+    // Copies database `name` into a new one stored by the `to_kind` engine,
+    // named `<name>_<to_kind>`. With `swap` the two trade names, so the copy
+    // is `name` and the original is kept as `<name>_<its kind>`. Replies
+    // with the `db` the copy is named.
+    ConvertDb {
+        name: String,
+        to_kind: SchemaKind,
+        swap: bool,
+    },
     Alter {
         db: String,
         table: String,
@@ -117,6 +328,25 @@ pub enum Query {
     ShowTables {
         db: String,
     },
+    // The `Create` command that makes `table` as it is now
+    ShowCreate {
+        db: String,
+        table: String,
+    },
+    // A row for every column of every table of `db`, by table and then
+    // position: `table`, `column`, `type` and `position`, plus `default`
+    // and `comment` if the column has them
+    DescribeDb {
+        db: String,
+    },
+    // Describes `table`, or its `column` if given. An empty text removes
+    // the comment.
+    Comment {
+        db: String,
+        table: String,
+        column: Option<String>,
+        text: String,
+    },
     Join {
         db: String,
         table1: String,
@@ -124,9 +354,128 @@ pub enum Query {
         columns: Vec<String>,
         conditions: ColumnSet,
         join_on: HashMap<String, String>,
+        // Columns of the same name in both tables to join on, each returned
+        // once without a table prefix
+        using: Vec<String>,
+        max_rows: Option<usize>,
+        // Tests on the joined `table.column`s, applied to the rows being returned
+        post_filter: Predicates,
+        // Only the first this many rows of the sorted result, after the post filter
+        limit: Option<usize>,
+    },
+    Repair {
+        db: String,
+    },
+    // Reports invalid values and serial counters, changing nothing
+    CheckIntegrity {
+        db: String,
+    },
+    SelectDeleted {
+        db: String,
+        from: String,
+        conditions: ColumnSet,
+    },
+    Undelete {
+        db: String,
+        table: String,
+        offset: u64,
+    },
+    ReloadSchema {
+        db: String,
+    },
+    // Copies the rows of `src` matching `conditions` into `dst`. `column_map`
+    // maps `src` columns to `dst` ones; when empty, columns are copied by name.
+    CopyInto {
+        db: String,
+        src: String,
+        dst: String,
+        conditions: ColumnSet,
+        column_map: HashMap<String, String>,
+    },
+    // Deletes the rows whose `column` holds one of `keys`
+    DeleteKeys {
+        db: String,
+        from: String,
+        column: String,
+        keys: Vec<TypedValue>,
+    },
+    // Removes deleted rows from the table file
+    Vacuum {
+        db: String,
+        table: String,
+    },
+    // Writes the open tables and the schema of `db` through to the disk,
+    // closing the tables too if `close`
+    Flush {
+        db: String,
+        close: bool,
+    },
+    // Remembers the serial counter of every table of `db`, so selects made
+    // in the snapshot leave out the rows inserted after it. Rows updated or
+    // deleted since are seen as they are now. Replies with the `snapshot` id.
+    // Rows are told apart by their serial, so only tables with a `Serial`
+    // column can be selected from in a snapshot.
+    Snapshot {
+        db: String,
+    },
+    // Forgets a snapshot opened with `Snapshot`
+    ReleaseSnapshot {
+        snapshot: u64,
+    },
+    // Creates `table` from the result of a `Select` or a `Join`
+    CreateAs {
+        db: String,
+        table: String,
+        select: Box<Query>,
     },
 }
 
+impl Query {
+    /// Tells if the query may change rows, a schema or the databases
+    pub fn mutates(&self) -> bool {
+        match self {
+            Query::Insert { .. }
+            | Query::Update { .. }
+            | Query::Delete { .. }
+            | Query::Create { .. }
+            | Query::CreateDb { .. }
+            | Query::Drop { .. }
+            | Query::DropDb { .. }
+            | Query::ConvertDb { .. }
+            | Query::Alter { .. }
+            | Query::Comment { .. }
+            | Query::Repair { .. }
+            | Query::Undelete { .. }
+            | Query::CopyInto { .. }
+            | Query::DeleteKeys { .. }
+            | Query::Vacuum { .. }
+            | Query::CreateAs { .. } => true,
+            Query::Select { .. }
+            | Query::SelectOne { .. }
+            | Query::Count { .. }
+            | Query::Sample { .. }
+            | Query::First { .. }
+            | Query::ShowTables { .. }
+            | Query::ShowCreate { .. }
+            | Query::DescribeDb { .. }
+            | Query::Join { .. }
+            | Query::CheckIntegrity { .. }
+            | Query::SelectDeleted { .. }
+            | Query::ReloadSchema { .. }
+            | Query::Flush { .. }
+            | Query::Snapshot { .. }
+            | Query::ReleaseSnapshot { .. } => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OrderBy {
+    pub column: String,
+    #[serde(default)]
+    pub descending: bool,
+}
+
 // Used for checking restrictions on columns
 // Use None to prevent any checks
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -138,7 +487,7 @@ pub enum TableMethod {
     None,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, PartialOrd)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum TypedValue {
     Int(i64),
@@ -147,6 +496,52 @@ pub enum TypedValue {
     String(String),
     Serial(u32),
     Email(String),
+    // Milliseconds since the Unix epoch
+    Timestamp(i64),
+    Int32(i32),
+    Int16(i16),
+}
+
+// Integers of different widths are compared by value, everything else
+// compares within its own type and orders by type across types.
+impl PartialOrd for TypedValue {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        if let (Some(a), Some(b)) = (self.as_int(), other.as_int()) {
+            return a.partial_cmp(&b);
+        }
+
+        match (self, other) {
+            (TypedValue::Float(a), TypedValue::Float(b)) => a.partial_cmp(b),
+            (TypedValue::Char(a), TypedValue::Char(b)) => a.partial_cmp(b),
+            (TypedValue::String(a), TypedValue::String(b)) => a.partial_cmp(b),
+            (TypedValue::Serial(a), TypedValue::Serial(b)) => a.partial_cmp(b),
+            (TypedValue::Email(a), TypedValue::Email(b)) => a.partial_cmp(b),
+            (TypedValue::Timestamp(a), TypedValue::Timestamp(b)) => a.partial_cmp(b),
+            _ => i32::from(self.data_type()).partial_cmp(&i32::from(other.data_type())),
+        }
+    }
+}
+
+impl PartialEq for TypedValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(std::cmp::Ordering::Equal)
+    }
+}
+
+/// A `TypedValue` that is `Eq` and `Hash`, to key maps and sets by value.
+/// Values that are equal key equal: integers of every width key by their
+/// value and `-0.0` keys like `0.0`. Unlike comparing them, all NaNs key the
+/// same, so they count as one value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CanonicalValue {
+    Int(i64),
+    // Bit pattern of the float
+    Float(u64),
+    Char(char),
+    String(String),
+    Serial(u32),
+    Email(String),
+    Timestamp(i64),
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, PartialOrd, Ord)]
@@ -158,6 +553,9 @@ pub enum DataType {
     String = 3,
     Serial = 4,
     Email = 5,
+    Timestamp = 6,
+    Int32 = 7,
+    Int16 = 8,
 }
 
 impl From<DataType> for i32 {
@@ -169,6 +567,9 @@ impl From<DataType> for i32 {
             DataType::String => 3,
             DataType::Serial => 4,
             DataType::Email => 5,
+            DataType::Timestamp => 6,
+            DataType::Int32 => 7,
+            DataType::Int16 => 8,
         }
     }
 }
@@ -182,13 +583,81 @@ impl rusqlite::ToSql for TypedValue {
             TypedValue::Char(c) => Ok(ToSqlOutput::from(c.to_string())),
             TypedValue::Serial(u) => Ok(ToSqlOutput::from(u.to_string())),
             TypedValue::Email(e) => e.to_sql(),
+            TypedValue::Timestamp(t) => t.to_sql(),
+            TypedValue::Int32(i) => i.to_sql(),
+            TypedValue::Int16(i) => i.to_sql(),
         }
     }
 }
 
+/// Lays `rows` out as the values of `columns`, in that order
+pub fn row_vectors(columns: &[String], rows: Vec<ColumnSet>) -> Vec<Vec<TypedValue>> {
+    rows.into_iter()
+        .map(|row| columns.iter().map(|column| row[column].clone()).collect())
+        .collect()
+}
+
+/// Tells if `a` and `b` hold the same columns with values of equal
+/// `canonical_key`. Unlike `==`, a NaN is equal to any other NaN, so a row
+/// holding one is equal to itself.
+pub fn column_sets_equal(a: &ColumnSet, b: &ColumnSet) -> bool {
+    a.len() == b.len()
+        && a.iter().all(|(column, value)| {
+            b.get(column)
+                .is_some_and(|other| value.canonical_key() == other.canonical_key())
+        })
+}
+
+/// Milliseconds since the Unix epoch by the server clock
+pub fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default()
+}
+
 impl TypedValue {
+    pub fn now() -> Self {
+        TypedValue::Timestamp(now_millis())
+    }
+
+    /// The key of the value, see `CanonicalValue`. Integers of every width
+    /// key as `i64`, both zeros key alike and every NaN keys as the same one.
+    pub fn canonical_key(&self) -> CanonicalValue {
+        if let Some(i) = self.as_int() {
+            return CanonicalValue::Int(i);
+        }
+        match self {
+            TypedValue::Float(f) if f.is_nan() => CanonicalValue::Float(f64::NAN.to_bits()),
+            // Both zeros, as they are equal
+            TypedValue::Float(f) if *f == 0.0 => CanonicalValue::Float(0f64.to_bits()),
+            TypedValue::Float(f) => CanonicalValue::Float(f.to_bits()),
+            TypedValue::Char(c) => CanonicalValue::Char(*c),
+            TypedValue::String(s) => CanonicalValue::String(s.clone()),
+            TypedValue::Serial(s) => CanonicalValue::Serial(*s),
+            TypedValue::Email(e) => CanonicalValue::Email(e.clone()),
+            TypedValue::Timestamp(t) => CanonicalValue::Timestamp(*t),
+            TypedValue::Int(_) | TypedValue::Int32(_) | TypedValue::Int16(_) => {
+                unreachable!("integers are keyed above")
+            }
+        }
+    }
+
+    // The value of any of the integer types, widened to `i64`
+    pub(crate) fn as_int(&self) -> Option<i64> {
+        match self {
+            TypedValue::Int(i) => Some(*i),
+            TypedValue::Int32(i) => Some(*i as i64),
+            TypedValue::Int16(i) => Some(*i as i64),
+            _ => None,
+        }
+    }
+
     pub fn validate(&self) -> Result<(), PoorlyError> {
         match self {
+            TypedValue::String(s) | TypedValue::Email(s) if s.len() > MAX_STRING_LEN => {
+                return Err(PoorlyError::InvalidValue(self.clone(), self.data_type()));
+            }
             TypedValue::Email(email) => {
                 let email_regex = regex::Regex::new(r"^[\w\-\.]+@([\w-]+\.)+[\w\-]{2,4}$").unwrap();
                 if !email_regex.is_match(email) {
@@ -208,16 +677,54 @@ impl TypedValue {
             TypedValue::String(_) => DataType::String,
             TypedValue::Serial(_) => DataType::Serial,
             TypedValue::Email(_) => DataType::Email,
+            TypedValue::Timestamp(_) => DataType::Timestamp,
+            TypedValue::Int32(_) => DataType::Int32,
+            TypedValue::Int16(_) => DataType::Int16,
         }
     }
 
-    pub fn read<R: io::Read>(data_type: DataType, reader: &mut R) -> Result<Self, io::Error> {
+    pub fn read<R: io::Read>(
+        data_type: DataType,
+        reader: &mut R,
+        format: RowFormat,
+    ) -> Result<Self, io::Error> {
         let mut read_string = || {
-            let mut length = [0; 8];
-            reader.read_exact(&mut length)?;
-            let length = u64::from_le_bytes(length);
-            let mut buf = vec![0; length as usize];
-            reader.read_exact(&mut buf)?;
+            let length = match format {
+                RowFormat::V1 => {
+                    let mut length = [0; 8];
+                    reader.read_exact(&mut length)?;
+                    u64::from_le_bytes(length) as usize
+                }
+                RowFormat::V2 | RowFormat::V3 => {
+                    let mut length = [0; 4];
+                    reader.read_exact(&mut length)?;
+                    u32::from_le_bytes(length) as usize
+                }
+            };
+            let mut compressed = [0];
+            if format == RowFormat::V3 {
+                reader.read_exact(&mut compressed)?;
+            }
+            // The length may be read from the wrong bytes, so only what is
+            // there is read rather than allocating it all up front
+            let mut buf = Vec::new();
+            (&mut *reader).take(length as u64).read_to_end(&mut buf)?;
+            if buf.len() < length {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "String longer than the rest of the file",
+                ));
+            }
+            match compressed[0] {
+                0 => {}
+                1 => buf = zstd::decode_all(buf.as_slice())?,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unknown string compression",
+                    ))
+                }
+            }
             String::from_utf8(buf)
                 .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 string"))
         };
@@ -245,14 +752,42 @@ impl TypedValue {
                 Ok(TypedValue::Serial(u32::from_le_bytes(buf)))
             }
             DataType::Email => Ok(TypedValue::Email(read_string()?)),
+            DataType::Timestamp => {
+                let mut buf = [0; 8];
+                reader.read_exact(&mut buf)?;
+                Ok(TypedValue::Timestamp(i64::from_le_bytes(buf)))
+            }
+            DataType::Int32 => {
+                let mut buf = [0; 4];
+                reader.read_exact(&mut buf)?;
+                Ok(TypedValue::Int32(i32::from_le_bytes(buf)))
+            }
+            DataType::Int16 => {
+                let mut buf = [0; 2];
+                reader.read_exact(&mut buf)?;
+                Ok(TypedValue::Int16(i16::from_le_bytes(buf)))
+            }
         }
     }
 
-    pub fn into_bytes(self) -> Vec<u8> {
+    // Numbers are little-endian regardless of the platform, strings and emails
+    // are UTF-8 prefixed with their length in bytes and chars are a single byte.
+    // Changing this layout requires a new `RowFormat`.
+    pub fn into_bytes(self, format: RowFormat) -> Vec<u8> {
         let convert_string = |s: String| {
             let bytes = s.into_bytes();
-            let length = (bytes.len() as u64).to_le_bytes().to_vec();
-            [length, bytes].concat()
+            match format {
+                RowFormat::V1 => [(bytes.len() as u64).to_le_bytes().to_vec(), bytes].concat(),
+                RowFormat::V2 => [(bytes.len() as u32).to_le_bytes().to_vec(), bytes].concat(),
+                RowFormat::V3 => {
+                    let (flag, bytes) = match zstd::bulk::compress(&bytes, 0) {
+                        Ok(compressed) if compressed.len() < bytes.len() => (1, compressed),
+                        _ => (0, bytes),
+                    };
+                    let length = (bytes.len() as u32).to_le_bytes();
+                    [&length[..], &[flag], &bytes].concat()
+                }
+            }
         };
 
         match self {
@@ -262,6 +797,9 @@ impl TypedValue {
             TypedValue::String(s) => convert_string(s),
             TypedValue::Serial(u) => u.to_le_bytes().to_vec(),
             TypedValue::Email(s) => convert_string(s),
+            TypedValue::Timestamp(t) => t.to_le_bytes().to_vec(),
+            TypedValue::Int32(i) => i.to_le_bytes().to_vec(),
+            TypedValue::Int16(i) => i.to_le_bytes().to_vec(),
         }
     }
 
@@ -280,13 +818,19 @@ impl TypedValue {
 
         match (&self, to) {
             (TypedValue::Int(i), DataType::Float) => Ok(TypedValue::Float(*i as f64)),
-            (TypedValue::Int(i), DataType::Serial) => Ok(TypedValue::Serial(*i as u32)),
+            (TypedValue::Int(i), DataType::Serial) => u32::try_from(*i)
+                .map(TypedValue::Serial)
+                .map_err(|_| PoorlyError::InvalidValue(self.clone(), to)),
             (TypedValue::String(s), DataType::Char) => string_to_char(s).map(TypedValue::Char),
             (TypedValue::String(s), DataType::Email) => Ok(TypedValue::Email(s.to_owned())),
             (TypedValue::String(s), DataType::Int) => s
                 .parse::<i64>()
                 .map(TypedValue::Int)
                 .map_err(|_| PoorlyError::InvalidValue(self, to)),
+            (TypedValue::String(s), DataType::Serial) => s
+                .parse::<u32>()
+                .map(TypedValue::Serial)
+                .map_err(|_| PoorlyError::InvalidValue(self, to)),
             (TypedValue::String(s), DataType::Float) => s
                 .parse::<f64>()
                 .map(TypedValue::Float)
@@ -309,6 +853,28 @@ impl TypedValue {
                 .map_err(|_| PoorlyError::InvalidValue(self, to)),
             (TypedValue::Email(s), DataType::String) => Ok(TypedValue::String(s.to_owned())),
             (TypedValue::Serial(i), DataType::Int) => Ok(TypedValue::Int(*i as i64)),
+            (TypedValue::Int(i), DataType::Timestamp) => Ok(TypedValue::Timestamp(*i)),
+            (TypedValue::String(s), DataType::Timestamp) => s
+                .parse::<i64>()
+                .map(TypedValue::Timestamp)
+                .map_err(|_| PoorlyError::InvalidValue(self, to)),
+            (TypedValue::Timestamp(t), DataType::Int) => Ok(TypedValue::Int(*t)),
+            (TypedValue::String(_) | TypedValue::Char(_), DataType::Int32 | DataType::Int16) => {
+                self.clone().coerce(DataType::Int)?.coerce(to)
+            }
+            (_, DataType::Int32) => self
+                .as_int()
+                .and_then(|i| i32::try_from(i).ok())
+                .map(TypedValue::Int32)
+                .ok_or_else(|| PoorlyError::InvalidValue(self.clone(), to)),
+            (_, DataType::Int16) => self
+                .as_int()
+                .and_then(|i| i16::try_from(i).ok())
+                .map(TypedValue::Int16)
+                .ok_or_else(|| PoorlyError::InvalidValue(self.clone(), to)),
+            (TypedValue::Int32(_) | TypedValue::Int16(_), _) => {
+                TypedValue::Int(self.as_int().unwrap()).coerce(to)
+            }
 
             (v, _) => Err(PoorlyError::InvalidValue(v.clone(), to)),
         }
@@ -327,6 +893,18 @@ impl From<f64> for TypedValue {
     }
 }
 
+impl From<i32> for TypedValue {
+    fn from(value: i32) -> Self {
+        TypedValue::Int32(value)
+    }
+}
+
+impl From<i16> for TypedValue {
+    fn from(value: i16) -> Self {
+        TypedValue::Int16(value)
+    }
+}
+
 impl From<u32> for TypedValue {
     fn from(value: u32) -> Self {
         TypedValue::Serial(value)
@@ -360,6 +938,9 @@ impl ToString for TypedValue {
             TypedValue::String(s) => s.to_string(),
             TypedValue::Serial(u) => u.to_string(),
             TypedValue::Email(e) => e.to_string(),
+            TypedValue::Timestamp(t) => t.to_string(),
+            TypedValue::Int32(i) => i.to_string(),
+            TypedValue::Int16(i) => i.to_string(),
         }
     }
 }
@@ -373,6 +954,9 @@ impl fmt::Debug for DataType {
             DataType::String => write!(f, "string"),
             DataType::Serial => write!(f, "serial"),
             DataType::Email => write!(f, "email"),
+            DataType::Timestamp => write!(f, "timestamp"),
+            DataType::Int32 => write!(f, "int32"),
+            DataType::Int16 => write!(f, "int16"),
         }
     }
 }
@@ -388,6 +972,9 @@ impl TryFrom<&str> for DataType {
             "string" => Ok(DataType::String),
             "serial" => Ok(DataType::Serial),
             "email" => Ok(DataType::Email),
+            "timestamp" => Ok(DataType::Timestamp),
+            "int32" => Ok(DataType::Int32),
+            "int16" => Ok(DataType::Int16),
             _ => Err(PoorlyError::InvalidDataType(s.to_string())),
         }
     }
@@ -402,16 +989,49 @@ impl From<i32> for DataType {
             3 => DataType::String,
             4 => DataType::Serial,
             5 => DataType::Email,
+            6 => DataType::Timestamp,
+            7 => DataType::Int32,
+            8 => DataType::Int16,
             _ => unreachable!("Invalid data type"),
         }
     }
 }
 
+/// How the value of a column is stored in a row
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnLayout {
+    /// Always this many bytes
+    Fixed(usize),
+    /// A little-endian length of `prefix` bytes, then, if `compression_flag`,
+    /// a byte that is 1 if the value is zstd-compressed, then the value
+    Variable {
+        prefix: usize,
+        compression_flag: bool,
+    },
+}
+
 impl DataType {
+    /// How values of the type are written in rows of `format`, see
+    /// `TypedValue::into_bytes`
+    pub fn layout(&self, format: RowFormat) -> ColumnLayout {
+        match self {
+            DataType::Int | DataType::Float | DataType::Timestamp => ColumnLayout::Fixed(8),
+            DataType::Serial | DataType::Int32 => ColumnLayout::Fixed(4),
+            DataType::Int16 => ColumnLayout::Fixed(2),
+            DataType::Char => ColumnLayout::Fixed(1),
+            DataType::String | DataType::Email => ColumnLayout::Variable {
+                prefix: if format == RowFormat::V1 { 8 } else { 4 },
+                compression_flag: format == RowFormat::V3,
+            },
+        }
+    }
+
     pub fn to_sql(&self) -> String {
         match self {
             DataType::Int => "INTEGER".to_string(),
             DataType::Float => "REAL".to_string(),
+            DataType::Timestamp | DataType::Int32 | DataType::Int16 => "INTEGER".to_string(),
             _ => "TEXT".to_string(),
         }
     }
@@ -0,0 +1,180 @@
+use super::*;
+
+#[test]
+fn string_round_trip() -> Result<(), io::Error> {
+    for format in [RowFormat::V1, RowFormat::V2, RowFormat::V3] {
+        for value in [
+            TypedValue::String("hello".to_string()),
+            TypedValue::Email("test@gmail.com".to_string()),
+            TypedValue::String("abc".repeat(100)),
+        ] {
+            let data_type = value.data_type();
+            let bytes = value.clone().into_bytes(format);
+            let read = TypedValue::read(data_type, &mut bytes.as_slice(), format)?;
+            assert_eq!(read, value);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn compact_string_length() {
+    let value = TypedValue::String("hello".to_string());
+
+    assert_eq!(value.clone().into_bytes(RowFormat::V1).len(), 8 + 5);
+    assert_eq!(value.into_bytes(RowFormat::V2).len(), 4 + 5);
+}
+
+#[test]
+fn compressed_string() {
+    // Short strings are kept as they are, behind the length and a flag byte
+    let short = TypedValue::String("hello".to_string());
+    assert_eq!(short.into_bytes(RowFormat::V3).len(), 4 + 1 + 5);
+
+    let long = TypedValue::String("abc".repeat(100));
+    let bytes = long.into_bytes(RowFormat::V3);
+    assert_eq!(bytes[4], 1);
+    assert!(bytes.len() < 100);
+}
+
+#[test]
+fn golden_bytes() -> Result<(), io::Error> {
+    let values = [
+        (
+            TypedValue::Int(-2),
+            vec![0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ),
+        (
+            TypedValue::Float(1.5),
+            vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf8, 0x3f],
+        ),
+        (TypedValue::Char('a'), vec![0x61]),
+        (TypedValue::Serial(258), vec![0x02, 0x01, 0x00, 0x00]),
+        (
+            TypedValue::String("hi".to_string()),
+            vec![0x02, 0x00, 0x00, 0x00, 0x68, 0x69],
+        ),
+        (
+            TypedValue::Timestamp(1),
+            vec![0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        ),
+    ];
+
+    for (value, bytes) in values {
+        assert_eq!(value.clone().into_bytes(RowFormat::V2), bytes);
+        let read = TypedValue::read(value.data_type(), &mut bytes.as_slice(), RowFormat::V2)?;
+        assert_eq!(read, value);
+    }
+
+    assert_eq!(
+        TypedValue::Email("a@b.cd".to_string()).into_bytes(RowFormat::V1),
+        [&[0x06, 0, 0, 0, 0, 0, 0, 0][..], b"a@b.cd"].concat()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn narrow_ints() -> Result<(), PoorlyError> {
+    assert_eq!(
+        TypedValue::Int(70_000).coerce(DataType::Int32)?,
+        TypedValue::Int32(70_000)
+    );
+    assert!(TypedValue::Int(70_000).coerce(DataType::Int16).is_err());
+    assert!(TypedValue::Int(i64::MAX).coerce(DataType::Int32).is_err());
+    assert_eq!(
+        TypedValue::String("-12".to_string()).coerce(DataType::Int16)?,
+        TypedValue::Int16(-12)
+    );
+    assert_eq!(
+        TypedValue::Int16(7).coerce(DataType::Float)?,
+        TypedValue::Float(7.0)
+    );
+
+    assert_eq!(TypedValue::Int32(1).into_bytes(RowFormat::V2).len(), 4);
+    assert_eq!(
+        TypedValue::Int16(-2).into_bytes(RowFormat::V2),
+        [0xfe, 0xff]
+    );
+
+    // Different widths compare by value
+    assert_eq!(TypedValue::Int16(5), TypedValue::Int(5));
+    assert!(TypedValue::Int32(-1) < TypedValue::Int16(3));
+    assert_ne!(TypedValue::Int(5), TypedValue::Float(5.0));
+
+    Ok(())
+}
+
+#[test]
+fn canonical_keys() {
+    let key = |value: f64| TypedValue::Float(value).canonical_key();
+    assert_eq!(key(-0.0), key(0.0));
+    assert_ne!(key(1.0), key(1.5));
+    assert_ne!(key(1.0), key(-1.0));
+    assert_eq!(key(f64::NAN), key(-f64::NAN));
+
+    // Equal values key equal, whatever their width
+    assert_eq!(
+        TypedValue::Int16(5).canonical_key(),
+        TypedValue::Int(5).canonical_key()
+    );
+    assert_ne!(
+        TypedValue::Int(5).canonical_key(),
+        TypedValue::Float(5.0).canonical_key()
+    );
+
+    let keys: std::collections::HashSet<_> = [0.0, -0.0, 2.5, 2.5].into_iter().map(key).collect();
+    assert_eq!(keys.len(), 2);
+}
+
+#[test]
+fn equal_column_sets() {
+    let row = |id: i64, price: f64| -> ColumnSet {
+        [
+            ("id".to_string(), TypedValue::Int(id)),
+            ("price".to_string(), TypedValue::Float(price)),
+        ]
+        .into()
+    };
+    assert!(column_sets_equal(&row(1, 0.0), &row(1, -0.0)));
+    assert!(!column_sets_equal(&row(1, 0.0), &row(2, 0.0)));
+
+    // A NaN equals any NaN, where `==` finds a row unequal to itself
+    let nan = row(1, f64::NAN);
+    assert_ne!(nan, nan);
+    assert!(column_sets_equal(&nan, &nan));
+    assert!(column_sets_equal(&nan, &row(1, -f64::NAN)));
+    assert!(!column_sets_equal(&nan, &row(1, 0.0)));
+
+    // Missing and extra columns make rows unequal
+    let mut wider = row(1, 0.0);
+    wider.insert("name".to_string(), TypedValue::from("x"));
+    assert!(!column_sets_equal(&row(1, 0.0), &wider));
+    assert!(!column_sets_equal(&wider, &row(1, 0.0)));
+}
+
+#[test]
+fn error_codes() {
+    let errors = [
+        (
+            PoorlyError::TableNotFound("users".into()),
+            "TABLE_NOT_FOUND",
+        ),
+        (
+            PoorlyError::ColumnNotFound("id".into(), "users".into()),
+            "COLUMN_NOT_FOUND",
+        ),
+        (PoorlyError::ResultTooLarge(10), "RESULT_TOO_LARGE"),
+        (
+            PoorlyError::IoError(io::ErrorKind::NotFound.into()),
+            "IO_ERROR",
+        ),
+    ];
+    for (error, code) in errors {
+        assert_eq!(error.code(), code);
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["code"], code);
+        assert_eq!(json["message"], error.to_string());
+    }
+}
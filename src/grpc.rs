@@ -1,14 +1,32 @@
 use proto::database_server::{self as service, DatabaseServer};
-use proto::{query, typed_value};
-use tonic::{transport::Server, Request, Response, Status};
+use proto::{predicate, query, typed_value};
+use tonic::{transport::Server, Code, Request, Response, Status};
 
-use crate::core::types::{ColumnSet, PoorlyError, Query, TypedValue};
+use crate::core::engine::{Change, ChangeKind};
+use crate::core::expression::Expression;
+use crate::core::predicate::{Predicate, Predicates};
+use crate::core::schema::ColumnDefault;
+use crate::core::types::{
+    now_millis, row_vectors, ColumnSet, OrderBy, PoorlyError, Query, QueryStats, TypedValue,
+};
 use crate::core::DatabaseEng;
+use crate::rate_limit::{RateLimiter, API_KEY_HEADER};
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
 
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tonic::codegen::tokio_stream::{wrappers::ReceiverStream, Stream};
+
+use session::{Sessions, SESSION_HEADER};
+
+pub mod session;
+
+#[cfg(test)]
+mod tests;
+
 #[allow(clippy::derive_partial_eq_without_eq)]
 pub mod proto {
     tonic::include_proto!("database");
@@ -16,66 +34,273 @@ pub mod proto {
 
 pub struct DatabaseService {
     db: Arc<dyn DatabaseEng>,
+    sessions: Sessions,
+}
+
+impl DatabaseService {
+    pub fn new(db: Arc<dyn DatabaseEng>) -> Self {
+        Self {
+            db,
+            sessions: Sessions::default(),
+        }
+    }
+
+    pub fn with_sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    // A snapshot no session uses any more
+    async fn release_snapshot(&self, snapshot: u64) {
+        if let Err(err) = self.db.execute(Query::ReleaseSnapshot { snapshot }).await {
+            log::warn!(target: "api::grpc", "Failed to release snapshot {}: {}", snapshot, err);
+        }
+    }
+}
+
+// Changes a subscriber's stream holds before the relay waits for it, after
+// which the engine's own buffer fills up and it starts missing changes
+const SUBSCRIBER_BUFFER: usize = 64;
+
+impl From<Change> for proto::Change {
+    fn from(change: Change) -> Self {
+        let kind = match change.kind {
+            ChangeKind::Insert => proto::change::Kind::Insert,
+            ChangeKind::Update => proto::change::Kind::Update,
+            ChangeKind::Delete => proto::change::Kind::Delete,
+        };
+        proto::Change {
+            kind: kind.into(),
+            db: change.db,
+            table: change.table,
+            row: change.row.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            missed: 0,
+        }
+    }
 }
 
 #[tonic::async_trait]
 impl service::Database for DatabaseService {
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<proto::Change, Status>> + Send>>;
+
     async fn execute(
         &self,
         request: Request<proto::Query>,
     ) -> Result<Response<proto::Reply>, Status> {
-        let query = request.into_inner();
+        let session = match request.metadata().get(SESSION_HEADER) {
+            Some(id) => {
+                let id = id
+                    .to_str()
+                    .map_err(|_| Status::invalid_argument("Invalid session id"))?;
+                if !self.sessions.touch(id) {
+                    return Err(Status::not_found(format!("Session {} not found", id)));
+                }
+                Some(id.to_string())
+            }
+            None => None,
+        };
+
+        let proto::Query {
+            query,
+            params: query_params,
+        } = request.into_inner();
         let db = Arc::clone(&self.db);
-        if let Some(query) = query.query {
-            let query = query.into();
+        if let Some(mut query) = query.filter(|query| !is_empty(query)) {
+            bind_params(&mut query, &query_params)
+                .map_err(|n| Status::invalid_argument(format!("No value for parameter ${}", n)))?;
+            let vectors = match &query {
+                query::Query::Select(select) if select.as_vectors => Some(select.columns.clone()),
+                _ => None,
+            };
+            let with_types = matches!(&query, query::Query::Select(select) if select.with_types);
+            let mut query: Query = query.try_into()?;
+            // Selects under a session are made in its snapshot, unless they name one
+            if let (Query::Select { snapshot, .. }, Some(id)) = (&mut query, &session) {
+                if snapshot.is_none() {
+                    *snapshot = self.sessions.snapshot(id);
+                }
+            }
+            let opens_snapshot = matches!(query, Query::Snapshot { .. });
             log::info!(target: "api::grpc", "Executing query: {:?}", &query);
-            match db.execute(query).await {
-                Ok(result) => Ok(Response::new(result.into())),
+            let types = if with_types {
+                db.column_types(query.clone()).await?
+            } else {
+                HashMap::new()
+            };
+            match db.execute_with_stats(query).await {
+                Ok((result, stats)) => {
+                    if let (true, Some(id)) = (opens_snapshot, &session) {
+                        if let Some(TypedValue::Int(snapshot)) = result[0].get("snapshot") {
+                            if let Some(old) = self.sessions.set_snapshot(id, *snapshot as u64) {
+                                self.release_snapshot(old).await;
+                            }
+                        }
+                    }
+                    let mut reply = match vectors {
+                        Some(columns) => proto::Reply::vectors(columns, result),
+                        None => result.into(),
+                    };
+                    reply.stats = Some(stats.into());
+                    reply.types = types
+                        .into_iter()
+                        .map(|(column, data_type)| (column, data_type.into()))
+                        .collect();
+                    Ok(Response::new(reply))
+                }
                 Err(err) => Err(err.into()),
             }
         } else {
             Err(Status::invalid_argument("Query is empty"))
         }
     }
+
+    async fn now(
+        &self,
+        _request: Request<proto::TimeRequest>,
+    ) -> Result<Response<proto::ServerTime>, Status> {
+        Ok(Response::new(proto::ServerTime {
+            millis: now_millis(),
+        }))
+    }
+
+    async fn open_session(
+        &self,
+        _request: Request<proto::NewSession>,
+    ) -> Result<Response<proto::Session>, Status> {
+        match self.sessions.open() {
+            Some(id) => Ok(Response::new(proto::Session { id })),
+            None => Err(Status::resource_exhausted(
+                "Too many sessions are open, close some first",
+            )),
+        }
+    }
+
+    async fn close_session(
+        &self,
+        request: Request<proto::Session>,
+    ) -> Result<Response<proto::SessionClosed>, Status> {
+        let id = request.into_inner().id;
+        match self.sessions.close(&id) {
+            Some(session) => {
+                if let Some(snapshot) = session.snapshot {
+                    self.release_snapshot(snapshot).await;
+                }
+                Ok(Response::new(proto::SessionClosed {
+                    queries: session.queries,
+                }))
+            }
+            None => Err(Status::not_found(format!("Session {} not found", id))),
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::Subscription>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let proto::Subscription { db, table } = request.into_inner();
+        let mut changes = self.db.subscribe().await;
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        log::info!(target: "api::grpc", "Subscribing to changes of {} {:?}", db, table);
+
+        // Relays until the subscriber hangs up
+        tokio::spawn(async move {
+            loop {
+                let received = tokio::select! {
+                    received = changes.recv() => received,
+                    _ = sender.closed() => break,
+                };
+                let change = match received {
+                    Ok(change) if change.db != db => continue,
+                    Ok(change) if table.as_ref().is_some_and(|table| *table != change.table) => {
+                        continue
+                    }
+                    Ok(change) => change.into(),
+                    Err(RecvError::Lagged(missed)) => proto::Change {
+                        kind: proto::change::Kind::Lagged.into(),
+                        db: db.clone(),
+                        missed,
+                        ..Default::default()
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                if sender.send(Ok(change)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
 }
 
+// `Status` is what tonic interceptors fail with
+#[allow(clippy::result_large_err)]
 pub async fn serve(
     db: Arc<dyn DatabaseEng>,
     address: impl Into<SocketAddr>,
+    limiter: Option<Arc<RateLimiter>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let service = DatabaseService { db };
+    let service = DatabaseService::new(db);
     let address = address.into();
 
     log::info!(target: "api::grpc", "Starting gRPC server on {}", address);
 
     Server::builder()
-        .add_service(DatabaseServer::new(service))
+        .add_service(DatabaseServer::with_interceptor(service, move |request| {
+            throttle(limiter.as_deref(), request)
+        }))
         .serve(address)
         .await?;
 
     Ok(())
 }
 
+// Refuses requests of clients over their rate limit
+#[allow(clippy::result_large_err)]
+fn throttle(limiter: Option<&RateLimiter>, request: Request<()>) -> Result<Request<()>, Status> {
+    let Some(limiter) = limiter else {
+        return Ok(request);
+    };
+    let api_key = request
+        .metadata()
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok());
+    let client = limiter.client_key(api_key, request.remote_addr().map(|a| a.ip()));
+    if limiter.allow(&client) {
+        Ok(request)
+    } else {
+        Err(Status::resource_exhausted("Too many requests"))
+    }
+}
+
 impl From<PoorlyError> for Status {
+    // The error's `code` is sent in the details so clients don't have to
+    // match on the message
     fn from(err: PoorlyError) -> Self {
-        match &err {
-            PoorlyError::TableNotFound(_) => Status::not_found(err.to_string()),
-            PoorlyError::ColumnNotFound(_, _) => Status::not_found(err.to_string()),
-            PoorlyError::TableAlreadyExists(_) => Status::already_exists(err.to_string()),
-            PoorlyError::ColumnAlreadyExists(_, _) => Status::already_exists(err.to_string()),
-            PoorlyError::NoColumns => Status::invalid_argument(err.to_string()),
-            PoorlyError::InvalidName(_) => Status::invalid_argument(err.to_string()),
-            PoorlyError::InvalidValue(_, _) => Status::invalid_argument(err.to_string()),
-            PoorlyError::InvalidDataType(_) => Status::invalid_argument(err.to_string()),
-            PoorlyError::IncompleteData(_, _) => Status::invalid_argument(err.to_string()),
-            PoorlyError::SqlError(_) => Status::invalid_argument(err.to_string()),
-            PoorlyError::IoError(_) => Status::internal(err.to_string()),
-            PoorlyError::DatabaseNotFound(_) => Status::not_found(err.to_string()),
-            PoorlyError::DatabaseAlreadyExists(_) => Status::already_exists(err.to_string()),
-            PoorlyError::InvalidOperation(_) => Status::invalid_argument(err.to_string()),
-            PoorlyError::InvalidEmail => Status::invalid_argument(err.to_string()),
-            PoorlyError::CannotDropDefaultDb => Status::invalid_argument(err.to_string()),
-        }
+        let code = match &err {
+            PoorlyError::TableNotFound(_) => Code::NotFound,
+            PoorlyError::ColumnNotFound(_, _) => Code::NotFound,
+            PoorlyError::TableAlreadyExists(_) => Code::AlreadyExists,
+            PoorlyError::ColumnAlreadyExists(_, _) => Code::AlreadyExists,
+            PoorlyError::NoColumns => Code::InvalidArgument,
+            PoorlyError::InvalidName(_) => Code::InvalidArgument,
+            PoorlyError::InvalidValue(_, _) => Code::InvalidArgument,
+            PoorlyError::InvalidDataType(_) => Code::InvalidArgument,
+            PoorlyError::IncompleteData(_, _) => Code::InvalidArgument,
+            PoorlyError::SqlError(_) => Code::InvalidArgument,
+            PoorlyError::IoError(_) => Code::Internal,
+            PoorlyError::SchemaRowMismatch(_) => Code::DataLoss,
+            PoorlyError::SchemaCorrupted(_) => Code::DataLoss,
+            PoorlyError::IncompatibleSchema(_) => Code::FailedPrecondition,
+            PoorlyError::DatabaseNotFound(_) => Code::NotFound,
+            PoorlyError::DatabaseAlreadyExists(_) => Code::AlreadyExists,
+            PoorlyError::InvalidOperation(_) => Code::InvalidArgument,
+            PoorlyError::ResultTooLarge(_) => Code::ResourceExhausted,
+            PoorlyError::RowNotFound(_) => Code::NotFound,
+            PoorlyError::TooManyRows(_) => Code::FailedPrecondition,
+            PoorlyError::InvalidEmail => Code::InvalidArgument,
+            PoorlyError::CannotDropDefaultDb => Code::InvalidArgument,
+        };
+        Status::with_details(code, err.to_string(), err.code().into())
     }
 }
 
@@ -88,99 +313,550 @@ impl From<Vec<ColumnSet>> for proto::Reply {
                     data: row.into_iter().map(|(k, v)| (k, v.into())).collect(),
                 })
                 .collect(),
+            stats: None,
+            ..Default::default()
         }
     }
 }
 
-impl From<proto::Reply> for Vec<ColumnSet> {
-    fn from(reply: proto::Reply) -> Self {
+impl proto::Reply {
+    // Without `columns`, rows hold all their columns, sorted by name.
+    // Names are taken from the rows, as the server may ignore their case.
+    fn vectors(columns: Vec<String>, rows: Vec<ColumnSet>) -> Self {
+        let columns = match rows.first() {
+            Some(row) if columns.is_empty() => {
+                let mut columns: Vec<String> = row.keys().cloned().collect();
+                columns.sort();
+                columns
+            }
+            Some(row) => columns
+                .into_iter()
+                .map(|column| {
+                    if row.contains_key(&column) {
+                        return column;
+                    }
+                    row.keys()
+                        .find(|key| key.eq_ignore_ascii_case(&column))
+                        .cloned()
+                        .unwrap_or(column)
+                })
+                .collect(),
+            None => columns,
+        };
+        let vectors = row_vectors(&columns, rows)
+            .into_iter()
+            .map(|values| proto::reply::Vector {
+                values: values.into_iter().map(Into::into).collect(),
+            })
+            .collect();
+        proto::Reply {
+            columns,
+            vectors,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<QueryStats> for proto::QueryStats {
+    fn from(stats: QueryStats) -> Self {
+        proto::QueryStats {
+            rows_scanned: stats.rows_scanned,
+            rows_matched: stats.rows_matched,
+            bytes_stored: stats.bytes_stored,
+            bytes_raw: stats.bytes_raw,
+        }
+    }
+}
+
+impl TryFrom<proto::Reply> for Vec<ColumnSet> {
+    type Error = Status;
+
+    #[allow(clippy::result_large_err)]
+    fn try_from(reply: proto::Reply) -> Result<Self, Status> {
         reply
             .rows
             .into_iter()
             .map(|row| {
                 row.data
                     .into_iter()
-                    .filter_map(|(k, v)| v.data.map(|v| (k, v.into())))
+                    .filter_map(|(k, v)| v.data.map(|v| Ok((k, v.try_into()?))))
                     .collect()
             })
             .collect()
     }
 }
 
-impl From<proto::query::Query> for Query {
-    fn from(query: query::Query) -> Self {
+// A `CreateAs` without the query to create the table from
+fn is_empty(query: &query::Query) -> bool {
+    match query {
+        query::Query::CreateAs(create) => create
+            .select
+            .as_ref()
+            .and_then(|select| select.query.as_ref())
+            .is_none_or(is_empty),
+        _ => false,
+    }
+}
+
+fn bind_predicate(
+    predicate: &mut proto::Predicate,
+    bind: &impl Fn(&mut proto::TypedValue) -> Result<(), u32>,
+) -> Result<(), u32> {
+    match &mut predicate.predicate {
+        Some(
+            predicate::Predicate::Eq(v)
+            | predicate::Predicate::Ne(v)
+            | predicate::Predicate::Lt(v)
+            | predicate::Predicate::Le(v)
+            | predicate::Predicate::Gt(v)
+            | predicate::Predicate::Ge(v),
+        ) => bind(v),
+        Some(predicate::Predicate::Between(range)) => range
+            .low
+            .iter_mut()
+            .chain(range.high.iter_mut())
+            .try_for_each(bind),
+        Some(predicate::Predicate::In(v)) => v.values.iter_mut().try_for_each(bind),
+        Some(predicate::Predicate::All(list)) => list
+            .predicates
+            .iter_mut()
+            .try_for_each(|predicate| bind_predicate(predicate, bind)),
+        Some(predicate::Predicate::Len(predicate)) => bind_predicate(predicate, bind),
+        Some(predicate::Predicate::Like(_)) | None => Ok(()),
+    }
+}
+
+fn bind_expression(
+    expression: &mut proto::Expression,
+    bind: &impl Fn(&mut proto::TypedValue) -> Result<(), u32>,
+) -> Result<(), u32> {
+    use proto::expression::Expression as Kind;
+    match &mut expression.expression {
+        Some(Kind::Literal(value)) => bind(value),
+        Some(
+            Kind::Add(operands)
+            | Kind::Sub(operands)
+            | Kind::Mul(operands)
+            | Kind::Div(operands)
+            | Kind::Mod(operands),
+        ) => operands
+            .left
+            .iter_mut()
+            .chain(operands.right.iter_mut())
+            .try_for_each(|operand| bind_expression(operand, bind)),
+        Some(Kind::Column(_)) | None => Ok(()),
+    }
+}
+
+// Replaces `param` placeholders in `query` with the values they refer to.
+// Fails with the number of the first placeholder that has no value.
+fn bind_params(query: &mut query::Query, params: &[proto::TypedValue]) -> Result<(), u32> {
+    let bind = |value: &mut proto::TypedValue| -> Result<(), u32> {
+        if let Some(typed_value::Data::Param(n)) = value.data {
+            let param = (n as usize)
+                .checked_sub(1)
+                .and_then(|i| params.get(i))
+                .filter(|param| !matches!(param.data, Some(typed_value::Data::Param(_))))
+                .ok_or(n)?;
+            *value = param.clone();
+        }
+        Ok(())
+    };
+    let values = |values: &mut HashMap<String, proto::TypedValue>| -> Result<(), u32> {
+        values.values_mut().try_for_each(bind)
+    };
+    let predicates = |predicates: &mut HashMap<String, proto::Predicate>| -> Result<(), u32> {
+        predicates
+            .values_mut()
+            .try_for_each(|predicate| bind_predicate(predicate, &bind))
+    };
+
+    match query {
+        query::Query::Select(q) => {
+            values(&mut q.conditions)?;
+            predicates(&mut q.predicates)?;
+            predicates(&mut q.post_filter)?;
+            for select in q.in_select.values_mut() {
+                let mut inner = query::Query::Select(std::mem::take(select));
+                let bound = bind_params(&mut inner, params);
+                if let query::Query::Select(inner) = inner {
+                    *select = inner;
+                }
+                bound?;
+            }
+            Ok(())
+        }
+        query::Query::SelectOne(q) => values(&mut q.conditions),
+        query::Query::Count(q) => values(&mut q.conditions),
+        query::Query::SelectDeleted(q) => values(&mut q.conditions),
+        query::Query::Insert(q) => values(&mut q.values),
+        query::Query::Update(q) => {
+            values(&mut q.set)?;
+            for expression in q.expressions.values_mut() {
+                bind_expression(expression, &bind)?;
+            }
+            values(&mut q.conditions)?;
+            predicates(&mut q.predicates)
+        }
+        query::Query::Delete(q) => {
+            values(&mut q.conditions)?;
+            predicates(&mut q.predicates)
+        }
+        query::Query::Join(q) => {
+            values(&mut q.conditions)?;
+            predicates(&mut q.post_filter)
+        }
+        query::Query::First(q) => predicates(&mut q.predicates),
+        query::Query::CopyInto(q) => values(&mut q.conditions),
+        query::Query::DeleteKeys(q) => q.keys.iter_mut().try_for_each(bind),
+        query::Query::CreateAs(q) => match q.select.as_mut().and_then(|s| s.query.as_mut()) {
+            Some(select) => bind_params(select, params),
+            None => Ok(()),
+        },
+        query::Query::Create(_)
+        | query::Query::CreateDb(_)
+        | query::Query::Drop(_)
+        | query::Query::DropDb(_)
+        | query::Query::ConvertDb(_)
+        | query::Query::Alter(_)
+        | query::Query::ShowTables(_)
+        | query::Query::ShowCreate(_)
+        | query::Query::DescribeDb(_)
+        | query::Query::Comment(_)
+        | query::Query::Repair(_)
+        | query::Query::CheckIntegrity(_)
+        | query::Query::ReloadSchema(_)
+        | query::Query::Vacuum(_)
+        | query::Query::Flush(_)
+        | query::Query::Snapshot(_)
+        | query::Query::ReleaseSnapshot(_)
+        | query::Query::Sample(_)
+        | query::Query::Undelete(_) => Ok(()),
+    }
+}
+
+// Refused if any part of the expression is missing, as the update would
+// otherwise succeed without setting the column
+#[allow(clippy::result_large_err)]
+fn convert_expression(column: &str, expression: proto::Expression) -> Result<Expression, Status> {
+    use proto::expression::{Expression as Kind, Operands};
+    let incomplete =
+        || Status::invalid_argument(format!("Incomplete expression for column {}", column));
+    let both = |operator: fn(Box<Expression>, Box<Expression>) -> Expression,
+                operands: Box<Operands>| {
+        let left = convert_expression(column, *operands.left.ok_or_else(incomplete)?)?;
+        let right = convert_expression(column, *operands.right.ok_or_else(incomplete)?)?;
+        Ok(operator(Box::new(left), Box::new(right)))
+    };
+    match expression.expression.ok_or_else(incomplete)? {
+        Kind::Column(column) => Ok(Expression::Column(column)),
+        Kind::Literal(value) => Ok(Expression::Literal(
+            value.data.ok_or_else(incomplete)?.try_into()?,
+        )),
+        Kind::Add(operands) => both(Expression::Add, operands),
+        Kind::Sub(operands) => both(Expression::Sub, operands),
+        Kind::Mul(operands) => both(Expression::Mul, operands),
+        Kind::Div(operands) => both(Expression::Div, operands),
+        Kind::Mod(operands) => both(Expression::Mod, operands),
+    }
+}
+
+// Predicates with a missing operand are refused, as dropping one would make
+// an update or delete match more rows than asked for
+#[allow(clippy::result_large_err)]
+fn convert_predicates(predicates: HashMap<String, proto::Predicate>) -> Result<Predicates, Status> {
+    predicates
+        .into_iter()
+        .map(|(column, p)| match convert_predicate(p) {
+            Some(predicate) => Ok((column, predicate)),
+            None => Err(Status::invalid_argument(format!(
+                "Incomplete predicate on column {}",
+                column
+            ))),
+        })
+        .collect()
+}
+
+fn convert_predicate(p: proto::Predicate) -> Option<Predicate> {
+    let value = |value: Option<proto::TypedValue>| -> Option<TypedValue> {
+        value.and_then(|v| v.data)?.try_into().ok()
+    };
+
+    let predicate = match p.predicate? {
+        predicate::Predicate::Eq(v) => Predicate::Eq(value(Some(v))?),
+        predicate::Predicate::Ne(v) => Predicate::Ne(value(Some(v))?),
+        predicate::Predicate::Lt(v) => Predicate::Lt(value(Some(v))?),
+        predicate::Predicate::Le(v) => Predicate::Le(value(Some(v))?),
+        predicate::Predicate::Gt(v) => Predicate::Gt(value(Some(v))?),
+        predicate::Predicate::Ge(v) => Predicate::Ge(value(Some(v))?),
+        predicate::Predicate::Between(range) => {
+            Predicate::Between(value(range.low)?, value(range.high)?)
+        }
+        predicate::Predicate::In(values) => Predicate::In(
+            values
+                .values
+                .into_iter()
+                .map(|v| value(Some(v)))
+                .collect::<Option<_>>()?,
+        ),
+        predicate::Predicate::Like(pattern) => Predicate::Like(pattern),
+        predicate::Predicate::All(list) => Predicate::All(
+            list.predicates
+                .into_iter()
+                .map(convert_predicate)
+                .collect::<Option<_>>()?,
+        ),
+        predicate::Predicate::Len(predicate) => {
+            Predicate::Len(Box::new(convert_predicate(*predicate)?))
+        }
+    };
+    Some(predicate)
+}
+
+impl TryFrom<proto::query::Query> for Query {
+    type Error = Status;
+
+    #[allow(clippy::result_large_err)]
+    fn try_from(query: query::Query) -> Result<Self, Status> {
         let convert = |field_set: HashMap<String, proto::TypedValue>| {
             field_set
                 .into_iter()
-                .filter_map(|(k, v)| v.data.map(|v| (k, v.into())))
-                .collect()
+                .filter_map(|(k, v)| v.data.map(|v| Ok((k, v.try_into()?))))
+                .collect::<Result<_, Status>>()
         };
 
-        match query {
+        let query = match query {
             query::Query::Select(select) => Query::Select {
                 db: select.db,
                 from: select.from,
                 columns: select.columns,
-                conditions: convert(select.conditions),
+                conditions: convert(select.conditions)?,
+                predicates: convert_predicates(select.predicates)?,
+                order_by: select
+                    .order_by
+                    .into_iter()
+                    .map(|order| OrderBy {
+                        column: order.column,
+                        descending: order.descending,
+                    })
+                    .collect(),
+                distinct_on: select.distinct_on,
+                max_rows: select.max_rows.map(|n| n as usize),
+                since_serial: select.since_serial,
+                post_filter: convert_predicates(select.post_filter)?,
+                include_deleted: select.include_deleted,
+                snapshot: select.snapshot,
+                in_select: select
+                    .in_select
+                    .into_iter()
+                    .map(|(column, select)| Ok((column, query::Query::Select(select).try_into()?)))
+                    .collect::<Result<_, Status>>()?,
+            },
+            query::Query::SelectOne(select) => Query::SelectOne {
+                db: select.db,
+                from: select.from,
+                conditions: convert(select.conditions)?,
+            },
+            query::Query::Count(count) => Query::Count {
+                db: count.db,
+                from: count.from,
+                conditions: convert(count.conditions)?,
+            },
+            query::Query::SelectDeleted(select) => Query::SelectDeleted {
+                db: select.db,
+                from: select.from,
+                conditions: convert(select.conditions)?,
+            },
+            query::Query::Undelete(undelete) => Query::Undelete {
+                db: undelete.db,
+                table: undelete.table,
+                offset: undelete.offset,
             },
             query::Query::Insert(insert) => Query::Insert {
                 db: insert.db,
                 into: insert.into,
-                values: convert(insert.values),
+                values: convert(insert.values)?,
+                with_offset: insert.with_offset,
+                returning: insert.returning,
             },
             query::Query::Update(update) => Query::Update {
                 db: update.db,
                 table: update.table,
-                set: convert(update.set),
-                conditions: convert(update.conditions),
+                set: convert(update.set)?,
+                expressions: update
+                    .expressions
+                    .into_iter()
+                    .map(|(column, e)| {
+                        let expression = convert_expression(&column, e)?;
+                        Ok((column, expression))
+                    })
+                    .collect::<Result<_, Status>>()?,
+                conditions: convert(update.conditions)?,
+                predicates: convert_predicates(update.predicates)?,
+                with_offset: update.with_offset,
+                limit: update.limit.map(|n| n as usize),
             },
             query::Query::Delete(delete) => Query::Delete {
                 db: delete.db,
                 from: delete.from,
-                conditions: convert(delete.conditions),
+                conditions: convert(delete.conditions)?,
+                predicates: convert_predicates(delete.predicates)?,
+                limit: delete.limit.map(|n| n as usize),
+                compact: delete.compact,
             },
             query::Query::Create(create) => Query::Create {
                 db: create.db,
                 table: create.table,
+                // Repeated names are kept, the schema refuses them
                 columns: create
                     .columns
                     .into_iter()
-                    .map(|(k, v)| (k, v.into()))
+                    .map(|column| (column.name, column.data_type.into()))
                     .collect(),
+                defaults: create
+                    .defaults
+                    .into_iter()
+                    .map(|(k, v)| match proto::ColumnDefault::try_from(v) {
+                        Ok(proto::ColumnDefault::Now) => Ok((k, ColumnDefault::Now)),
+                        Ok(proto::ColumnDefault::AutoUpdate) => Ok((k, ColumnDefault::AutoUpdate)),
+                        Ok(proto::ColumnDefault::AutoIncrement) => {
+                            Ok((k, ColumnDefault::AutoIncrement))
+                        }
+                        Err(_) => Err(Status::invalid_argument(format!(
+                            "Unknown default {} for column {}",
+                            v, k
+                        ))),
+                    })
+                    .collect::<Result<_, Status>>()?,
+                compressed: create.compressed,
+                if_not_exists: create.if_not_exists,
+                segment_size: create.segment_size,
             },
             query::Query::CreateDb(createDb) => Query::CreateDb { name: createDb.db },
             query::Query::Drop(drop) => Query::Drop {
                 db: drop.db,
                 table: drop.table,
+                if_exists: drop.if_exists,
             },
             query::Query::DropDb(dropDb) => Query::DropDb { name: dropDb.db },
+            query::Query::ConvertDb(convert) => Query::ConvertDb {
+                name: convert.db,
+                to_kind: convert.to_kind.parse().map_err(Status::invalid_argument)?,
+                swap: convert.swap,
+            },
             query::Query::Alter(alter) => Query::Alter {
                 db: alter.db,
                 table: alter.table,
                 rename: alter.rename,
             },
             query::Query::ShowTables(show) => Query::ShowTables { db: show.db },
+            query::Query::DescribeDb(describe) => Query::DescribeDb { db: describe.db },
+            query::Query::ShowCreate(show) => Query::ShowCreate {
+                db: show.db,
+                table: show.table,
+            },
+            query::Query::Comment(comment) => Query::Comment {
+                db: comment.db,
+                table: comment.table,
+                column: comment.column,
+                text: comment.text,
+            },
             query::Query::Join(join) => Query::Join {
                 db: join.db,
                 table1: join.table1,
                 table2: join.table2,
                 columns: join.columns,
-                conditions: convert(join.conditions),
+                conditions: convert(join.conditions)?,
                 join_on: join.join_on,
+                using: join.using,
+                max_rows: join.max_rows.map(|n| n as usize),
+                post_filter: convert_predicates(join.post_filter)?,
+                limit: join.limit.map(|n| n as usize),
             },
-        }
+            query::Query::Repair(repair) => Query::Repair { db: repair.db },
+            query::Query::CheckIntegrity(check) => Query::CheckIntegrity { db: check.db },
+            query::Query::ReloadSchema(reload) => Query::ReloadSchema { db: reload.db },
+            // Keys without data are dropped, like conditions without data
+            query::Query::DeleteKeys(delete) => Query::DeleteKeys {
+                db: delete.db,
+                from: delete.from,
+                column: delete.column,
+                keys: delete
+                    .keys
+                    .into_iter()
+                    .filter_map(|key| key.data.map(TypedValue::try_from))
+                    .collect::<Result<_, Status>>()?,
+            },
+            query::Query::Vacuum(vacuum) => Query::Vacuum {
+                db: vacuum.db,
+                table: vacuum.table,
+            },
+            query::Query::Flush(flush) => Query::Flush {
+                db: flush.db,
+                close: flush.close,
+            },
+            query::Query::Snapshot(snapshot) => Query::Snapshot { db: snapshot.db },
+            query::Query::ReleaseSnapshot(release) => Query::ReleaseSnapshot {
+                snapshot: release.snapshot,
+            },
+            query::Query::Sample(sample) => Query::Sample {
+                db: sample.db,
+                table: sample.table,
+                n: sample.n,
+            },
+            query::Query::First(first) => Query::First {
+                db: first.db,
+                from: first.from,
+                predicates: convert_predicates(first.predicates)?,
+                order_by: OrderBy {
+                    column: first.column,
+                    descending: first.descending,
+                },
+            },
+            // `execute` rejects a `CreateAs` without a select
+            query::Query::CreateAs(create) => Query::CreateAs {
+                db: create.db,
+                table: create.table,
+                select: Box::new(create.select.and_then(|s| s.query).unwrap().try_into()?),
+            },
+            query::Query::CopyInto(copy) => Query::CopyInto {
+                db: copy.db,
+                src: copy.src,
+                dst: copy.dst,
+                conditions: convert(copy.conditions)?,
+                column_map: copy.column_map,
+            },
+        };
+        Ok(query)
     }
 }
 
-impl From<typed_value::Data> for TypedValue {
-    fn from(data: typed_value::Data) -> Self {
-        match data {
+impl TryFrom<typed_value::Data> for TypedValue {
+    type Error = Status;
+
+    #[allow(clippy::result_large_err)]
+    fn try_from(data: typed_value::Data) -> Result<Self, Status> {
+        let value = match data {
             typed_value::Data::Int(i) => TypedValue::Int(i),
             typed_value::Data::Float(f) => TypedValue::Float(f),
             typed_value::Data::String(s) => TypedValue::String(s),
             typed_value::Data::Serial(u) => TypedValue::Serial(u),
             typed_value::Data::Email(e) => TypedValue::Email(e),
-        }
+            typed_value::Data::Timestamp(t) => TypedValue::Timestamp(t),
+            typed_value::Data::Int32(i) => TypedValue::Int32(i),
+            // Out of range values are left for `coerce` to reject
+            typed_value::Data::Int16(i) => i16::try_from(i)
+                .map(TypedValue::Int16)
+                .unwrap_or(TypedValue::Int(i as i64)),
+            // `bind_params` replaces every placeholder it has a value for
+            typed_value::Data::Param(n) => {
+                return Err(Status::invalid_argument(format!(
+                    "No value for parameter ${}",
+                    n
+                )))
+            }
+        };
+        Ok(value)
     }
 }
 
@@ -205,6 +881,15 @@ impl From<TypedValue> for proto::TypedValue {
             TypedValue::Email(e) => proto::TypedValue {
                 data: Some(typed_value::Data::Email(e)),
             },
+            TypedValue::Timestamp(t) => proto::TypedValue {
+                data: Some(typed_value::Data::Timestamp(t)),
+            },
+            TypedValue::Int32(i) => proto::TypedValue {
+                data: Some(typed_value::Data::Int32(i)),
+            },
+            TypedValue::Int16(i) => proto::TypedValue {
+                data: Some(typed_value::Data::Int16(i as i32)),
+            },
         }
     }
 }
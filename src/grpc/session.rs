@@ -0,0 +1,141 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Metadata key a request names its session with
+pub const SESSION_HEADER: &str = "x-session-id";
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// Sessions that may be open at once, idle ones included
+pub const MAX_SESSIONS: usize = 10_000;
+
+/// State kept for a client between requests: its activity and the snapshot
+/// its selects are made in. Transactions and cursors will live here too.
+#[derive(Debug)]
+pub struct Session {
+    last_used: Instant,
+    pub queries: u64,
+    // Last snapshot opened under the session, see `Query::Snapshot`
+    pub snapshot: Option<u64>,
+}
+
+/// Open sessions by id. Sessions idle for longer than `idle_timeout` are
+/// evicted the next time the registry is touched.
+#[derive(Debug)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    idle_timeout: Duration,
+    max_sessions: usize,
+}
+
+impl Sessions {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            idle_timeout,
+            max_sessions: MAX_SESSIONS,
+        }
+    }
+
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+
+    /// Opens a session under a new random id. Returns `None` if
+    /// `max_sessions` are open already.
+    pub fn open(&self) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.evict_idle(&mut sessions);
+        if sessions.len() >= self.max_sessions {
+            log::warn!(target: "api::grpc", "Refused a session, {} are open", sessions.len());
+            return None;
+        }
+
+        let id = loop {
+            let mut bytes = [0; 16];
+            OsRng.fill_bytes(&mut bytes);
+            let id = format!("{:032x}", u128::from_be_bytes(bytes));
+            if !sessions.contains_key(&id) {
+                break id;
+            }
+        };
+
+        log::info!(target: "api::grpc", "Opened session {}", id);
+        sessions.insert(
+            id.clone(),
+            Session {
+                last_used: Instant::now(),
+                queries: 0,
+                snapshot: None,
+            },
+        );
+        Some(id)
+    }
+
+    /// Records a query made under session `id`. Returns `false` if there is
+    /// no such session or it has expired.
+    pub fn touch(&self, id: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.evict_idle(&mut sessions);
+
+        match sessions.get_mut(id) {
+            Some(session) => {
+                session.last_used = Instant::now();
+                session.queries += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn snapshot(&self, id: &str) -> Option<u64> {
+        self.sessions.lock().unwrap().get(id)?.snapshot
+    }
+
+    /// Makes `snapshot` the one the selects of session `id` are made in.
+    /// Returns the one it replaces, which is no longer used.
+    pub fn set_snapshot(&self, id: &str, snapshot: u64) -> Option<u64> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get_mut(id)?
+            .snapshot
+            .replace(snapshot)
+    }
+
+    pub fn close(&self, id: &str) -> Option<Session> {
+        let session = self.sessions.lock().unwrap().remove(id);
+        if session.is_some() {
+            log::info!(target: "api::grpc", "Closed session {}", id);
+        }
+        session
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn evict_idle(&self, sessions: &mut HashMap<String, Session>) {
+        sessions.retain(|id, session| {
+            let alive = session.last_used.elapsed() < self.idle_timeout;
+            if !alive {
+                log::info!(target: "api::grpc", "Session {} expired", id);
+            }
+            alive
+        });
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDLE_TIMEOUT)
+    }
+}
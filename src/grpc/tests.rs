@@ -0,0 +1,694 @@
+use super::*;
+use crate::core::database::DEFAULT_DB;
+use crate::core::types::DataType;
+use crate::core::Poorly;
+use service::Database;
+
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+fn database_service(dir: &tempfile::TempDir) -> DatabaseService {
+    let poorly = Poorly::open(dir.path().to_path_buf());
+    poorly.init().unwrap();
+    DatabaseService::new(Arc::new(Mutex::new(poorly)))
+}
+
+fn column(name: &str, data_type: DataType) -> proto::Column {
+    proto::Column {
+        name: name.to_string(),
+        data_type: data_type.into(),
+    }
+}
+
+fn show_tables(session: Option<&str>) -> Request<proto::Query> {
+    let mut request = Request::new(proto::Query {
+        query: Some(query::Query::ShowTables(proto::ShowTables {
+            db: DEFAULT_DB.to_string(),
+        })),
+        params: vec![],
+    });
+    if let Some(id) = session {
+        request
+            .metadata_mut()
+            .insert(SESSION_HEADER, id.parse().unwrap());
+    }
+    request
+}
+
+#[tokio::test]
+async fn session() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+
+    let session = service
+        .open_session(Request::new(proto::NewSession {}))
+        .await
+        .unwrap()
+        .into_inner();
+
+    service
+        .execute(show_tables(Some(&session.id)))
+        .await
+        .unwrap();
+    // Queries without a session keep working
+    service.execute(show_tables(None)).await.unwrap();
+
+    let closed = service
+        .close_session(Request::new(session.clone()))
+        .await
+        .unwrap();
+    assert_eq!(closed.into_inner().queries, 1);
+
+    let err = service
+        .execute(show_tables(Some(&session.id)))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::NotFound);
+}
+
+#[tokio::test]
+async fn session_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let query = |query, session: Option<&str>| {
+        let mut request = Request::new(proto::Query {
+            query: Some(query),
+            params: vec![],
+        });
+        if let Some(id) = session {
+            request
+                .metadata_mut()
+                .insert(SESSION_HEADER, id.parse().unwrap());
+        }
+        request
+    };
+    let insert = || {
+        query::Query::Insert(proto::Insert {
+            db: DEFAULT_DB.to_string(),
+            into: "users".to_string(),
+            values: HashMap::new(),
+            with_offset: false,
+            returning: vec![],
+        })
+    };
+    let select = |snapshot| {
+        query::Query::Select(proto::Select {
+            db: DEFAULT_DB.to_string(),
+            from: "users".to_string(),
+            snapshot,
+            ..Default::default()
+        })
+    };
+
+    let create = query::Query::Create(proto::Create {
+        db: DEFAULT_DB.to_string(),
+        table: "users".to_string(),
+        columns: vec![column("id", DataType::Serial)],
+        defaults: HashMap::new(),
+        compressed: false,
+        segment_size: None,
+        if_not_exists: false,
+    });
+    service.execute(query(create, None)).await.unwrap();
+    service.execute(query(insert(), None)).await.unwrap();
+
+    let session = service
+        .open_session(Request::new(proto::NewSession {}))
+        .await
+        .unwrap()
+        .into_inner();
+    let snapshot = query::Query::Snapshot(proto::Snapshot {
+        db: DEFAULT_DB.to_string(),
+    });
+    service
+        .execute(query(snapshot, Some(&session.id)))
+        .await
+        .unwrap();
+    service.execute(query(insert(), None)).await.unwrap();
+
+    // Selects of the session are made in its snapshot
+    let rows = |reply: Response<proto::Reply>| reply.into_inner().rows.len();
+    let reply = service.execute(query(select(None), Some(&session.id)));
+    assert_eq!(rows(reply.await.unwrap()), 1);
+    let reply = service.execute(query(select(None), None));
+    assert_eq!(rows(reply.await.unwrap()), 2);
+
+    // Closing the session releases it
+    let id = service.sessions.snapshot(&session.id).unwrap();
+    service
+        .close_session(Request::new(session.clone()))
+        .await
+        .unwrap();
+    let err = service
+        .execute(query(select(Some(id)), None))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn idle_session_expires() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir).with_sessions(Sessions::new(Duration::ZERO));
+
+    let id = service.sessions.open().unwrap();
+    assert!(!service.sessions.touch(&id));
+    assert!(service.sessions.is_empty());
+}
+
+#[tokio::test]
+async fn sessions_are_capped() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir).with_sessions(Sessions::default().with_max_sessions(2));
+
+    let first = service.sessions.open().unwrap();
+    let second = service.sessions.open().unwrap();
+    assert_eq!(first.len(), 32);
+    assert_ne!(first, second);
+
+    let err = service
+        .open_session(Request::new(proto::NewSession {}))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+
+    service.sessions.close(&first).unwrap();
+    assert!(service.sessions.open().is_some());
+}
+
+#[tokio::test]
+async fn bind_params() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let param = |n| proto::TypedValue {
+        data: Some(typed_value::Data::Param(n)),
+    };
+
+    let create = proto::Query {
+        query: Some(query::Query::Create(proto::Create {
+            db: DEFAULT_DB.to_string(),
+            table: "users".to_string(),
+            columns: vec![
+                column("name", DataType::String),
+                column("age", DataType::Int),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })),
+        params: vec![],
+    };
+    service.execute(Request::new(create)).await.unwrap();
+
+    let insert = proto::Query {
+        query: Some(query::Query::Insert(proto::Insert {
+            db: DEFAULT_DB.to_string(),
+            into: "users".to_string(),
+            values: [
+                ("name".to_string(), param(1)),
+                ("age".to_string(), param(2)),
+            ]
+            .into(),
+            with_offset: false,
+            returning: vec![],
+        })),
+        params: vec![TypedValue::from("bob").into(), TypedValue::Int(42).into()],
+    };
+    service.execute(Request::new(insert)).await.unwrap();
+
+    let select = |params: Vec<proto::TypedValue>| proto::Query {
+        query: Some(query::Query::SelectOne(proto::SelectOne {
+            db: DEFAULT_DB.to_string(),
+            from: "users".to_string(),
+            conditions: [("name".to_string(), param(1))].into(),
+        })),
+        params,
+    };
+    let reply = service
+        .execute(Request::new(select(vec![TypedValue::from("bob").into()])))
+        .await
+        .unwrap()
+        .into_inner();
+    let row = &reply.rows[0].data;
+    assert_eq!(row["age"], TypedValue::Int(42).into());
+
+    let err = service
+        .execute(Request::new(select(vec![])))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+    // A placeholder that is never bound is refused when converted
+    let err = TypedValue::try_from(typed_value::Data::Param(3)).unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn incomplete_predicate() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let execute = |query| {
+        service.execute(Request::new(proto::Query {
+            query: Some(query),
+            params: vec![],
+        }))
+    };
+    let name = |name: &str| proto::TypedValue::from(TypedValue::String(name.to_string()));
+    execute(query::Query::Create(proto::Create {
+        db: DEFAULT_DB.to_string(),
+        table: "users".to_string(),
+        columns: vec![column("name", DataType::String)],
+        defaults: HashMap::new(),
+        compressed: false,
+        segment_size: None,
+        if_not_exists: false,
+    }))
+    .await
+    .unwrap();
+    for value in ["ann", "bob"] {
+        execute(query::Query::Insert(proto::Insert {
+            db: DEFAULT_DB.to_string(),
+            into: "users".to_string(),
+            values: [("name".to_string(), name(value))].into(),
+            with_offset: false,
+            returning: vec![],
+        }))
+        .await
+        .unwrap();
+    }
+
+    // Without its value the predicate would match every row
+    let delete = query::Query::Delete(proto::Delete {
+        db: DEFAULT_DB.to_string(),
+        from: "users".to_string(),
+        predicates: [(
+            "name".to_string(),
+            proto::Predicate {
+                predicate: Some(predicate::Predicate::Eq(proto::TypedValue { data: None })),
+            },
+        )]
+        .into(),
+        ..Default::default()
+    });
+    let err = execute(delete).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    assert!(err.message().contains("name"));
+
+    let reply = execute(query::Query::Select(proto::Select {
+        db: DEFAULT_DB.to_string(),
+        from: "users".to_string(),
+        ..Default::default()
+    }))
+    .await
+    .unwrap()
+    .into_inner();
+    assert_eq!(reply.rows.len(), 2);
+}
+
+#[tokio::test]
+async fn incomplete_expression() {
+    use proto::expression::{Expression as Kind, Operands};
+
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let execute = |query| {
+        service.execute(Request::new(proto::Query {
+            query: Some(query),
+            params: vec![],
+        }))
+    };
+    execute(query::Query::Create(proto::Create {
+        db: DEFAULT_DB.to_string(),
+        table: "items".to_string(),
+        columns: vec![column("price", DataType::Int)],
+        defaults: HashMap::new(),
+        compressed: false,
+        segment_size: None,
+        if_not_exists: false,
+    }))
+    .await
+    .unwrap();
+
+    // `price + ` without its right operand
+    let expression = proto::Expression {
+        expression: Some(Kind::Add(Box::new(Operands {
+            left: Some(Box::new(proto::Expression {
+                expression: Some(Kind::Column("price".to_string())),
+            })),
+            right: None,
+        }))),
+    };
+    let update = query::Query::Update(proto::Update {
+        db: DEFAULT_DB.to_string(),
+        table: "items".to_string(),
+        expressions: [("price".to_string(), expression)].into(),
+        ..Default::default()
+    });
+    let err = execute(update).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    assert!(err.message().contains("price"));
+}
+
+#[tokio::test]
+async fn select_as_vectors() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let create = proto::Query {
+        query: Some(query::Query::Create(proto::Create {
+            db: DEFAULT_DB.to_string(),
+            table: "users".to_string(),
+            columns: vec![
+                column("name", DataType::String),
+                column("age", DataType::Int),
+            ],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        })),
+        params: vec![],
+    };
+    service.execute(Request::new(create)).await.unwrap();
+    let insert = proto::Query {
+        query: Some(query::Query::Insert(proto::Insert {
+            db: DEFAULT_DB.to_string(),
+            into: "users".to_string(),
+            values: [
+                ("name".to_string(), TypedValue::from("bob").into()),
+                ("age".to_string(), TypedValue::Int(42).into()),
+            ]
+            .into(),
+            with_offset: false,
+            returning: vec![],
+        })),
+        params: vec![],
+    };
+    service.execute(Request::new(insert)).await.unwrap();
+
+    let select = |columns: &[&str]| proto::Query {
+        query: Some(query::Query::Select(proto::Select {
+            db: DEFAULT_DB.to_string(),
+            from: "users".to_string(),
+            columns: columns.iter().map(|column| column.to_string()).collect(),
+            as_vectors: true,
+            ..Default::default()
+        })),
+        params: vec![],
+    };
+    let reply = service
+        .execute(Request::new(select(&["name", "age"])))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(reply.rows.is_empty());
+    assert_eq!(reply.columns, ["name", "age"]);
+    assert_eq!(
+        reply.vectors[0].values,
+        [TypedValue::from("bob").into(), TypedValue::Int(42).into()]
+    );
+
+    // All the columns, sorted by name
+    let reply = service
+        .execute(Request::new(select(&[])))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.columns, ["age", "name"]);
+    assert_eq!(
+        reply.vectors[0].values,
+        [TypedValue::Int(42).into(), TypedValue::from("bob").into()]
+    );
+}
+
+#[tokio::test]
+async fn create_keeps_column_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let create = proto::Query {
+        query: Some(query::Query::Create(proto::Create {
+            db: DEFAULT_DB.to_string(),
+            table: "users".to_string(),
+            columns: vec![
+                column("name", DataType::String),
+                column("id", DataType::Serial),
+                column("age", DataType::Int),
+            ],
+            ..Default::default()
+        })),
+        params: vec![],
+    };
+    service.execute(Request::new(create)).await.unwrap();
+
+    let show_create = proto::Query {
+        query: Some(query::Query::ShowCreate(proto::ShowCreate {
+            db: DEFAULT_DB.to_string(),
+            table: "users".to_string(),
+        })),
+        params: vec![],
+    };
+    let reply = service
+        .execute(Request::new(show_create))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(
+        reply.rows[0].data["ddl"],
+        TypedValue::from("Create poorly users name=string,id=serial,age=int").into()
+    );
+}
+
+#[tokio::test]
+async fn create_duplicate_column() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let create = proto::Query {
+        query: Some(query::Query::Create(proto::Create {
+            db: DEFAULT_DB.to_string(),
+            table: "users".to_string(),
+            columns: vec![
+                column("name", DataType::String),
+                column("age", DataType::Int),
+                column("name", DataType::Char),
+            ],
+            ..Default::default()
+        })),
+        params: vec![],
+    };
+    let err = service.execute(Request::new(create)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::AlreadyExists);
+}
+
+#[tokio::test]
+async fn create_unknown_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let create = proto::Query {
+        query: Some(query::Query::Create(proto::Create {
+            db: DEFAULT_DB.to_string(),
+            table: "users".to_string(),
+            columns: vec![column("joined", DataType::Timestamp)],
+            defaults: [("joined".to_string(), 99)].into(),
+            ..Default::default()
+        })),
+        params: vec![],
+    };
+    let err = service.execute(Request::new(create)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    assert!(err.message().contains("joined"));
+}
+
+#[tokio::test]
+async fn select_with_types() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let create = proto::Query {
+        query: Some(query::Query::Create(proto::Create {
+            db: DEFAULT_DB.to_string(),
+            table: "users".to_string(),
+            columns: vec![
+                column("id", DataType::Serial),
+                column("initial", DataType::Char),
+                column("name", DataType::String),
+            ],
+            ..Default::default()
+        })),
+        params: vec![],
+    };
+    service.execute(Request::new(create)).await.unwrap();
+
+    // The types are known even without rows to infer them from
+    let select = proto::Query {
+        query: Some(query::Query::Select(proto::Select {
+            db: DEFAULT_DB.to_string(),
+            from: "users".to_string(),
+            columns: vec!["id".to_string(), "initial".to_string()],
+            with_types: true,
+            ..Default::default()
+        })),
+        params: vec![],
+    };
+    let reply = service
+        .execute(Request::new(select))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(reply.rows.is_empty());
+    assert_eq!(
+        reply.types,
+        [
+            ("id".to_string(), DataType::Serial.into()),
+            ("initial".to_string(), DataType::Char.into()),
+        ]
+        .into()
+    );
+}
+
+#[tokio::test]
+async fn now() {
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+
+    let time = service
+        .now(Request::new(proto::TimeRequest {}))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!((time.millis - now_millis()).abs() < 5_000);
+}
+
+#[tokio::test]
+async fn subscribe() {
+    use tonic::codegen::tokio_stream::StreamExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let service = database_service(&dir);
+    let execute = |query| {
+        service.execute(Request::new(proto::Query {
+            query: Some(query),
+            params: vec![],
+        }))
+    };
+    let name = |name: &str| proto::TypedValue::from(TypedValue::String(name.to_string()));
+    for table in ["users", "others"] {
+        let create = query::Query::Create(proto::Create {
+            db: DEFAULT_DB.to_string(),
+            table: table.to_string(),
+            columns: vec![column("name", DataType::String)],
+            defaults: HashMap::new(),
+            compressed: false,
+            segment_size: None,
+            if_not_exists: false,
+        });
+        execute(create).await.unwrap();
+    }
+    let insert = |table: &str, value: &str| {
+        query::Query::Insert(proto::Insert {
+            db: DEFAULT_DB.to_string(),
+            into: table.to_string(),
+            values: [("name".to_string(), name(value))].into(),
+            with_offset: false,
+            returning: vec![],
+        })
+    };
+
+    let subscription = proto::Subscription {
+        db: DEFAULT_DB.to_string(),
+        table: Some("users".to_string()),
+    };
+    let mut changes = service
+        .subscribe(Request::new(subscription.clone()))
+        .await
+        .unwrap()
+        .into_inner();
+
+    execute(insert("users", "ann")).await.unwrap();
+    // Other tables aren't sent
+    execute(insert("others", "bob")).await.unwrap();
+    execute(query::Query::Update(proto::Update {
+        db: DEFAULT_DB.to_string(),
+        table: "users".to_string(),
+        set: [("name".to_string(), name("cid"))].into(),
+        conditions: [("name".to_string(), name("ann"))].into(),
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+    execute(query::Query::Delete(proto::Delete {
+        db: DEFAULT_DB.to_string(),
+        from: "users".to_string(),
+        conditions: [("name".to_string(), name("cid"))].into(),
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+
+    use proto::change::Kind;
+    for (kind, value) in [
+        (Kind::Insert, "ann"),
+        (Kind::Update, "cid"),
+        (Kind::Delete, "cid"),
+    ] {
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!((change.kind(), change.table.as_str()), (kind, "users"));
+        assert_eq!(change.row["name"], name(value));
+    }
+
+    // A subscriber that doesn't keep up is told how much it missed
+    let mut lagging = service
+        .subscribe(Request::new(subscription))
+        .await
+        .unwrap()
+        .into_inner();
+    for _ in 0..2_000 {
+        execute(insert("users", "dan")).await.unwrap();
+    }
+    let mut missed = 0;
+    while let Some(change) = lagging.next().await {
+        let change = change.unwrap();
+        if change.kind() == Kind::Lagged {
+            missed = change.missed;
+            break;
+        }
+    }
+    assert!(missed > 0);
+}
+
+#[test]
+fn rate_limit() {
+    let limiter = RateLimiter::new(1, 2).with_api_keys(["alice".to_string(), "bob".to_string()]);
+    let request = |api_key: &str| {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(API_KEY_HEADER, api_key.parse().unwrap());
+        request
+    };
+
+    assert!(throttle(Some(&limiter), request("alice")).is_ok());
+    assert!(throttle(Some(&limiter), request("alice")).is_ok());
+    let status = throttle(Some(&limiter), request("alice")).unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert!(throttle(Some(&limiter), request("bob")).is_ok());
+    // Without a limiter nothing is refused
+    assert!(throttle(None, request("alice")).is_ok());
+}
+
+#[test]
+fn rate_limit_unknown_keys() {
+    let limiter = RateLimiter::new(1, 2).with_api_keys(["alice".to_string()]);
+    let request = |api_key: &str| {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(API_KEY_HEADER, api_key.parse().unwrap());
+        request
+    };
+
+    // Keys not on the list share the bucket of the client's address
+    assert!(throttle(Some(&limiter), request("random-1")).is_ok());
+    assert!(throttle(Some(&limiter), request("random-2")).is_ok());
+    let status = throttle(Some(&limiter), request("random-3")).unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert!(throttle(Some(&limiter), request("alice")).is_ok());
+}
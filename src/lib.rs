@@ -1,3 +1,4 @@
 pub mod core;
 pub mod grpc;
+pub mod rate_limit;
 pub mod rest;
@@ -0,0 +1,84 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Header or metadata key a client can name itself with. Only keys given to
+/// `RateLimiter::with_api_keys` are used, other clients are limited by
+/// their address.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+// Clients tracked before full buckets are forgotten
+const MAX_IDLE_CLIENTS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets by client. A client may make `burst` requests at once and
+/// then `per_second` more every second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    api_keys: HashSet<String>,
+    per_second: f64,
+    burst: f64,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            api_keys: HashSet::new(),
+            per_second: per_second as f64,
+            burst: burst.max(1) as f64,
+        }
+    }
+
+    /// API keys that get a bucket of their own. Any other key is ignored,
+    /// as a client could otherwise pick a new one for every request.
+    pub fn with_api_keys(mut self, api_keys: impl IntoIterator<Item = String>) -> Self {
+        self.api_keys = api_keys.into_iter().collect();
+        self
+    }
+
+    /// Names the client of a request for `allow`
+    pub fn client_key(&self, api_key: Option<&str>, address: Option<IpAddr>) -> String {
+        match (api_key, address) {
+            (Some(key), _) if self.api_keys.contains(key) => format!("key:{}", key),
+            (_, Some(address)) => format!("ip:{}", address),
+            (_, None) => "unknown".to_string(),
+        }
+    }
+
+    /// Takes a token from the bucket of `client`. Returns `false` if there
+    /// is none left and the request should be refused.
+    pub fn allow(&self, client: &str) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(client) && buckets.len() >= MAX_IDLE_CLIENTS {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            log::warn!(target: "poorly::rate_limit", "Client {} is over its rate limit", client);
+            false
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second).min(self.burst)
+    }
+}
@@ -1,29 +1,154 @@
-use crate::core::types::{ColumnSet, DataType, PoorlyError, Query};
+use crate::core::predicate::{self, Predicate, Predicates};
+use crate::core::types::{
+    now_millis, ColumnSet, DataType, PoorlyError, Query, QueryStats, TypedValue,
+};
 use crate::core::{database, DatabaseEng};
+use crate::rate_limit::{RateLimiter, API_KEY_HEADER};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
+use std::io::Write;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
 use once_cell::sync::Lazy;
 use rusqlite::ffi::SQLITE_DBCONFIG_MAINDBNAME;
 use serde::{Deserialize, Serialize};
+use warp::http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
 use warp::http::StatusCode;
 use warp::Filter;
 
+#[cfg(test)]
+mod tests;
+
 impl warp::reject::Reject for PoorlyError {}
 
+/// Request header asking a select to reply with `{"rows": [...], "types": {...}}`,
+/// the declared type of every column of the rows included
+pub const WITH_TYPES_HEADER: &str = "x-with-types";
+
+/// Request header confirming that an update or delete without conditions
+/// is meant for every row, see `Options::allow_unconditional_writes`
+pub const ALL_ROWS_HEADER: &str = "x-all-rows";
+
+/// Request header overriding the most rows the server lets a select return,
+/// as the query string holds its conditions
+pub const MAX_ROWS_HEADER: &str = "x-max-rows";
+
+// Smaller responses are sent as they are, compressing them saves next to nothing
+const MIN_COMPRESSED_BYTES: usize = 1024;
+
+// The client is over its rate limit
+#[derive(Debug)]
+struct Throttled;
+
+impl warp::reject::Reject for Throttled {}
+
+// Body of a create, the columns in the order they are written. A name
+// written twice is kept twice, for the schema to refuse.
+#[derive(Debug)]
+struct CreateColumns(Vec<(String, DataType)>);
+
+impl<'de> Deserialize<'de> for CreateColumns {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = CreateColumns;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of column names to types")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut columns = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(column) = map.next_entry()? {
+                    columns.push(column);
+                }
+                Ok(CreateColumns(columns))
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+// Query string of a join, whose conditions are in the body
+#[derive(Debug, Deserialize)]
+struct JoinOptions {
+    max_rows: Option<usize>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct JoinQuery {
     conditions: ColumnSet,
+    #[serde(default)]
     join_on: HashMap<String, String>,
+    #[serde(default)]
+    using: Vec<String>,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+static OPENAPI_SPEC: Lazy<serde_json::Value> =
+    Lazy::new(|| parse_spec(include_str!("../openapi.yaml")));
+
+// A broken spec leaves the docs empty rather than failing the requests for it
+fn parse_spec(spec: &str) -> serde_json::Value {
+    serde_yaml::from_str(spec).unwrap_or_else(|err| {
+        log::error!(target: "api::rest", "Invalid OpenAPI spec: {}", err);
+        serde_json::json!({
+            "openapi": "3.0.0",
+            "info": { "title": "poorly API", "version": env!("CARGO_PKG_VERSION") },
+            "paths": {},
+        })
+    })
+}
+
+// The static spec with the rows of every table of the default database as
+// components named `<db>.<table>`, which can't clash with the spec's own
+async fn openapi_spec(database: &dyn DatabaseEng) -> serde_json::Value {
+    let mut spec = OPENAPI_SPEC.clone();
+    let schema = match database.schema(database::DEFAULT_DB).await {
+        Ok(schema) => schema,
+        Err(err) => {
+            log::warn!(target: "api::rest", "No tables in the OpenAPI spec: {}", err);
+            return spec;
+        }
+    };
+    if let (Some(components), serde_json::Value::Object(tables)) = (
+        spec["components"]["schemas"].as_object_mut(),
+        schema.to_json_schema(),
+    ) {
+        for (table, table_schema) in tables {
+            components.insert(format!("{}.{}", database::DEFAULT_DB, table), table_schema);
+        }
+    }
+    spec
 }
 
-static OPENAPI_SPEC: Lazy<serde_json::Value> = Lazy::new(|| {
-    let spec = include_str!("../openapi.yaml");
-    serde_yaml::from_str(spec).unwrap()
-});
+// `op:value` for a comparison, anything else is compared for equality. Values
+// that start with an operator can be written as `eq:value`. `len:` tests the
+// length of a string instead, like `len:gt:10`.
+fn parse_condition(value: String) -> Predicate {
+    let operand = |v: &str| TypedValue::String(v.to_string());
+    match value.split_once(':') {
+        Some(("eq", v)) => Predicate::Eq(operand(v)),
+        Some(("ne", v)) => Predicate::Ne(operand(v)),
+        Some(("lt", v)) => Predicate::Lt(operand(v)),
+        Some(("le", v)) => Predicate::Le(operand(v)),
+        Some(("gt", v)) => Predicate::Gt(operand(v)),
+        Some(("ge", v)) => Predicate::Ge(operand(v)),
+        Some(("like", v)) => Predicate::Like(v.to_string()),
+        Some(("len", v)) => Predicate::Len(Box::new(parse_condition(v.to_string()))),
+        _ => Predicate::Eq(TypedValue::String(value)),
+    }
+}
 
 impl PoorlyError {
     pub fn status_code(&self) -> StatusCode {
@@ -38,9 +163,15 @@ impl PoorlyError {
             PoorlyError::IncompleteData(_, _) => StatusCode::BAD_REQUEST,
             PoorlyError::InvalidDataType(_) => StatusCode::BAD_REQUEST,
             PoorlyError::InvalidOperation(_) => StatusCode::BAD_REQUEST,
+            PoorlyError::ResultTooLarge(_) => StatusCode::BAD_REQUEST,
+            PoorlyError::RowNotFound(_) => StatusCode::NOT_FOUND,
+            PoorlyError::TooManyRows(_) => StatusCode::CONFLICT,
             PoorlyError::InvalidEmail => StatusCode::BAD_REQUEST,
             PoorlyError::SqlError(_) => StatusCode::BAD_REQUEST,
             PoorlyError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PoorlyError::SchemaRowMismatch(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PoorlyError::SchemaCorrupted(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PoorlyError::IncompatibleSchema(_) => StatusCode::CONFLICT,
             PoorlyError::DatabaseNotFound(_) => StatusCode::NOT_FOUND,
             PoorlyError::DatabaseAlreadyExists(_) => StatusCode::CONFLICT,
             PoorlyError::CannotDropDefaultDb => StatusCode::BAD_REQUEST,
@@ -48,26 +179,139 @@ impl PoorlyError {
     }
 }
 
-pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAddr>) {
+/// Settings of the REST server
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Requests of clients over their rate limit are refused with
+    /// `429 Too Many Requests`
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// Conditions given an empty value, like `?name=`, are left out rather
+    /// than matching rows where the column is empty
+    pub ignore_empty_conditions: bool,
+    /// Updates and deletes without conditions change every row as they
+    /// are. Otherwise they are refused unless they set `ALL_ROWS_HEADER`.
+    pub allow_unconditional_writes: bool,
+}
+
+pub async fn serve(db: Arc<dyn DatabaseEng>, address: impl Into<SocketAddr>, options: Options) {
+    warp::serve(routes_with(db, options)).run(address).await;
+}
+
+pub fn routes(
+    db: Arc<dyn DatabaseEng>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+    routes_with(db, Options::default())
+}
+
+pub fn routes_with(
+    db_itself: Arc<dyn DatabaseEng>,
+    options: Options,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+    let Options {
+        limiter,
+        ignore_empty_conditions,
+        allow_unconditional_writes,
+    } = options;
+    let throttle = warp::addr::remote()
+        .and(warp::header::optional::<String>(API_KEY_HEADER))
+        .and_then(
+            move |address: Option<SocketAddr>, api_key: Option<String>| {
+                let limiter = limiter.clone();
+                async move {
+                    let Some(limiter) = limiter else {
+                        return Ok(());
+                    };
+                    let client = limiter.client_key(api_key.as_deref(), address.map(|a| a.ip()));
+                    if limiter.allow(&client) {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(Throttled))
+                    }
+                }
+            },
+        )
+        .untuple_one();
+
+    // A column may be repeated, e.g. `price=gt:10&price=lt:20`, and has to
+    // pass every one of its predicates
+    let conditions =
+        warp::query::<Vec<(String, String)>>().map(move |conditions: Vec<(String, String)>| {
+            let mut predicates = Predicates::new();
+            for (column, value) in conditions {
+                if ignore_empty_conditions && value.is_empty() {
+                    continue;
+                }
+                predicate::and(&mut predicates, column, parse_condition(value));
+            }
+            predicates
+        });
+
+    // Conditions of an update or delete, which has to confirm it is meant
+    // for every row if there are none
+    let write_conditions = conditions
+        .and(warp::header::optional::<bool>(ALL_ROWS_HEADER))
+        .and_then(
+            move |predicates: Predicates, all_rows: Option<bool>| async move {
+                if predicates.is_empty() && !allow_unconditional_writes && all_rows != Some(true) {
+                    return Err(warp::reject::custom(PoorlyError::InvalidOperation(
+                        format!(
+                            "no conditions, set the {} header to true to change every row",
+                            ALL_ROWS_HEADER
+                        ),
+                    )));
+                }
+                Ok(predicates)
+            },
+        );
+
+    // Before `select`, which would take `schema` for a table
     let database = Arc::clone(&db_itself);
-    let select = warp::get()
-        .and(warp::path::param())
+    let describe_db = warp::get()
         .and(warp::path::param())
+        .and(warp::path("schema"))
         .and(warp::path::end())
-        .and(warp::query::<ColumnSet>())
-        .and_then(move |db: String, from: String, conditions: ColumnSet| {
+        .and_then(move |db: String| {
             let database = Arc::clone(&database);
-            execute_on(
-                database,
-                Query::Select {
-                    db,
-                    from,
-                    conditions,
-                    columns: vec![],
-                },
-            )
+            describe_db(database, db)
         });
 
+    let database = Arc::clone(&db_itself);
+    let select = warp::get()
+        .and(warp::path::param())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(conditions)
+        .and(warp::header::optional::<bool>(WITH_TYPES_HEADER))
+        .and(warp::header::optional::<usize>(MAX_ROWS_HEADER))
+        .and_then(
+            move |db: String,
+                  from: String,
+                  predicates: Predicates,
+                  with_types: Option<bool>,
+                  max_rows: Option<usize>| {
+                let database = Arc::clone(&database);
+                execute_select(
+                    database,
+                    Query::Select {
+                        db,
+                        from,
+                        conditions: HashMap::new(),
+                        predicates,
+                        order_by: vec![],
+                        distinct_on: vec![],
+                        columns: vec![],
+                        max_rows,
+                        since_serial: None,
+                        post_filter: HashMap::new(),
+                        include_deleted: false,
+                        snapshot: None,
+                        in_select: HashMap::new(),
+                    },
+                    with_types.unwrap_or(false),
+                )
+            },
+        );
+
     let database = Arc::clone(&db_itself);
     let insert = warp::post()
         .and(warp::path::param())
@@ -76,7 +320,16 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
         .and(warp::body::json())
         .and_then(move |db: String, into: String, values: ColumnSet| {
             let database = Arc::clone(&database);
-            execute_on(database, Query::Insert { db, into, values })
+            execute_on(
+                database,
+                Query::Insert {
+                    db,
+                    into,
+                    values,
+                    with_offset: false,
+                    returning: vec![],
+                },
+            )
         })
         .map(|reply| warp::reply::with_status(reply, StatusCode::CREATED));
 
@@ -85,18 +338,22 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
         .and(warp::path::param())
         .and(warp::path::param())
         .and(warp::path::end())
-        .and(warp::query::<ColumnSet>())
+        .and(write_conditions)
         .and(warp::body::json())
         .and_then(
-            move |db: String, table: String, conditions: ColumnSet, set: ColumnSet| {
+            move |db: String, table: String, predicates: Predicates, set: ColumnSet| {
                 let database = Arc::clone(&database);
                 execute_on(
                     database,
                     Query::Update {
                         db,
                         table,
-                        conditions,
+                        expressions: HashMap::new(),
+                        conditions: HashMap::new(),
+                        predicates,
                         set,
+                        with_offset: false,
+                        limit: None,
                     },
                 )
             },
@@ -107,15 +364,18 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
         .and(warp::path::param())
         .and(warp::path::param())
         .and(warp::path::end())
-        .and(warp::query::<ColumnSet>())
-        .and_then(move |db: String, from: String, conditions: ColumnSet| {
+        .and(write_conditions)
+        .and_then(move |db: String, from: String, predicates: Predicates| {
             let database = Arc::clone(&database);
             execute_on(
                 database,
                 Query::Delete {
                     db,
                     from,
-                    conditions,
+                    conditions: HashMap::new(),
+                    predicates,
+                    limit: None,
+                    compact: false,
                 },
             )
         });
@@ -128,7 +388,14 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
         .and(warp::path::end())
         .and_then(move |db: String, table: String| {
             let database = Arc::clone(&database);
-            execute_on(database, Query::Drop { db, table })
+            execute_on(
+                database,
+                Query::Drop {
+                    db,
+                    table,
+                    if_exists: false,
+                },
+            )
         });
 
     let database = Arc::clone(&db_itself);
@@ -138,13 +405,21 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
         .and(warp::path::param())
         .and(warp::path::end())
         .and(warp::body::json())
-        .and_then(
-            move |db: String, table: String, columns: HashMap<String, DataType>| {
-                let database = Arc::clone(&database);
-                let columns = Vec::from_iter(columns.into_iter());
-                execute_on(database, Query::Create { db, table, columns })
-            },
-        )
+        .and_then(move |db: String, table: String, CreateColumns(columns)| {
+            let database = Arc::clone(&database);
+            execute_on(
+                database,
+                Query::Create {
+                    db,
+                    table,
+                    columns,
+                    defaults: HashMap::new(),
+                    compressed: false,
+                    segment_size: None,
+                    if_not_exists: false,
+                },
+            )
+        })
         .map(|reply| warp::reply::with_status(reply, StatusCode::CREATED));
 
     let database = Arc::clone(&db_itself);
@@ -179,10 +454,19 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
             execute_on(database, Query::DropDb { name })
         });
 
+    let now = warp::get()
+        .and(warp::path("now"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&serde_json::json!({ "millis": now_millis() })));
+
+    let database = Arc::clone(&db_itself);
     let openapi = warp::get()
         .and(warp::path("openapi.json"))
         .and(warp::path::end())
-        .map(|| warp::reply::json(&*OPENAPI_SPEC));
+        .then(move || {
+            let database = Arc::clone(&database);
+            async move { warp::reply::json(&openapi_spec(database.as_ref()).await) }
+        });
 
     let index = warp::get()
         .and(warp::path::end())
@@ -194,9 +478,14 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
         .and(warp::path::param())
         .and(warp::path::param())
         .and(warp::path::end())
+        .and(warp::query::<JoinOptions>())
         .and(warp::body::json())
         .and_then(
-            move |db: String, table1: String, table2: String, join_query: JoinQuery| {
+            move |db: String,
+                  table1: String,
+                  table2: String,
+                  options: JoinOptions,
+                  join_query: JoinQuery| {
                 let database = Arc::clone(&database);
                 execute_on(
                     database,
@@ -207,12 +496,17 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
                         columns: vec![],
                         conditions: join_query.conditions,
                         join_on: join_query.join_on,
+                        using: join_query.using,
+                        max_rows: options.max_rows,
+                        post_filter: HashMap::new(),
+                        limit: join_query.limit,
                     },
                 )
             },
         );
 
-    let routes = select
+    let routes = describe_db
+        .or(select)
         .or(insert)
         .or(update)
         .or(delete)
@@ -221,13 +515,99 @@ pub async fn serve(db_itself: Arc<dyn DatabaseEng>, address: impl Into<SocketAdd
         .or(alter)
         .or(create_db)
         .or(drop_db)
+        .or(now)
         .or(openapi)
         .or(index)
-        .or(join)
+        .or(join);
+
+    let routes = throttle
+        .and(routes)
         .with(warp::log("api::rest"))
         .recover(handle_rejection);
 
-    warp::serve(routes).run(address).await;
+    // An `Accept-Encoding` that isn't text is taken as accepting nothing
+    warp::header::optional::<String>(ACCEPT_ENCODING.as_str())
+        .or_else(|_| async { Ok::<_, Infallible>((None,)) })
+        .and(routes)
+        .then(compress)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+// The first of gzip and deflate in `accept`, unless it has `q=0`
+fn accepted_encoding(accept: &str) -> Option<Encoding> {
+    accept.split(',').find_map(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let encoding = match params.next()?.to_ascii_lowercase().as_str() {
+            "gzip" => Encoding::Gzip,
+            "deflate" => Encoding::Deflate,
+            _ => return None,
+        };
+        let refused = params
+            .filter_map(|param| param.strip_prefix("q="))
+            .any(|q| q.parse::<f32>() == Ok(0.0));
+        (!refused).then_some(encoding)
+    })
+}
+
+// Compresses the body of `reply` with an encoding the client accepts, if
+// it's large enough to be worth it
+async fn compress(accept: Option<String>, reply: impl warp::Reply) -> warp::reply::Response {
+    let response = reply.into_response();
+    let encoding = match accept.as_deref().and_then(accepted_encoding) {
+        Some(encoding) if !response.headers().contains_key(CONTENT_ENCODING) => encoding,
+        _ => return response,
+    };
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .insert(VARY, HeaderValue::from_static("accept-encoding"));
+    let body = match warp::hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            log::error!(target: "api::rest", "Failed to read a response to compress: {}", err);
+            return warp::Reply::into_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    if body.len() < MIN_COMPRESSED_BYTES {
+        return warp::reply::Response::from_parts(parts, body.into());
+    }
+
+    let (name, compressed) = match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            (
+                "gzip",
+                encoder.write_all(&body).and_then(|_| encoder.finish()),
+            )
+        }
+        // HTTP's deflate is the zlib format, not a raw deflate stream
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            (
+                "deflate",
+                encoder.write_all(&body).and_then(|_| encoder.finish()),
+            )
+        }
+    };
+    match compressed {
+        Ok(compressed) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(name));
+            parts.headers.remove(CONTENT_LENGTH);
+            warp::reply::Response::from_parts(parts, compressed.into())
+        }
+        Err(err) => {
+            log::warn!(target: "api::rest", "Sending a response uncompressed: {}", err);
+            warp::reply::Response::from_parts(parts, body.into())
+        }
+    }
 }
 
 async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
@@ -236,6 +616,11 @@ async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infa
             warp::reply::json(&error),
             error.status_code(),
         ))
+    } else if err.find::<Throttled>().is_some() {
+        Ok(warp::reply::with_status(
+            warp::reply::json(&"Too many requests"),
+            StatusCode::TOO_MANY_REQUESTS,
+        ))
     } else {
         Ok(warp::reply::with_status(
             warp::reply::json(&"Invalid request"),
@@ -248,6 +633,44 @@ async fn execute_on(
     db: Arc<dyn DatabaseEng>,
     query: Query,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let result = db.execute(query).await?;
-    Ok(warp::reply::json(&result))
+    let (result, stats) = db.execute_with_stats(query).await?;
+    Ok(with_stats(warp::reply::json(&result), stats))
+}
+
+// The columns of every table of `db`, in order, by table
+async fn describe_db(
+    db: Arc<dyn DatabaseEng>,
+    name: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (rows, stats) = db
+        .execute_with_stats(Query::DescribeDb { db: name })
+        .await?;
+    let mut tables: BTreeMap<String, Vec<ColumnSet>> = BTreeMap::new();
+    for mut row in rows {
+        if let Some(TypedValue::String(table)) = row.remove("table") {
+            tables.entry(table).or_default().push(row);
+        }
+    }
+    Ok(with_stats(warp::reply::json(&tables), stats))
+}
+
+// Replies to a select asked `WITH_TYPES_HEADER` with the rows and the
+// declared type of every column they have
+async fn execute_select(
+    db: Arc<dyn DatabaseEng>,
+    query: Query,
+    with_types: bool,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if !with_types {
+        return execute_on(db, query).await.map(warp::Reply::into_response);
+    }
+    let types = db.column_types(query.clone()).await?;
+    let (rows, stats) = db.execute_with_stats(query).await?;
+    let reply = warp::reply::json(&serde_json::json!({ "rows": rows, "types": types }));
+    Ok(warp::Reply::into_response(with_stats(reply, stats)))
+}
+
+fn with_stats(reply: impl warp::Reply, stats: QueryStats) -> impl warp::Reply {
+    let reply = warp::reply::with_header(reply, "X-Rows-Scanned", stats.rows_scanned.to_string());
+    warp::reply::with_header(reply, "X-Rows-Matched", stats.rows_matched.to_string())
 }
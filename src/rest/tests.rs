@@ -0,0 +1,506 @@
+use super::*;
+use crate::core::database::DEFAULT_DB;
+use crate::core::types::TypedValue;
+use crate::core::Poorly;
+
+use tokio::sync::Mutex;
+
+fn database(dir: &tempfile::TempDir) -> Arc<dyn DatabaseEng> {
+    let poorly = Poorly::open(dir.path().to_path_buf());
+    poorly.init().unwrap();
+    Arc::new(Mutex::new(poorly))
+}
+
+#[tokio::test]
+async fn select_by_serial() {
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/{}/create/items", DEFAULT_DB))
+        .json(&serde_json::json!({ "id": "serial", "name": "string" }))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    for i in 0..12 {
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/items", DEFAULT_DB))
+            .json(&serde_json::json!({ "name": format!("item{}", i) }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    let response = warp::test::request()
+        .path(&format!("/{}/items?id=10", DEFAULT_DB))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let rows: Vec<ColumnSet> = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["name"], TypedValue::String("item10".to_string()));
+}
+
+#[tokio::test]
+async fn now() {
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+
+    let response = warp::test::request().path("/now").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let reply: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let millis = reply["millis"].as_i64().unwrap();
+    assert!((millis - now_millis()).abs() < 5_000);
+}
+
+#[tokio::test]
+async fn rate_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let limiter =
+        Arc::new(RateLimiter::new(1, 2).with_api_keys(["alice".to_string(), "bob".to_string()]));
+    let routes = routes_with(
+        database(&dir),
+        Options {
+            limiter: Some(limiter),
+            ..Default::default()
+        },
+    );
+    let now = |api_key: &str| {
+        warp::test::request()
+            .path("/now")
+            .header(API_KEY_HEADER, api_key)
+            .reply(&routes)
+    };
+
+    assert_eq!(now("alice").await.status(), StatusCode::OK);
+    assert_eq!(now("alice").await.status(), StatusCode::OK);
+    assert_eq!(now("alice").await.status(), StatusCode::TOO_MANY_REQUESTS);
+    // Other clients have their own bucket
+    assert_eq!(now("bob").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn rate_limit_unknown_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let limiter = Arc::new(RateLimiter::new(1, 2));
+    let routes = routes_with(
+        database(&dir),
+        Options {
+            limiter: Some(limiter),
+            ..Default::default()
+        },
+    );
+    let now = |api_key: &str| {
+        warp::test::request()
+            .path("/now")
+            .remote_addr(([10, 0, 0, 1], 4000).into())
+            .header(API_KEY_HEADER, api_key)
+            .reply(&routes)
+    };
+
+    // A new key for every request doesn't get the client a new bucket
+    assert_eq!(now("random-1").await.status(), StatusCode::OK);
+    assert_eq!(now("random-2").await.status(), StatusCode::OK);
+    assert_eq!(
+        now("random-3").await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+}
+
+#[tokio::test]
+async fn openapi() {
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+
+    let response = warp::test::request()
+        .path("/openapi.json")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let spec: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(spec["info"]["title"], "poorly API");
+
+    let fallback = parse_spec("paths: [unclosed");
+    assert_eq!(fallback["openapi"], "3.0.0");
+    assert!(fallback["paths"].as_object().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn openapi_tables() {
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/{}/create/items", DEFAULT_DB))
+        .json(&serde_json::json!({ "name": "string", "price": "float" }))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = warp::test::request()
+        .path("/openapi.json")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let spec: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let items = &spec["components"]["schemas"][format!("{}.items", DEFAULT_DB)];
+    assert_eq!(items["type"], "object");
+    assert_eq!(items["properties"]["name"]["type"], "string");
+    assert_eq!(items["properties"]["price"]["type"], "number");
+    assert_eq!(items["required"], serde_json::json!(["name", "price"]));
+    // The spec's own components are kept
+    assert!(spec["components"]["schemas"]["Error"].is_object());
+}
+
+#[tokio::test]
+async fn describe_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+
+    // Raw bodies, as a JSON object would have its keys sorted
+    for (table, columns) in [
+        ("items", r#"{ "name": "string", "price": "float" }"#),
+        ("users", r#"{ "id": "serial", "email": "email" }"#),
+    ] {
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/create/{}", DEFAULT_DB, table))
+            .header("content-type", "application/json")
+            .body(columns)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    let response = warp::test::request()
+        .path(&format!("/{}/schema", DEFAULT_DB))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let tables: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(
+        tables,
+        serde_json::json!({
+            "items": [
+                { "column": "name", "type": "string", "position": 0 },
+                { "column": "price", "type": "float", "position": 1 },
+            ],
+            "users": [
+                { "column": "id", "type": "serial", "position": 0 },
+                { "column": "email", "type": "email", "position": 1 },
+            ],
+        })
+    );
+
+    let response = warp::test::request()
+        .path("/missing/schema")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn empty_conditions() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = database(&dir);
+    let strict = routes(Arc::clone(&db));
+    let ignoring = routes_with(
+        db,
+        Options {
+            ignore_empty_conditions: true,
+            ..Default::default()
+        },
+    );
+
+    warp::test::request()
+        .method("POST")
+        .path(&format!("/{}/create/notes", DEFAULT_DB))
+        .json(&serde_json::json!({ "text": "string", "tag": "string" }))
+        .reply(&strict)
+        .await;
+    for (text, tag) in [("untagged", ""), ("tagged", "todo")] {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/notes", DEFAULT_DB))
+            .json(&serde_json::json!({ "text": text, "tag": tag }))
+            .reply(&strict)
+            .await;
+    }
+
+    async fn select(
+        routes: &(impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + 'static),
+    ) -> Vec<ColumnSet> {
+        let response = warp::test::request()
+            .path(&format!("/{}/notes?tag=", DEFAULT_DB))
+            .reply(routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(response.body()).unwrap()
+    }
+    // By default an empty value is compared like any other
+    let rows = select(&strict).await;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["text"], TypedValue::from("untagged"));
+    // Or it is no condition at all
+    assert_eq!(select(&ignoring).await.len(), 2);
+}
+
+#[tokio::test]
+async fn repeated_conditions() {
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+
+    warp::test::request()
+        .method("POST")
+        .path(&format!("/{}/create/products", DEFAULT_DB))
+        .json(&serde_json::json!({ "name": "string", "price": "int" }))
+        .reply(&routes)
+        .await;
+    for (name, price) in [("pen", 5), ("book", 15), ("lamp", 20), ("chair", 40)] {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/products", DEFAULT_DB))
+            .json(&serde_json::json!({ "name": name, "price": price }))
+            .reply(&routes)
+            .await;
+    }
+
+    // The open interval (10, 20) leaves out the bounds
+    let response = warp::test::request()
+        .path(&format!("/{}/products?price=gt:10&price=lt:20", DEFAULT_DB))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let rows: Vec<ColumnSet> = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["name"], TypedValue::from("book"));
+}
+
+#[tokio::test]
+async fn read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let routes = routes(database(&dir));
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/create/items", DEFAULT_DB))
+            .json(&serde_json::json!({ "name": "string" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/items", DEFAULT_DB))
+            .json(&serde_json::json!({ "name": "ann" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    let poorly = Poorly::open(dir.path().to_path_buf()).with_read_only(true);
+    poorly.init().unwrap();
+    let routes = routes(Arc::new(Mutex::new(poorly)));
+
+    let response = warp::test::request()
+        .path(&format!("/{}/items", DEFAULT_DB))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let rows: Vec<ColumnSet> = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(rows.len(), 1);
+
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/{}/items", DEFAULT_DB))
+        .json(&serde_json::json!({ "name": "bob" }))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn create_duplicate_column() {
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/{}/create/items", DEFAULT_DB))
+        .header("content-type", "application/json")
+        .body(r#"{ "name": "string", "price": "float", "name": "int" }"#)
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn gzip() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+    let response = warp::test::request()
+        .method("POST")
+        .path(&format!("/{}/create/items", DEFAULT_DB))
+        .json(&serde_json::json!({ "name": "string" }))
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    for i in 0..100 {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/items", DEFAULT_DB))
+            .json(&serde_json::json!({ "name": format!("item{}", i) }))
+            .reply(&routes)
+            .await;
+    }
+
+    let response = warp::test::request()
+        .path(&format!("/{}/items", DEFAULT_DB))
+        .header("accept-encoding", "br;q=1.0, gzip;q=0.8")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    let mut body = String::new();
+    GzDecoder::new(&response.body()[..])
+        .read_to_string(&mut body)
+        .unwrap();
+    let rows: Vec<ColumnSet> = serde_json::from_str(&body).unwrap();
+    assert_eq!(rows.len(), 100);
+
+    // Not when refused, nor for small responses
+    let response = warp::test::request()
+        .path(&format!("/{}/items", DEFAULT_DB))
+        .header("accept-encoding", "gzip;q=0")
+        .reply(&routes)
+        .await;
+    assert!(!response.headers().contains_key("content-encoding"));
+    let response = warp::test::request()
+        .path("/now")
+        .header("accept-encoding", "gzip")
+        .reply(&routes)
+        .await;
+    assert!(!response.headers().contains_key("content-encoding"));
+    let _: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+}
+
+#[tokio::test]
+async fn unconditional_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = database(&dir);
+    let strict = routes(Arc::clone(&db));
+    let allowing = routes_with(
+        db,
+        Options {
+            allow_unconditional_writes: true,
+            ..Default::default()
+        },
+    );
+
+    warp::test::request()
+        .method("POST")
+        .path(&format!("/{}/create/items", DEFAULT_DB))
+        .json(&serde_json::json!({ "name": "string" }))
+        .reply(&strict)
+        .await;
+    let insert = || async {
+        for name in ["a", "b"] {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/{}/items", DEFAULT_DB))
+                .json(&serde_json::json!({ "name": name }))
+                .reply(&strict)
+                .await;
+        }
+    };
+    let delete = |query: &str| {
+        warp::test::request()
+            .method("DELETE")
+            .path(&format!("/{}/items{}", DEFAULT_DB, query))
+    };
+    let deleted = |response: warp::http::Response<warp::hyper::body::Bytes>| {
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice::<Vec<ColumnSet>>(response.body())
+            .unwrap()
+            .len()
+    };
+    insert().await;
+
+    let response = delete("").reply(&strict).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = warp::test::request()
+        .method("PUT")
+        .path(&format!("/{}/items", DEFAULT_DB))
+        .json(&serde_json::json!({ "name": "c" }))
+        .reply(&strict)
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    // Conditions or a confirmation let them through
+    let response = delete("?name=a").reply(&strict).await;
+    assert_eq!(deleted(response), 1);
+    let response = delete("")
+        .header(ALL_ROWS_HEADER, "true")
+        .reply(&strict)
+        .await;
+    assert_eq!(deleted(response), 1);
+
+    insert().await;
+    assert_eq!(deleted(delete("").reply(&allowing).await), 2);
+}
+
+#[tokio::test]
+async fn max_rows_override() {
+    let dir = tempfile::tempdir().unwrap();
+    let routes = routes(database(&dir));
+
+    for table in ["items", "prices"] {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/{}/create/{}", DEFAULT_DB, table))
+            .json(&serde_json::json!({ "id": "int" }))
+            .reply(&routes)
+            .await;
+        for id in 0..3 {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/{}/{}", DEFAULT_DB, table))
+                .json(&serde_json::json!({ "id": id }))
+                .reply(&routes)
+                .await;
+        }
+    }
+
+    let select = |max_rows: usize| {
+        warp::test::request()
+            .path(&format!("/{}/items", DEFAULT_DB))
+            .header(MAX_ROWS_HEADER, max_rows)
+    };
+    let response = select(2).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = select(3).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let join = |query: &str| {
+        warp::test::request()
+            .method("PUT")
+            .path(&format!("/{}/items/prices{}", DEFAULT_DB, query))
+            .json(&serde_json::json!({
+                "conditions": {},
+                "join_on": { "items.id": "prices.id" },
+            }))
+    };
+    let response = join("?max_rows=2").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = join("").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let rows: Vec<ColumnSet> = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(rows.len(), 3);
+}